@@ -0,0 +1,127 @@
+use std::fmt;
+
+/// A location in an allot_asm program. Lines and columns start at 1.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    /// Length in chars.
+    pub len: usize,
+}
+impl Span {
+    /// Creates a span from a byte index and byte length in a line.
+    pub(crate) fn new(line_number: usize, line: &str, index: usize, len: usize) -> Self {
+        Self {
+            line: line_number + 1,
+            column: line[0..index].chars().count() + 1,
+            len: line[index..index + len].chars().count(),
+        }
+    }
+
+    /// A span from the start of this one to the end of another one after it on
+    /// the same line, or this one if it is on another line.
+    pub(crate) fn to(&self, end: &Span) -> Self {
+        match end.line == self.line && end.column >= self.column {
+            true => Self {
+                line: self.line,
+                column: self.column,
+                len: end.column + end.len - self.column,
+            },
+            false => *self,
+        }
+    }
+
+    /// A zero length span right after this one.
+    pub(crate) fn end(&self) -> Self {
+        Self {
+            line: self.line,
+            column: self.column + self.len,
+            len: 0,
+        }
+    }
+}
+
+/// An error found while compiling an allot_asm program.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+    pub expected: Option<String>,
+    /// What was found instead, source text is quoted with backticks.
+    pub found: Option<String>,
+}
+impl Diagnostic {
+    pub(crate) fn new(
+        message: &str,
+        span: Span,
+        expected: Option<&str>,
+        found: Option<&str>,
+    ) -> Self {
+        Self {
+            message: message.to_string(),
+            span,
+            expected: expected.map(String::from),
+            found: found.map(String::from),
+        }
+    }
+
+    /// Renders the diagnostic with the line of source it points to.
+    ///
+    /// ```text
+    /// error: unexpected token
+    ///  --> fib.ala:6:8
+    ///   |
+    /// 6 | cpy r2 i32(5)
+    ///   |        ^^^ expected register, found `i32`
+    /// ```
+    pub fn render(&self, program: &str, path: &str) -> String {
+        let line = program
+            .split('\n')
+            .nth(self.span.line.saturating_sub(1))
+            .unwrap_or("")
+            .trim_end();
+        let number = self.span.line.to_string();
+        let pad = " ".repeat(number.len());
+
+        let mut label = String::new();
+        if let Some(expected) = &self.expected {
+            label.push_str(&format!("expected {expected}"));
+        }
+        if let Some(found) = &self.found {
+            if !label.is_empty() {
+                label.push_str(", ");
+            }
+            label.push_str(&format!("found {found}"));
+        }
+
+        let mut s = format!("error: {}\n", self.message);
+        s.push_str(&format!(
+            "{pad}--> {path}:{}:{}\n",
+            self.span.line, self.span.column
+        ));
+        s.push_str(&format!("{pad} |\n"));
+        s.push_str(&format!("{number} | {line}\n"));
+        s.push_str(&format!(
+            "{pad} | {}{} {label}\n",
+            " ".repeat(self.span.column.saturating_sub(1)),
+            "^".repeat(self.span.len.max(1))
+        ));
+        s
+    }
+}
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.span.line, self.span.column, self.message
+        )?;
+        if let Some(expected) = &self.expected {
+            write!(f, ", expected {expected}")?;
+        }
+        if let Some(found) = &self.found {
+            write!(f, ", found {found}")?;
+        }
+        Ok(())
+    }
+}
@@ -1,21 +1,40 @@
 use allot_lib::{OpPrim1, OpPrim2, Operation, RawInstruction, RawType};
 use lazy_regex::{regex, regex_captures};
 
+use crate::{Diagnostic, Span};
+
 #[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
-pub enum Token {
+pub enum TokenKind {
     Instruction(RawInstruction),
     Operation(Operation),
     Type(RawType),
     Register(u8),
     Data(String),
+    /// A label definition, `name:`.
+    Label(String),
+    /// A struct declaration, `.struct Name { field: type, ... }`, with its
+    /// name and the text of its fields.
+    Struct(String, String),
+    /// A struct, `Name`, or a field of one, `Name.field`.
+    Name(String),
+    /// Something that could not be lexed, it has already been reported.
+    Error,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+    /// The source text of the token.
+    pub text: String,
 }
 
-pub fn lex(program: &str) -> Vec<Token> {
+pub fn lex(program: &str) -> (Vec<Token>, Vec<Diagnostic>) {
     let mut token_list = Vec::new();
+    let mut diagnostics = Vec::new();
 
     let lines = program.split('\n');
-    for line in lines {
-        let line = line.trim();
+    for (line_number, line) in lines.enumerate() {
         let mut line = String::from(line);
         line.push(' ');
         let line = line.as_str();
@@ -24,294 +43,439 @@ pub fn lex(program: &str) -> Vec<Token> {
         while index < line.len() {
             let s = &line[index..line.len()];
 
+            // Ignore Comments
+            if regex!("^;").is_match(s) {
+                break;
+            }
+            // Skip Whitespace
+            if let Some(c) = s.chars().next().filter(|c| c.is_whitespace()) {
+                index += c.len_utf8();
+                continue;
+            }
+
+            // Label Matching
+            if let Some((_, name)) = regex_captures!("^([A-Za-z_][A-Za-z0-9_]*):\\s", s) {
+                token_list.push(Token {
+                    kind: TokenKind::Label(String::from(name)),
+                    span: Span::new(line_number, line, index, name.len() + 1),
+                    text: String::from(&s[0..name.len() + 1]),
+                });
+                index += name.len() + 1;
+                continue;
+            }
+
+            // Struct Matching
+            let (kind, len) = if let Some((all, name, fields)) =
+                regex_captures!("^\\.struct\\s+(\\S+)\\s*\\{([^}]*)\\}\\s", s)
+            {
+                (
+                    TokenKind::Struct(String::from(name), String::from(fields)),
+                    all.trim_end().len(),
+                )
+            }
+            else if regex!("^\\.struct\\s").is_match(s) {
+                let len = s.trim_end().len();
+                diagnostics.push(Diagnostic::new(
+                    "invalid struct declaration",
+                    Span::new(line_number, line, index, len),
+                    Some("`.struct Name { field: type, ... }`"),
+                    Some(&format!("`{}`", &s[0..len])),
+                ));
+                (TokenKind::Error, s.len())
+            }
             // Instruction Matching
-            if regex!("^nop\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::Nop));
-                index += 3;
+            else if regex!("^nop\\s").is_match(s) {
+                (TokenKind::Instruction(RawInstruction::Nop), 3)
             }
             else if regex!("^op\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::Op));
-                index += 2;
+                (TokenKind::Instruction(RawInstruction::Op), 2)
             }
             else if regex!("^mov\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::Mov));
-                index += 3;
+                (TokenKind::Instruction(RawInstruction::Mov), 3)
             }
             else if regex!("^cpy\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::Cpy));
-                index += 3;
+                (TokenKind::Instruction(RawInstruction::Cpy), 3)
             }
             else if regex!("^cast\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::Cast));
-                index += 4;
+                (TokenKind::Instruction(RawInstruction::Cast), 4)
             }
             else if regex!("^lea\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::Lea));
-                index += 3;
+                (TokenKind::Instruction(RawInstruction::Lea), 3)
             }
             else if regex!("^jmp\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::Jmp));
-                index += 3;
+                (TokenKind::Instruction(RawInstruction::Jmp), 3)
+            }
+            else if regex!("^calladdr\\s").is_match(s) {
+                (TokenKind::Instruction(RawInstruction::CallAddr), 8)
             }
             else if regex!("^ret\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::Ret));
-                index += 3;
+                (TokenKind::Instruction(RawInstruction::Ret), 3)
             }
             else if regex!("^call\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::Call));
-                index += 4;
+                (TokenKind::Instruction(RawInstruction::Call), 4)
             }
             else if regex!("^exit\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::Exit));
-                index += 4;
+                (TokenKind::Instruction(RawInstruction::Exit), 4)
             }
             else if regex!("^push\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::Push));
-                index += 4;
+                (TokenKind::Instruction(RawInstruction::Push), 4)
             }
             else if regex!("^pushcpy\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::PushCpy));
-                index += 7;
+                (TokenKind::Instruction(RawInstruction::PushCpy), 7)
             }
             else if regex!("^pop\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::Pop));
-                index += 3;
+                (TokenKind::Instruction(RawInstruction::Pop), 3)
             }
             else if regex!("^popmany\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::PopMany));
-                index += 7;
+                (TokenKind::Instruction(RawInstruction::PopMany), 7)
             }
             else if regex!("^stackcpy\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::StackCpy));
-                index += 8;
+                (TokenKind::Instruction(RawInstruction::StackCpy), 8)
             }
             else if regex!("^pushframe\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::PushFrame));
-                index += 9;
+                (TokenKind::Instruction(RawInstruction::PushFrame), 9)
             }
             else if regex!("^popframe\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::PopFrame));
-                index += 8;
+                (TokenKind::Instruction(RawInstruction::PopFrame), 8)
             }
             else if regex!("^takefrom\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::TakeFrom));
-                index += 8;
+                (TokenKind::Instruction(RawInstruction::TakeFrom), 8)
             }
             else if regex!("^giveto\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::GiveTo));
-                index += 6;
+                (TokenKind::Instruction(RawInstruction::GiveTo), 6)
+            }
+            else if regex!("^arrpush\\s").is_match(s) {
+                (TokenKind::Instruction(RawInstruction::ArrPush), 7)
+            }
+            else if regex!("^arrpop\\s").is_match(s) {
+                (TokenKind::Instruction(RawInstruction::ArrPop), 6)
+            }
+            else if regex!("^arrget\\s").is_match(s) {
+                (TokenKind::Instruction(RawInstruction::ArrGet), 6)
+            }
+            else if regex!("^arrset\\s").is_match(s) {
+                (TokenKind::Instruction(RawInstruction::ArrSet), 6)
+            }
+            else if regex!("^arrlen\\s").is_match(s) {
+                (TokenKind::Instruction(RawInstruction::ArrLen), 6)
+            }
+            else if regex!("^arrslice\\s").is_match(s) {
+                (TokenKind::Instruction(RawInstruction::ArrSlice), 8)
+            }
+            else if regex!("^structnew\\s").is_match(s) {
+                (TokenKind::Instruction(RawInstruction::StructNew), 9)
+            }
+            else if regex!("^fieldget\\s").is_match(s) {
+                (TokenKind::Instruction(RawInstruction::FieldGet), 8)
+            }
+            else if regex!("^fieldset\\s").is_match(s) {
+                (TokenKind::Instruction(RawInstruction::FieldSet), 8)
+            }
+            else if regex!("^tag\\s").is_match(s) {
+                (TokenKind::Instruction(RawInstruction::Tag), 3)
+            }
+            else if regex!("^match\\s").is_match(s) {
+                (TokenKind::Instruction(RawInstruction::Match), 5)
             }
             else if regex!("^threadcreate\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::ThreadCreate));
-                index += 12;
+                (TokenKind::Instruction(RawInstruction::ThreadCreate), 12)
             }
             else if regex!("^threadjoin\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::ThreadJoin));
-                index += 10;
+                (TokenKind::Instruction(RawInstruction::ThreadJoin), 10)
             }
             else if regex!("^assert\\s").is_match(s) {
-                token_list.push(Token::Instruction(RawInstruction::Assert));
-                index += 6;
+                (TokenKind::Instruction(RawInstruction::Assert), 6)
             }
             // Operation Matching
             else if regex!("^\\+\\+\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim1(OpPrim1::Increment)));
-                index += 2;
+                (
+                    TokenKind::Operation(Operation::Prim1(OpPrim1::Increment)),
+                    2,
+                )
             }
             else if regex!("^--\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim1(OpPrim1::Decrement)));
-                index += 2;
+                (
+                    TokenKind::Operation(Operation::Prim1(OpPrim1::Decrement)),
+                    2,
+                )
             }
             else if regex!("^!\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim1(OpPrim1::Not)));
-                index += 1;
+                (TokenKind::Operation(Operation::Prim1(OpPrim1::Not)), 1)
             }
             else if regex!("^~\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim1(OpPrim1::BitwiseNot)));
-                index += 1;
+                (
+                    TokenKind::Operation(Operation::Prim1(OpPrim1::BitwiseNot)),
+                    1,
+                )
             }
             else if regex!("^\\+\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::Add)));
-                index += 1;
+                (TokenKind::Operation(Operation::Prim2(OpPrim2::Add)), 1)
             }
             else if regex!("^-\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::Subtract)));
-                index += 1;
+                (TokenKind::Operation(Operation::Prim2(OpPrim2::Subtract)), 1)
             }
             else if regex!("^\\*\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::Multiplication)));
-                index += 1;
+                (
+                    TokenKind::Operation(Operation::Prim2(OpPrim2::Multiplication)),
+                    1,
+                )
             }
             else if regex!("^/\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::Division)));
-                index += 1;
+                (TokenKind::Operation(Operation::Prim2(OpPrim2::Division)), 1)
             }
             else if regex!("^%\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::Modulus)));
-                index += 1;
+                (TokenKind::Operation(Operation::Prim2(OpPrim2::Modulus)), 1)
             }
             else if regex!("^&&\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::And)));
-                index += 2;
+                (TokenKind::Operation(Operation::Prim2(OpPrim2::And)), 2)
             }
             else if regex!("^\\|\\|\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::Or)));
-                index += 2;
+                (TokenKind::Operation(Operation::Prim2(OpPrim2::Or)), 2)
             }
             else if regex!("^\\^\\^\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::Xor)));
-                index += 2;
+                (TokenKind::Operation(Operation::Prim2(OpPrim2::Xor)), 2)
             }
             else if regex!("^==\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::Equal)));
-                index += 2;
+                (TokenKind::Operation(Operation::Prim2(OpPrim2::Equal)), 2)
             }
             else if regex!("^!=\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::NotEqual)));
-                index += 2;
+                (TokenKind::Operation(Operation::Prim2(OpPrim2::NotEqual)), 2)
             }
             else if regex!("^>\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::Greater)));
-                index += 1;
+                (TokenKind::Operation(Operation::Prim2(OpPrim2::Greater)), 1)
             }
             else if regex!("^<\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::Less)));
-                index += 1;
+                (TokenKind::Operation(Operation::Prim2(OpPrim2::Less)), 1)
             }
             else if regex!("^>=\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::GreaterEqual)));
-                index += 2;
+                (
+                    TokenKind::Operation(Operation::Prim2(OpPrim2::GreaterEqual)),
+                    2,
+                )
             }
             else if regex!("^<=\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::LessEqual)));
-                index += 2;
+                (
+                    TokenKind::Operation(Operation::Prim2(OpPrim2::LessEqual)),
+                    2,
+                )
             }
             else if regex!("^&\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::BitwiseAnd)));
-                index += 1;
+                (
+                    TokenKind::Operation(Operation::Prim2(OpPrim2::BitwiseAnd)),
+                    1,
+                )
             }
             else if regex!("^\\|\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::BitwiseOr)));
-                index += 1;
+                (
+                    TokenKind::Operation(Operation::Prim2(OpPrim2::BitwiseOr)),
+                    1,
+                )
             }
             else if regex!("^\\^\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::BitwiseXor)));
-                index += 1;
+                (
+                    TokenKind::Operation(Operation::Prim2(OpPrim2::BitwiseXor)),
+                    1,
+                )
             }
             else if regex!("^<<\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::ShiftLeft)));
-                index += 2;
+                (
+                    TokenKind::Operation(Operation::Prim2(OpPrim2::ShiftLeft)),
+                    2,
+                )
             }
             else if regex!("^>>\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::ShiftRight)));
-                index += 2;
+                (
+                    TokenKind::Operation(Operation::Prim2(OpPrim2::ShiftRight)),
+                    2,
+                )
             }
             else if regex!("^<>\\s").is_match(s) {
-                token_list.push(Token::Operation(Operation::Prim2(OpPrim2::SameType)));
-                index += 2;
+                (TokenKind::Operation(Operation::Prim2(OpPrim2::SameType)), 2)
             }
             // Type Matching
             else if regex!("^none").is_match(s) {
-                token_list.push(Token::Type(RawType::None));
-                index += 4;
+                (TokenKind::Type(RawType::None), 4)
             }
             else if regex!("^i8").is_match(s) {
-                token_list.push(Token::Type(RawType::Int8));
-                index += 2;
+                (TokenKind::Type(RawType::Int8), 2)
             }
             else if regex!("^i16").is_match(s) {
-                token_list.push(Token::Type(RawType::Int16));
-                index += 3;
+                (TokenKind::Type(RawType::Int16), 3)
             }
             else if regex!("^i32").is_match(s) {
-                token_list.push(Token::Type(RawType::Int32));
-                index += 3;
+                (TokenKind::Type(RawType::Int32), 3)
             }
             else if regex!("^isize").is_match(s) {
-                token_list.push(Token::Type(RawType::Int));
-                index += 5;
+                (TokenKind::Type(RawType::Int), 5)
             }
             else if regex!("^i64").is_match(s) {
-                token_list.push(Token::Type(RawType::Int64));
-                index += 3;
+                (TokenKind::Type(RawType::Int64), 3)
             }
             else if regex!("^i128").is_match(s) {
-                token_list.push(Token::Type(RawType::Int128));
-                index += 4;
+                (TokenKind::Type(RawType::Int128), 4)
             }
             else if regex!("^u8").is_match(s) {
-                token_list.push(Token::Type(RawType::UInt8));
-                index += 2;
+                (TokenKind::Type(RawType::UInt8), 2)
             }
             else if regex!("^u16").is_match(s) {
-                token_list.push(Token::Type(RawType::UInt16));
-                index += 3;
+                (TokenKind::Type(RawType::UInt16), 3)
             }
             else if regex!("^u32").is_match(s) {
-                token_list.push(Token::Type(RawType::UInt32));
-                index += 3;
+                (TokenKind::Type(RawType::UInt32), 3)
             }
             else if regex!("^usize").is_match(s) {
-                token_list.push(Token::Type(RawType::UInt));
-                index += 5;
+                (TokenKind::Type(RawType::UInt), 5)
             }
             else if regex!("^u64").is_match(s) {
-                token_list.push(Token::Type(RawType::UInt64));
-                index += 3;
+                (TokenKind::Type(RawType::UInt64), 3)
             }
             else if regex!("^u128").is_match(s) {
-                token_list.push(Token::Type(RawType::UInt128));
-                index += 4;
+                (TokenKind::Type(RawType::UInt128), 4)
             }
             else if regex!("^f32").is_match(s) {
-                token_list.push(Token::Type(RawType::Float32));
-                index += 3;
+                (TokenKind::Type(RawType::Float32), 3)
             }
             else if regex!("^f64").is_match(s) {
-                token_list.push(Token::Type(RawType::Float64));
-                index += 3;
+                (TokenKind::Type(RawType::Float64), 3)
             }
             else if regex!("^chr").is_match(s) {
-                token_list.push(Token::Type(RawType::Char));
-                index += 3;
+                (TokenKind::Type(RawType::Char), 3)
             }
             else if regex!("^str").is_match(s) {
-                token_list.push(Token::Type(RawType::String));
-                index += 3;
+                (TokenKind::Type(RawType::String), 3)
             }
             else if regex!("^bool").is_match(s) {
-                token_list.push(Token::Type(RawType::Boolean));
-                index += 4;
+                (TokenKind::Type(RawType::Boolean), 4)
             }
             else if regex!("^add").is_match(s) {
-                token_list.push(Token::Type(RawType::Address));
-                index += 1;
+                (TokenKind::Type(RawType::Address), 3)
             }
             else if regex!("^reg").is_match(s) {
-                token_list.push(Token::Type(RawType::Register));
-                index += 1;
+                (TokenKind::Type(RawType::Register), 3)
+            }
+            else if regex!("^arr").is_match(s) {
+                (TokenKind::Type(RawType::Array), 3)
+            }
+            else if regex!("^map").is_match(s) {
+                (TokenKind::Type(RawType::Map), 3)
+            }
+            else if regex!("^tag").is_match(s) {
+                (TokenKind::Type(RawType::Tagged), 3)
+            }
+            // Name Matching, struct names start with an uppercase letter so
+            // they are never confused with types.
+            else if let Some((_, name)) =
+                regex_captures!("^([A-Z][A-Za-z0-9_]*(?:\\.[A-Za-z_][A-Za-z0-9_]*)?)\\s", s)
+            {
+                (TokenKind::Name(String::from(name)), name.len())
             }
             // Register Matching
             else if let Some((_, num)) = regex_captures!("^r([\\d]+)\\s", s) {
-                token_list.push(Token::Register(num.parse::<u8>().unwrap()));
-                index += 1 + num.len();
+                match num.parse::<u8>() {
+                    Ok(r) => (TokenKind::Register(r), 1 + num.len()),
+                    Err(_) => {
+                        diagnostics.push(Diagnostic::new(
+                            "invalid register",
+                            Span::new(line_number, line, index, 1 + num.len()),
+                            Some("a register from r0 to r255"),
+                            Some(&format!("`{}`", &s[0..1 + num.len()])),
+                        ));
+                        (TokenKind::Error, 1 + num.len())
+                    }
+                }
             }
             // Data Collecting
             else if regex!("^\\(").is_match(s) {
-                let ri = s.rfind(')').unwrap();
-                token_list.push(Token::Data(String::from(&s[1..ri])));
-                index += ri + 1;
-            }
-            // Ignore Comments
-            else if regex!("^;").is_match(s) {
-                break;
+                let string = matches!(
+                    token_list.last(),
+                    Some(Token {
+                        kind: TokenKind::Type(RawType::String),
+                        ..
+                    })
+                );
+                let end = match string {
+                    true => string_end(s),
+                    false => data_end(s),
+                };
+                // Data like `str(:()` that is never closed ends at the last `)`.
+                match end.or_else(|| s.rfind(')')) {
+                    Some(ri) => (TokenKind::Data(String::from(&s[1..ri])), ri + 1),
+                    None => {
+                        diagnostics.push(Diagnostic::new(
+                            "unterminated data",
+                            Span::new(line_number, line, index, 1),
+                            Some("`)`"),
+                            Some("end of line"),
+                        ));
+                        (TokenKind::Error, s.len())
+                    }
+                }
             }
             else {
-                index += 1;
-            }
+                let len = s.find(char::is_whitespace).unwrap_or(s.len());
+                diagnostics.push(Diagnostic::new(
+                    "unknown token",
+                    Span::new(line_number, line, index, len),
+                    Some("an instruction, operation, type, register or data"),
+                    Some(&format!("`{}`", &s[0..len])),
+                ));
+                (TokenKind::Error, len)
+            };
+
+            token_list.push(Token {
+                kind,
+                span: Span::new(line_number, line, index, len),
+                text: String::from(&s[0..len]),
+            });
+            index += len;
         }
     }
 
     token_list.reverse();
-    token_list
+    (token_list, diagnostics)
+}
+
+/// Finds the `)` that closes the data at the start of s. Strings in the data,
+/// like the one in `arr(str(a)b))`, are skipped over with string_end.
+pub(crate) fn data_end(s: &str) -> Option<usize> {
+    let mut depth = 0_usize;
+    let mut i = 0;
+    while let Some(c) = s[i..].chars().next() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth = depth.checked_sub(1)?;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            's' if s[i..].starts_with("str(") && s[..i].ends_with(['(', ' ', '\t']) => {
+                i += "str".len();
+                i += string_end(&s[i..])?;
+            }
+            _ => {}
+        }
+        i += c.len_utf8();
+    }
+    None
+}
+
+/// Finds the `)` that closes the string data at the start of s. A `)` that is
+/// not matched by a `(` in the string only closes it when whitespace, another
+/// `)`, or the end of the line comes after it, so `str(a)b)` is `a)b`.
+pub(crate) fn string_end(s: &str) -> Option<usize> {
+    let mut depth = 0_usize;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth > 1 => depth -= 1,
+            ')' => match s[i + 1..].chars().next() {
+                None | Some(')') => return Some(i),
+                Some(c) if c.is_whitespace() => return Some(i),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    None
 }
@@ -1,11 +1,66 @@
 extern crate core;
 
-use allot_lib::Instruction;
+use allot_lib::{Instruction, StructDef};
+pub use diagnostic::*;
+pub use symbols::*;
 
+mod diagnostic;
 mod lexer;
 mod parser;
+mod symbols;
 
-pub fn compile(program: String) -> Vec<Instruction> {
-    let tokens = lexer::lex(&program);
-    parser::parse(tokens)
+/// Compiles an allot_asm program, returns every diagnostic found if it could
+/// not be compiled. Programs that declare structs need compile_with_structs.
+pub fn compile(program: String) -> Result<Vec<Instruction>, Vec<Diagnostic>> {
+    compile_with_symbols(program).map(|(instructions, _)| instructions)
+}
+
+/// Compiles an allot_asm program, and keeps the labels and source lines of the
+/// instructions for tools like debuggers. Programs that declare structs need
+/// compile_with_structs.
+pub fn compile_with_symbols(
+    program: String,
+) -> Result<(Vec<Instruction>, Symbols), Vec<Diagnostic>> {
+    let (instructions, structs, symbols) = compile_program(program)?;
+    match structs.is_empty() {
+        true => Ok((instructions, symbols)),
+        false => Err(structs
+            .into_iter()
+            .map(|(_, span)| {
+                Diagnostic::new(
+                    "structs are not kept, compile with compile_with_structs",
+                    span,
+                    None,
+                    None,
+                )
+            })
+            .collect()),
+    }
+}
+
+/// Compiles an allot_asm program, and keeps the structs it declares along with
+/// the labels and source lines of the instructions.
+pub fn compile_with_structs(
+    program: String,
+) -> Result<(Vec<Instruction>, Vec<StructDef>, Symbols), Vec<Diagnostic>> {
+    let (instructions, structs, symbols) = compile_program(program)?;
+    let structs = structs.into_iter().map(|(def, _)| def).collect();
+    Ok((instructions, structs, symbols))
+}
+
+#[allow(clippy::type_complexity)]
+fn compile_program(
+    program: String,
+) -> Result<(Vec<Instruction>, Vec<(StructDef, Span)>, Symbols), Vec<Diagnostic>> {
+    let (tokens, mut diagnostics) = lexer::lex(&program);
+    let (instructions, structs, symbols, parse_diagnostics) = parser::parse(tokens);
+
+    diagnostics.extend(parse_diagnostics);
+    if diagnostics.is_empty() {
+        Ok((instructions, structs, symbols))
+    }
+    else {
+        diagnostics.sort_by_key(|d| d.span);
+        Err(diagnostics)
+    }
 }
@@ -1,271 +1,676 @@
-use allot_lib::{Instruction, RawInstruction, RawType, Register, Type};
+use std::collections::{BTreeMap, HashMap};
 
-use crate::lexer::Token;
+use allot_lib::{
+    Instruction, MapKey, Operation, RawInstruction, RawType, Register, StructDef, Type, MAX_DEPTH,
+};
+use lazy_regex::{regex, regex_captures};
 
-pub fn parse(tokens: Vec<Token>) -> Vec<Instruction> {
+use crate::{
+    lexer::{self, Token, TokenKind},
+    Diagnostic, Span, Symbols,
+};
+
+/// Parses the tokens in two passes, the first parses instructions and collects
+/// labels, the second resolves label references into addresses. Structs are
+/// returned with where they are declared.
+#[allow(clippy::type_complexity)]
+pub fn parse(
+    tokens: Vec<Token>,
+) -> (
+    Vec<Instruction>,
+    Vec<(StructDef, Span)>,
+    Symbols,
+    Vec<Diagnostic>,
+) {
     let mut p = Parser::new(tokens);
     p.parse();
+    p.resolve();
+
+    let symbols = Symbols {
+        labels: p
+            .labels
+            .into_iter()
+            .map(|(name, (address, _))| (name, address))
+            .collect(),
+        lines: p.lines,
+    };
+    let structs = p
+        .structs
+        .into_iter()
+        .map(|def| {
+            let span = p.struct_ids[&def.name].1;
+            (def, span)
+        })
+        .collect();
+    (p.instructions, structs, symbols, p.diagnostics)
+}
 
-    p.instructions
+/// Why the items of an array, map, or tagged value could not be parsed.
+enum InvalidItems {
+    Data,
+    TooDeep,
+}
+
+/// A use of a label that needs to be resolved into an address.
+struct Reference {
+    instruction: usize,
+    /// Which address of the instruction it is, for instructions with more
+    /// than one.
+    slot: usize,
+    name: String,
+    span: Span,
 }
 
 struct Parser {
     tokens: Vec<Token>,
     instructions: Vec<Instruction>,
+    diagnostics: Vec<Diagnostic>,
+    /// Label name to (address, span of definition).
+    labels: HashMap<String, (usize, Span)>,
+    references: Vec<Reference>,
+    structs: Vec<StructDef>,
+    /// Struct name to (id, span of declaration).
+    struct_ids: HashMap<String, (usize, Span)>,
+    /// Source line of each instruction.
+    lines: Vec<usize>,
+    last_span: Span,
+    /// How many arrays, maps, or tagged values the values being parsed are in.
+    depth: usize,
+    /// Set when values are nested deeper than MAX_DEPTH, so the values they are
+    /// in can say so.
+    too_deep: bool,
+    /// Set when an error token from the lexer is reached, so that it is not
+    /// reported twice.
+    lexer_error: bool,
 }
 impl Parser {
     fn new(tokens: Vec<Token>) -> Self {
         Self {
             tokens,
             instructions: Vec::new(),
+            diagnostics: Vec::new(),
+            labels: HashMap::new(),
+            references: Vec::new(),
+            structs: Vec::new(),
+            struct_ids: HashMap::new(),
+            lines: Vec::new(),
+            last_span: Span::default(),
+            depth: 0,
+            too_deep: false,
+            lexer_error: false,
         }
     }
 
     fn parse(&mut self) {
-        while !self.tokens.is_empty() {
-            let t = self.tokens.pop().expect("No token?");
+        while let Some(t) = self.tokens.pop() {
+            self.last_span = t.span;
+
+            let result = match t.kind {
+                TokenKind::Instruction(i) => self.parse_instruction(i, t.span),
+                TokenKind::Label(name) => self.define_label(name, t.span),
+                TokenKind::Struct(name, fields) => self.define_struct(name, &fields, t.span),
+                TokenKind::Error => {
+                    self.lexer_error = true;
+                    Err(Parser::unexpected(&t, "instruction"))
+                }
+                _ => Err(Parser::unexpected(&t, "instruction")),
+            };
 
-            match t {
-                Token::Instruction(i) => self.parse_instruction(i),
-                _ => panic!("Parse Error: Expected instruction."),
+            if let Err(d) = result {
+                if !std::mem::take(&mut self.lexer_error) {
+                    self.diagnostics.push(d);
+                }
+                // References from an instruction that failed to parse.
+                let len = self.instructions.len();
+                self.references.retain(|r| r.instruction < len);
+                self.recover();
             }
         }
     }
 
-    fn parse_instruction(&mut self, i: RawInstruction) {
-        match i {
-            RawInstruction::Nop => self.instructions.push(Instruction::Nop),
-            RawInstruction::Op => match (self.tokens.pop(), self.tokens.pop(), self.tokens.pop()) {
-                (Some(t1), Some(t2), Some(t3)) => match (t1, t2, t3) {
-                    (Token::Operation(op), Token::Register(r1), Token::Register(r2)) => {
-                        self.instructions.push(Instruction::Op(
-                            op,
-                            [
-                                Register::try_from(r1).unwrap(),
-                                Register::try_from(r2).unwrap(),
-                            ],
-                        ))
-                    }
-                    _ => panic!("Parse Error: Unexpected token."),
-                },
-                _ => panic!("No token?"),
-            },
-            RawInstruction::Mov => match (self.tokens.pop(), self.tokens.pop()) {
-                (Some(t1), Some(t2)) => match (t1, t2) {
-                    (Token::Register(r), Token::Type(t)) => {
-                        self.instructions.push(Instruction::Mov(
-                            Register::try_from(r).unwrap(),
-                            Parser::parse_type(t, self.tokens.pop().unwrap()),
-                        ))
-                    }
-                    _ => panic!("Parse Error: Unexpected token."),
-                },
-                _ => panic!("No token?"),
-            },
-            RawInstruction::Cpy => match (self.tokens.pop(), self.tokens.pop()) {
-                (Some(t1), Some(t2)) => match (t1, t2) {
-                    (Token::Register(r1), Token::Register(r2)) => {
-                        self.instructions.push(Instruction::Cpy(
-                            Register::try_from(r1).unwrap(),
-                            Register::try_from(r2).unwrap(),
-                        ))
-                    }
-                    _ => panic!("Parse Error: Unexpected token."),
-                },
-                _ => panic!("No token?"),
-            },
-            RawInstruction::Cast => match (self.tokens.pop(), self.tokens.pop()) {
-                (Some(t1), Some(t2)) => match (t1, t2) {
-                    (Token::Register(r), Token::Type(t)) => self
-                        .instructions
-                        .push(Instruction::Cast(Register::try_from(r).unwrap(), t)),
-                    _ => panic!("Parse Error: Unexpected token."),
-                },
-                _ => panic!("No token?"),
-            },
-            RawInstruction::Lea => match (self.tokens.pop(), self.tokens.pop()) {
-                (Some(t1), Some(t2)) => match (t1, t2) {
-                    (Token::Register(r), Token::Data(d)) => {
-                        self.instructions.push(Instruction::Lea(
-                            Register::try_from(r).unwrap(),
-                            d.parse::<usize>().unwrap(),
-                        ))
-                    }
-                    _ => panic!("Parse Error: Expected data."),
-                },
-                _ => panic!("No token?"),
-            },
-            RawInstruction::Jmp => match (self.tokens.pop(), self.tokens.pop()) {
-                (Some(t1), Some(t2)) => match (t1, t2) {
-                    (Token::Register(r), Token::Type(t)) => {
-                        let r = Register::try_from(r).unwrap();
-                        let r = match r {
-                            Register::None => None,
-                            _ => Some(r),
-                        };
-                        self.instructions.push(Instruction::Jmp(
-                            r,
-                            Parser::parse_type(t, self.tokens.pop().unwrap()),
-                        ))
-                    }
-                    _ => panic!("Parse Error: Unexpected token."),
-                },
-                _ => panic!("No token?"),
-            },
-            RawInstruction::Ret => self.instructions.push(Instruction::Ret),
-            RawInstruction::Call => match self.tokens.pop() {
-                None => panic!("No token?"),
-                Some(t) => match t {
-                    Token::Data(d) => self.instructions.push(Instruction::Call(d)),
-                    _ => panic!("Parse Error: Expected data."),
-                },
-            },
-            RawInstruction::Exit => match self.tokens.pop() {
-                None => panic!("No token?"),
-                Some(t) => match t {
-                    Token::Type(t) => self.instructions.push(Instruction::Exit(
-                        Parser::parse_type(t, self.tokens.pop().unwrap()),
-                    )),
-                    _ => panic!("Parse Error: Expected type."),
-                },
-            },
-            RawInstruction::Push => match self.tokens.pop() {
-                None => panic!("No token?"),
-                Some(t) => match t {
-                    Token::Register(r) => self
-                        .instructions
-                        .push(Instruction::Push(Register::try_from(r).unwrap())),
-                    _ => panic!("Parse Error: Expected register."),
-                },
-            },
-            RawInstruction::PushCpy => match self.tokens.pop() {
-                None => panic!("No token?"),
-                Some(t) => match t {
-                    Token::Register(r) => self
-                        .instructions
-                        .push(Instruction::PushCpy(Register::try_from(r).unwrap())),
-                    _ => panic!("Parse Error: Expected register."),
-                },
-            },
-            RawInstruction::Pop => match self.tokens.pop() {
-                None => panic!("No token?"),
-                Some(t) => match t {
-                    Token::Register(r) => {
-                        let r = Register::try_from(r).unwrap();
-                        let r = match r {
-                            Register::None => None,
-                            _ => Some(r),
-                        };
-                        self.instructions.push(Instruction::Pop(r))
-                    }
-                    _ => panic!("Parse Error: Expected register."),
-                },
-            },
-            RawInstruction::PopMany => match self.tokens.pop() {
-                None => panic!("No token?"),
-                Some(t) => match t {
-                    Token::Type(t) => {
-                        self.instructions
-                            .push(Instruction::PopMany(Parser::parse_type(
-                                t,
-                                self.tokens.pop().unwrap(),
-                            )))
-                    }
-                    _ => panic!("Parse Error: Expected type."),
-                },
-            },
-            RawInstruction::StackCpy => match (self.tokens.pop(), self.tokens.pop()) {
-                (Some(t1), Some(t2)) => match (t1, t2) {
-                    (Token::Register(r), Token::Type(t)) => {
-                        self.instructions.push(Instruction::StackCpy(
-                            Register::try_from(r).unwrap(),
-                            Parser::parse_type(t, self.tokens.pop().unwrap()),
-                        ))
-                    }
-                    _ => panic!("Parse Error: Unexpected token."),
-                },
-                _ => panic!("No token?"),
-            },
-            RawInstruction::PushFrame => match self.tokens.pop() {
-                None => panic!("No token?"),
-                Some(t) => match t {
-                    Token::Data(d) => self
-                        .instructions
-                        .push(Instruction::PushFrame(d.parse::<bool>().unwrap())),
-                    _ => panic!("Parse Error: Expected data."),
-                },
-            },
-            RawInstruction::PopFrame => self.instructions.push(Instruction::PopFrame),
-            RawInstruction::TakeFrom => self.instructions.push(Instruction::TakeFrom),
-            RawInstruction::GiveTo => self.instructions.push(Instruction::GiveTo),
-            RawInstruction::ThreadCreate => {
-                match self.tokens.pop() {
-                    None => panic!("No token?"),
-                    Some(t) => {
-                        match t {
-                            Token::Type(t) => self.instructions.push(Instruction::ThreadCreate(
-                                Parser::parse_type(t, self.tokens.pop().unwrap()),
-                            )),
-                            _ => panic!("Parse Error: Expected type."),
-                        }
-                    }
+    fn define_label(&mut self, name: String, span: Span) -> Result<(), Diagnostic> {
+        if let Some((_, first)) = self.labels.get(&name) {
+            return Err(Diagnostic::new(
+                &format!("label `{}` is already defined on line {}", name, first.line),
+                span,
+                None,
+                None,
+            ));
+        }
+
+        self.labels.insert(name, (self.instructions.len(), span));
+        Ok(())
+    }
+
+    /// Declares a struct, it has to be declared before it is used.
+    fn define_struct(&mut self, name: String, fields: &str, span: Span) -> Result<(), Diagnostic> {
+        if !regex!("^[A-Z][A-Za-z0-9_]*$").is_match(&name) {
+            return Err(Diagnostic::new(
+                "invalid struct name",
+                span,
+                Some("a name that starts with an uppercase letter"),
+                Some(&format!("`{name}`")),
+            ));
+        }
+        if let Some((_, first)) = self.struct_ids.get(&name) {
+            return Err(Diagnostic::new(
+                &format!(
+                    "struct `{}` is already defined on line {}",
+                    name, first.line
+                ),
+                span,
+                None,
+                None,
+            ));
+        }
+
+        let mut def = StructDef {
+            name,
+            fields: Vec::new(),
+        };
+        // A trailing comma is allowed.
+        let fields = fields.trim().trim_end_matches(',');
+        for field in fields.split(',').filter(|_| !fields.is_empty()) {
+            let field = field.trim();
+            let (field_name, raw) =
+                match regex_captures!("^([A-Za-z_][A-Za-z0-9_]*)\\s*:\\s*(\\S+)$", field) {
+                    Some((_, field_name, t)) => (field_name, Parser::field_type(t)),
+                    None => (field, None),
+                };
+            let raw = raw.ok_or_else(|| {
+                Diagnostic::new(
+                    "invalid field",
+                    span,
+                    Some("`name: type`"),
+                    Some(&format!("`{field}`")),
+                )
+            })?;
+            if def.field(field_name).is_some() {
+                return Err(Diagnostic::new(
+                    &format!("field `{field_name}` is declared twice"),
+                    span,
+                    None,
+                    None,
+                ));
+            }
+            def.fields.push((field_name.to_string(), raw));
+        }
+
+        self.struct_ids
+            .insert(def.name.clone(), (self.structs.len(), span));
+        self.structs.push(def);
+        Ok(())
+    }
+
+    /// Parses a type name, like `f64`.
+    fn field_type(t: &str) -> Option<RawType> {
+        let (tokens, diagnostics) = lexer::lex(t);
+        match (tokens.as_slice(), diagnostics.is_empty()) {
+            (
+                [Token {
+                    kind: TokenKind::Type(raw),
+                    text,
+                    ..
+                }],
+                true,
+            ) if text == t => Some(*raw),
+            _ => None,
+        }
+    }
+
+    /// Replaces label references with the address of the label.
+    fn resolve(&mut self) {
+        for r in self.references.drain(..) {
+            let address = match self.labels.get(&r.name) {
+                None => {
+                    self.diagnostics.push(Diagnostic::new(
+                        "undefined label",
+                        r.span,
+                        Some("a defined label"),
+                        Some(&format!("`{}`", r.name)),
+                    ));
+                    continue;
                 }
+                Some((address, _)) => *address,
+            };
+
+            let slot = match self.instructions.get_mut(r.instruction) {
+                Some(Instruction::Lea(_, a)) => Some(a),
+                Some(Instruction::Match(_, _, table)) => table.get_mut(r.slot),
+                Some(
+                    Instruction::Mov(_, t)
+                    | Instruction::Jmp(_, t)
+                    | Instruction::CallAddr(t)
+                    | Instruction::Exit(t)
+                    | Instruction::PopMany(t)
+                    | Instruction::StackCpy(_, t)
+                    | Instruction::ThreadCreate(t)
+                    | Instruction::Assert(_, t),
+                ) => {
+                    *t = Type::Address(address);
+                    continue;
+                }
+                _ => None,
+            };
+            match slot {
+                Some(a) => *a = address,
+                None => self.diagnostics.push(Diagnostic::new(
+                    "unresolved label",
+                    r.span,
+                    Some("an instruction that takes an address"),
+                    Some(&format!("`{}`", r.name)),
+                )),
+            }
+        }
+    }
+
+    /// Parses an address, or records a reference if it is a label.
+    fn address(&mut self, d: &str, t: &Token) -> Result<usize, Diagnostic> {
+        self.address_at(d, t, 0)
+    }
+
+    /// Parses an address, the slot is which address of the instruction it is.
+    fn address_at(&mut self, d: &str, t: &Token, slot: usize) -> Result<usize, Diagnostic> {
+        if let Ok(address) = d.parse::<usize>() {
+            return Ok(address);
+        }
+        if !regex!("^[A-Za-z_][A-Za-z0-9_]*$").is_match(d) {
+            return Err(Parser::invalid(t, "an address or label"));
+        }
+
+        self.references.push(Reference {
+            instruction: self.instructions.len(),
+            slot,
+            name: d.to_string(),
+            span: t.span,
+        });
+        Ok(0)
+    }
+
+    /// Skips tokens until the next instruction, label, or struct.
+    fn recover(&mut self) {
+        while let Some(t) = self.tokens.last() {
+            if let TokenKind::Instruction(_) | TokenKind::Label(_) | TokenKind::Struct(..) = t.kind
+            {
+                break;
             }
-            RawInstruction::ThreadJoin => match self.tokens.pop() {
-                None => panic!("No token?"),
-                Some(t) => match t {
-                    Token::Register(r) => self
-                        .instructions
-                        .push(Instruction::ThreadJoin(Register::try_from(r).unwrap())),
-                    _ => panic!("Parse Error: Expected register."),
-                },
+            self.tokens.pop();
+        }
+    }
+
+    /// Takes the next token, instructions, labels, and structs are left in
+    /// place so that the parser can recover at them.
+    fn next(&mut self, expected: &str) -> Result<Token, Diagnostic> {
+        match self.tokens.last() {
+            None => Err(Diagnostic::new(
+                "unexpected end of program",
+                self.last_span.end(),
+                Some(expected),
+                Some("end of program"),
+            )),
+            Some(t) => match t.kind {
+                TokenKind::Instruction(_) | TokenKind::Label(_) | TokenKind::Struct(..) => {
+                    Err(Parser::unexpected(t, expected))
+                }
+                TokenKind::Error => {
+                    self.lexer_error = true;
+                    let t = self.tokens.pop().unwrap();
+                    Err(Parser::unexpected(&t, expected))
+                }
+                _ => {
+                    let t = self.tokens.pop().unwrap();
+                    self.last_span = t.span;
+                    Ok(t)
+                }
+            },
+        }
+    }
+
+    fn unexpected(t: &Token, expected: &str) -> Diagnostic {
+        Diagnostic::new(
+            "unexpected token",
+            t.span,
+            Some(expected),
+            Some(&format!("`{}`", t.text)),
+        )
+    }
+
+    fn invalid(t: &Token, expected: &str) -> Diagnostic {
+        Diagnostic::new(
+            "invalid value",
+            t.span,
+            Some(expected),
+            Some(&format!("`{}`", t.text)),
+        )
+    }
+
+    fn register(&mut self) -> Result<Register, Diagnostic> {
+        let t = self.next("register")?;
+        match t.kind {
+            TokenKind::Register(r) => Register::try_from(r)
+                .map_err(|_| Parser::invalid(&t, "a register from r0 to r29 or r255")),
+            _ => Err(Parser::unexpected(&t, "register")),
+        }
+    }
+
+    fn opt_register(&mut self) -> Result<Option<Register>, Diagnostic> {
+        let r = self.register()?;
+        Ok(match r {
+            Register::None => None,
+            _ => Some(r),
+        })
+    }
+
+    fn operation(&mut self) -> Result<Operation, Diagnostic> {
+        let t = self.next("operation")?;
+        match t.kind {
+            TokenKind::Operation(op) => Ok(op),
+            _ => Err(Parser::unexpected(&t, "operation")),
+        }
+    }
+
+    fn raw_type(&mut self) -> Result<RawType, Diagnostic> {
+        let t = self.next("type")?;
+        match t.kind {
+            TokenKind::Type(raw) => Ok(raw),
+            _ => Err(Parser::unexpected(&t, "type")),
+        }
+    }
+
+    fn data(&mut self) -> Result<(String, Token), Diagnostic> {
+        let t = self.next("data")?;
+        match &t.kind {
+            TokenKind::Data(d) => Ok((d.clone(), t)),
+            _ => Err(Parser::unexpected(&t, "data")),
+        }
+    }
+
+    /// Parses a struct name into its id.
+    fn struct_id(&mut self) -> Result<usize, Diagnostic> {
+        let t = self.next("struct")?;
+        match &t.kind {
+            TokenKind::Name(name) if !name.contains('.') => self.lookup_struct(name, &t),
+            _ => Err(Parser::unexpected(&t, "struct")),
+        }
+    }
+
+    /// Parses `Name.field` into the struct id and the index of the field.
+    fn field(&mut self) -> Result<(usize, usize), Diagnostic> {
+        let t = self.next("field")?;
+        let (name, field) = match &t.kind {
+            TokenKind::Name(name) => match name.split_once('.') {
+                Some(split) => split,
+                None => return Err(Parser::unexpected(&t, "field")),
             },
-            RawInstruction::Assert => match (self.tokens.pop(), self.tokens.pop()) {
-                (Some(t1), Some(t2)) => match (t1, t2) {
-                    (Token::Register(r), Token::Type(t)) => {
-                        self.instructions.push(Instruction::Assert(
-                            Register::try_from(r).unwrap(),
-                            Parser::parse_type(t, self.tokens.pop().unwrap()),
-                        ))
-                    }
-                    _ => panic!("Parse Error: Unexpected token."),
-                },
-                _ => panic!("No token?"),
+            _ => return Err(Parser::unexpected(&t, "field")),
+        };
+
+        let id = self.lookup_struct(name, &t)?;
+        match self.structs[id].field(field) {
+            Some(index) => Ok((id, index)),
+            None => Err(Diagnostic::new(
+                "undefined field",
+                t.span,
+                Some(&format!("a field of `{name}`")),
+                Some(&format!("`{field}`")),
+            )),
+        }
+    }
+
+    fn lookup_struct(&self, name: &str, t: &Token) -> Result<usize, Diagnostic> {
+        match self.struct_ids.get(name) {
+            Some((id, _)) => Ok(*id),
+            None => Err(Diagnostic::new(
+                "undefined struct",
+                t.span,
+                Some("a struct declared before it is used"),
+                Some(&format!("`{name}`")),
+            )),
+        }
+    }
+
+    fn parse_instruction(&mut self, i: RawInstruction, span: Span) -> Result<(), Diagnostic> {
+        let instruction = match i {
+            RawInstruction::Nop => Instruction::Nop,
+            RawInstruction::Op => {
+                Instruction::Op(self.operation()?, [self.register()?, self.register()?])
+            }
+            RawInstruction::Mov => Instruction::Mov(self.register()?, self.parse_type()?),
+            RawInstruction::Cpy => Instruction::Cpy(self.register()?, self.register()?),
+            RawInstruction::Cast => Instruction::Cast(self.register()?, self.raw_type()?),
+            RawInstruction::Lea => {
+                let r = self.register()?;
+                let (d, t) = self.data()?;
+                Instruction::Lea(r, self.address(&d, &t)?)
+            }
+            RawInstruction::Jmp => Instruction::Jmp(self.opt_register()?, self.parse_type()?),
+            RawInstruction::CallAddr => Instruction::CallAddr(self.parse_type()?),
+            RawInstruction::Ret => Instruction::Ret,
+            RawInstruction::Call => Instruction::Call(self.data()?.0),
+            RawInstruction::Exit => Instruction::Exit(self.parse_type()?),
+            RawInstruction::Push => Instruction::Push(self.register()?),
+            RawInstruction::PushCpy => Instruction::PushCpy(self.register()?),
+            RawInstruction::Pop => Instruction::Pop(self.opt_register()?),
+            RawInstruction::PopMany => Instruction::PopMany(self.parse_type()?),
+            RawInstruction::StackCpy => Instruction::StackCpy(self.register()?, self.parse_type()?),
+            RawInstruction::PushFrame => {
+                let (d, t) = self.data()?;
+                let isolated = d
+                    .parse::<bool>()
+                    .map_err(|_| Parser::invalid(&t, "a bool"))?;
+                Instruction::PushFrame(isolated)
+            }
+            RawInstruction::PopFrame => Instruction::PopFrame,
+            RawInstruction::TakeFrom => Instruction::TakeFrom,
+            RawInstruction::GiveTo => Instruction::GiveTo,
+            RawInstruction::ArrPush => Instruction::ArrPush(self.register()?, self.register()?),
+            RawInstruction::ArrPop => Instruction::ArrPop(self.register()?, self.register()?),
+            RawInstruction::ArrGet => {
+                Instruction::ArrGet(self.register()?, self.register()?, self.index()?)
+            }
+            RawInstruction::ArrSet => {
+                Instruction::ArrSet(self.register()?, self.index()?, self.register()?)
+            }
+            RawInstruction::ArrLen => Instruction::ArrLen(self.register()?, self.register()?),
+            RawInstruction::ArrSlice => {
+                Instruction::ArrSlice(self.register()?, self.index()?, self.index()?)
+            }
+            RawInstruction::StructNew => {
+                Instruction::StructNew(self.register()?, self.struct_id()?)
+            }
+            RawInstruction::FieldGet => {
+                let (reg, rec) = (self.register()?, self.register()?);
+                let (id, field) = self.field()?;
+                Instruction::FieldGet(reg, rec, id, field)
+            }
+            RawInstruction::FieldSet => {
+                let rec = self.register()?;
+                let (id, field) = self.field()?;
+                Instruction::FieldSet(rec, id, field, self.register()?)
+            }
+            RawInstruction::Tag => {
+                let reg = self.register()?;
+                let (d, t) = self.data()?;
+                let tag = d
+                    .parse::<usize>()
+                    .map_err(|_| Parser::invalid(&t, "a usize tag"))?;
+                Instruction::Tag(reg, tag)
+            }
+            RawInstruction::Match => {
+                let (reg, val) = (self.register()?, self.register()?);
+                let (d, t) = self.data()?;
+                let table = d
+                    .split_whitespace()
+                    .enumerate()
+                    .map(|(slot, a)| self.address_at(a, &t, slot))
+                    .collect::<Result<_, _>>()?;
+                Instruction::Match(reg, val, table)
+            }
+            RawInstruction::ThreadCreate => Instruction::ThreadCreate(self.parse_type()?),
+            RawInstruction::ThreadJoin => Instruction::ThreadJoin(self.register()?),
+            RawInstruction::Assert => Instruction::Assert(self.register()?, self.parse_type()?),
+            RawInstruction::Dbg | RawInstruction::Dump => {
+                return Err(Diagnostic::new(
+                    "instruction is not supported by allot_asm",
+                    self.last_span,
+                    None,
+                    None,
+                ))
+            }
+        };
+
+        self.instructions.push(instruction);
+        self.lines.push(span.line);
+        Ok(())
+    }
+
+    fn parse_type(&mut self) -> Result<Type, Diagnostic> {
+        let raw = self.raw_type()?;
+        let (d, t) = self.data()?;
+
+        let parsed = match raw {
+            RawType::None => Some(Type::None),
+            RawType::Int8 => d.parse::<i8>().ok().map(Type::Int8),
+            RawType::Int16 => d.parse::<i16>().ok().map(Type::Int16),
+            RawType::Int32 => d.parse::<i32>().ok().map(Type::Int32),
+            RawType::Int => d.parse::<isize>().ok().map(Type::Int),
+            RawType::Int64 => d.parse::<i64>().ok().map(Type::Int64),
+            RawType::Int128 => d.parse::<i128>().ok().map(Type::Int128),
+            RawType::UInt8 => d.parse::<u8>().ok().map(Type::UInt8),
+            RawType::UInt16 => d.parse::<u16>().ok().map(Type::UInt16),
+            RawType::UInt32 => d.parse::<u32>().ok().map(Type::UInt32),
+            RawType::UInt => d.parse::<usize>().ok().map(Type::UInt),
+            RawType::UInt64 => d.parse::<u64>().ok().map(Type::UInt64),
+            RawType::UInt128 => d.parse::<u128>().ok().map(Type::UInt128),
+            RawType::Float32 => d.parse::<f32>().ok().map(Type::Float32),
+            RawType::Float64 => d.parse::<f64>().ok().map(Type::Float64),
+            RawType::Char => d.parse::<char>().ok().map(Type::Char),
+            RawType::String => Some(Type::String(d)),
+            RawType::Boolean => d.parse::<bool>().ok().map(Type::Boolean),
+            RawType::Address => Some(Type::Address(self.address(&d, &t)?)),
+            RawType::Register => d
+                .parse::<u8>()
+                .ok()
+                .and_then(|r| Register::try_from(r).ok())
+                .map(Type::Register),
+            RawType::Pointer => {
+                return Err(Diagnostic::new(
+                    "pointers cannot be written in allot_asm",
+                    t.span,
+                    None,
+                    None,
+                ))
+            }
+            RawType::Struct => {
+                return Err(Diagnostic::new(
+                    "records cannot be written in allot_asm",
+                    t.span,
+                    Some("an array turned into a record with structnew"),
+                    None,
+                ))
+            }
+            RawType::Array => self
+                .nested(Parser::items(&d, self.depth), &t)?
+                .map(Type::Array),
+            RawType::Map => self.nested(Parser::map(&d, self.depth), &t)?,
+            RawType::Tagged => match self.nested(Parser::items(&d, self.depth), &t)?.as_deref() {
+                Some([Type::UInt(tag), t]) => Some(Type::Tagged(*tag, Box::new(t.clone()))),
+                _ => None,
             },
-            RawInstruction::Dbg => {
-                todo!()
+        };
+
+        parsed.ok_or_else(|| Parser::invalid(&t, &format!("{:?} data", raw)))
+    }
+
+    /// Parses an index, a usize or a register.
+    fn index(&mut self) -> Result<Type, Diagnostic> {
+        let start = self.tokens.last().map(|t| t.span);
+        match self.parse_type()? {
+            i @ (Type::UInt(_) | Type::Register(_)) => Ok(i),
+            found => Err(Diagnostic::new(
+                "invalid value",
+                start.map_or(self.last_span, |s| s.to(&self.last_span)),
+                Some("a usize or register index"),
+                Some(&format!("`{:?}`", found.to_raw())),
+            )),
+        }
+    }
+
+    /// Turns items that are nested too deeply into a diagnostic, and other
+    /// invalid items into None.
+    fn nested<T>(
+        &mut self,
+        items: Result<T, InvalidItems>,
+        t: &Token,
+    ) -> Result<Option<T>, Diagnostic> {
+        match items {
+            Ok(items) => Ok(Some(items)),
+            Err(InvalidItems::Data) => Ok(None),
+            Err(InvalidItems::TooDeep) => {
+                self.too_deep = true;
+                Err(Diagnostic::new(
+                    "values are nested too deeply",
+                    t.span,
+                    Some(&format!("at most {MAX_DEPTH} levels of nesting")),
+                    None,
+                ))
             }
-            RawInstruction::Dump => {
-                todo!()
+        }
+    }
+
+    /// Parses the items of an array or map, which are types separated by
+    /// whitespace, like `arr(u8(1) str(two))`. The array or map is in depth
+    /// other values.
+    fn items(d: &str, depth: usize) -> Result<Vec<Type>, InvalidItems> {
+        let mut items = Vec::new();
+        let mut rest = d.trim_start();
+        while !rest.is_empty() {
+            let open = rest.find('(').ok_or(InvalidItems::Data)?;
+            let end = match rest[..open].trim_end() {
+                "str" => lexer::string_end(&rest[open..]),
+                _ => lexer::data_end(&rest[open..]),
+            };
+            let (item, after) = rest.split_at(open + end.ok_or(InvalidItems::Data)? + 1);
+            items.push(Parser::array_item(item, depth)?);
+            rest = after.trim_start();
+        }
+        Ok(items)
+    }
+
+    /// Parses keys followed by their values, like `map(str(one) u8(1))`.
+    fn map(d: &str, depth: usize) -> Result<Type, InvalidItems> {
+        let items = Parser::items(d, depth)?;
+        if items.len() % 2 != 0 {
+            return Err(InvalidItems::Data);
+        }
+
+        let mut map = BTreeMap::new();
+        let mut items = items.into_iter();
+        while let (Some(k), Some(t)) = (items.next(), items.next()) {
+            let k = MapKey::new(k).map_err(|_| InvalidItems::Data)?;
+            if map.insert(k, t).is_some() {
+                return Err(InvalidItems::Data);
             }
         }
+        Ok(Type::Map(map))
     }
 
-    fn parse_type(t: RawType, next: Token) -> Type {
-        match (t, next) {
-            (RawType::None, Token::Data(_)) => Type::None,
-            (RawType::Int8, Token::Data(d)) => Type::Int8(d.parse::<i8>().unwrap()),
-            (RawType::Int16, Token::Data(d)) => Type::Int16(d.parse::<i16>().unwrap()),
-            (RawType::Int32, Token::Data(d)) => Type::Int32(d.parse::<i32>().unwrap()),
-            (RawType::Int, Token::Data(d)) => Type::Int(d.parse::<isize>().unwrap()),
-            (RawType::Int64, Token::Data(d)) => Type::Int64(d.parse::<i64>().unwrap()),
-            (RawType::Int128, Token::Data(d)) => Type::Int128(d.parse::<i128>().unwrap()),
-            (RawType::UInt8, Token::Data(d)) => Type::UInt8(d.parse::<u8>().unwrap()),
-            (RawType::UInt16, Token::Data(d)) => Type::UInt16(d.parse::<u16>().unwrap()),
-            (RawType::UInt32, Token::Data(d)) => Type::UInt32(d.parse::<u32>().unwrap()),
-            (RawType::UInt, Token::Data(d)) => Type::UInt(d.parse::<usize>().unwrap()),
-            (RawType::UInt64, Token::Data(d)) => Type::UInt64(d.parse::<u64>().unwrap()),
-            (RawType::UInt128, Token::Data(d)) => Type::UInt128(d.parse::<u128>().unwrap()),
-            (RawType::Float32, Token::Data(d)) => Type::Float32(d.parse::<f32>().unwrap()),
-            (RawType::Float64, Token::Data(d)) => Type::Float64(d.parse::<f64>().unwrap()),
-            (RawType::Char, Token::Data(d)) => Type::Char(d.parse::<char>().unwrap()),
-            (RawType::String, Token::Data(d)) => Type::String(d),
-            (RawType::Boolean, Token::Data(d)) => Type::Boolean(d.parse::<bool>().unwrap()),
-            (RawType::Address, Token::Data(d)) => Type::Address(d.parse::<usize>().unwrap()),
-            (RawType::Register, Token::Data(d)) => {
-                Type::Register(Register::try_from(d.parse::<u8>().unwrap()).unwrap())
+    fn array_item(item: &str, depth: usize) -> Result<Type, InvalidItems> {
+        let depth = depth + 1;
+        if depth > MAX_DEPTH {
+            return Err(InvalidItems::TooDeep);
+        }
+        let (tokens, diagnostics) = lexer::lex(item);
+        let mut p = Parser::new(tokens);
+        p.depth = depth;
+        let t = p.parse_type();
+        // Labels cannot be resolved inside of an array.
+        match t {
+            _ if p.too_deep => Err(InvalidItems::TooDeep),
+            Ok(t) if diagnostics.is_empty() && p.tokens.is_empty() && p.references.is_empty() => {
+                Ok(t)
             }
-            _ => panic!("Parser Error: Unsupported type or next token was not a data token."),
+            _ => Err(InvalidItems::Data),
         }
     }
 }
@@ -0,0 +1,36 @@
+use std::collections::HashMap;
+
+/// Where the instructions of an allot_asm program came from.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Symbols {
+    /// Label name to address.
+    pub labels: HashMap<String, usize>,
+    /// Source line of each instruction, starting at 1.
+    pub lines: Vec<usize>,
+}
+impl Symbols {
+    pub fn label(&self, name: &str) -> Option<usize> {
+        self.labels.get(name).copied()
+    }
+
+    /// The address of the first instruction on or after the line.
+    pub fn address_of_line(&self, line: usize) -> Option<usize> {
+        self.lines.iter().position(|l| *l >= line)
+    }
+
+    pub fn line_of(&self, address: usize) -> Option<usize> {
+        self.lines.get(address).copied()
+    }
+
+    /// The labels that point to the address.
+    pub fn labels_at(&self, address: usize) -> Vec<&str> {
+        let mut labels: Vec<&str> = self
+            .labels
+            .iter()
+            .filter(|(_, a)| **a == address)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        labels.sort_unstable();
+        labels
+    }
+}
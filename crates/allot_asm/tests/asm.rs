@@ -0,0 +1,351 @@
+use std::collections::BTreeMap;
+
+use allot_asm::{compile, compile_with_structs, compile_with_symbols, Span};
+use allot_lib::{
+    Instruction, MapKey, OpPrim2, Operation, RawType, Register, StructDef, Type, MAX_DEPTH,
+};
+
+#[test]
+fn compile_program() {
+    let program = "mov r1 usize(5) ; Counter
+op != r1 r2
+jmp r1 add(0)
+call (println)
+exit i32(0)";
+
+    assert_eq!(
+        compile(program.to_string()),
+        Ok(vec![
+            Instruction::Mov(Register::R1, Type::UInt(5)),
+            Instruction::Op(
+                Operation::Prim2(OpPrim2::NotEqual),
+                [Register::R1, Register::R2]
+            ),
+            Instruction::Jmp(Some(Register::R1), Type::Address(0)),
+            Instruction::Call("println".to_string()),
+            Instruction::Exit(Type::Int32(0)),
+        ])
+    );
+}
+
+#[test]
+fn diagnostics() {
+    let program = "mov r1 usize(5)
+  cpy r2 i32(5)
+mov r3 i32(abc)
+op + r1
+exit i32(0)
+foo r1";
+
+    let diagnostics = compile(program.to_string()).unwrap_err();
+    let spans: Vec<Span> = diagnostics.iter().map(|d| d.span).collect();
+    assert_eq!(
+        spans,
+        vec![
+            Span {
+                line: 2,
+                column: 10,
+                len: 3
+            },
+            Span {
+                line: 3,
+                column: 11,
+                len: 5
+            },
+            Span {
+                line: 5,
+                column: 1,
+                len: 4
+            },
+            Span {
+                line: 6,
+                column: 1,
+                len: 3
+            },
+        ]
+    );
+
+    assert_eq!(diagnostics[0].expected.as_deref(), Some("register"));
+    assert_eq!(diagnostics[0].found.as_deref(), Some("`i32`"));
+    assert_eq!(
+        diagnostics[0].render(program, "test.ala"),
+        "error: unexpected token
+ --> test.ala:2:10
+  |
+2 |   cpy r2 i32(5)
+  |          ^^^ expected register, found `i32`
+"
+    );
+}
+
+#[test]
+fn diagnostics_end() {
+    let diagnostics = compile("mov r1".to_string()).unwrap_err();
+
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "unexpected end of program");
+    assert_eq!(
+        diagnostics[0].span,
+        Span {
+            line: 1,
+            column: 7,
+            len: 0
+        }
+    );
+}
+
+#[test]
+fn labels() {
+    let program = "start: mov r1 add(end)
+loop:
+lea r2 (start)
+jmp r255 add(loop)
+threadcreate add(end)
+end:
+exit i32(0)";
+
+    assert_eq!(
+        compile(program.to_string()),
+        Ok(vec![
+            Instruction::Mov(Register::R1, Type::Address(4)),
+            Instruction::Lea(Register::R2, 0),
+            Instruction::Jmp(None, Type::Address(1)),
+            Instruction::ThreadCreate(Type::Address(4)),
+            Instruction::Exit(Type::Int32(0)),
+        ])
+    );
+}
+
+#[test]
+fn call_addr_labels() {
+    let program = "calladdr add(function)
+exit i32(0)
+function:
+calladdr reg(1)
+ret";
+
+    assert_eq!(
+        compile(program.to_string()),
+        Ok(vec![
+            Instruction::CallAddr(Type::Address(2)),
+            Instruction::Exit(Type::Int32(0)),
+            Instruction::CallAddr(Type::Register(Register::R1)),
+            Instruction::Ret,
+        ])
+    );
+}
+
+#[test]
+fn label_errors() {
+    let program = "loop:
+jmp r255 add(missing)
+loop:
+jmp r255 add(loop)";
+
+    let diagnostics = compile(program.to_string()).unwrap_err();
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0].message, "undefined label");
+    assert_eq!(diagnostics[0].found.as_deref(), Some("`missing`"));
+    assert_eq!(diagnostics[0].span.line, 2);
+    assert_eq!(
+        diagnostics[1].message,
+        "label `loop` is already defined on line 1"
+    );
+    assert_eq!(diagnostics[1].span.line, 3);
+}
+
+#[test]
+fn symbols() {
+    let program = "; comment
+start: mov r1 add(end)
+loop:
+lea r2 (start)
+
+jmp r255 add(loop)
+end:
+exit i32(0)";
+
+    let (_, symbols) = compile_with_symbols(program.to_string()).unwrap();
+    assert_eq!(symbols.lines, vec![2, 4, 6, 8]);
+    assert_eq!(symbols.label("loop"), Some(1));
+    assert_eq!(symbols.labels_at(3), vec!["end"]);
+    assert_eq!(symbols.address_of_line(5), Some(2));
+    assert_eq!(symbols.line_of(3), Some(8));
+}
+
+#[test]
+fn arrays() {
+    let program = "mov r1 arr(u8(1) str(a (b)) arr(bool(true))) ; Items (3)
+arrget r2 r1 usize(0)
+arrslice r1 usize(1) reg(3)
+mov r1 arr()";
+
+    assert_eq!(
+        compile(program.to_string()),
+        Ok(vec![
+            Instruction::Mov(
+                Register::R1,
+                Type::Array(vec![
+                    Type::UInt8(1),
+                    Type::String("a (b)".to_string()),
+                    Type::Array(vec![Type::Boolean(true)]),
+                ])
+            ),
+            Instruction::ArrGet(Register::R2, Register::R1, Type::UInt(0)),
+            Instruction::ArrSlice(Register::R1, Type::UInt(1), Type::Register(Register::R3)),
+            Instruction::Mov(Register::R1, Type::Array(vec![])),
+        ])
+    );
+
+    let diagnostics =
+        compile("mov r1 arr(u8(1) x)\narrget r1 r1 add(l)\nl:".to_string()).unwrap_err();
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0].span.line, 1);
+    assert_eq!(diagnostics[1].span.line, 2);
+
+    // A `)` in a string does not end the array.
+    assert_eq!(
+        compile("mov r1 arr(str(a)b))\nmov r1 str(a)b)".to_string()),
+        Ok(vec![
+            Instruction::Mov(
+                Register::R1,
+                Type::Array(vec![Type::String("a)b".to_string())])
+            ),
+            Instruction::Mov(Register::R1, Type::String("a)b".to_string())),
+        ])
+    );
+
+    // The whole index is reported, not just its last token.
+    let diagnostics = compile("arrget r1 r1 str(a)".to_string()).unwrap_err();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        (diagnostics[0].span.column, diagnostics[0].span.len),
+        (14, 6)
+    );
+
+    // Arrays nested in each other, n deep, around a value.
+    let nested = |n: usize| format!("mov r1 {}u8(1){}", "arr(".repeat(n), ")".repeat(n));
+    assert!(compile(nested(MAX_DEPTH)).is_ok());
+    let diagnostics = compile(nested(MAX_DEPTH + 1)).unwrap_err();
+    assert_eq!(diagnostics[0].message, "values are nested too deeply");
+
+    let nested = format!("mov r1 {}{}", "arr(".repeat(1000), ")".repeat(1000));
+    let diagnostics = compile(nested).unwrap_err();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "values are nested too deeply");
+}
+
+#[test]
+fn maps() {
+    let program = "mov r1 map(str(b) u8(2) str(a) map())
+mov r1 map(str(a))
+mov r1 map(str(a) u8(1) str(a) u8(2))
+mov r1 map(f32(1.0) u8(1))";
+
+    let diagnostics = compile(program.to_string()).unwrap_err();
+    assert_eq!(
+        diagnostics.iter().map(|d| d.span.line).collect::<Vec<_>>(),
+        vec![2, 3, 4]
+    );
+
+    let mut map = BTreeMap::new();
+    map.insert(
+        MapKey::new(Type::String("b".to_string())).unwrap(),
+        Type::UInt8(2),
+    );
+    map.insert(
+        MapKey::new(Type::String("a".to_string())).unwrap(),
+        Type::Map(BTreeMap::new()),
+    );
+    assert_eq!(
+        compile("mov r1 map(str(b) u8(2) str(a) map())".to_string()),
+        Ok(vec![Instruction::Mov(Register::R1, Type::Map(map))])
+    );
+}
+
+#[test]
+fn structs() {
+    let program = ".struct Point { x: f64, y: f64 }
+.struct Empty {}
+mov r1 arr(f64(1) f64(2))
+structnew r1 Point
+fieldget r2 r1 Point.y
+fieldset r1 Point.x r2";
+
+    let (instructions, structs, _) = compile_with_structs(program.to_string()).unwrap();
+    assert_eq!(
+        structs,
+        vec![
+            StructDef {
+                name: "Point".to_string(),
+                fields: vec![
+                    ("x".to_string(), RawType::Float64),
+                    ("y".to_string(), RawType::Float64),
+                ],
+            },
+            StructDef {
+                name: "Empty".to_string(),
+                fields: vec![],
+            },
+        ]
+    );
+    assert_eq!(
+        instructions[1..],
+        [
+            Instruction::StructNew(Register::R1, 0),
+            Instruction::FieldGet(Register::R2, Register::R1, 0, 1),
+            Instruction::FieldSet(Register::R1, 0, 0, Register::R2),
+        ]
+    );
+    // The structs would be lost.
+    let diagnostics = compile(program.to_string()).unwrap_err();
+    assert_eq!(
+        diagnostics.iter().map(|d| d.span.line).collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+
+    let program = ".struct Point { x: f64, x: f64 }
+.struct Line { a: point }
+.struct Point {}
+structnew r1 Line
+fieldget r1 r1 Point.z
+.struct Point
+";
+    let diagnostics = compile(program.to_string()).unwrap_err();
+    assert_eq!(
+        diagnostics.iter().map(|d| d.span.line).collect::<Vec<_>>(),
+        vec![1, 2, 4, 5, 6]
+    );
+}
+
+#[test]
+fn tagged() {
+    let program = "mov r1 tag(usize(1) str(x))
+tag r1 (0)
+match r2 r1 (none 0)
+none:
+exit i32(0)";
+
+    assert_eq!(
+        compile(program.to_string()),
+        Ok(vec![
+            Instruction::Mov(
+                Register::R1,
+                Type::Tagged(1, Box::new(Type::String("x".to_string())))
+            ),
+            Instruction::Tag(Register::R1, 0),
+            Instruction::Match(Register::R2, Register::R1, vec![3, 0]),
+            Instruction::Exit(Type::Int32(0)),
+        ])
+    );
+
+    let program = "tag r1 (x)
+match r2 r1 (a missing)
+mov r1 tag(u8(1) none())
+a:";
+    let diagnostics = compile(program.to_string()).unwrap_err();
+    assert_eq!(
+        diagnostics.iter().map(|d| d.span.line).collect::<Vec<_>>(),
+        vec![1, 2, 3]
+    );
+}
@@ -0,0 +1,80 @@
+use std::{error::Error, fmt};
+
+use crate::MAX_DEPTH;
+
+/// Why a piece of bytecode could not be decoded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DecodeCause {
+    /// The bytecode ended before the item was complete.
+    UnexpectedEnd,
+    /// The bytecode was made for another BYTECODE_VERSION.
+    VersionMismatch(u64),
+    /// The byte does not translate into the expected item.
+    UnknownByte(u8),
+    /// A length is larger than the rest of the bytecode.
+    ImplausibleLength(u64),
+    /// A number does not fit into the expected item.
+    OutOfRange(u64),
+    /// A string was not valid UTF-8.
+    InvalidUtf8,
+    /// A number was not a valid char.
+    InvalidChar(u32),
+    /// A map key was not a key type, or was repeated.
+    InvalidMapKey,
+    /// The program declares structs, but was parsed without them.
+    UnexpectedStructs(u64),
+    /// Values are nested deeper than MAX_DEPTH.
+    TooDeep,
+}
+impl fmt::Display for DecodeCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeCause::UnexpectedEnd => write!(f, "unexpected end of bytecode"),
+            DecodeCause::VersionMismatch(v) => write!(
+                f,
+                "bytecode version {v} is not equal to current bytecode version {}",
+                crate::BYTECODE_VERSION
+            ),
+            DecodeCause::UnknownByte(b) => write!(f, "unknown byte {b:#04X}"),
+            DecodeCause::ImplausibleLength(l) => {
+                write!(f, "length {l} is larger than the rest of the bytecode")
+            }
+            DecodeCause::OutOfRange(v) => write!(f, "{v} is out of range"),
+            DecodeCause::InvalidUtf8 => write!(f, "invalid utf-8"),
+            DecodeCause::InvalidChar(c) => write!(f, "{c:#X} is not a valid char"),
+            DecodeCause::InvalidMapKey => write!(f, "invalid or repeated map key"),
+            DecodeCause::UnexpectedStructs(n) => {
+                write!(f, "{n} structs are declared, parse_with_structs keeps them")
+            }
+            DecodeCause::TooDeep => write!(f, "values are nested deeper than {MAX_DEPTH} levels"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DecodeError {
+    /// Byte offset of the item that could not be decoded.
+    pub offset: usize,
+    /// The item that was being decoded.
+    pub expected: &'static str,
+    pub cause: DecodeCause,
+}
+impl DecodeError {
+    pub fn new(offset: usize, expected: &'static str, cause: DecodeCause) -> Self {
+        Self {
+            offset,
+            expected,
+            cause,
+        }
+    }
+}
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "could not decode {} at byte {}: {}",
+            self.expected, self.offset, self.cause
+        )
+    }
+}
+impl Error for DecodeError {}
@@ -1,74 +1,133 @@
-use allot_lib::{Instruction, Operation, Register, Type};
+use allot_lib::{Instruction, Operation, Register, StructDef, Type};
 
 use crate::{Buffer, BYTECODE_VERSION};
 
 pub fn gen(instructions: Vec<Instruction>) -> Vec<u8> {
+    gen_with_structs(&instructions, &[])
+}
+
+pub fn gen_with_structs(instructions: &[Instruction], structs: &[StructDef]) -> Vec<u8> {
     let mut buffer = Buffer::new();
     buffer.write_u64(BYTECODE_VERSION as u64);
 
+    buffer.write_u64(structs.len() as u64);
+    for s in structs {
+        write_struct(&mut buffer, s);
+    }
+
     for i in instructions {
-        write_instruction(&mut buffer, &i);
+        write_instruction(&mut buffer, i);
 
         match i {
             Instruction::Nop => {}
             Instruction::Op(v1, v2) => {
-                write_op(&mut buffer, &v1);
+                write_op(&mut buffer, v1);
                 write_register(&mut buffer, &v2[0]);
                 write_register(&mut buffer, &v2[1]);
             }
             Instruction::Mov(v1, v2) => {
-                write_register(&mut buffer, &v1);
-                write_type(&mut buffer, &v2);
+                write_register(&mut buffer, v1);
+                write_type(&mut buffer, v2);
             }
             Instruction::Cpy(v1, v2) => {
-                write_register(&mut buffer, &v1);
-                write_register(&mut buffer, &v2);
+                write_register(&mut buffer, v1);
+                write_register(&mut buffer, v2);
             }
             Instruction::Cast(v1, v2) => {
-                write_register(&mut buffer, &v1);
-                buffer.write_u8(v2.into());
+                write_register(&mut buffer, v1);
+                buffer.write_u8((*v2).into());
             }
             Instruction::Lea(v1, v2) => {
-                write_register(&mut buffer, &v1);
-                buffer.write_u64(v2 as u64);
+                write_register(&mut buffer, v1);
+                buffer.write_u64(*v2 as u64);
             }
             Instruction::Jmp(v1, v2) => {
                 match v1 {
                     None => write_register(&mut buffer, &Register::None),
-                    Some(v) => write_register(&mut buffer, &v),
+                    Some(v) => write_register(&mut buffer, v),
                 }
-                write_type(&mut buffer, &v2);
+                write_type(&mut buffer, v2);
             }
+            Instruction::CallAddr(v) => write_type(&mut buffer, v),
             Instruction::Ret => {}
-            Instruction::Call(v) => buffer.write_string(&v),
-            Instruction::Exit(v) => write_type(&mut buffer, &v),
-            Instruction::Push(v) => write_register(&mut buffer, &v),
-            Instruction::PushCpy(v) => write_register(&mut buffer, &v),
+            Instruction::Call(v) => buffer.write_string(v),
+            Instruction::Exit(v) => write_type(&mut buffer, v),
+            Instruction::Push(v) => write_register(&mut buffer, v),
+            Instruction::PushCpy(v) => write_register(&mut buffer, v),
             Instruction::Pop(v) => match v {
                 None => write_register(&mut buffer, &Register::None),
-                Some(v) => write_register(&mut buffer, &v),
+                Some(v) => write_register(&mut buffer, v),
             },
-            Instruction::PopMany(v) => write_type(&mut buffer, &v),
+            Instruction::PopMany(v) => write_type(&mut buffer, v),
             Instruction::StackCpy(v1, v2) => {
-                write_register(&mut buffer, &v1);
-                write_type(&mut buffer, &v2);
+                write_register(&mut buffer, v1);
+                write_type(&mut buffer, v2);
             }
-            Instruction::PushFrame(v) => buffer.write_bool(v),
+            Instruction::PushFrame(v) => buffer.write_bool(*v),
             Instruction::PopFrame => {}
             Instruction::TakeFrom => {}
             Instruction::GiveTo => {}
-            Instruction::ThreadCreate(v) => write_type(&mut buffer, &v),
-            Instruction::ThreadJoin(v) => write_register(&mut buffer, &v),
+            Instruction::ArrPush(v1, v2)
+            | Instruction::ArrPop(v1, v2)
+            | Instruction::ArrLen(v1, v2) => {
+                write_register(&mut buffer, v1);
+                write_register(&mut buffer, v2);
+            }
+            Instruction::ArrGet(v1, v2, v3) => {
+                write_register(&mut buffer, v1);
+                write_register(&mut buffer, v2);
+                write_type(&mut buffer, v3);
+            }
+            Instruction::ArrSet(v1, v2, v3) => {
+                write_register(&mut buffer, v1);
+                write_type(&mut buffer, v2);
+                write_register(&mut buffer, v3);
+            }
+            Instruction::ArrSlice(v1, v2, v3) => {
+                write_register(&mut buffer, v1);
+                write_type(&mut buffer, v2);
+                write_type(&mut buffer, v3);
+            }
+            Instruction::StructNew(v1, v2) => {
+                write_register(&mut buffer, v1);
+                buffer.write_u64(*v2 as u64);
+            }
+            Instruction::FieldGet(v1, v2, v3, v4) => {
+                write_register(&mut buffer, v1);
+                write_register(&mut buffer, v2);
+                buffer.write_u64(*v3 as u64);
+                buffer.write_u64(*v4 as u64);
+            }
+            Instruction::FieldSet(v1, v2, v3, v4) => {
+                write_register(&mut buffer, v1);
+                buffer.write_u64(*v2 as u64);
+                buffer.write_u64(*v3 as u64);
+                write_register(&mut buffer, v4);
+            }
+            Instruction::Tag(v1, v2) => {
+                write_register(&mut buffer, v1);
+                buffer.write_u64(*v2 as u64);
+            }
+            Instruction::Match(v1, v2, v3) => {
+                write_register(&mut buffer, v1);
+                write_register(&mut buffer, v2);
+                buffer.write_u64(v3.len() as u64);
+                for a in v3 {
+                    buffer.write_u64(*a as u64);
+                }
+            }
+            Instruction::ThreadCreate(v) => write_type(&mut buffer, v),
+            Instruction::ThreadJoin(v) => write_register(&mut buffer, v),
             Instruction::Assert(v1, v2) => {
-                write_register(&mut buffer, &v1);
-                write_type(&mut buffer, &v2);
+                write_register(&mut buffer, v1);
+                write_type(&mut buffer, v2);
             }
-            Instruction::Dbg(v) => write_register(&mut buffer, &v),
-            Instruction::Dump(v) => buffer.write_u8(v),
+            Instruction::Dbg(v) => write_register(&mut buffer, v),
+            Instruction::Dump(v) => buffer.write_u8(*v),
         }
     }
 
-    buffer.0
+    buffer.into_inner()
 }
 
 fn write_instruction(buffer: &mut Buffer, i: &Instruction) {
@@ -76,12 +135,21 @@ fn write_instruction(buffer: &mut Buffer, i: &Instruction) {
     buffer.write_u8(b);
 }
 
+pub fn write_struct(buffer: &mut Buffer, s: &StructDef) {
+    buffer.write_string(&s.name);
+    buffer.write_u64(s.fields.len() as u64);
+    for (name, raw) in &s.fields {
+        buffer.write_string(name);
+        buffer.write_u8((*raw).into());
+    }
+}
+
 fn write_register(buffer: &mut Buffer, r: &Register) {
     let b: u8 = (*r).into();
     buffer.write_u8(b);
 }
 
-fn write_type(buffer: &mut Buffer, t: &Type) {
+pub fn write_type(buffer: &mut Buffer, t: &Type) {
     let b = t.to_raw().into();
     buffer.write_u8(b);
 
@@ -107,6 +175,30 @@ fn write_type(buffer: &mut Buffer, t: &Type) {
         Type::Address(v) => buffer.write_u64(*v as u64),
         Type::Pointer(v) => buffer.write_u64(*v as u64),
         Type::Register(v) => write_register(buffer, v),
+        Type::Array(v) => {
+            buffer.write_u64(v.len() as u64);
+            for t in v {
+                write_type(buffer, t);
+            }
+        }
+        Type::Map(v) => {
+            buffer.write_u64(v.len() as u64);
+            for (k, t) in v {
+                write_type(buffer, k.get());
+                write_type(buffer, t);
+            }
+        }
+        Type::Tagged(tag, t) => {
+            buffer.write_u64(*tag as u64);
+            write_type(buffer, t);
+        }
+        Type::Struct(id, fields) => {
+            buffer.write_u64(*id as u64);
+            buffer.write_u64(fields.len() as u64);
+            for t in fields {
+                write_type(buffer, t);
+            }
+        }
     }
 }
 
@@ -1,36 +1,52 @@
 use std::mem::size_of;
 
+pub use allot_lib::MAX_DEPTH;
+pub use error::*;
 #[cfg(feature = "forms")]
 pub use forms::*;
 #[cfg(feature = "gen")]
-pub use gen::gen;
+pub use gen::{gen, gen_with_structs, write_struct, write_type};
 #[cfg(feature = "parse")]
-pub use parse::parse;
+pub use parse::{parse, parse_with_structs, read_len, read_struct, read_type, read_usize};
 
+mod error;
 #[cfg(feature = "gen")]
 mod gen;
 #[cfg(feature = "parse")]
 mod parse;
 
-/// For now the layout of allot files the BYTECODE_VERSION, then just a linear
-/// list of instructions.
-// TODO: Allow some data about the program to be stored.
-
-pub const BYTECODE_VERSION: usize = 0;
+/// The layout of allot files is the BYTECODE_VERSION, the structs the program
+/// declares, then a linear list of instructions. Structs are a count, then the
+/// name, field count, and each field's name and RawType.
+pub const BYTECODE_VERSION: usize = 5;
 
 #[derive(Clone, Debug, Default)]
-pub struct Buffer(Vec<u8>);
+pub struct Buffer(Vec<u8>, usize);
 impl Buffer {
     pub fn new() -> Self {
         Self::default()
     }
 
     pub fn with(vec: Vec<u8>) -> Self {
-        Self(vec)
+        Self(vec, 0)
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.0
     }
 
+    /// Returns true if there is nothing left to read.
     pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.remaining() == 0
+    }
+
+    /// The offset of the next byte that will be read.
+    pub fn offset(&self) -> usize {
+        self.1
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.0.len() - self.1
     }
 
     // Write
@@ -121,143 +137,116 @@ impl Buffer {
 
     // Read
 
-    pub fn read_i8(&mut self) -> i8 {
-        let v: [u8; size_of::<i8>()] = self
-            .0
-            .drain(0..size_of::<i8>())
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap();
-        i8::from_le_bytes(v)
-    }
-
-    pub fn read_i16(&mut self) -> i16 {
-        let v: [u8; size_of::<i16>()] = self
-            .0
-            .drain(0..size_of::<i16>())
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap();
-        i16::from_le_bytes(v)
-    }
-
-    pub fn read_i32(&mut self) -> i32 {
-        let v: [u8; size_of::<i32>()] = self
-            .0
-            .drain(0..size_of::<i32>())
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap();
-        i32::from_le_bytes(v)
-    }
-
-    pub fn read_i64(&mut self) -> i64 {
-        let v: [u8; size_of::<i64>()] = self
-            .0
-            .drain(0..size_of::<i64>())
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap();
-        i64::from_le_bytes(v)
-    }
-
-    pub fn read_i128(&mut self) -> i128 {
-        let v: [u8; size_of::<i128>()] = self
-            .0
-            .drain(0..size_of::<i128>())
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap();
-        i128::from_le_bytes(v)
-    }
-
-    pub fn read_u8(&mut self) -> u8 {
-        let v: [u8; size_of::<u8>()] = self
-            .0
-            .drain(0..size_of::<u8>())
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap();
-        u8::from_le_bytes(v)
-    }
-
-    pub fn read_u16(&mut self) -> u16 {
-        let v: [u8; size_of::<u16>()] = self
-            .0
-            .drain(0..size_of::<u16>())
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap();
-        u16::from_le_bytes(v)
-    }
-
-    pub fn read_u32(&mut self) -> u32 {
-        let v: [u8; size_of::<u32>()] = self
-            .0
-            .drain(0..size_of::<u32>())
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap();
-        u32::from_le_bytes(v)
-    }
-
-    pub fn read_u64(&mut self) -> u64 {
-        let v: [u8; size_of::<u64>()] = self
-            .0
-            .drain(0..size_of::<u64>())
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap();
-        u64::from_le_bytes(v)
-    }
-
-    pub fn read_u128(&mut self) -> u128 {
-        let v: [u8; size_of::<u128>()] = self
-            .0
-            .drain(0..size_of::<u128>())
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap();
-        u128::from_le_bytes(v)
-    }
-
-    pub fn read_f32(&mut self) -> f32 {
-        let v: [u8; size_of::<f32>()] = self
-            .0
-            .drain(0..size_of::<f32>())
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap();
-        f32::from_le_bytes(v)
-    }
-
-    pub fn read_f64(&mut self) -> f64 {
-        let v: [u8; size_of::<f64>()] = self
-            .0
-            .drain(0..size_of::<f64>())
-            .collect::<Vec<u8>>()
-            .try_into()
-            .unwrap();
-        f64::from_le_bytes(v)
-    }
-
-    pub fn read_char(&mut self) -> char {
-        let num = self.read_u32();
-        char::from_u32(num).unwrap()
-    }
-
-    pub fn read_str(&mut self) -> String {
-        self.read_string()
+    fn read_bytes<const N: usize>(
+        &mut self,
+        expected: &'static str,
+    ) -> Result<[u8; N], DecodeError> {
+        let start = self.1;
+        let bytes = self.0.get(start..start + N).ok_or(DecodeError::new(
+            start,
+            expected,
+            DecodeCause::UnexpectedEnd,
+        ))?;
+        self.1 += N;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    pub fn read_i8(&mut self) -> Result<i8, DecodeError> {
+        let v = self.read_bytes::<{ size_of::<i8>() }>("i8")?;
+        Ok(i8::from_le_bytes(v))
+    }
+
+    pub fn read_i16(&mut self) -> Result<i16, DecodeError> {
+        let v = self.read_bytes::<{ size_of::<i16>() }>("i16")?;
+        Ok(i16::from_le_bytes(v))
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32, DecodeError> {
+        let v = self.read_bytes::<{ size_of::<i32>() }>("i32")?;
+        Ok(i32::from_le_bytes(v))
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64, DecodeError> {
+        let v = self.read_bytes::<{ size_of::<i64>() }>("i64")?;
+        Ok(i64::from_le_bytes(v))
+    }
+
+    pub fn read_i128(&mut self) -> Result<i128, DecodeError> {
+        let v = self.read_bytes::<{ size_of::<i128>() }>("i128")?;
+        Ok(i128::from_le_bytes(v))
     }
 
-    pub fn read_string(&mut self) -> String {
-        let len = self.read_u64() as usize;
-        let v: Vec<u8> = self.0.drain(0..len).collect();
-        String::from_utf8(v).unwrap()
+    pub fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        let v = self.read_bytes::<{ size_of::<u8>() }>("u8")?;
+        Ok(u8::from_le_bytes(v))
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, DecodeError> {
+        let v = self.read_bytes::<{ size_of::<u16>() }>("u16")?;
+        Ok(u16::from_le_bytes(v))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        let v = self.read_bytes::<{ size_of::<u32>() }>("u32")?;
+        Ok(u32::from_le_bytes(v))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, DecodeError> {
+        let v = self.read_bytes::<{ size_of::<u64>() }>("u64")?;
+        Ok(u64::from_le_bytes(v))
+    }
+
+    pub fn read_u128(&mut self) -> Result<u128, DecodeError> {
+        let v = self.read_bytes::<{ size_of::<u128>() }>("u128")?;
+        Ok(u128::from_le_bytes(v))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, DecodeError> {
+        let v = self.read_bytes::<{ size_of::<f32>() }>("f32")?;
+        Ok(f32::from_le_bytes(v))
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64, DecodeError> {
+        let v = self.read_bytes::<{ size_of::<f64>() }>("f64")?;
+        Ok(f64::from_le_bytes(v))
+    }
+
+    pub fn read_char(&mut self) -> Result<char, DecodeError> {
+        let offset = self.1;
+        let num = self.read_u32()?;
+        char::from_u32(num).ok_or(DecodeError::new(
+            offset,
+            "char",
+            DecodeCause::InvalidChar(num),
+        ))
+    }
+
+    pub fn read_str(&mut self) -> Result<String, DecodeError> {
+        self.read_string()
     }
 
-    pub fn read_bool(&mut self) -> bool {
-        let num = self.read_u8();
-        !matches!(num, 0)
+    /// Reads a string, errors if its length is larger than the rest of the
+    /// buffer.
+    pub fn read_string(&mut self) -> Result<String, DecodeError> {
+        let offset = self.1;
+        let len = self.read_u64()?;
+        if len > self.remaining() as u64 {
+            return Err(DecodeError::new(
+                offset,
+                "string",
+                DecodeCause::ImplausibleLength(len),
+            ));
+        }
+
+        let start = self.1;
+        self.1 += len as usize;
+        let v = self.0[start..self.1].to_vec();
+        String::from_utf8(v)
+            .map_err(|_| DecodeError::new(offset, "string", DecodeCause::InvalidUtf8))
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, DecodeError> {
+        let num = self.read_u8()?;
+        Ok(!matches!(num, 0))
     }
 }
@@ -1,108 +1,292 @@
+use std::{collections::BTreeMap, mem::size_of};
+
 use allot_lib::{
-    Instruction, Instruction::Cast, Operation, RawInstruction, RawType, Register, Type,
+    Instruction, MapKey, Operation, RawInstruction, RawType, Register, StructDef, Type,
 };
 
-use crate::{Buffer, BYTECODE_VERSION};
+use crate::{Buffer, DecodeCause, DecodeError, BYTECODE_VERSION, MAX_DEPTH};
 
-pub fn parse(bytes: Vec<u8>) -> Vec<Instruction> {
+/// Parses the instructions of a program that declares no structs.
+pub fn parse(bytes: Vec<u8>) -> Result<Vec<Instruction>, DecodeError> {
+    let (instructions, structs) = parse_with_structs(bytes)?;
+    match structs.len() {
+        0 => Ok(instructions),
+        // The count of structs comes right after the version.
+        len => Err(DecodeError::new(
+            size_of::<u64>(),
+            "structs",
+            DecodeCause::UnexpectedStructs(len as u64),
+        )),
+    }
+}
+
+pub fn parse_with_structs(
+    bytes: Vec<u8>,
+) -> Result<(Vec<Instruction>, Vec<StructDef>), DecodeError> {
     let mut instructions = Vec::new();
     let mut buffer = Buffer::with(bytes);
 
-    let bytecode_version = buffer.read_u64() as usize;
-    if bytecode_version != BYTECODE_VERSION {
-        panic!("Give bytecode version is not equal to current bytecode version.");
-        // TODO: Return an error instead.
+    let bytecode_version = buffer.read_u64().map_err(|e| expecting(e, "version"))?;
+    if bytecode_version != BYTECODE_VERSION as u64 {
+        return Err(DecodeError::new(
+            0,
+            "version",
+            DecodeCause::VersionMismatch(bytecode_version),
+        ));
     }
 
+    let len = read_len(&mut buffer, "structs")?;
+    let structs = (0..len)
+        .map(|_| read_struct(&mut buffer))
+        .collect::<Result<_, _>>()?;
+
     while !buffer.is_empty() {
-        instructions.push(read_instruction(&mut buffer));
+        instructions.push(read_instruction(&mut buffer)?);
     }
 
-    instructions
+    Ok((instructions, structs))
 }
 
-fn read_instruction(buffer: &mut Buffer) -> Instruction {
-    let raw = RawInstruction::try_from(buffer.read_u8())
-        .expect("Byte did not translate into RawInstruction.");
-    match raw {
+pub fn read_struct(buffer: &mut Buffer) -> Result<StructDef, DecodeError> {
+    let name = buffer.read_string()?;
+    let len = read_len(buffer, "fields")?;
+    let fields = (0..len)
+        .map(|_| Ok((buffer.read_string()?, read_raw_type(buffer)?)))
+        .collect::<Result<_, _>>()?;
+    Ok(StructDef { name, fields })
+}
+
+/// Replaces what was expected, but keeps the offset and cause.
+#[inline]
+fn expecting(err: DecodeError, expected: &'static str) -> DecodeError {
+    DecodeError { expected, ..err }
+}
+
+fn read_instruction(buffer: &mut Buffer) -> Result<Instruction, DecodeError> {
+    let offset = buffer.offset();
+    let byte = buffer
+        .read_u8()
+        .map_err(|e| expecting(e, "RawInstruction"))?;
+    let raw = RawInstruction::try_from(byte)
+        .map_err(|_| DecodeError::new(offset, "RawInstruction", DecodeCause::UnknownByte(byte)))?;
+
+    Ok(match raw {
         RawInstruction::Nop => Instruction::Nop,
         RawInstruction::Op => Instruction::Op(
-            read_op(buffer),
-            [read_register(buffer), read_register(buffer)],
+            read_op(buffer)?,
+            [read_register(buffer)?, read_register(buffer)?],
         ),
-        RawInstruction::Mov => Instruction::Mov(read_register(buffer), read_type(buffer)),
-        RawInstruction::Cpy => Instruction::Cpy(read_register(buffer), read_register(buffer)),
-        RawInstruction::Cast => Cast(
-            read_register(buffer),
-            RawType::try_from(buffer.read_u8()).expect("Byte did not translate into RawType."),
-        ),
-        RawInstruction::Lea => Instruction::Lea(read_register(buffer), buffer.read_u64() as usize),
+        RawInstruction::Mov => Instruction::Mov(read_register(buffer)?, read_type(buffer)?),
+        RawInstruction::Cpy => Instruction::Cpy(read_register(buffer)?, read_register(buffer)?),
+        RawInstruction::Cast => Instruction::Cast(read_register(buffer)?, read_raw_type(buffer)?),
+        RawInstruction::Lea => Instruction::Lea(read_register(buffer)?, read_usize(buffer)?),
         RawInstruction::Jmp => {
-            let reg = read_register(buffer);
+            let reg = read_register(buffer)?;
             let reg = match reg {
                 Register::None => None,
                 _ => Some(reg),
             };
-            Instruction::Jmp(reg, read_type(buffer))
+            Instruction::Jmp(reg, read_type(buffer)?)
         }
+        RawInstruction::CallAddr => Instruction::CallAddr(read_type(buffer)?),
         RawInstruction::Ret => Instruction::Ret,
-        RawInstruction::Call => Instruction::Call(buffer.read_string()),
-        RawInstruction::Exit => Instruction::Exit(read_type(buffer)),
-        RawInstruction::Push => Instruction::Push(read_register(buffer)),
-        RawInstruction::PushCpy => Instruction::PushCpy(read_register(buffer)),
+        RawInstruction::Call => Instruction::Call(buffer.read_string()?),
+        RawInstruction::Exit => Instruction::Exit(read_type(buffer)?),
+        RawInstruction::Push => Instruction::Push(read_register(buffer)?),
+        RawInstruction::PushCpy => Instruction::PushCpy(read_register(buffer)?),
         RawInstruction::Pop => {
-            let reg = read_register(buffer);
+            let reg = read_register(buffer)?;
             let reg = match reg {
                 Register::None => None,
                 _ => Some(reg),
             };
             Instruction::Pop(reg)
         }
-        RawInstruction::PopMany => Instruction::PopMany(read_type(buffer)),
-        RawInstruction::StackCpy => Instruction::StackCpy(read_register(buffer), read_type(buffer)),
-        RawInstruction::PushFrame => Instruction::PushFrame(buffer.read_bool()),
+        RawInstruction::PopMany => Instruction::PopMany(read_type(buffer)?),
+        RawInstruction::StackCpy => {
+            Instruction::StackCpy(read_register(buffer)?, read_type(buffer)?)
+        }
+        RawInstruction::PushFrame => Instruction::PushFrame(buffer.read_bool()?),
         RawInstruction::PopFrame => Instruction::PopFrame,
         RawInstruction::TakeFrom => Instruction::TakeFrom,
         RawInstruction::GiveTo => Instruction::GiveTo,
-        RawInstruction::ThreadCreate => Instruction::ThreadCreate(read_type(buffer)),
-        RawInstruction::ThreadJoin => Instruction::ThreadJoin(read_register(buffer)),
-        RawInstruction::Assert => Instruction::Assert(read_register(buffer), read_type(buffer)),
-        RawInstruction::Dbg => Instruction::Dbg(read_register(buffer)),
-        RawInstruction::Dump => Instruction::Dump(buffer.read_u8()),
-    }
+        RawInstruction::ArrPush => {
+            Instruction::ArrPush(read_register(buffer)?, read_register(buffer)?)
+        }
+        RawInstruction::ArrPop => {
+            Instruction::ArrPop(read_register(buffer)?, read_register(buffer)?)
+        }
+        RawInstruction::ArrGet => Instruction::ArrGet(
+            read_register(buffer)?,
+            read_register(buffer)?,
+            read_type(buffer)?,
+        ),
+        RawInstruction::ArrSet => Instruction::ArrSet(
+            read_register(buffer)?,
+            read_type(buffer)?,
+            read_register(buffer)?,
+        ),
+        RawInstruction::ArrLen => {
+            Instruction::ArrLen(read_register(buffer)?, read_register(buffer)?)
+        }
+        RawInstruction::ArrSlice => Instruction::ArrSlice(
+            read_register(buffer)?,
+            read_type(buffer)?,
+            read_type(buffer)?,
+        ),
+        RawInstruction::StructNew => {
+            Instruction::StructNew(read_register(buffer)?, read_usize(buffer)?)
+        }
+        RawInstruction::FieldGet => Instruction::FieldGet(
+            read_register(buffer)?,
+            read_register(buffer)?,
+            read_usize(buffer)?,
+            read_usize(buffer)?,
+        ),
+        RawInstruction::FieldSet => Instruction::FieldSet(
+            read_register(buffer)?,
+            read_usize(buffer)?,
+            read_usize(buffer)?,
+            read_register(buffer)?,
+        ),
+        RawInstruction::Tag => Instruction::Tag(read_register(buffer)?, read_usize(buffer)?),
+        RawInstruction::Match => {
+            let (reg, val) = (read_register(buffer)?, read_register(buffer)?);
+            let len = read_len(buffer, "addresses")?;
+            Instruction::Match(
+                reg,
+                val,
+                (0..len)
+                    .map(|_| read_usize(buffer))
+                    .collect::<Result<_, _>>()?,
+            )
+        }
+        RawInstruction::ThreadCreate => Instruction::ThreadCreate(read_type(buffer)?),
+        RawInstruction::ThreadJoin => Instruction::ThreadJoin(read_register(buffer)?),
+        RawInstruction::Assert => Instruction::Assert(read_register(buffer)?, read_type(buffer)?),
+        RawInstruction::Dbg => Instruction::Dbg(read_register(buffer)?),
+        RawInstruction::Dump => Instruction::Dump(buffer.read_u8()?),
+    })
+}
+
+fn read_register(buffer: &mut Buffer) -> Result<Register, DecodeError> {
+    let offset = buffer.offset();
+    let byte = buffer.read_u8().map_err(|e| expecting(e, "Register"))?;
+    Register::try_from(byte)
+        .map_err(|_| DecodeError::new(offset, "Register", DecodeCause::UnknownByte(byte)))
+}
+
+fn read_raw_type(buffer: &mut Buffer) -> Result<RawType, DecodeError> {
+    let offset = buffer.offset();
+    let byte = buffer.read_u8().map_err(|e| expecting(e, "RawType"))?;
+    RawType::try_from(byte)
+        .map_err(|_| DecodeError::new(offset, "RawType", DecodeCause::UnknownByte(byte)))
+}
+
+pub fn read_usize(buffer: &mut Buffer) -> Result<usize, DecodeError> {
+    let offset = buffer.offset();
+    let num = buffer.read_u64().map_err(|e| expecting(e, "usize"))?;
+    usize::try_from(num)
+        .map_err(|_| DecodeError::new(offset, "usize", DecodeCause::OutOfRange(num)))
+}
+
+fn read_isize(buffer: &mut Buffer) -> Result<isize, DecodeError> {
+    let offset = buffer.offset();
+    let num = buffer.read_i64().map_err(|e| expecting(e, "isize"))?;
+    isize::try_from(num)
+        .map_err(|_| DecodeError::new(offset, "isize", DecodeCause::OutOfRange(num as u64)))
 }
 
-fn read_register(buffer: &mut Buffer) -> Register {
-    Register::try_from(buffer.read_u8()).expect("Byte did not translate into Register.")
+pub fn read_type(buffer: &mut Buffer) -> Result<Type, DecodeError> {
+    read_nested(buffer, 0)
 }
 
-fn read_type(buffer: &mut Buffer) -> Type {
-    let raw = RawType::try_from(buffer.read_u8()).expect("Byte did not translate into RawType.");
-    match raw {
+/// Reads a type that is inside depth other values.
+fn read_nested(buffer: &mut Buffer, depth: usize) -> Result<Type, DecodeError> {
+    let offset = buffer.offset();
+    if depth > MAX_DEPTH {
+        return Err(DecodeError::new(offset, "Type", DecodeCause::TooDeep));
+    }
+    let depth = depth + 1;
+    let raw = read_raw_type(buffer)?;
+    Ok(match raw {
         RawType::None => Type::None,
-        RawType::Int8 => Type::Int8(buffer.read_i8()),
-        RawType::Int16 => Type::Int16(buffer.read_i16()),
-        RawType::Int32 => Type::Int32(buffer.read_i32()),
-        RawType::Int => Type::Int(buffer.read_i64() as isize),
-        RawType::Int64 => Type::Int64(buffer.read_i64()),
-        RawType::Int128 => Type::Int128(buffer.read_i128()),
-        RawType::UInt8 => Type::UInt8(buffer.read_u8()),
-        RawType::UInt16 => Type::UInt16(buffer.read_u16()),
-        RawType::UInt32 => Type::UInt32(buffer.read_u32()),
-        RawType::UInt => Type::UInt(buffer.read_u64() as usize),
-        RawType::UInt64 => Type::UInt64(buffer.read_u64()),
-        RawType::UInt128 => Type::UInt128(buffer.read_u128()),
-        RawType::Float32 => Type::Float32(buffer.read_f32()),
-        RawType::Float64 => Type::Float64(buffer.read_f64()),
-        RawType::Char => Type::Char(buffer.read_char()),
-        RawType::String => Type::String(buffer.read_string()),
-        RawType::Boolean => Type::Boolean(buffer.read_bool()),
-        RawType::Address => Type::Address(buffer.read_u64() as usize),
-        RawType::Pointer => Type::Pointer(buffer.read_u64() as usize),
-        RawType::Register => Type::Register(read_register(buffer)),
+        RawType::Int8 => Type::Int8(buffer.read_i8()?),
+        RawType::Int16 => Type::Int16(buffer.read_i16()?),
+        RawType::Int32 => Type::Int32(buffer.read_i32()?),
+        RawType::Int => Type::Int(read_isize(buffer)?),
+        RawType::Int64 => Type::Int64(buffer.read_i64()?),
+        RawType::Int128 => Type::Int128(buffer.read_i128()?),
+        RawType::UInt8 => Type::UInt8(buffer.read_u8()?),
+        RawType::UInt16 => Type::UInt16(buffer.read_u16()?),
+        RawType::UInt32 => Type::UInt32(buffer.read_u32()?),
+        RawType::UInt => Type::UInt(read_usize(buffer)?),
+        RawType::UInt64 => Type::UInt64(buffer.read_u64()?),
+        RawType::UInt128 => Type::UInt128(buffer.read_u128()?),
+        RawType::Float32 => Type::Float32(buffer.read_f32()?),
+        RawType::Float64 => Type::Float64(buffer.read_f64()?),
+        RawType::Char => Type::Char(buffer.read_char()?),
+        RawType::String => Type::String(buffer.read_string()?),
+        RawType::Boolean => Type::Boolean(buffer.read_bool()?),
+        RawType::Address => Type::Address(read_usize(buffer)?),
+        RawType::Pointer => Type::Pointer(read_usize(buffer)?),
+        RawType::Register => Type::Register(read_register(buffer)?),
+        RawType::Array => {
+            let len = read_len(buffer, "array")?;
+            Type::Array(
+                (0..len)
+                    .map(|_| read_nested(buffer, depth))
+                    .collect::<Result<_, _>>()?,
+            )
+        }
+        RawType::Map => {
+            let len = read_len(buffer, "map")?;
+            let mut map = BTreeMap::new();
+            for _ in 0..len {
+                let offset = buffer.offset();
+                let key = MapKey::new(read_nested(buffer, depth)?)
+                    .map_err(|_| DecodeError::new(offset, "map key", DecodeCause::InvalidMapKey))?;
+                if map.insert(key, read_nested(buffer, depth)?).is_some() {
+                    return Err(DecodeError::new(
+                        offset,
+                        "map key",
+                        DecodeCause::InvalidMapKey,
+                    ));
+                }
+            }
+            Type::Map(map)
+        }
+        RawType::Tagged => Type::Tagged(read_usize(buffer)?, Box::new(read_nested(buffer, depth)?)),
+        RawType::Struct => {
+            let id = read_usize(buffer)?;
+            let len = read_len(buffer, "struct")?;
+            Type::Struct(
+                id,
+                (0..len)
+                    .map(|_| read_nested(buffer, depth))
+                    .collect::<Result<_, _>>()?,
+            )
+        }
+    })
+}
+
+/// Reads the length of a collection, every item takes at least a byte.
+pub fn read_len(buffer: &mut Buffer, expected: &'static str) -> Result<usize, DecodeError> {
+    let offset = buffer.offset();
+    let len = read_usize(buffer)?;
+    if len > buffer.remaining() {
+        return Err(DecodeError::new(
+            offset,
+            expected,
+            DecodeCause::ImplausibleLength(len as u64),
+        ));
     }
+    Ok(len)
 }
 
-fn read_op(buffer: &mut Buffer) -> Operation {
-    Operation::try_from(buffer.read_u8()).expect("Byte did not translate into Operation.")
+fn read_op(buffer: &mut Buffer) -> Result<Operation, DecodeError> {
+    let offset = buffer.offset();
+    let byte = buffer.read_u8().map_err(|e| expecting(e, "Operation"))?;
+    Operation::try_from(byte)
+        .map_err(|_| DecodeError::new(offset, "Operation", DecodeCause::UnknownByte(byte)))
 }
@@ -9,18 +9,250 @@ fn gen_parse() {
     let i = vec![
         Instruction::Mov(Register::R9, Type::String("Hello!".to_string())),
         Instruction::Call("println".to_string()),
+        Instruction::CallAddr(Type::Register(Register::R3)),
         Instruction::Exit(Type::Int32(0)),
     ];
 
     let bytecode = gen(i);
-    let i = parse(bytecode);
+    let i = parse(bytecode).unwrap();
 
     assert_eq!(
         i,
         vec![
             Instruction::Mov(Register::R9, Type::String("Hello!".to_string())),
             Instruction::Call("println".to_string()),
+            Instruction::CallAddr(Type::Register(Register::R3)),
             Instruction::Exit(Type::Int32(0))
         ]
     );
 }
+
+#[test]
+#[cfg(feature = "gen")]
+#[cfg(feature = "parse")]
+fn parse_truncated() {
+    use allot_bytecode::{gen, parse, DecodeCause};
+
+    let mut bytecode = gen(vec![Instruction::Mov(Register::R9, Type::UInt64(64))]);
+    bytecode.truncate(bytecode.len() - 2);
+
+    let err = parse(bytecode).unwrap_err();
+    assert_eq!(err.offset, 19);
+    assert_eq!(err.expected, "u64");
+    assert_eq!(err.cause, DecodeCause::UnexpectedEnd);
+}
+
+#[test]
+#[cfg(feature = "parse")]
+fn parse_version() {
+    use allot_bytecode::{parse, DecodeCause};
+
+    let err = parse(1_u64.to_le_bytes().to_vec()).unwrap_err();
+    assert_eq!(err.offset, 0);
+    assert_eq!(err.cause, DecodeCause::VersionMismatch(1));
+
+    let err = parse(vec![0, 0, 0]).unwrap_err();
+    assert_eq!(err.expected, "version");
+    assert_eq!(err.cause, DecodeCause::UnexpectedEnd);
+}
+
+#[test]
+#[cfg(feature = "parse")]
+fn parse_unknown_bytes() {
+    use allot_bytecode::{parse, DecodeCause, BYTECODE_VERSION};
+
+    // The version, then no structs.
+    let header = [(BYTECODE_VERSION as u64).to_le_bytes(), 0_u64.to_le_bytes()].concat();
+
+    let err = parse([header.clone(), vec![250]].concat()).unwrap_err();
+    assert_eq!(err.offset, 16);
+    assert_eq!(err.expected, "RawInstruction");
+    assert_eq!(err.cause, DecodeCause::UnknownByte(250));
+
+    let push: u8 = allot_lib::RawInstruction::Push.into();
+    let err = parse([header, vec![push, 100]].concat()).unwrap_err();
+    assert_eq!(err.offset, 17);
+    assert_eq!(err.expected, "Register");
+    assert_eq!(err.cause, DecodeCause::UnknownByte(100));
+}
+
+#[test]
+#[cfg(feature = "parse")]
+fn parse_strings() {
+    use allot_bytecode::{parse, DecodeCause, BYTECODE_VERSION};
+
+    // The version, then no structs.
+    let header = [(BYTECODE_VERSION as u64).to_le_bytes(), 0_u64.to_le_bytes()].concat();
+    let call: u8 = allot_lib::RawInstruction::Call.into();
+
+    let huge = u64::MAX.to_le_bytes().to_vec();
+    let err = parse([header.clone(), vec![call], huge].concat()).unwrap_err();
+    assert_eq!(err.offset, 17);
+    assert_eq!(err.expected, "string");
+    assert_eq!(err.cause, DecodeCause::ImplausibleLength(u64::MAX));
+
+    let len = 2_u64.to_le_bytes().to_vec();
+    let err = parse([header, vec![call], len, vec![0xC3, 0x28]].concat()).unwrap_err();
+    assert_eq!(err.cause, DecodeCause::InvalidUtf8);
+}
+
+#[test]
+#[cfg(feature = "gen")]
+#[cfg(feature = "parse")]
+fn gen_parse_arrays() {
+    use allot_bytecode::{gen, parse, DecodeCause, MAX_DEPTH};
+
+    let i = vec![
+        Instruction::Mov(
+            Register::R1,
+            Type::Array(vec![
+                Type::UInt8(1),
+                Type::String("two".to_string()),
+                Type::Array(vec![]),
+            ]),
+        ),
+        Instruction::ArrPush(Register::R1, Register::R2),
+        Instruction::ArrGet(Register::R3, Register::R1, Type::UInt(0)),
+        Instruction::ArrSet(Register::R1, Type::Register(Register::R4), Register::R3),
+        Instruction::ArrSlice(Register::R1, Type::UInt(0), Type::UInt(1)),
+    ];
+    assert_eq!(parse(gen(i.clone())), Ok(i));
+
+    let mut bytecode = gen(vec![Instruction::Mov(Register::R1, Type::Array(vec![]))]);
+    let len = bytecode.len();
+    bytecode[len - 8..].copy_from_slice(&u64::MAX.to_le_bytes());
+    let err = parse(bytecode).unwrap_err();
+    assert_eq!(err.offset, 19);
+    assert_eq!(err.expected, "array");
+    assert_eq!(err.cause, DecodeCause::ImplausibleLength(u64::MAX));
+
+    // Arrays nested in each other, n deep, around a None.
+    let nested = |n: usize| {
+        let mut bytecode = gen(vec![Instruction::Mov(Register::R1, Type::Array(vec![]))]);
+        let array = bytecode[bytecode.len() - 9];
+        bytecode.truncate(bytecode.len() - 9);
+        for _ in 0..n {
+            bytecode.push(array);
+            bytecode.extend(1u64.to_le_bytes());
+        }
+        bytecode.extend(gen_type(Type::None));
+        bytecode
+    };
+    assert!(parse(nested(MAX_DEPTH)).is_ok());
+    let err = parse(nested(MAX_DEPTH + 1)).unwrap_err();
+    assert_eq!(err.offset, 18 + 9 * (MAX_DEPTH + 1));
+    assert_eq!(err.cause, DecodeCause::TooDeep);
+    // Too deep to read with recursion.
+    let err = parse(nested(1_000_000)).unwrap_err();
+    assert_eq!(err.cause, DecodeCause::TooDeep);
+}
+
+#[test]
+#[cfg(feature = "gen")]
+#[cfg(feature = "parse")]
+fn gen_parse_maps() {
+    use std::collections::BTreeMap;
+
+    use allot_bytecode::{gen, parse, DecodeCause};
+    use allot_lib::MapKey;
+
+    let mut map = BTreeMap::new();
+    map.insert(MapKey::new(Type::Int(-1)).unwrap(), Type::Array(vec![]));
+    map.insert(
+        MapKey::new(Type::Int(2)).unwrap(),
+        Type::String("two".to_string()),
+    );
+    let i = vec![Instruction::Mov(Register::R1, Type::Map(map))];
+    assert_eq!(parse(gen(i.clone())), Ok(i));
+
+    // A float key.
+    let mut bytecode = gen(vec![Instruction::Mov(
+        Register::R1,
+        Type::Map(BTreeMap::new()),
+    )]);
+    let len = bytecode.len();
+    bytecode[len - 8..].copy_from_slice(&1u64.to_le_bytes());
+    bytecode.extend(gen_type(Type::Float64(1.0)));
+    bytecode.extend(gen_type(Type::None));
+    let err = parse(bytecode).unwrap_err();
+    assert_eq!(err.expected, "map key");
+    assert_eq!(err.cause, DecodeCause::InvalidMapKey);
+
+    // The same key twice.
+    let mut bytecode = gen(vec![Instruction::Mov(
+        Register::R1,
+        Type::Map(BTreeMap::new()),
+    )]);
+    let len = bytecode.len();
+    bytecode[len - 8..].copy_from_slice(&2u64.to_le_bytes());
+    for _ in 0..2 {
+        bytecode.extend(gen_type(Type::Boolean(true)));
+        bytecode.extend(gen_type(Type::None));
+    }
+    let err = parse(bytecode).unwrap_err();
+    assert_eq!(err.cause, DecodeCause::InvalidMapKey);
+}
+
+#[test]
+#[cfg(feature = "gen")]
+#[cfg(feature = "parse")]
+fn gen_parse_structs() {
+    use allot_bytecode::{
+        gen, gen_with_structs, parse, parse_with_structs, DecodeCause, DecodeError,
+    };
+    use allot_lib::{RawType, StructDef};
+
+    let structs = vec![StructDef {
+        name: "Point".to_string(),
+        fields: vec![
+            ("x".to_string(), RawType::Float64),
+            ("y".to_string(), RawType::Float64),
+        ],
+    }];
+    let i = vec![
+        Instruction::Mov(
+            Register::R1,
+            Type::Struct(0, vec![Type::Float64(1.0), Type::Float64(2.0)]),
+        ),
+        Instruction::StructNew(Register::R2, 0),
+        Instruction::FieldGet(Register::R3, Register::R1, 0, 1),
+        Instruction::FieldSet(Register::R1, 0, 0, Register::R3),
+    ];
+
+    let bytecode = gen_with_structs(&i, &structs);
+    assert_eq!(
+        parse(bytecode.clone()),
+        Err(DecodeError::new(
+            8,
+            "structs",
+            DecodeCause::UnexpectedStructs(1)
+        ))
+    );
+    assert_eq!(parse_with_structs(bytecode), Ok((i.clone(), structs)));
+    assert_eq!(parse_with_structs(gen(i.clone())), Ok((i, vec![])));
+}
+
+#[test]
+#[cfg(feature = "gen")]
+#[cfg(feature = "parse")]
+fn gen_parse_tagged() {
+    use allot_bytecode::{gen, parse};
+
+    let i = vec![
+        Instruction::Mov(
+            Register::R1,
+            Type::Tagged(1, Box::new(Type::Tagged(0, Box::new(Type::None)))),
+        ),
+        Instruction::Tag(Register::R1, 2),
+        Instruction::Match(Register::R2, Register::R1, vec![]),
+        Instruction::Match(Register::R2, Register::R1, vec![4, 0, 1]),
+    ];
+    assert_eq!(parse(gen(i.clone())), Ok(i));
+}
+
+#[cfg(feature = "gen")]
+fn gen_type(t: Type) -> Vec<u8> {
+    let mut buffer = allot_bytecode::Buffer::new();
+    allot_bytecode::write_type(&mut buffer, &t);
+    buffer.into_inner()
+}
@@ -79,6 +79,6 @@ pub fn lib_return(input: LibReturnMacroInput) -> TokenStream {
     });
 
     quote! {
-        return (#(#fields)*)
+        return Ok((#(#fields)*))
     }
 }
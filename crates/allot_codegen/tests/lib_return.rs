@@ -2,17 +2,20 @@
 
 use allot_codegen::lib_return;
 
-type Return = (
-    Option<i32>,
-    Option<i32>,
-    Option<i32>,
-    Option<i32>,
-    Option<i32>,
-);
+type Return = Result<
+    (
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+        Option<i32>,
+    ),
+    (),
+>;
 
 #[test]
 fn t1() {
-    assert_eq!((Some(1), Some(2), Some(3), Some(4), Some(5)), t1_ret())
+    assert_eq!(Ok((Some(1), Some(2), Some(3), Some(4), Some(5))), t1_ret())
 }
 
 fn t1_ret() -> Return {
@@ -21,7 +24,7 @@ fn t1_ret() -> Return {
 
 #[test]
 fn t2() {
-    assert_eq!((Some(1), None, None, None, None), t2_ret())
+    assert_eq!(Ok((Some(1), None, None, None, None)), t2_ret())
 }
 
 fn t2_ret() -> Return {
@@ -30,7 +33,7 @@ fn t2_ret() -> Return {
 
 #[test]
 fn t3() {
-    assert_eq!((None, None, None, None, None), t3_ret())
+    assert_eq!(Ok((None, None, None, None, None)), t3_ret())
 }
 
 fn t3_ret() -> Return {
@@ -8,7 +8,9 @@ pub enum Instruction {
     /// Does nothing.
     Nop,
 
-    /// Does an operation on register(s).
+    /// Does an operation on register(s). Integer arithmetic is checked, so
+    /// overflow, and shifts by the number of bits in the type or more, trap in
+    /// every build profile.
     Op(Operation, [Register; 2]),
     /// Checks if a register is a type.
     // IsType(Register, RawType),
@@ -28,6 +30,9 @@ pub enum Instruction {
     Lea(Register, usize),
     /// Jumps to a label, depending on the value in the register.
     Jmp(Option<Register>, Type), // Type = Address || Register
+    /// Pushes the address of the next instruction onto the stack and jumps to
+    /// the label. Use Ret to return.
+    CallAddr(Type), // Type = Address || Register
     /// Pops the stack and jumps to that label.
     Ret,
 
@@ -50,21 +55,60 @@ pub enum Instruction {
     PopMany(Type), // Type = None | UInt | Register
     /// Copies an item at the stack offset into a register.
     StackCpy(Register, Type), // Type = None | UInt | Register
-    /// Pushes a new stack frame.
+    /// Pushes a new stack frame, values cannot be moved between an isolated
+    /// stack frame and the one below it.
     PushFrame(bool),
     /// Pops the top stack frame. Errors if it is the root stack frame.
     PopFrame,
     /// Pops from the last stack frame and pushes it to the current one.
-    /// May fail if the last stack frame is isolated.
+    /// Errors if the current stack frame is isolated.
     TakeFrom,
     /// Pops from the current stack frame and pushes onto the last stack frame.
-    /// May fail if the current stack frame is isolated.
+    /// Errors if the current stack frame is isolated.
     GiveTo,
 
+    /// Moves the value in the second register onto the end of the array in the
+    /// first register.
+    ArrPush(Register, Register),
+    /// Pops the last item of the array in the second register into the first
+    /// register.
+    ArrPop(Register, Register),
+    /// Copies the item at the index of the array in the second register into
+    /// the first register.
+    ArrGet(Register, Register, Type), // Type = UInt | Register
+    /// Moves the value in the last register into the array in the first
+    /// register at the index.
+    ArrSet(Register, Type, Register), // Type = UInt | Register
+    /// Puts the length of the array in the second register into the first
+    /// register as a UInt.
+    ArrLen(Register, Register),
+    /// Keeps the items of the array in the register from the first index up to
+    /// the second index.
+    ArrSlice(Register, Type, Type), // Type = UInt | Register
+
+    /// Turns the array in the register into a record of the struct with the
+    /// id. The items are the fields in the order they were declared.
+    StructNew(Register, usize),
+    /// Copies a field of the record in the second register into the first
+    /// register. The field is the struct id then the index of the field.
+    FieldGet(Register, Register, usize, usize),
+    /// Moves the value in the last register into a field of the record in the
+    /// first register.
+    FieldSet(Register, usize, usize, Register),
+
+    /// Wraps the value in the register in a tagged value with the tag.
+    Tag(Register, usize),
+    /// Moves the payload of the tagged value in the second register into the
+    /// first register, then jumps to the address at the index of its tag.
+    /// Tags without an address go on to the next instruction.
+    Match(Register, Register, Vec<usize>),
+
     /// Takes the current stack frame (Errors if it is the root stack frame) and
     /// runs it on a new thread starting at the label. Threads have their
-    /// own registers and stack frames, the heap is shared between all threads.
-    /// Puts its handle into register 5.
+    /// own registers, stack frames, and heap. Only values moved into the
+    /// shared region of the heap can be reached by other threads.
+    /// Puts a pointer to its handle, which is in the shared region, into
+    /// register 5.
     /// To stop the thread use Instruction::Exit.
     ThreadCreate(Type), // Type = Address || Register
     /// Joins a thread and pushes its stack frame. Accepts a pointer to its join
@@ -1,8 +1,15 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+
 use allot_codegen::RawEnum;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 use crate::Register;
 
+/// How deep arrays, maps, tagged values and records can be nested in each
+/// other. A value inside more than MAX_DEPTH others is rejected, so a value can
+/// not take more stack to read than there is.
+pub const MAX_DEPTH: usize = 128;
+
 #[derive(Clone, Debug, PartialEq, PartialOrd, RawEnum)]
 pub enum Type {
     None,
@@ -33,4 +40,78 @@ pub enum Type {
     Address(usize),
     Pointer(usize),
     Register(Register),
+
+    // Collections
+    Array(Vec<Type>),
+    /// Keys are kept in order.
+    Map(BTreeMap<MapKey, Type>),
+    /// A record of the struct with the id, its fields are in the order they
+    /// were declared.
+    Struct(usize, Vec<Type>),
+    /// A tag and its payload, like Some(value) or Err(value). What each tag
+    /// means is up to the program.
+    Tagged(usize, Box<Type>),
+}
+
+/// A record type declared by a program. Its id is its index in the program's
+/// list of structs.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StructDef {
+    pub name: String,
+    /// Field names and types. A Struct field can hold a record of any struct.
+    pub fields: Vec<(String, RawType)>,
+}
+impl StructDef {
+    /// The index of the field.
+    pub fn field(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|(n, _)| n == name)
+    }
+}
+
+/// A type that can be used as a map key, an int, char, string, or boolean.
+/// Keys are ordered by their type, then their value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapKey(Type);
+impl MapKey {
+    /// Gives the type back if it cannot be a key.
+    pub fn new(t: Type) -> Result<Self, Type> {
+        match t {
+            Type::Int8(_)
+            | Type::Int16(_)
+            | Type::Int32(_)
+            | Type::Int(_)
+            | Type::Int64(_)
+            | Type::Int128(_)
+            | Type::UInt8(_)
+            | Type::UInt16(_)
+            | Type::UInt32(_)
+            | Type::UInt(_)
+            | Type::UInt64(_)
+            | Type::UInt128(_)
+            | Type::Char(_)
+            | Type::String(_)
+            | Type::Boolean(_) => Ok(Self(t)),
+            _ => Err(t),
+        }
+    }
+
+    pub fn get(&self) -> &Type {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> Type {
+        self.0
+    }
+}
+impl Eq for MapKey {}
+impl Ord for MapKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Keys cannot be floats, so they always have an order.
+        self.0.partial_cmp(&other.0).unwrap()
+    }
+}
+impl PartialOrd for MapKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
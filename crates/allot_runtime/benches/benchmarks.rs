@@ -36,7 +36,7 @@ fn copy_speed(c: &mut Criterion) {
         b.iter(|| {
             let i = instructions.clone();
             let mut runtime = AllotRuntime::new_arc(i);
-            runtime.run();
+            runtime.run().unwrap();
         })
     });
 }
@@ -65,7 +65,7 @@ fn move_speed(c: &mut Criterion) {
         b.iter(|| {
             let i = instructions.clone();
             let mut runtime = AllotRuntime::new_arc(i);
-            runtime.run();
+            runtime.run().unwrap();
         })
     });
 }
@@ -0,0 +1,196 @@
+use std::{error::Error, fmt};
+
+use allot_lib::{Instruction, RawType, Register, Type};
+
+use crate::Limit;
+
+/// Why the runtime stopped executing a program.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trap {
+    /// There is no instruction at the address.
+    InvalidAddress(usize),
+    /// The register cannot hold a value. (Register::None)
+    InvalidRegister(Register),
+    /// Tried to put a Register type into a register.
+    RegisterInRegister,
+    /// A value was not the type that was expected.
+    UnexpectedType {
+        expected: &'static str,
+        found: Type,
+    },
+    /// An operation was used on types it does not support.
+    InvalidOperation(&'static str),
+    /// A value cannot be cast into the raw type.
+    InvalidCast(Type, RawType),
+    /// A number was not a valid char.
+    InvalidChar(u32),
+    DivisionByZero,
+    /// An integer operation overflowed, or shifted by more bits than the type
+    /// has, in any build profile.
+    Overflow,
+    /// Tried to pop from a stack but it was empty.
+    StackEmpty,
+    /// There is no item on the stack at the offset.
+    StackOffset(usize),
+    /// There is no item in the array at the index.
+    IndexOutOfBounds {
+        index: usize,
+        len: usize,
+    },
+    /// Tried to pop from an array but it was empty.
+    ArrayEmpty,
+    /// There is no struct with the id.
+    UnknownStruct(usize),
+    /// The struct does not have a field at the index.
+    UnknownField {
+        name: String,
+        field: usize,
+    },
+    /// A value was not a record of the struct that was expected.
+    StructMismatch {
+        expected: String,
+        found: Type,
+    },
+    /// A record was made with the wrong number of fields.
+    FieldCount {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// A value was not the type of the field, `Name.field`.
+    FieldTypeMismatch {
+        field: String,
+        expected: RawType,
+        found: Type,
+    },
+    /// There are no stack frames.
+    NoStackFrame,
+    /// Tried to remove the root stack frame, or reach below it.
+    RootStackFrame,
+    /// Tried to move a value between an isolated stack frame and the one below
+    /// it.
+    IsolatedStackFrame,
+    /// Tried to call a function that does not exist.
+    UnknownFunction(String),
+    /// The pointer does not point to anything in the heap.
+    InvalidPointer(usize),
+    /// The value the pointer pointed to was freed.
+    DanglingPointer(usize),
+    /// The pointer points into the heap of another thread.
+    ForeignPointer(usize),
+    /// The value in the heap is not the type that was expected.
+    HeapTypeMismatch {
+        pointer: usize,
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// Tried to take a lock held by a thread that exited.
+    AbandonedLock(usize),
+    /// Every green thread is waiting on another one.
+    Deadlock,
+    /// A library function failed.
+    Library(String),
+    /// A thread panicked and could not be joined.
+    ThreadPanicked,
+    /// A joined thread stopped because of a trap.
+    Thread(Box<RuntimeError>),
+    /// The program went over one of the runtime's Limits.
+    LimitExceeded(Limit),
+    /// Returned by library functions to stop the program with a code. The
+    /// runtime never reports this as an error, run returns the code instead.
+    Exit(i32),
+}
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trap::InvalidAddress(a) => write!(f, "there is no instruction at {a}"),
+            Trap::InvalidRegister(r) => write!(f, "{r:?} is not a valid register"),
+            Trap::RegisterInRegister => write!(f, "tried to put a Register type into a register"),
+            Trap::UnexpectedType { expected, found } => {
+                write!(f, "expected {expected}, found {found:?}")
+            }
+            Trap::InvalidOperation(m) => write!(f, "{m}"),
+            Trap::InvalidCast(t, raw) => write!(f, "cannot cast {t:?} into {raw:?}"),
+            Trap::InvalidChar(c) => write!(f, "{c:#X} is not a valid char"),
+            Trap::DivisionByZero => write!(f, "division by zero"),
+            Trap::Overflow => write!(f, "integer overflow"),
+            Trap::StackEmpty => write!(f, "tried to pop from stack but it was empty"),
+            Trap::StackOffset(o) => write!(f, "there is no item on the stack at offset {o}"),
+            Trap::IndexOutOfBounds { index, len } => {
+                write!(
+                    f,
+                    "index {index} is out of bounds for an array of length {len}"
+                )
+            }
+            Trap::ArrayEmpty => write!(f, "tried to pop from array but it was empty"),
+            Trap::UnknownStruct(id) => write!(f, "there is no struct with id {id}"),
+            Trap::UnknownField { name, field } => {
+                write!(f, "struct {name} has no field at index {field}")
+            }
+            Trap::StructMismatch { expected, found } => {
+                write!(f, "expected a {expected} record, found {found:?}")
+            }
+            Trap::FieldCount {
+                name,
+                expected,
+                found,
+            } => write!(f, "struct {name} has {expected} fields, found {found}"),
+            Trap::FieldTypeMismatch {
+                field,
+                expected,
+                found,
+            } => write!(f, "field {field} is a {expected:?}, found {found:?}"),
+            Trap::NoStackFrame => write!(f, "there are no stack frames"),
+            Trap::RootStackFrame => write!(f, "cannot take the root stack frame"),
+            Trap::IsolatedStackFrame => {
+                write!(
+                    f,
+                    "cannot move values out of or into an isolated stack frame"
+                )
+            }
+            Trap::UnknownFunction(n) => write!(f, "function {n:?} does not exist"),
+            Trap::InvalidPointer(p) => {
+                write!(f, "pointer {p:X?} does not point to anything in the heap")
+            }
+            Trap::DanglingPointer(p) => write!(f, "pointer {p:X?} points to a freed value"),
+            Trap::ForeignPointer(p) => {
+                write!(f, "pointer {p:X?} points into the heap of another thread")
+            }
+            Trap::HeapTypeMismatch {
+                pointer,
+                expected,
+                found,
+            } => write!(
+                f,
+                "pointer {pointer:X?} points to a {found}, not a {expected}"
+            ),
+            Trap::AbandonedLock(p) => {
+                write!(f, "lock {p:X?} is held by a thread that exited")
+            }
+            Trap::Deadlock => write!(f, "every thread is waiting on another thread"),
+            Trap::Library(m) => write!(f, "{m}"),
+            Trap::ThreadPanicked => write!(f, "thread panicked"),
+            Trap::Thread(e) => write!(f, "thread trapped: {e}"),
+            Trap::LimitExceeded(l) => write!(f, "{l} limit exceeded"),
+            Trap::Exit(code) => write!(f, "exit with code {code}"),
+        }
+    }
+}
+
+/// A trap along with the state of the runtime when it happened.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeError {
+    pub trap: Trap,
+    /// Index of the instruction that trapped.
+    pub current: usize,
+    /// The instruction that trapped, if there was one at current.
+    pub instruction: Option<Instruction>,
+    /// Snapshot of all registers, indexed by register number.
+    pub registers: Vec<Type>,
+}
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "trap at instruction {}: {}", self.current, self.trap)
+    }
+}
+impl Error for RuntimeError {}
@@ -0,0 +1,246 @@
+//! Green threads run as tasks on the thread that called run. A task runs for a
+//! slice of instructions, then a generator seeded by the program picks the
+//! next one, so the same seed always runs the tasks in the same order.
+//!
+//! Time is counted by the scheduler so it can be reproduced too. Every
+//! instruction takes INSTRUCTION_TIME, and when every task is waiting the clock
+//! skips ahead to the first one that wakes up (after sleeping for real).
+//!
+//! Instructions and library functions that would wait call block instead. The
+//! task stops there and tries the instruction again once another task has run,
+//! and only the try that finishes uses fuel or is counted by a profiler.
+//!
+//! Blocked tasks are not told what they wait for, so all of them are tried
+//! again whenever any task makes progress, and the tasks that can run are
+//! listed again for every slice. Both take time in the number of tasks, which
+//! adds up with thousands of waiting tasks.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    panic::{self, AssertUnwindSafe},
+    time::Duration,
+};
+
+use crate::{AllotRuntime, RuntimeError, StackFrame, Trap};
+
+const INSTRUCTION_TIME: Duration = Duration::from_micros(1);
+
+/// What a thread leaves for ThreadJoin, None if it had no stack frame to give
+/// back.
+pub(crate) type ThreadResult = Option<(Result<i32, Box<RuntimeError>>, StackFrame)>;
+
+/// Put on the heap by ThreadCreate in place of the receiver of an OS thread.
+#[derive(Debug)]
+pub(crate) struct GreenHandle(pub usize);
+
+/// What a task remembers between tries of a blocked instruction.
+#[derive(Debug, Default)]
+struct Waiting {
+    deadline: Option<Duration>,
+    value: Option<u64>,
+}
+
+struct Task {
+    /// None for the runtime that runs the scheduler.
+    runtime: Option<AllotRuntime>,
+    waiting: Waiting,
+    blocked: bool,
+}
+impl Task {
+    fn new(runtime: Option<AllotRuntime>) -> Self {
+        Self {
+            runtime,
+            waiting: Waiting::default(),
+            blocked: false,
+        }
+    }
+}
+
+/// What instructions and library functions can reach while a task runs.
+#[derive(Default)]
+struct Scheduler {
+    clock: Duration,
+    waiting: Waiting,
+    /// Set by block, until the scheduler sees the instruction has to wait.
+    blocked: bool,
+    spawned: Vec<AllotRuntime>,
+    finished: BTreeMap<usize, ThreadResult>,
+}
+
+thread_local! {
+    static SCHEDULER: RefCell<Option<Scheduler>> = const { RefCell::new(None) };
+}
+
+/// Removes the scheduler when run returns, even if it panics.
+struct Running;
+impl Drop for Running {
+    fn drop(&mut self) {
+        SCHEDULER.with(|s| s.borrow_mut().take());
+    }
+}
+
+#[inline]
+fn with<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    SCHEDULER.with(|s| f(s.borrow_mut().as_mut().expect("No green scheduler.")))
+}
+
+/// Whether this thread is running green threads.
+pub(crate) fn is_running() -> bool {
+    SCHEDULER.with(|s| s.borrow().is_some())
+}
+
+pub(crate) fn spawn(runtime: AllotRuntime) {
+    with(|s| s.spawned.push(runtime));
+}
+
+/// Tells the scheduler the instruction has to wait, and returns the trap that
+/// stops it for now. The trap is only seen if every thread is waiting.
+pub(crate) fn block() -> Trap {
+    with(|s| s.blocked = true);
+    Trap::Deadlock
+}
+
+/// Whether the last instruction called block.
+pub(crate) fn blocked() -> bool {
+    SCHEDULER.with(|s| s.borrow().as_ref().is_some_and(|s| s.blocked))
+}
+
+/// Takes what the thread left once it has ended.
+pub(crate) fn join(thread: usize) -> Result<ThreadResult, Trap> {
+    if !is_running() {
+        // The thread can never run again.
+        return Err(Trap::Deadlock);
+    }
+    match with(|s| s.finished.remove(&thread)) {
+        Some(result) => Ok(result),
+        None => Err(block()),
+    }
+}
+
+/// Whether the time has passed since the first try of the instruction.
+pub(crate) fn slept(time: Duration) -> bool {
+    with(|s| {
+        let deadline = *s.waiting.deadline.get_or_insert(s.clock + time);
+        s.clock >= deadline
+    })
+}
+
+/// Remembers the value on the first try of the instruction, and returns what
+/// was remembered on later tries.
+pub(crate) fn remember(value: u64) -> Option<u64> {
+    with(|s| match s.waiting.value {
+        None => {
+            s.waiting.value = Some(value);
+            None
+        }
+        remembered => remembered,
+    })
+}
+
+/// Picks the next task. (SplitMix64)
+struct Generator(u64);
+impl Generator {
+    fn next(&mut self, len: usize) -> usize {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        ((z ^ (z >> 31)) % len as u64) as usize
+    }
+}
+
+/// Runs the runtime and the threads it creates until the runtime ends.
+pub(crate) fn run(
+    main: &mut AllotRuntime,
+    seed: u64,
+    slice: usize,
+) -> Result<i32, Box<RuntimeError>> {
+    SCHEDULER.with(|s| *s.borrow_mut() = Some(Scheduler::default()));
+    let _running = Running;
+
+    let mut tasks = vec![Task::new(None)];
+    let mut generator = Generator(seed);
+    loop {
+        let ready: Vec<usize> = (0..tasks.len()).filter(|i| !tasks[*i].blocked).collect();
+        if ready.is_empty() {
+            let deadline = tasks.iter().filter_map(|t| t.waiting.deadline).min();
+            match deadline {
+                Some(deadline) => {
+                    let clock = with(|s| std::mem::replace(&mut s.clock, deadline));
+                    // The next instruction stops the program if it ran out of time.
+                    let wait = deadline.saturating_sub(clock);
+                    std::thread::sleep(main.budget.time_left().map_or(wait, |left| wait.min(left)));
+                    tasks.iter_mut().for_each(|t| t.blocked = false);
+                    continue;
+                }
+                None => return Err(main.error(Trap::Deadlock)),
+            }
+        }
+
+        let i = ready[generator.next(ready.len())];
+        let task = &mut tasks[i];
+        with(|s| s.waiting = std::mem::take(&mut task.waiting));
+
+        let is_main = task.runtime.is_none();
+        let runtime = match &mut task.runtime {
+            Some(runtime) => runtime,
+            None => &mut *main,
+        };
+        let mut progressed = false;
+        let mut ended = None;
+        for _ in 0..slice.max(1) {
+            // A thread that panics is joined as one, like an OS thread. The main
+            // runtime has no one to join it, so its panic goes on.
+            let result = match is_main {
+                true => Ok(runtime.tick()),
+                false => panic::catch_unwind(AssertUnwindSafe(|| runtime.tick())),
+            };
+            match result {
+                Ok(Ok(None)) => {
+                    progressed = true;
+                    with(|s| {
+                        s.clock += INSTRUCTION_TIME;
+                        s.waiting = Waiting::default();
+                    });
+                }
+                Ok(Err(_)) if with(|s| std::mem::take(&mut s.blocked)) => {
+                    task.blocked = true;
+                    break;
+                }
+                Ok(Ok(Some(code))) => {
+                    ended = Some(Some(Ok(code)));
+                    break;
+                }
+                Ok(Err(e)) => {
+                    ended = Some(Some(Err(e)));
+                    break;
+                }
+                Err(_) => {
+                    with(|s| s.blocked = false);
+                    ended = Some(None);
+                    break;
+                }
+            }
+        }
+
+        task.waiting = with(|s| std::mem::take(&mut s.waiting));
+        let spawned = with(|s| std::mem::take(&mut s.spawned));
+        tasks.extend(spawned.into_iter().map(|runtime| Task::new(Some(runtime))));
+
+        if let Some(result) = ended {
+            let mut runtime = match tasks.remove(i).runtime {
+                None => return result.expect("the main runtime does not catch panics"),
+                Some(runtime) => runtime,
+            };
+            let left =
+                result.and_then(|result| runtime.stack_frames.pop().map(|frame| (result, frame)));
+            with(|s| s.finished.insert(runtime.thread, left));
+            progressed = true;
+        }
+        // Whatever the blocked tasks wait for may have happened.
+        if progressed {
+            tasks.iter_mut().for_each(|t| t.blocked = false);
+        }
+    }
+}
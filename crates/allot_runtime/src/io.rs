@@ -0,0 +1,182 @@
+use std::{
+    any::Any,
+    io,
+    io::{BufRead, BufReader, Read, Write},
+    sync::{mpsc, Arc, Mutex, MutexGuard},
+    time::Instant,
+};
+
+use crate::{limits, RuntimeError, Trap};
+
+/// The stdin, stdout, and stderr that library functions use.
+/// Shared between a runtime and the threads it creates.
+pub struct Io {
+    stdin: Mutex<Stdin>,
+    stdout: Mutex<Box<dyn Write + Send>>,
+    stderr: Mutex<Box<dyn Write + Send>>,
+}
+impl Io {
+    pub fn new(
+        stdin: Box<dyn Read + Send>,
+        stdout: Box<dyn Write + Send>,
+        stderr: Box<dyn Write + Send>,
+    ) -> Self {
+        Self {
+            stdin: Mutex::new(Stdin::Here(Box::new(BufReader::new(stdin)))),
+            stdout: Mutex::new(stdout),
+            stderr: Mutex::new(stderr),
+        }
+    }
+
+    /// Uses the stdin, stdout, and stderr of the process.
+    pub fn std() -> Self {
+        Self::new(
+            Box::new(io::stdin()),
+            Box::new(io::stdout()),
+            Box::new(io::stderr()),
+        )
+    }
+
+    /// Reads from the bytes and writes into captures.
+    pub fn capture(stdin: &[u8]) -> (Self, Capture, Capture) {
+        let stdout = Capture::default();
+        let stderr = Capture::default();
+        let io = Self::new(
+            Box::new(io::Cursor::new(stdin.to_vec())),
+            Box::new(stdout.clone()),
+            Box::new(stderr.clone()),
+        );
+
+        (io, stdout, stderr)
+    }
+
+    /// Runs f with stdin. The first time a program with a time limit reads,
+    /// stdin moves to a thread of its own that runs every read after it, so the
+    /// program can stop if a read does not finish in time. What a read that was
+    /// given up on reads is lost.
+    pub fn read<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut dyn BufRead) -> R + Send + 'static,
+    ) -> Result<R, Trap> {
+        let mut stdin = self
+            .stdin
+            .lock()
+            .map_err(|_| Trap::Library("stdin is poisoned.".to_string()))?;
+        let left = limits::time_left();
+        if let Stdin::Here(reader) = &mut *stdin {
+            match left {
+                None => return Ok(f(&mut **reader)),
+                Some(_) => stdin.spawn(),
+            }
+        }
+
+        let Stdin::Thread {
+            reads,
+            results,
+            sent,
+        } = &mut *stdin
+        else {
+            unreachable!("stdin was moved to its thread");
+        };
+        *sent += 1;
+        let read: ReadJob = Box::new(move |stdin| Box::new(f(stdin)));
+        let gone = || Trap::Library("stdin panicked.".to_string());
+        reads.send((*sent, read)).map_err(|_| gone())?;
+
+        let deadline = left.map(|left| Instant::now() + left);
+        loop {
+            let left = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            let (id, r) = limits::recv(results, left, gone())?;
+            // Reads that were given up on still send back what they read.
+            if id == *sent {
+                return Ok(*r.downcast::<R>().expect("a read sends back its own type"));
+            }
+        }
+    }
+
+    pub fn stdout(&self) -> Result<MutexGuard<'_, Box<dyn Write + Send>>, Trap> {
+        self.stdout
+            .lock()
+            .map_err(|_| Trap::Library("stdout is poisoned.".to_string()))
+    }
+
+    pub fn stderr(&self) -> Result<MutexGuard<'_, Box<dyn Write + Send>>, Trap> {
+        self.stderr
+            .lock()
+            .map_err(|_| Trap::Library("stderr is poisoned.".to_string()))
+    }
+}
+impl Default for Io {
+    fn default() -> Self {
+        Self::std()
+    }
+}
+
+/// A read for the stdin thread to run, and what it read.
+type ReadJob = Box<dyn FnOnce(&mut dyn BufRead) -> Box<dyn Any + Send> + Send>;
+
+enum Stdin {
+    Here(Box<dyn BufRead + Send>),
+    /// Moved to a thread that runs reads in the order they are sent, and sends
+    /// back what they read with the id they were sent with.
+    Thread {
+        reads: mpsc::Sender<(u64, ReadJob)>,
+        results: mpsc::Receiver<(u64, Box<dyn Any + Send>)>,
+        /// The id of the last read sent.
+        sent: u64,
+    },
+}
+impl Stdin {
+    /// Moves stdin to its own thread.
+    fn spawn(&mut self) {
+        let (reads, jobs) = mpsc::channel::<(u64, ReadJob)>();
+        let (sender, results) = mpsc::channel();
+        let thread = Stdin::Thread {
+            reads,
+            results,
+            sent: 0,
+        };
+        let mut reader = match std::mem::replace(self, thread) {
+            Stdin::Here(reader) => reader,
+            Stdin::Thread { .. } => unreachable!("stdin is already on its thread"),
+        };
+        std::thread::spawn(move || {
+            for (id, read) in jobs {
+                if sender.send((id, read(&mut *reader))).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// A writer that keeps everything written to it.
+#[derive(Clone, Debug, Default)]
+pub struct Capture(Arc<Mutex<Vec<u8>>>);
+impl Capture {
+    pub fn bytes(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn string(&self) -> String {
+        String::from_utf8_lossy(&self.bytes()).into_owned()
+    }
+}
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What a program wrote while running with AllotRuntime::run_captured.
+#[derive(Debug)]
+pub struct Captured {
+    pub code: Result<i32, Box<RuntimeError>>,
+    pub stdout: String,
+    pub stderr: String,
+}
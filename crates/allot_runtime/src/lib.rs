@@ -1,65 +1,230 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
-use allot_lib::{Instruction, Type};
+pub use error::*;
+pub use io::{Capture, Captured, Io};
+pub use library::{Library, LibraryFunction, LibraryRegisters, LibraryReturn, LibraryValues};
+pub use limits::{Limit, Limits};
+pub use memory::{CrossHeap, Heap, Registers, SharedHeap, SlotSnapshot, StackFrame};
+pub use profile::{CallStats, Frame, Profile, Profiler, ThreadStats};
+pub use snapshot::{instruction_hash, SnapshotError, SNAPSHOT_VERSION};
 pub use tick::*;
+pub use trace::{CallTrace, JsonLinesTracer, RegisterWrite, TraceRecord, Tracer};
 
-use crate::memory::{CrossHeap, Heap, Registers, StackFrame};
+use crate::{library::Alive, limits::Budget, profile::ThreadProfiler};
 
+mod error;
+mod green;
+mod io;
 mod library;
+mod limits;
 mod memory;
 mod operations;
+mod profile;
+mod snapshot;
 #[doc(hidden)]
 mod tick;
+mod trace;
+
+/// What happens when a program calls the exit library function.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ExitPolicy {
+    /// Stop the runtime (or thread) and return the code from run.
+    #[default]
+    Return,
+    /// Flush stdout and stderr, then exit the whole process with the code.
+    Process,
+}
+
+/// How ThreadCreate runs threads.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Threading {
+    /// Every thread is an OS thread.
+    #[default]
+    Os,
+    /// Threads are tasks run by the thread that called run. Every slice
+    /// instructions, the next task is picked by a generator seeded with seed,
+    /// so the same seed always runs threads in the same order. Only used by
+    /// run, ticking a runtime still creates OS threads.
+    Green { seed: u64, slice: usize },
+}
 
 pub struct AllotRuntime {
+    /// 0 for the main runtime, threads are numbered in the order they were
+    /// created.
+    pub thread: usize,
     pub current: usize,
     pub instructions: Arc<Vec<Instruction>>,
     pub registers: Registers,
     pub stack_frames: Vec<StackFrame>,
+    /// Threads only share the shared region of the heap.
     pub heap: CrossHeap,
+    /// The structs the program declares, indexed by id. Shared with threads
+    /// created by this runtime.
+    pub structs: Arc<Vec<StructDef>>,
+    /// The traps of the Mov instructions whose values have records that do not
+    /// match the structs, by address. Found by set_structs, since the values in
+    /// instructions do not change. Shared with threads created by this runtime.
+    invalid_records: Arc<HashMap<usize, Trap>>,
+    /// Shared with threads created by this runtime.
+    pub library: Arc<Library>,
+    /// Shared with threads created by this runtime.
+    pub io: Arc<Io>,
+    /// Shared with threads created by this runtime.
+    pub exit_policy: ExitPolicy,
+    /// Shared with threads created by this runtime.
+    pub threading: Threading,
+    /// Shared with threads created by this runtime.
+    budget: Arc<Budget>,
+    /// Shared with threads created by this runtime.
+    tracer: Option<Arc<dyn Tracer>>,
+    profiler: Option<Box<ThreadProfiler>>,
+    /// Dropped with the runtime, so locks it holds can tell it is gone.
+    alive: Arc<Alive>,
 }
 impl AllotRuntime {
+    /// Creates a runtime with the default library functions.
     pub fn new(instructions: Vec<Instruction>) -> Self {
-        Self {
-            instructions: Arc::new(instructions),
-            registers: Registers::new(),
-            stack_frames: vec![StackFrame::default()],
-            heap: Heap::cross_new(),
-            current: 0,
-        }
+        Self::new_arc(Arc::new(instructions))
     }
 
     pub fn new_arc(instructions: Arc<Vec<Instruction>>) -> Self {
-        Self {
+        Self::with_library(instructions, Library::with_defaults())
+    }
+
+    /// Creates a runtime that can only call the functions in the library.
+    pub fn with_library(instructions: Arc<Vec<Instruction>>, library: Library) -> Self {
+        let mut runtime = Self {
             instructions,
             registers: Registers::new(),
             stack_frames: vec![StackFrame::default()],
-            heap: Heap::cross_new(),
+            heap: CrossHeap::new(),
+            structs: Arc::new(Vec::new()),
+            invalid_records: Arc::default(),
+            library: Arc::new(library),
+            io: Arc::new(Io::std()),
+            exit_policy: ExitPolicy::default(),
+            threading: Threading::default(),
+            budget: Arc::new(Budget::default()),
+            tracer: None,
+            profiler: None,
+            alive: Arc::default(),
+            thread: 0,
             current: 0,
-        }
+        };
+        runtime.invalid_records = Arc::new(runtime.find_invalid_records());
+        runtime
     }
 
-    pub fn new_thread(
-        instructions: Arc<Vec<Instruction>>,
-        stack_frame: StackFrame,
-        heap: CrossHeap,
-        current: usize,
-    ) -> Self {
+    /// Creates a runtime for a thread, which shares everything but registers,
+    /// stack frames, and its own heap with this runtime.
+    pub fn new_thread(&self, thread: usize, mut stack_frame: StackFrame, current: usize) -> Self {
+        // A frame moved by a traced ThreadCreate is still keeping what is popped.
+        stack_frame.take_journal();
         Self {
-            instructions,
+            instructions: self.instructions.clone(),
             registers: Registers::new(),
             stack_frames: vec![stack_frame],
-            heap,
+            heap: self.heap.for_thread(thread),
+            structs: self.structs.clone(),
+            invalid_records: self.invalid_records.clone(),
+            library: self.library.clone(),
+            io: self.io.clone(),
+            exit_policy: self.exit_policy,
+            threading: self.threading,
+            budget: self.budget.clone(),
+            tracer: self.tracer.clone(),
+            profiler: self.profiler.as_ref().map(|p| {
+                let len = self.instructions.len();
+                Box::new(ThreadProfiler::new(p.shared(), thread, current, len))
+            }),
+            alive: Arc::default(),
+            thread,
             current,
         }
     }
 
-    pub fn run(&mut self) -> i32 {
-        let mut code = self.tick();
-        while code.is_none() {
-            code = self.tick();
+    /// Registers a function that programs can call, replacing any function
+    /// with the same name. Threads created after this will also have it.
+    pub fn register_function<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(LibraryRegisters, &mut StackFrame, &mut CrossHeap, &Io) -> LibraryReturn
+            + Send
+            + Sync
+            + 'static,
+    {
+        Arc::make_mut(&mut self.library).register(name, f);
+    }
+
+    /// Sets the structs the program declares. Threads created after this will
+    /// also have them.
+    pub fn set_structs(&mut self, structs: Vec<StructDef>) {
+        self.structs = Arc::new(structs);
+        self.invalid_records = Arc::new(self.find_invalid_records());
+    }
+
+    /// Replaces the stdin, stdout, and stderr used by library functions.
+    /// Threads created after this will also use it.
+    pub fn set_io(&mut self, io: Io) {
+        self.io = Arc::new(io);
+    }
+
+    /// Sets the limits for this runtime and the threads it creates. This
+    /// resets the fuel, threads, and time used so far.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.heap.set_limit(limits.heap_entries);
+        self.budget = Arc::new(Budget::new(limits));
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.budget.limits
+    }
+
+    /// Records every instruction run by this runtime and the threads it
+    /// creates after this.
+    pub fn set_tracer(&mut self, tracer: Arc<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Counts every instruction run by this runtime and the threads it creates
+    /// after this. The profile of a runtime is added to the profiler when it
+    /// is dropped.
+    pub fn set_profiler(&mut self, profiler: Arc<Profiler>) {
+        self.profiler = Some(Box::new(ThreadProfiler::new(
+            profiler,
+            self.thread,
+            self.current,
+            self.instructions.len(),
+        )));
+    }
+
+    pub fn run(&mut self) -> Result<i32, Box<RuntimeError>> {
+        if let Threading::Green { seed, slice } = self.threading {
+            if !green::is_running() {
+                return green::run(self, seed, slice);
+            }
+        }
+
+        loop {
+            match self.tick() {
+                Ok(Some(code)) => return Ok(code),
+                Ok(None) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Runs the program with the bytes as stdin, and captures what it writes to
+    /// stdout and stderr.
+    pub fn run_captured(&mut self, stdin: &[u8]) -> Captured {
+        let (io, stdout, stderr) = Io::capture(stdin);
+        self.set_io(io);
+        let code = self.run();
+
+        Captured {
+            code,
+            stdout: stdout.string(),
+            stderr: stderr.string(),
         }
-        code.unwrap()
     }
 
     pub fn take_stack_frame(&mut self) -> StackFrame {
@@ -68,38 +233,189 @@ impl AllotRuntime {
 }
 impl AllotRuntime {
     #[inline]
-    fn get_uint(t: &Type, registers: &mut Registers) -> usize {
+    fn get_uint(t: &Type, registers: &mut Registers) -> Result<usize, Trap> {
         match t {
-            Type::UInt(i) => *i,
-            Type::Register(reg) => match registers.get(*reg) {
-                Type::UInt(i) => *i,
-                _ => panic!("Register did not hold a UInt type."),
+            Type::UInt(i) => Ok(*i),
+            Type::Register(reg) => match registers.get(*reg)? {
+                Type::UInt(i) => Ok(*i),
+                found => Err(Trap::UnexpectedType {
+                    expected: "UInt",
+                    found: found.clone(),
+                }),
             },
-            _ => panic!("Type was not a UInt or Register."),
+            _ => Err(Trap::UnexpectedType {
+                expected: "UInt or Register",
+                found: t.clone(),
+            }),
         }
     }
 
     #[inline]
-    fn get_int32(t: &Type, registers: &mut Registers) -> i32 {
+    fn get_int32(t: &Type, registers: &mut Registers) -> Result<i32, Trap> {
         match t {
-            Type::Int32(i) => *i,
-            Type::Register(reg) => match registers.get(*reg) {
-                Type::Int32(i) => *i,
-                _ => panic!("Register did not hold a Int32 type."),
+            Type::Int32(i) => Ok(*i),
+            Type::Register(reg) => match registers.get(*reg)? {
+                Type::Int32(i) => Ok(*i),
+                found => Err(Trap::UnexpectedType {
+                    expected: "Int32",
+                    found: found.clone(),
+                }),
             },
-            _ => panic!("Type was not a Int32 or Register."),
+            _ => Err(Trap::UnexpectedType {
+                expected: "Int32 or Register",
+                found: t.clone(),
+            }),
+        }
+    }
+
+    #[inline]
+    fn get_array(register: Register, registers: &mut Registers) -> Result<&mut Vec<Type>, Trap> {
+        match registers.get_mut(register)? {
+            Type::Array(v) => Ok(v),
+            found => Err(Trap::UnexpectedType {
+                expected: "Array",
+                found: found.clone(),
+            }),
+        }
+    }
+
+    /// Takes the value to put into the array or record in another register,
+    /// once nothing else can trap. Copies it if it is the array or record
+    /// itself.
+    #[inline]
+    fn take_element(
+        register: Register,
+        into: Register,
+        registers: &mut Registers,
+    ) -> Result<Type, Trap> {
+        match register == into {
+            true => registers.clone(register),
+            false => registers.take(register),
+        }
+    }
+
+    #[inline]
+    fn get_struct(&self, id: usize) -> Result<Arc<Vec<StructDef>>, Trap> {
+        match id < self.structs.len() {
+            true => Ok(self.structs.clone()),
+            false => Err(Trap::UnknownStruct(id)),
+        }
+    }
+
+    /// Gets the fields of the record in the register, if it is a record of the
+    /// struct.
+    #[inline]
+    fn get_record<'a>(
+        register: Register,
+        id: usize,
+        def: &StructDef,
+        registers: &'a mut Registers,
+    ) -> Result<&'a mut Vec<Type>, Trap> {
+        match registers.get(register)? {
+            Type::Struct(i, _) if *i == id => {}
+            found => {
+                return Err(Trap::StructMismatch {
+                    expected: def.name.clone(),
+                    found: found.clone(),
+                })
+            }
+        }
+        match registers.get_mut(register)? {
+            Type::Struct(_, fields) => Ok(fields),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Checks that the value can be put in the field.
+    #[inline]
+    fn check_field(def: &StructDef, field: usize, t: &Type) -> Result<(), Trap> {
+        let (name, raw) = def.fields.get(field).ok_or_else(|| Trap::UnknownField {
+            name: def.name.clone(),
+            field,
+        })?;
+        match t.to_raw() == *raw {
+            true => Ok(()),
+            false => Err(Trap::FieldTypeMismatch {
+                field: format!("{}.{name}", def.name),
+                expected: *raw,
+                found: t.clone(),
+            }),
+        }
+    }
+
+    /// Checks that the values can be the fields of a record of the struct.
+    #[inline]
+    fn check_fields(def: &StructDef, fields: &[Type]) -> Result<(), Trap> {
+        if fields.len() != def.fields.len() {
+            return Err(Trap::FieldCount {
+                name: def.name.clone(),
+                expected: def.fields.len(),
+                found: fields.len(),
+            });
+        }
+        for (field, t) in fields.iter().enumerate() {
+            AllotRuntime::check_field(def, field, t)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the records in a value written into an instruction, which was not
+    /// made by StructNew.
+    fn check_records(&self, t: &Type) -> Result<(), Trap> {
+        match t {
+            Type::Struct(id, fields) => {
+                let structs = self.get_struct(*id)?;
+                AllotRuntime::check_fields(&structs[*id], fields)?;
+                fields.iter().try_for_each(|t| self.check_records(t))
+            }
+            Type::Array(values) => values.iter().try_for_each(|t| self.check_records(t)),
+            Type::Map(map) => map.values().try_for_each(|t| self.check_records(t)),
+            Type::Tagged(_, t) => self.check_records(t),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the records in the values of every Mov instruction.
+    fn find_invalid_records(&self) -> HashMap<usize, Trap> {
+        self.instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(address, instruction)| match instruction {
+                Instruction::Mov(_, t) => self.check_records(t).err().map(|trap| (address, trap)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The frame below the current one and the current one, if values can move
+    /// between them.
+    #[inline]
+    fn frame_pair(
+        stack_frames: &mut [StackFrame],
+    ) -> Result<(&mut StackFrame, &mut StackFrame), Trap> {
+        match stack_frames {
+            [] => Err(Trap::NoStackFrame),
+            [_] => Err(Trap::RootStackFrame),
+            [.., _, current] if current.is_isolated() => Err(Trap::IsolatedStackFrame),
+            [.., below, current] => Ok((below, current)),
         }
     }
 
     #[inline]
-    fn get_address(t: &Type, registers: &mut Registers) -> usize {
+    fn get_address(t: &Type, registers: &mut Registers) -> Result<usize, Trap> {
         match t {
-            Type::Address(i) => *i,
-            Type::Register(reg) => match registers.get(*reg) {
-                Type::Address(i) => *i,
-                _ => panic!("Register did not hold a Label type."),
+            Type::Address(i) => Ok(*i),
+            Type::Register(reg) => match registers.get(*reg)? {
+                Type::Address(i) => Ok(*i),
+                found => Err(Trap::UnexpectedType {
+                    expected: "Address",
+                    found: found.clone(),
+                }),
             },
-            _ => panic!("Type was not a Label or Register."),
+            _ => Err(Trap::UnexpectedType {
+                expected: "Address or Register",
+                found: t.clone(),
+            }),
         }
     }
 }
@@ -0,0 +1,280 @@
+//! Atomic integer cells that live in the shared region of the heap. Each thread
+//! remembers the cells it has used, so an access only locks the shared region
+//! the first time, or after an entry has left the region since the last time.
+//! Arithmetic that overflows traps and leaves the atomic as it was.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    sync::{
+        atomic::{
+            AtomicI16, AtomicI32, AtomicI64, AtomicI8, AtomicIsize, AtomicU16, AtomicU32,
+            AtomicU64, AtomicU8, AtomicUsize, Ordering,
+        },
+        Arc, Weak,
+    },
+};
+
+use allot_codegen::lib_return;
+use allot_lib::Type;
+
+use crate::{
+    library::{LibraryRegisters, LibraryReturn},
+    CrossHeap, Io, StackFrame, Trap,
+};
+
+const ORDERING: Ordering = Ordering::SeqCst;
+
+#[derive(Debug)]
+enum AtomicCell {
+    Int8(AtomicI8),
+    Int16(AtomicI16),
+    Int32(AtomicI32),
+    Int(AtomicIsize),
+    Int64(AtomicI64),
+    UInt8(AtomicU8),
+    UInt16(AtomicU16),
+    UInt32(AtomicU32),
+    UInt(AtomicUsize),
+    UInt64(AtomicU64),
+}
+
+/// Runs the expression with the atomic and the value if they are the same
+/// type, and wraps the result back into that type.
+macro_rules! with_value {
+    ($cell:expr, $t:expr, | $a:ident, $v:ident | $e:expr) => {
+        with_value!(
+            $cell, $t, |$a, $v| $e,
+            Int8, Int16, Int32, Int, Int64, UInt8, UInt16, UInt32, UInt, UInt64
+        )
+    };
+    ($cell:expr, $t:expr, | $a:ident, $v:ident | $e:expr, $($variant:ident),*) => {
+        match ($cell, $t) {
+            $((AtomicCell::$variant($a), Type::$variant($v)) => Ok(Type::$variant($e)),)*
+            (_, found) => Err(Trap::UnexpectedType {
+                expected: "the integer type of the atomic",
+                found: found.clone(),
+            }),
+        }
+    };
+}
+
+impl AtomicCell {
+    fn new(t: &Type) -> Result<Self, Trap> {
+        Ok(match t {
+            Type::Int8(v) => AtomicCell::Int8(AtomicI8::new(*v)),
+            Type::Int16(v) => AtomicCell::Int16(AtomicI16::new(*v)),
+            Type::Int32(v) => AtomicCell::Int32(AtomicI32::new(*v)),
+            Type::Int(v) => AtomicCell::Int(AtomicIsize::new(*v)),
+            Type::Int64(v) => AtomicCell::Int64(AtomicI64::new(*v)),
+            Type::UInt8(v) => AtomicCell::UInt8(AtomicU8::new(*v)),
+            Type::UInt16(v) => AtomicCell::UInt16(AtomicU16::new(*v)),
+            Type::UInt32(v) => AtomicCell::UInt32(AtomicU32::new(*v)),
+            Type::UInt(v) => AtomicCell::UInt(AtomicUsize::new(*v)),
+            Type::UInt64(v) => AtomicCell::UInt64(AtomicU64::new(*v)),
+            found => {
+                return Err(Trap::UnexpectedType {
+                    expected: "Int or UInt of 64 bits or less",
+                    found: found.clone(),
+                })
+            }
+        })
+    }
+
+    fn load(&self) -> Type {
+        match self {
+            AtomicCell::Int8(a) => Type::Int8(a.load(ORDERING)),
+            AtomicCell::Int16(a) => Type::Int16(a.load(ORDERING)),
+            AtomicCell::Int32(a) => Type::Int32(a.load(ORDERING)),
+            AtomicCell::Int(a) => Type::Int(a.load(ORDERING)),
+            AtomicCell::Int64(a) => Type::Int64(a.load(ORDERING)),
+            AtomicCell::UInt8(a) => Type::UInt8(a.load(ORDERING)),
+            AtomicCell::UInt16(a) => Type::UInt16(a.load(ORDERING)),
+            AtomicCell::UInt32(a) => Type::UInt32(a.load(ORDERING)),
+            AtomicCell::UInt(a) => Type::UInt(a.load(ORDERING)),
+            AtomicCell::UInt64(a) => Type::UInt64(a.load(ORDERING)),
+        }
+    }
+}
+
+/// A cell this thread has used, and the removals of its region when it was
+/// found live.
+struct Cached {
+    cell: Weak<AtomicCell>,
+    removals: Arc<AtomicUsize>,
+    seen: usize,
+}
+impl Cached {
+    /// The cell, if no entry has left its region since it was found, so the
+    /// pointer to it still works.
+    fn get(&self) -> Option<Arc<AtomicCell>> {
+        match self.removals.load(ORDERING) == self.seen {
+            true => self.cell.upgrade(),
+            false => None,
+        }
+    }
+}
+
+thread_local! {
+    /// The cells this thread has used, by the shared region they are in and
+    /// their pointer, which includes the generation of the slot.
+    static CELLS: RefCell<HashMap<(usize, usize), Cached>> = RefCell::new(HashMap::new());
+}
+
+#[inline]
+fn pointer(function: &str, t: &Type) -> Result<usize, Trap> {
+    match t {
+        Type::Pointer(p) => Ok(*p),
+        _ => Err(Trap::Library(format!("{function} expects a pointer."))),
+    }
+}
+
+/// Finds the cell r5 points to, only locking the shared region if this thread
+/// can not tell that the pointer still works.
+fn cell(function: &str, t: &Type, heap: &mut CrossHeap) -> Result<Arc<AtomicCell>, Trap> {
+    let p = pointer(function, t)?;
+    if !CrossHeap::is_shared(p) {
+        return heap.local.get::<Arc<AtomicCell>>(p).cloned();
+    }
+    let key = (Arc::as_ptr(&heap.shared) as usize, p);
+    if let Some(cell) = CELLS.with(|cells| cells.borrow().get(&key).and_then(Cached::get)) {
+        return Ok(cell);
+    }
+
+    let shared = heap.shared.lock().unwrap();
+    let cell = shared.get::<Arc<AtomicCell>>(p)?.clone();
+    let removals = shared.removals().clone();
+    let seen = removals.load(ORDERING);
+    drop(shared);
+    CELLS.with(|cells| {
+        let mut cells = cells.borrow_mut();
+        cells.retain(|_, cached| cached.get().is_some());
+        cells.insert(
+            key,
+            Cached {
+                cell: Arc::downgrade(&cell),
+                removals,
+                seen,
+            },
+        );
+    });
+    Ok(cell)
+}
+
+/// Creates an atomic holding r5, an Int or UInt of 64 bits or less, and puts a
+/// pointer to it in r5.
+pub fn new(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let cell = AtomicCell::new(args.0)?;
+    let p = heap.share(Arc::new(cell))?;
+    lib_return!(p)
+}
+
+/// Puts the value of the atomic r5 points to in r6.
+pub fn load(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let t = cell("atomic::load", args.0, heap)?.load();
+    Ok((None, Some(t), None, None, None))
+}
+
+/// Sets the atomic r5 points to to r6.
+pub fn store(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let cell = cell("atomic::store", args.0, heap)?;
+    with_value!(&*cell, args.1, |a, v| {
+        a.store(*v, ORDERING);
+        *v
+    })?;
+    lib_return!()
+}
+
+/// Adds r6 to the atomic r5 points to, and puts the old value in r6.
+pub fn fetch_add(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let cell = cell("atomic::fetch_add", args.0, heap)?;
+    let old = with_value!(&*cell, args.1, |a, v| {
+        a.fetch_update(ORDERING, ORDERING, |old| old.checked_add(*v))
+            .map_err(|_| Trap::Overflow)?
+    })?;
+    Ok((None, Some(old), None, None, None))
+}
+
+/// Subtracts r6 from the atomic r5 points to, and puts the old value in r6.
+pub fn fetch_sub(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let cell = cell("atomic::fetch_sub", args.0, heap)?;
+    let old = with_value!(&*cell, args.1, |a, v| {
+        a.fetch_update(ORDERING, ORDERING, |old| old.checked_sub(*v))
+            .map_err(|_| Trap::Overflow)?
+    })?;
+    Ok((None, Some(old), None, None, None))
+}
+
+/// Sets the atomic r5 points to to r6, and puts the old value in r6.
+pub fn swap(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let cell = cell("atomic::swap", args.0, heap)?;
+    let old = with_value!(&*cell, args.1, |a, v| a.swap(*v, ORDERING))?;
+    Ok((None, Some(old), None, None, None))
+}
+
+/// Sets the atomic r5 points to to r7 if it is equal to r6. Puts the value it
+/// had in r6, and whether it was set in r7.
+pub fn compare_exchange(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let cell = cell("atomic::compare_exchange", args.0, heap)?;
+    macro_rules! exchange {
+        ($($variant:ident),*) => {
+            match (&*cell, args.1, args.2) {
+                $((AtomicCell::$variant(a), Type::$variant(current), Type::$variant(new)) => {
+                    match a.compare_exchange(*current, *new, ORDERING, ORDERING) {
+                        Ok(old) => (Type::$variant(old), true),
+                        Err(old) => (Type::$variant(old), false),
+                    }
+                })*
+                (cell, current, new) => {
+                    // Reports whichever register does not match the atomic.
+                    let found = match with_value!(cell, current, |_a, v| *v) {
+                        Ok(_) => new,
+                        Err(_) => current,
+                    };
+                    return Err(Trap::UnexpectedType {
+                        expected: "the integer type of the atomic",
+                        found: found.clone(),
+                    });
+                }
+            }
+        };
+    }
+    let (old, exchanged) =
+        exchange!(Int8, Int16, Int32, Int, Int64, UInt8, UInt16, UInt32, UInt, UInt64);
+    Ok((None, Some(old), Some(Type::Boolean(exchanged)), None, None))
+}
@@ -0,0 +1,141 @@
+use allot_codegen::lib_return;
+use allot_lib::Type;
+
+use crate::{
+    library::{LibraryRegisters, LibraryReturn},
+    CrossHeap, Io, StackFrame, Trap,
+};
+
+#[inline]
+fn pointer(function: &str, t: &Type) -> Result<usize, Trap> {
+    match t {
+        Type::Pointer(p) => Ok(*p),
+        _ => Err(Trap::Library(format!("{function} expects a pointer."))),
+    }
+}
+
+/// Moves a copy of the value in r5 onto the heap of this thread, and puts the
+/// pointer to it in r5.
+pub fn alloc(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let p = heap.push(args.0.clone())?;
+    lib_return!(p)
+}
+
+/// Moves a copy of the value in r5 into the shared region of the heap, and
+/// puts the pointer to it in r5.
+pub fn alloc_shared(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let p = heap.share(args.0.clone())?;
+    lib_return!(p)
+}
+
+/// Moves the value r5 points to from the heap of this thread into the shared
+/// region, so other threads can use it. Puts the new pointer in r5, the old one
+/// stops working.
+pub fn share(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let p = pointer("heap::share", args.0)?;
+    if CrossHeap::is_shared(p) {
+        return Err(Trap::Library(
+            "heap::share expects a pointer into the heap of this thread.".to_string(),
+        ));
+    }
+    let p = heap.transfer(p)?;
+    lib_return!(p)
+}
+
+/// Moves the value r5 points to from the shared region into the heap of this
+/// thread. Puts the new pointer in r5, the old one stops working.
+pub fn claim(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let p = pointer("heap::claim", args.0)?;
+    if !CrossHeap::is_shared(p) {
+        return Err(Trap::Library(
+            "heap::claim expects a pointer into the shared region.".to_string(),
+        ));
+    }
+    let p = heap.transfer(p)?;
+    lib_return!(p)
+}
+
+/// Puts a copy of the value r5 points to in r5.
+pub fn load(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let p = pointer("heap::load", args.0)?;
+    let t = heap.with(p, |heap| heap.get::<Type>(p).cloned())?;
+    lib_return!(t)
+}
+
+/// Overwrites the value r5 points to with a copy of r6.
+pub fn store(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let p = pointer("heap::store", args.0)?;
+    heap.with(p, |heap| {
+        heap.get_mut::<Type>(p).map(|t| *t = args.1.clone())
+    })?;
+    lib_return!()
+}
+
+/// Puts r6 into the value r5 points to, and puts the old value in r6.
+pub fn swap(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let p = pointer("heap::swap", args.0)?;
+    let old = heap.with(p, |heap| {
+        heap.get_mut::<Type>(p)
+            .map(|t| std::mem::replace(t, args.1.clone()))
+    })?;
+    Ok((None, Some(old), None, None, None))
+}
+
+/// Puts whether r5 points to a value that has not been freed in r5.
+pub fn is_live(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let p = pointer("heap::is_live", args.0)?;
+    let live = heap.with(p, |heap| heap.is_live(p));
+    lib_return!(Type::Boolean(live))
+}
+
+/// Frees the value r5 points to.
+pub fn free(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let p = pointer("heap::free", args.0)?;
+    heap.with(p, |heap| heap.free(p))?;
+    lib_return!()
+}
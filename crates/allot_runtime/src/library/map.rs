@@ -0,0 +1,140 @@
+//! Every function takes a map in r5, or a pointer to a map on the heap. Either
+//! is changed in place, so a map on the heap can be shared. Results are put in
+//! r6.
+
+use std::collections::BTreeMap;
+
+use allot_lib::{MapKey, Type};
+
+use crate::{
+    library::{LibraryRegisters, LibraryReturn},
+    limits, CrossHeap, Io, StackFrame, Trap,
+};
+
+type Map = BTreeMap<MapKey, Type>;
+
+fn key(t: &Type) -> Result<MapKey, Trap> {
+    MapKey::new(t.clone()).map_err(|found| Trap::UnexpectedType {
+        expected: "Int, UInt, Char, String, or Boolean key",
+        found,
+    })
+}
+
+fn change<R>(
+    function: &str,
+    t: &mut Type,
+    heap: &mut CrossHeap,
+    f: impl FnOnce(&mut Map) -> R,
+) -> Result<R, Trap> {
+    match t {
+        Type::Map(map) => Ok(f(map)),
+        Type::Pointer(p) => heap.with(*p, |heap| match heap.get_mut::<Type>(*p)? {
+            Type::Map(map) => Ok(f(map)),
+            found => Err(Trap::UnexpectedType {
+                expected: "Map",
+                found: found.clone(),
+            }),
+        }),
+        _ => Err(Trap::Library(format!(
+            "{function} expects a map or a pointer to one."
+        ))),
+    }
+}
+
+fn read<R>(
+    function: &str,
+    t: &Type,
+    heap: &mut CrossHeap,
+    f: impl FnOnce(&Map) -> R,
+) -> Result<R, Trap> {
+    match t {
+        Type::Map(map) => Ok(f(map)),
+        Type::Pointer(p) => heap.with(*p, |heap| match heap.get::<Type>(*p)? {
+            Type::Map(map) => Ok(f(map)),
+            found => Err(Trap::UnexpectedType {
+                expected: "Map",
+                found: found.clone(),
+            }),
+        }),
+        _ => Err(Trap::Library(format!(
+            "{function} expects a map or a pointer to one."
+        ))),
+    }
+}
+
+/// Inserts r7 at the key r6, puts the old value or None in r6.
+pub fn insert(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let k = key(args.1)?;
+    let old = change("map::insert", args.0, heap, |map| {
+        if !map.contains_key(&k) {
+            limits::collection(map.len() + 1)?;
+        }
+        Ok(map.insert(k, args.2.clone()))
+    })??;
+    Ok((None, Some(old.unwrap_or(Type::None)), None, None, None))
+}
+
+/// Puts a copy of the value at the key r6, or None, in r6.
+pub fn get(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let k = key(args.1)?;
+    let t = read("map::get", args.0, heap, |map| map.get(&k).cloned())?;
+    Ok((None, Some(t.unwrap_or(Type::None)), None, None, None))
+}
+
+/// Removes the key r6, puts its value or None in r6.
+pub fn remove(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let k = key(args.1)?;
+    let old = change("map::remove", args.0, heap, |map| map.remove(&k))?;
+    Ok((None, Some(old.unwrap_or(Type::None)), None, None, None))
+}
+
+/// Puts whether the map has the key r6 in r6.
+pub fn contains(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let k = key(args.1)?;
+    let b = read("map::contains", args.0, heap, |map| map.contains_key(&k))?;
+    Ok((None, Some(Type::Boolean(b)), None, None, None))
+}
+
+/// Puts the number of keys in r6 as a UInt.
+pub fn len(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let len = read("map::len", args.0, heap, |map| map.len())?;
+    Ok((None, Some(Type::UInt(len)), None, None, None))
+}
+
+/// Puts an array of the keys in r6, in order.
+pub fn keys(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let keys = read("map::keys", args.0, heap, |map| {
+        map.keys().map(|k| k.get().clone()).collect()
+    })?;
+    Ok((None, Some(Type::Array(keys)), None, None, None))
+}
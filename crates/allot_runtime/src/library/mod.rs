@@ -1,45 +1,104 @@
 // TODO: Allow optional libraries, like gui? (wasm plugins?)
 
 use std::{
-    io,
+    collections::{BTreeMap, HashMap},
+    fmt, io,
     io::{BufRead, Read, Write},
+    sync::Arc,
 };
 
 use allot_codegen::lib_return;
 use phf::phf_map;
 
-use crate::{CrossHeap, StackFrame, Type};
+use crate::{limits, limits::Budget, CrossHeap, Io, Limit, MapKey, StackFrame, Trap, Type};
 
+mod atomic;
+mod heap;
+mod map;
 mod standard;
+mod sync;
 mod thread;
 
-type LibraryRegisters<'a> = (&'a Type, &'a Type, &'a Type, &'a Type, &'a Type);
-type LibraryReturn = (
+pub(crate) use sync::{enter, Alive};
+
+/// Registers 5-9, r5 can be changed in place.
+pub type LibraryRegisters<'a> = (&'a mut Type, &'a Type, &'a Type, &'a Type, &'a Type);
+/// Values to put into registers 5-9, None leaves the register alone.
+pub type LibraryValues = (
     Option<Type>,
     Option<Type>,
     Option<Type>,
     Option<Type>,
     Option<Type>,
 );
-type LibraryFunction = fn(LibraryRegisters, &mut StackFrame, &mut CrossHeap) -> LibraryReturn;
+pub type LibraryReturn = Result<LibraryValues, Trap>;
+pub type LibraryFunction =
+    dyn Fn(LibraryRegisters, &mut StackFrame, &mut CrossHeap, &Io) -> LibraryReturn + Send + Sync;
+type DefaultFunction = fn(LibraryRegisters, &mut StackFrame, &mut CrossHeap, &Io) -> LibraryReturn;
 
-static FUNCTIONS: phf::Map<&'static str, LibraryFunction> = phf_map! {
+/// The functions that come with allot.
+static DEFAULT_FUNCTIONS: phf::Map<&'static str, DefaultFunction> = phf_map! {
     // Control
     "exit" => exit,
 
     // IO
     "print" => print,
     "println" => println,
+    "eprint" => eprint,
+    "eprintln" => eprintln,
     "std::print_amt" => standard::print_amt,
     "read" => read,
     "read_line" => read_line,
     "std::read_all" => standard::read_all,
 
     // Heap
-    "heap::free" => impl_heap_free,
+    "heap::alloc" => heap::alloc,
+    "heap::alloc_shared" => heap::alloc_shared,
+    "heap::share" => heap::share,
+    "heap::claim" => heap::claim,
+    "heap::load" => heap::load,
+    "heap::store" => heap::store,
+    "heap::swap" => heap::swap,
+    "heap::is_live" => heap::is_live,
+    "heap::free" => heap::free,
+
+    // Maps
+    "map::insert" => map::insert,
+    "map::get" => map::get,
+    "map::remove" => map::remove,
+    "map::contains" => map::contains,
+    "map::len" => map::len,
+    "map::keys" => map::keys,
 
     // Threads
     "thread::sleep" => thread::sleep,
+    "thread::channel" => thread::channel,
+    "thread::send" => thread::send,
+    "thread::recv" => thread::recv,
+    "thread::try_recv" => thread::try_recv,
+    "thread::recv_timeout" => thread::recv_timeout,
+
+    // Sync
+    "sync::mutex" => sync::mutex,
+    "sync::lock" => sync::lock,
+    "sync::unlock" => sync::unlock,
+    "sync::load" => sync::load,
+    "sync::store" => sync::store,
+    "sync::condvar" => sync::condvar,
+    "sync::wait" => sync::wait,
+    "sync::notify_one" => sync::notify_one,
+    "sync::notify_all" => sync::notify_all,
+    "sync::barrier" => sync::barrier,
+    "sync::barrier_wait" => sync::barrier_wait,
+
+    // Atomics
+    "atomic::new" => atomic::new,
+    "atomic::load" => atomic::load,
+    "atomic::store" => atomic::store,
+    "atomic::fetch_add" => atomic::fetch_add,
+    "atomic::fetch_sub" => atomic::fetch_sub,
+    "atomic::swap" => atomic::swap,
+    "atomic::compare_exchange" => atomic::compare_exchange,
 
     // Errors
     // "error" => error,
@@ -50,70 +109,99 @@ static FUNCTIONS: phf::Map<&'static str, LibraryFunction> = phf_map! {
     //TODO Allow RawTypes as type? Or just use a UInt to convert.
 };
 
-pub fn call(
-    function: &str,
-    args: LibraryRegisters,
-    stack_frame: &mut StackFrame,
-    heap: &mut CrossHeap,
-) -> LibraryReturn {
-    let f = match FUNCTIONS.get(function) {
-        None => panic!("Tried to call a function that does not exist."),
-        Some(func) => func,
-    };
+/// The functions a program can use with Instruction::Call.
+#[derive(Clone, Default)]
+pub struct Library {
+    functions: HashMap<String, Arc<LibraryFunction>>,
+}
+impl Library {
+    /// Creates a library with no functions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a library with the default functions registered.
+    pub fn with_defaults() -> Self {
+        let mut library = Self::new();
+        library.register_defaults();
+        library
+    }
+
+    /// Registers the functions that come with allot.
+    pub fn register_defaults(&mut self) {
+        for (name, f) in DEFAULT_FUNCTIONS.entries() {
+            self.register(name, *f);
+        }
+    }
+
+    /// Registers a function, replacing any function with the same name.
+    pub fn register<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(LibraryRegisters, &mut StackFrame, &mut CrossHeap, &Io) -> LibraryReturn
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.functions.insert(name.to_string(), Arc::new(f));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
 
-    f(args, stack_frame, heap)
+    pub fn call(
+        &self,
+        function: &str,
+        args: LibraryRegisters,
+        stack_frame: &mut StackFrame,
+        heap: &mut CrossHeap,
+        io: &Io,
+    ) -> LibraryReturn {
+        let f = match self.functions.get(function) {
+            None => return Err(Trap::UnknownFunction(function.to_string())),
+            Some(func) => func,
+        };
+
+        f(args, stack_frame, heap, io)
+    }
+}
+impl fmt::Debug for Library {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.functions.keys()).finish()
+    }
 }
 
 // Library functions
 //fn template(args: LibraryRegisters, stack_frame: &mut StackFrame, heap: &mut
-// CrossHeap) -> LibraryReturn {}
+// CrossHeap, io: &Io) -> LibraryReturn {}
 
+/// Stops the program with Type::Int32(CODE), see ExitPolicy.
 fn exit(
     args: LibraryRegisters,
     _stack_frame: &mut StackFrame,
     _heap: &mut CrossHeap,
+    _io: &Io,
 ) -> LibraryReturn {
-    let code = match args.0 {
-        Type::Int32(v) => *v,
-        _ => {
-            i_println(args.0);
-            0
-        }
-    };
-
-    std::process::exit(code);
+    match args.0 {
+        Type::Int32(code) => Err(Trap::Exit(*code)),
+        found => Err(Trap::UnexpectedType {
+            expected: "Int32",
+            found: found.clone(),
+        }),
+    }
 }
 
 fn print(
     args: LibraryRegisters,
     _stack_frame: &mut StackFrame,
     _heap: &mut CrossHeap,
+    io: &Io,
 ) -> LibraryReturn {
-    match args.0 {
-        Type::None => print!(""),
-        Type::Int8(v) => print!("{}", v),
-        Type::Int16(v) => print!("{}", v),
-        Type::Int32(v) => print!("{}", v),
-        Type::Int(v) => print!("{}", v),
-        Type::Int64(v) => print!("{}", v),
-        Type::Int128(v) => print!("{}", v),
-        Type::UInt8(v) => print!("{}", v),
-        Type::UInt16(v) => print!("{}", v),
-        Type::UInt32(v) => print!("{}", v),
-        Type::UInt(v) => print!("{}", v),
-        Type::UInt64(v) => print!("{}", v),
-        Type::UInt128(v) => print!("{}", v),
-        Type::Float32(v) => print!("{}", v),
-        Type::Float64(v) => print!("{}", v),
-        Type::Char(v) => print!("{}", v),
-        Type::String(v) => print!("{}", v),
-        Type::Boolean(v) => print!("{}", v),
-        Type::Pointer(v) => print!("{:X?}", v),
-        Type::Address(v) => print!("{:X?}", v),
-        Type::Register(v) => print!("{:?}", v),
-    }
-
-    io::stdout().flush().unwrap();
+    let mut stdout = io.stdout()?;
+    i_print(&mut *stdout, args.0)?;
+    stdout
+        .flush()
+        .map_err(|e| Trap::Library(format!("Failed to flush stdout: {e}")))?;
 
     lib_return!()
 }
@@ -122,94 +210,175 @@ fn println(
     args: LibraryRegisters,
     _stack_frame: &mut StackFrame,
     _heap: &mut CrossHeap,
+    io: &Io,
 ) -> LibraryReturn {
-    i_println(args.0);
+    i_println(&mut *io.stdout()?, args.0)?;
+    lib_return!()
+}
+
+fn eprint(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    _heap: &mut CrossHeap,
+    io: &Io,
+) -> LibraryReturn {
+    let mut stderr = io.stderr()?;
+    i_print(&mut *stderr, args.0)?;
+    stderr
+        .flush()
+        .map_err(|e| Trap::Library(format!("Failed to flush stderr: {e}")))?;
+
+    lib_return!()
+}
+
+fn eprintln(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    _heap: &mut CrossHeap,
+    io: &Io,
+) -> LibraryReturn {
+    i_println(&mut *io.stderr()?, args.0)?;
     lib_return!()
 }
 
 #[inline]
-fn i_println(arg: &Type) {
+fn i_print<W: Write + ?Sized>(out: &mut W, arg: &Type) -> Result<(), Trap> {
     match arg {
-        Type::None => println!(),
-        Type::Int8(v) => println!("{}", v),
-        Type::Int16(v) => println!("{}", v),
-        Type::Int32(v) => println!("{}", v),
-        Type::Int(v) => println!("{}", v),
-        Type::Int64(v) => println!("{}", v),
-        Type::Int128(v) => println!("{}", v),
-        Type::UInt8(v) => println!("{}", v),
-        Type::UInt16(v) => println!("{}", v),
-        Type::UInt32(v) => println!("{}", v),
-        Type::UInt(v) => println!("{}", v),
-        Type::UInt64(v) => println!("{}", v),
-        Type::UInt128(v) => println!("{}", v),
-        Type::Float32(v) => println!("{}", v),
-        Type::Float64(v) => println!("{}", v),
-        Type::Char(v) => println!("{}", v),
-        Type::String(v) => println!("{}", v),
-        Type::Boolean(v) => println!("{}", v),
-        Type::Pointer(v) => println!("{:X?}", v),
-        Type::Address(v) => println!("{:X?}", v),
-        Type::Register(v) => println!("{:?}", v),
+        Type::Array(items) => return i_print_list(out, ("[", "]"), items),
+        Type::Map(map) => return i_print_map(out, map),
+        Type::Struct(_, fields) => return i_print_list(out, ("(", ")"), fields),
+        Type::Tagged(tag, t) => {
+            write!(out, "#{tag}").map_err(write_error)?;
+            return i_print_list(out, ("(", ")"), std::slice::from_ref(t));
+        }
+        Type::None => write!(out, ""),
+        Type::Int8(v) => write!(out, "{}", v),
+        Type::Int16(v) => write!(out, "{}", v),
+        Type::Int32(v) => write!(out, "{}", v),
+        Type::Int(v) => write!(out, "{}", v),
+        Type::Int64(v) => write!(out, "{}", v),
+        Type::Int128(v) => write!(out, "{}", v),
+        Type::UInt8(v) => write!(out, "{}", v),
+        Type::UInt16(v) => write!(out, "{}", v),
+        Type::UInt32(v) => write!(out, "{}", v),
+        Type::UInt(v) => write!(out, "{}", v),
+        Type::UInt64(v) => write!(out, "{}", v),
+        Type::UInt128(v) => write!(out, "{}", v),
+        Type::Float32(v) => write!(out, "{}", v),
+        Type::Float64(v) => write!(out, "{}", v),
+        Type::Char(v) => write!(out, "{}", v),
+        Type::String(v) => write!(out, "{}", v),
+        Type::Boolean(v) => write!(out, "{}", v),
+        Type::Pointer(v) => write!(out, "{:X?}", v),
+        Type::Address(v) => write!(out, "{:X?}", v),
+        Type::Register(v) => write!(out, "{:?}", v),
+    }
+    .map_err(|e| Trap::Library(format!("Failed to write: {e}")))
+}
+
+/// Prints arrays like `[1, 2, "three"]`, records like `(1, "two")`, and the
+/// payload of tagged values like `#1("two")`. Chars and strings are quoted.
+fn i_print_list<W: Write + ?Sized>(
+    out: &mut W,
+    (open, close): (&str, &str),
+    items: &[Type],
+) -> Result<(), Trap> {
+    write!(out, "{open}").map_err(write_error)?;
+    for (i, t) in items.iter().enumerate() {
+        if i > 0 {
+            write!(out, ", ").map_err(write_error)?;
+        }
+        i_print_item(out, t)?;
+    }
+    write!(out, "{close}").map_err(write_error)
+}
+
+/// Prints like `{"one": 1, "two": 2}`, chars and strings are quoted.
+fn i_print_map<W: Write + ?Sized>(out: &mut W, map: &BTreeMap<MapKey, Type>) -> Result<(), Trap> {
+    write!(out, "{{").map_err(write_error)?;
+    for (i, (k, t)) in map.iter().enumerate() {
+        if i > 0 {
+            write!(out, ", ").map_err(write_error)?;
+        }
+        i_print_item(out, k.get())?;
+        write!(out, ": ").map_err(write_error)?;
+        i_print_item(out, t)?;
+    }
+    write!(out, "}}").map_err(write_error)
+}
+
+#[inline]
+fn i_print_item<W: Write + ?Sized>(out: &mut W, t: &Type) -> Result<(), Trap> {
+    match t {
+        Type::Char(v) => write!(out, "{:?}", v).map_err(write_error),
+        Type::String(v) => write!(out, "{:?}", v).map_err(write_error),
+        _ => i_print(out, t),
     }
 }
 
+#[inline]
+fn write_error(e: std::io::Error) -> Trap {
+    Trap::Library(format!("Failed to write: {e}"))
+}
+
+#[inline]
+fn i_println<W: Write + ?Sized>(out: &mut W, arg: &Type) -> Result<(), Trap> {
+    i_print(out, arg)?;
+    writeln!(out).map_err(|e| Trap::Library(format!("Failed to write: {e}")))
+}
+
 fn read(
     _args: LibraryRegisters,
     _stack_frame: &mut StackFrame,
     _heap: &mut CrossHeap,
+    io: &Io,
 ) -> LibraryReturn {
-    let mut buffer = [0_u8; 1];
-    let stdin = io::stdin();
-    let mut handle = stdin.lock();
+    let byte = io.read(|stdin| {
+        let mut buffer = [0_u8; 1];
+        stdin.read_exact(&mut buffer).map(|_| buffer[0])
+    })?;
+    let byte = byte.map_err(|e| Trap::Library(format!("Failed to read a byte from stdin: {e}")))?;
 
-    handle
-        .read_exact(&mut buffer)
-        .expect("Failed to read a byte from stdin.");
+    lib_return!(Type::UInt8(byte))
+}
 
-    lib_return!(Type::UInt8(buffer[0]))
+/// Reads up to and including the next newline. Stops after one byte more than
+/// the limit, which is enough to tell the line is too long.
+fn read_until_newline(stdin: &mut dyn BufRead, limit: Option<usize>) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    match limit {
+        None => stdin.read_until(b'\n', &mut line)?,
+        Some(limit) => Read::take(stdin, limit as u64 + 1).read_until(b'\n', &mut line)?,
+    };
+    Ok(line)
 }
 
 fn read_line(
     _args: LibraryRegisters,
     _stack_frame: &mut StackFrame,
     _heap: &mut CrossHeap,
+    io: &Io,
 ) -> LibraryReturn {
-    let mut buffer = String::new();
-    let stdin = io::stdin();
-    let mut handle = stdin.lock();
-
-    handle
-        .read_line(&mut buffer)
-        .expect("Failed to read line from stdin.");
+    let limit = limits::string_length();
+    let buffer = io.read(move |stdin| read_until_newline(stdin, limit))?;
+    let buffer =
+        buffer.map_err(|e| Trap::Library(format!("Failed to read line from stdin: {e}")))?;
+    Budget::check(limit, buffer.len(), Limit::StringLength)?;
+    let buffer = String::from_utf8(buffer)
+        .map_err(|e| Trap::Library(format!("Failed to read line from stdin: {e}")))?;
 
     lib_return!(Type::String(buffer))
 }
 
-fn impl_heap_free(
-    args: LibraryRegisters,
-    _stack_frame: &mut StackFrame,
-    heap: &mut CrossHeap,
-) -> LibraryReturn {
-    match args.0 {
-        Type::Pointer(p) => {
-            let mut handle = heap.lock().unwrap();
-            handle.free(*p);
-        }
-        _ => panic!("heap::free expects a pointer."),
-    }
-
-    lib_return!()
-}
-
 fn impl_string_trim(
     args: LibraryRegisters,
     _stack_frame: &mut StackFrame,
     _heap: &mut CrossHeap,
+    _io: &Io,
 ) -> LibraryReturn {
     let ret = match args.0 {
         Type::String(v) => Type::String(String::from(v.trim())),
-        _ => panic!("string::trim expects a string."),
+        _ => return Err(Trap::Library("string::trim expects a string.".to_string())),
     };
 
     lib_return!(ret)
@@ -1,25 +1,31 @@
-use std::{io, io::BufRead};
-
 use allot_codegen::lib_return;
 
 use crate::{
-    library::{i_println, LibraryRegisters, LibraryReturn},
-    CrossHeap, StackFrame, Type,
+    library::{i_println, read_until_newline, LibraryRegisters, LibraryReturn},
+    limits,
+    limits::Budget,
+    CrossHeap, Io, Limit, StackFrame, Trap, Type,
 };
 
 pub fn print_amt(
     args: LibraryRegisters,
     stack_frame: &mut StackFrame,
     _heap: &mut CrossHeap,
+    io: &Io,
 ) -> LibraryReturn {
     let amount = match args.0 {
         Type::UInt(i) => i,
-        _ => panic!("std::printamt expects a uint in the register."),
+        _ => {
+            return Err(Trap::Library(
+                "std::print_amt expects a uint in the register.".to_string(),
+            ))
+        }
     };
 
+    let mut stdout = io.stdout()?;
     for i in 0..*amount {
-        let t = stack_frame.clone_offset(i);
-        i_println(&t);
+        let t = stack_frame.clone_offset(i)?;
+        i_println(&mut *stdout, &t)?;
     }
 
     lib_return!()
@@ -29,15 +35,36 @@ pub fn read_all(
     _args: LibraryRegisters,
     _stack_frame: &mut StackFrame,
     _heap: &mut CrossHeap,
+    io: &Io,
 ) -> LibraryReturn {
-    let mut buffer = String::new();
-    let stdin = io::stdin();
-    let handle = stdin.lock();
-
-    for line in handle.lines() {
-        let line = line.expect("Failed to read lines from stdin.");
-        buffer.push_str(line.as_str());
-    }
+    let limit = limits::string_length();
+    let buffer = io.read(move |stdin| {
+        let mut buffer = Vec::new();
+        loop {
+            // The rest of the limit, and room for the "\r\n" that is not kept.
+            let left = limit.map(|l| l.saturating_sub(buffer.len()) + 1);
+            let mut line = read_until_newline(stdin, left)?;
+            if line.is_empty() {
+                break;
+            }
+            if line.ends_with(b"\n") {
+                line.pop();
+                if line.ends_with(b"\r") {
+                    line.pop();
+                }
+            }
+            buffer.append(&mut line);
+            if limit.is_some_and(|l| buffer.len() > l) {
+                break;
+            }
+        }
+        std::io::Result::Ok(buffer)
+    })?;
+    let buffer =
+        buffer.map_err(|e| Trap::Library(format!("Failed to read lines from stdin: {e}")))?;
+    Budget::check(limit, buffer.len(), Limit::StringLength)?;
+    let buffer = String::from_utf8(buffer)
+        .map_err(|e| Trap::Library(format!("Failed to read lines from stdin: {e}")))?;
 
     lib_return!(Type::String(buffer))
 }
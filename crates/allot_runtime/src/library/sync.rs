@@ -0,0 +1,376 @@
+//! Mutexes, condition variables, and barriers that live in the shared region of
+//! the heap. A mutex guards a value that can only be loaded and stored by the
+//! runtime holding it.
+
+use std::{
+    cell::RefCell,
+    sync::{Arc, Condvar, Mutex, MutexGuard, Weak},
+};
+
+use allot_codegen::lib_return;
+use allot_lib::Type;
+
+use crate::{
+    green,
+    library::{LibraryRegisters, LibraryReturn},
+    limits, CrossHeap, Io, StackFrame, Trap,
+};
+
+/// Dropped with its runtime. Wakes up the threads waiting for the locks it
+/// still holds, so they can find out the locks were abandoned.
+#[derive(Debug, Default)]
+pub(crate) struct Alive {
+    held: Mutex<Vec<Weak<SyncMutex>>>,
+}
+impl Drop for Alive {
+    fn drop(&mut self) {
+        for mutex in self.held.get_mut().unwrap().drain(..) {
+            if let Some(mutex) = mutex.upgrade() {
+                let _state = mutex.state.lock().unwrap();
+                mutex.released.notify_all();
+            }
+        }
+    }
+}
+
+/// The runtime holding a lock.
+#[derive(Clone, Debug)]
+struct Holder {
+    thread: usize,
+    alive: Weak<Alive>,
+}
+impl Holder {
+    fn is(&self, other: &Holder) -> bool {
+        self.thread == other.thread && self.alive.ptr_eq(&other.alive)
+    }
+}
+
+thread_local! {
+    /// The runtime calling a library function on this thread.
+    static CALLER: RefCell<Option<Holder>> = const { RefCell::new(None) };
+}
+
+/// Lets the library functions called on this thread know which runtime is
+/// calling them.
+#[inline]
+pub(crate) fn enter(thread: usize, alive: &Arc<Alive>) {
+    CALLER.with(|c| {
+        *c.borrow_mut() = Some(Holder {
+            thread,
+            alive: Arc::downgrade(alive),
+        })
+    });
+}
+
+fn caller() -> Result<Holder, Trap> {
+    CALLER
+        .with(|c| c.borrow().clone())
+        .ok_or_else(|| Trap::Library("sync functions can only be called by a runtime.".to_string()))
+}
+
+#[derive(Debug)]
+struct LockState {
+    holder: Option<Holder>,
+    value: Type,
+}
+
+#[derive(Debug)]
+struct SyncMutex {
+    state: Mutex<LockState>,
+    released: Condvar,
+}
+impl SyncMutex {
+    /// Waits until the lock is free and takes it.
+    fn lock(self: &Arc<Self>, pointer: usize) -> Result<(), Trap> {
+        let me = caller()?;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            match &state.holder {
+                None => {
+                    if let Some(alive) = me.alive.upgrade() {
+                        alive.held.lock().unwrap().push(Arc::downgrade(self));
+                    }
+                    state.holder = Some(me);
+                    return Ok(());
+                }
+                Some(holder) if holder.is(&me) => {
+                    return Err(Trap::Library(
+                        "sync::lock failed, the lock is already held by this thread.".to_string(),
+                    ))
+                }
+                Some(holder) if holder.alive.strong_count() == 0 => {
+                    return Err(Trap::AbandonedLock(pointer))
+                }
+                Some(_) if green::is_running() => return Err(green::block()),
+                Some(_) => state = limits::wait(&self.released, state)?,
+            }
+        }
+    }
+
+    /// Gets the state if this runtime holds the lock.
+    fn held(&self, function: &str) -> Result<MutexGuard<'_, LockState>, Trap> {
+        let state = self.state.lock().unwrap();
+        match &state.holder {
+            Some(holder) if holder.is(&caller()?) => Ok(state),
+            _ => Err(Trap::Library(format!(
+                "{function} failed, the lock is not held by this thread."
+            ))),
+        }
+    }
+
+    fn release(self: &Arc<Self>, mut state: MutexGuard<'_, LockState>) {
+        if let Some(alive) = state.holder.take().and_then(|h| h.alive.upgrade()) {
+            let me = Arc::downgrade(self);
+            alive.held.lock().unwrap().retain(|m| !m.ptr_eq(&me));
+        }
+        self.released.notify_one();
+    }
+}
+
+#[derive(Debug, Default)]
+struct SyncCondvar {
+    /// Goes up with every notification.
+    notifications: Mutex<u64>,
+    notified: Condvar,
+}
+
+#[derive(Debug)]
+struct SyncBarrier {
+    threads: usize,
+    /// The threads waiting, and how many times the barrier has let threads
+    /// through.
+    state: Mutex<(usize, u64)>,
+    released: Condvar,
+}
+impl SyncBarrier {
+    /// Waits until every thread has reached the barrier. Returns true for the
+    /// last one.
+    fn wait(&self) -> Result<bool, Trap> {
+        let mut state = self.state.lock().unwrap();
+        // A blocked green thread was counted on its first try.
+        if green::is_running() {
+            if let Some(seen) = green::remember(state.1) {
+                return match seen == state.1 {
+                    true => Err(green::block()),
+                    false => Ok(false),
+                };
+            }
+        }
+
+        let generation = state.1;
+        state.0 += 1;
+        if state.0 >= self.threads {
+            *state = (0, generation + 1);
+            self.released.notify_all();
+            return Ok(true);
+        }
+        if green::is_running() {
+            return Err(green::block());
+        }
+        while state.1 == generation {
+            state = limits::wait(&self.released, state)?;
+        }
+        Ok(false)
+    }
+}
+
+#[inline]
+fn pointer(function: &str, t: &Type) -> Result<usize, Trap> {
+    match t {
+        Type::Pointer(p) => Ok(*p),
+        _ => Err(Trap::Library(format!("{function} expects a pointer."))),
+    }
+}
+
+/// Copies the value out of the heap, so the heap is not held while waiting.
+#[inline]
+fn shared<T: Send + Sync + 'static>(
+    function: &str,
+    t: &Type,
+    heap: &mut CrossHeap,
+) -> Result<(usize, Arc<T>), Trap> {
+    let p = pointer(function, t)?;
+    let shared = heap.with(p, |heap| heap.get::<Arc<T>>(p).cloned())?;
+    Ok((p, shared))
+}
+
+/// Creates a mutex that guards a copy of r5, and puts a pointer to it in r5.
+pub fn mutex(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let mutex = SyncMutex {
+        state: Mutex::new(LockState {
+            holder: None,
+            value: args.0.clone(),
+        }),
+        released: Condvar::new(),
+    };
+    let p = heap.share(Arc::new(mutex))?;
+    lib_return!(p)
+}
+
+/// Waits until this thread holds the mutex r5 points to. Errors if the mutex is
+/// held by a thread that ended.
+pub fn lock(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let (p, mutex) = shared::<SyncMutex>("sync::lock", args.0, heap)?;
+    mutex.lock(p)?;
+    lib_return!()
+}
+
+/// Releases the mutex r5 points to.
+pub fn unlock(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let (_, mutex) = shared::<SyncMutex>("sync::unlock", args.0, heap)?;
+    mutex.release(mutex.held("sync::unlock")?);
+    lib_return!()
+}
+
+/// Puts a copy of the value guarded by the mutex r5 points to in r6.
+pub fn load(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let (_, mutex) = shared::<SyncMutex>("sync::load", args.0, heap)?;
+    let t = mutex.held("sync::load")?.value.clone();
+    Ok((None, Some(t), None, None, None))
+}
+
+/// Replaces the value guarded by the mutex r5 points to with a copy of r6.
+pub fn store(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let (_, mutex) = shared::<SyncMutex>("sync::store", args.0, heap)?;
+    mutex.held("sync::store")?.value = args.1.clone();
+    lib_return!()
+}
+
+/// Creates a condition variable, and puts a pointer to it in r5.
+pub fn condvar(
+    _args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let p = heap.share(Arc::new(SyncCondvar::default()))?;
+    lib_return!(p)
+}
+
+/// Releases the mutex r6 points to and waits for the condition variable r5
+/// points to be notified, then takes the mutex again. It can wake up without a
+/// notification, so check the condition in a loop.
+pub fn wait(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let (_, condvar) = shared::<SyncCondvar>("sync::wait", args.0, heap)?;
+    let (p, mutex) = shared::<SyncMutex>("sync::wait", args.1, heap)?;
+
+    if green::is_running() {
+        let notifications = *condvar.notifications.lock().unwrap();
+        match green::remember(notifications) {
+            None => {
+                mutex.release(mutex.held("sync::wait")?);
+                return Err(green::block());
+            }
+            Some(seen) if seen == notifications => return Err(green::block()),
+            Some(_) => {}
+        }
+        mutex.lock(p)?;
+        lib_return!()
+    }
+
+    // Taken before the mutex is released, so a notification cannot be missed.
+    let mut notifications = condvar.notifications.lock().unwrap();
+    let seen = *notifications;
+    mutex.release(mutex.held("sync::wait")?);
+    while *notifications == seen {
+        notifications = limits::wait(&condvar.notified, notifications)?;
+    }
+    drop(notifications);
+
+    mutex.lock(p)?;
+    lib_return!()
+}
+
+/// Wakes up one thread waiting on the condition variable r5 points to.
+pub fn notify_one(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let (_, condvar) = shared::<SyncCondvar>("sync::notify_one", args.0, heap)?;
+    *condvar.notifications.lock().unwrap() += 1;
+    condvar.notified.notify_one();
+    lib_return!()
+}
+
+/// Wakes up every thread waiting on the condition variable r5 points to.
+pub fn notify_all(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let (_, condvar) = shared::<SyncCondvar>("sync::notify_all", args.0, heap)?;
+    *condvar.notifications.lock().unwrap() += 1;
+    condvar.notified.notify_all();
+    lib_return!()
+}
+
+/// Creates a barrier for the number of threads in r5, a UInt, and puts a
+/// pointer to it in r5.
+pub fn barrier(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let threads = match args.0 {
+        Type::UInt(i) => *i,
+        _ => {
+            return Err(Trap::Library(
+                "sync::barrier expects a usize in the register.".to_string(),
+            ))
+        }
+    };
+    let barrier = SyncBarrier {
+        threads,
+        state: Mutex::new((0, 0)),
+        released: Condvar::new(),
+    };
+    let p = heap.share(Arc::new(barrier))?;
+    lib_return!(p)
+}
+
+/// Waits until every thread has reached the barrier r5 points to. Puts
+/// Boolean(true) in r6 for one of the threads.
+pub fn barrier_wait(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let (_, barrier) = shared::<SyncBarrier>("sync::barrier_wait", args.0, heap)?;
+    let leader = barrier.wait()?;
+    Ok((None, Some(Type::Boolean(leader)), None, None, None))
+}
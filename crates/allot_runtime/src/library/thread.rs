@@ -1,11 +1,16 @@
-use std::time::Duration;
+use std::{
+    collections::VecDeque,
+    sync::{mpsc::TryRecvError, Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
 
 use allot_codegen::lib_return;
 use allot_lib::Type;
 
 use crate::{
+    green,
     library::{LibraryRegisters, LibraryReturn},
-    CrossHeap, StackFrame,
+    limits, CrossHeap, Io, Limit, StackFrame, Trap,
 };
 
 /// Makes the current thread sleep for Type::UInt64(TIME).
@@ -13,13 +18,241 @@ pub fn sleep(
     args: LibraryRegisters,
     _stack_frame: &mut StackFrame,
     _heap: &mut CrossHeap,
+    _io: &Io,
 ) -> LibraryReturn {
     let time = match args.0 {
         Type::UInt64(i) => *i,
-        _ => panic!("thread::sleep expects a u64 in the register."),
+        _ => {
+            return Err(Trap::Library(
+                "thread::sleep expects a u64 in the register.".to_string(),
+            ))
+        }
     };
 
-    std::thread::sleep(Duration::from_millis(time));
+    let time = Duration::from_millis(time);
+    match green::is_running() {
+        true if !green::slept(time) => return Err(green::block()),
+        true => {}
+        false => match limits::time_left() {
+            Some(left) if left < time => {
+                std::thread::sleep(left);
+                return Err(Trap::LimitExceeded(Limit::Time));
+            }
+            _ => std::thread::sleep(time),
+        },
+    }
 
     lib_return!()
 }
+
+/// A channel any number of threads can receive from. Threads wait without
+/// holding the lock, so sends and other receivers are not held up.
+#[derive(Debug, Default)]
+struct Channel {
+    state: Mutex<ChannelState>,
+    ready: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ChannelState {
+    values: VecDeque<Type>,
+    /// Set once either end is freed.
+    closed: bool,
+}
+
+impl Channel {
+    fn send(&self, t: Type) -> Result<(), Trap> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(Trap::Library(
+                "thread::send failed, the channel is closed.".to_string(),
+            ));
+        }
+        state.values.push_back(t);
+        self.ready.notify_all();
+        Ok(())
+    }
+
+    fn try_recv(&self) -> Result<Type, TryRecvError> {
+        let mut state = self.state.lock().unwrap();
+        match state.values.pop_front() {
+            Some(t) => Ok(t),
+            None if state.closed => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Waits for a value for at most the timeout, None if the channel is
+    /// closed and empty or the time is up. Fails once the program runs out of
+    /// time.
+    fn recv(&self, timeout: Option<Duration>) -> Result<Option<Type>, Trap> {
+        let now = Instant::now();
+        let timeout = timeout.map(|t| now + t);
+        let limit = limits::time_left().map(|left| now + left);
+        let until = timeout.into_iter().chain(limit).min();
+
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(t) = state.values.pop_front() {
+                return Ok(Some(t));
+            }
+            if state.closed {
+                return Ok(None);
+            }
+            state = match until {
+                None => self.ready.wait(state).unwrap(),
+                Some(until) => {
+                    let now = Instant::now();
+                    if now >= until {
+                        return match timeout == Some(until) {
+                            true => Ok(None),
+                            false => Err(Trap::LimitExceeded(Limit::Time)),
+                        };
+                    }
+                    self.ready.wait_timeout(state, until - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// The ends of a channel that are put on the heap, the channel closes once
+/// either is dropped.
+#[derive(Debug)]
+struct Sender(Arc<Channel>);
+#[derive(Debug)]
+struct Receiver(Arc<Channel>);
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+#[inline]
+fn pointer(function: &str, t: &Type) -> Result<usize, Trap> {
+    match t {
+        Type::Pointer(p) => Ok(*p),
+        _ => Err(Trap::Library(format!(
+            "{function} expects a pointer to a channel endpoint."
+        ))),
+    }
+}
+
+/// The channel of the receiver, so threads can wait on it without holding the
+/// heap.
+#[inline]
+fn receiver(function: &str, t: &Type, heap: &mut CrossHeap) -> Result<Arc<Channel>, Trap> {
+    let p = pointer(function, t)?;
+    heap.with(p, |heap| heap.get::<Receiver>(p).map(|r| r.0.clone()))
+}
+
+/// The value and Boolean(true), or None and Boolean(false) if nothing was
+/// received.
+#[inline]
+fn received(t: Option<Type>) -> LibraryReturn {
+    let received = t.is_some();
+    Ok((
+        None,
+        Some(t.unwrap_or(Type::None)),
+        Some(Type::Boolean(received)),
+        None,
+        None,
+    ))
+}
+
+/// Creates a channel, puts a pointer to the sender in r5 and a pointer to the
+/// receiver in r6. Both are in the shared region of the heap, the channel
+/// closes once either is freed.
+pub fn channel(
+    _args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let channel = Arc::new(Channel::default());
+    let (sender, receiver) = heap.share_pair(Sender(channel.clone()), Receiver(channel))?;
+    lib_return!(sender, receiver)
+}
+
+/// Sends a copy of r6 through the sender r5 points to.
+pub fn send(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let p = pointer("thread::send", args.0)?;
+    let channel = heap.with(p, |heap| heap.get::<Sender>(p).map(|s| s.0.clone()))?;
+    channel.send(args.1.clone())?;
+    lib_return!()
+}
+
+/// Waits for a value from the receiver r5 points to. Puts the value in r6 and
+/// Boolean(true) in r7, or None and Boolean(false) once the channel is closed
+/// and empty.
+pub fn recv(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let channel = receiver("thread::recv", args.0, heap)?;
+    let t = match green::is_running() {
+        true => match channel.try_recv() {
+            Ok(t) => Some(t),
+            Err(TryRecvError::Empty) => return Err(green::block()),
+            Err(TryRecvError::Disconnected) => None,
+        },
+        false => channel.recv(None)?,
+    };
+    received(t)
+}
+
+/// Like thread::recv, but does not wait if there is no value.
+pub fn try_recv(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let t = receiver("thread::try_recv", args.0, heap)?.try_recv().ok();
+    received(t)
+}
+
+/// Like thread::recv, but waits for at most Type::UInt64(TIME) in r6.
+pub fn recv_timeout(
+    args: LibraryRegisters,
+    _stack_frame: &mut StackFrame,
+    heap: &mut CrossHeap,
+    _io: &Io,
+) -> LibraryReturn {
+    let time = match args.1 {
+        Type::UInt64(i) => *i,
+        _ => {
+            return Err(Trap::Library(
+                "thread::recv_timeout expects a u64 in r6.".to_string(),
+            ))
+        }
+    };
+    let time = Duration::from_millis(time);
+    let channel = receiver("thread::recv_timeout", args.0, heap)?;
+    let t = match green::is_running() {
+        true => match channel.try_recv() {
+            Ok(t) => Some(t),
+            Err(TryRecvError::Empty) if !green::slept(time) => return Err(green::block()),
+            Err(_) => None,
+        },
+        false => channel.recv(Some(time))?,
+    };
+    received(t)
+}
@@ -0,0 +1,243 @@
+use std::{
+    cell::Cell,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+        Condvar, MutexGuard, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{memory::MAX_THREAD, StackFrame, Trap};
+
+/// Hard limits for running untrusted programs. None means unlimited.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Limits {
+    /// Instructions run, counted across all threads.
+    pub fuel: Option<u64>,
+    /// Stack frames in one runtime or thread.
+    pub stack_frames: Option<usize>,
+    /// Values in one StackFrame.
+    pub stack_values: Option<usize>,
+    /// Live heap entries.
+    pub heap_entries: Option<usize>,
+    /// Length in bytes of a string put into a register.
+    pub string_length: Option<usize>,
+    /// Items in an array or map put into a register.
+    pub collection_length: Option<usize>,
+    /// Threads created with ThreadCreate.
+    pub threads: Option<usize>,
+    /// Time since the program started running.
+    pub time: Option<Duration>,
+}
+
+/// The limit that stopped a program.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Limit {
+    Fuel,
+    StackFrames,
+    StackValues,
+    HeapEntries,
+    StringLength,
+    CollectionLength,
+    Threads,
+    Time,
+}
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Fuel => write!(f, "fuel"),
+            Limit::StackFrames => write!(f, "stack frame"),
+            Limit::StackValues => write!(f, "stack value"),
+            Limit::HeapEntries => write!(f, "heap entry"),
+            Limit::StringLength => write!(f, "string length"),
+            Limit::CollectionLength => write!(f, "collection length"),
+            Limit::Threads => write!(f, "thread"),
+            Limit::Time => write!(f, "time"),
+        }
+    }
+}
+
+/// Limits and what has been used of them, shared between a runtime and its
+/// threads.
+#[derive(Debug, Default)]
+pub(crate) struct Budget {
+    pub limits: Limits,
+    fuel: AtomicU64,
+    threads: AtomicUsize,
+    started: OnceLock<Instant>,
+}
+impl Budget {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Uses one instruction worth of fuel and checks the time limit.
+    #[inline]
+    pub fn tick(&self) -> Result<(), Trap> {
+        if let Some(fuel) = self.limits.fuel {
+            if self.fuel.fetch_add(1, Ordering::Relaxed) >= fuel {
+                return Err(Trap::LimitExceeded(Limit::Fuel));
+            }
+        }
+        if let Some(time) = self.limits.time {
+            if self.started.get_or_init(Instant::now).elapsed() > time {
+                return Err(Trap::LimitExceeded(Limit::Time));
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives back the fuel of an instruction that did not run, like one a green
+    /// thread has to try again.
+    #[inline]
+    pub fn refund(&self) {
+        if self.limits.fuel.is_some() {
+            self.fuel.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The time left before the time limit, None if there is no time limit.
+    pub fn time_left(&self) -> Option<Duration> {
+        self.limits
+            .time
+            .map(|time| time.saturating_sub(self.started.get_or_init(Instant::now).elapsed()))
+    }
+
+    /// Lets the library functions called on this thread find out how long
+    /// they can wait for, and how long a string or collection can get.
+    #[inline]
+    pub fn enter(&self) {
+        let deadline = self
+            .limits
+            .time
+            .map(|time| *self.started.get_or_init(Instant::now) + time);
+        DEADLINE.with(|d| d.set(deadline));
+        STRING_LENGTH.with(|c| c.set(self.limits.string_length));
+        COLLECTION_LENGTH.with(|c| c.set(self.limits.collection_length));
+    }
+
+    /// Counts a new thread, and returns its id. Ids are never reused, and stop
+    /// at the highest one a heap can be owned by.
+    pub fn create_thread(&self) -> Result<usize, Trap> {
+        let created = self.threads.fetch_add(1, Ordering::Relaxed);
+        match self.limits.threads {
+            Some(threads) if created >= threads => Err(Trap::LimitExceeded(Limit::Threads)),
+            _ if created >= MAX_THREAD => Err(Trap::LimitExceeded(Limit::Threads)),
+            _ => Ok(created + 1),
+        }
+    }
+
+    /// Fails if the amount is over the limit.
+    #[inline]
+    pub fn check(limit: Option<usize>, amount: usize, kind: Limit) -> Result<(), Trap> {
+        match limit {
+            Some(max) if amount > max => Err(Trap::LimitExceeded(kind)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails if one more value on the frame would go over the limit.
+    #[inline]
+    pub fn push_value(&self, frame: &StackFrame) -> Result<(), Trap> {
+        Budget::check(
+            self.limits.stack_values,
+            frame.len() + 1,
+            Limit::StackValues,
+        )
+    }
+
+    /// Fails if one more stack frame would go over the limit.
+    #[inline]
+    pub fn push_frame(&self, frames: &[StackFrame]) -> Result<(), Trap> {
+        Budget::check(
+            self.limits.stack_frames,
+            frames.len() + 1,
+            Limit::StackFrames,
+        )
+    }
+
+    /// Fails if a string of the length would go over the limit.
+    #[inline]
+    pub fn string(&self, len: usize) -> Result<(), Trap> {
+        Budget::check(self.limits.string_length, len, Limit::StringLength)
+    }
+
+    /// Fails if an array or map of the length would go over the limit.
+    #[inline]
+    pub fn collection(&self, len: usize) -> Result<(), Trap> {
+        Budget::check(self.limits.collection_length, len, Limit::CollectionLength)
+    }
+}
+
+thread_local! {
+    /// When the program calling a library function on this thread runs out of
+    /// time.
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    /// The string length limit of the program calling a library function on
+    /// this thread.
+    static STRING_LENGTH: Cell<Option<usize>> = const { Cell::new(None) };
+    /// The collection length limit of the program calling a library function
+    /// on this thread.
+    static COLLECTION_LENGTH: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Fails if an array or map of the length, made by the library function being
+/// called, would go over the limit.
+pub(crate) fn collection(len: usize) -> Result<(), Trap> {
+    Budget::check(
+        COLLECTION_LENGTH.with(Cell::get),
+        len,
+        Limit::CollectionLength,
+    )
+}
+
+/// How long a string made by the library function being called can get, None
+/// if there is no limit.
+pub(crate) fn string_length() -> Option<usize> {
+    STRING_LENGTH.with(Cell::get)
+}
+
+/// How long the library function being called can wait before the program
+/// runs out of time, None if there is no time limit.
+pub(crate) fn time_left() -> Option<Duration> {
+    DEADLINE
+        .with(Cell::get)
+        .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+}
+
+/// Waits for the condition variable to be notified, or fails once the program
+/// runs out of time.
+pub(crate) fn wait<'a, T>(
+    condvar: &Condvar,
+    guard: MutexGuard<'a, T>,
+) -> Result<MutexGuard<'a, T>, Trap> {
+    match time_left() {
+        None => Ok(condvar.wait(guard).unwrap()),
+        Some(left) => match condvar.wait_timeout(guard, left).unwrap() {
+            (_, result) if result.timed_out() => Err(Trap::LimitExceeded(Limit::Time)),
+            (guard, _) => Ok(guard),
+        },
+    }
+}
+
+/// Waits for the value, or fails once the program runs out of time. Fails with
+/// the trap if the sender is gone.
+pub(crate) fn recv<T>(
+    receiver: &Receiver<T>,
+    left: Option<Duration>,
+    gone: Trap,
+) -> Result<T, Trap> {
+    match left {
+        None => receiver.recv().map_err(|_| gone),
+        Some(left) => match receiver.recv_timeout(left) {
+            Ok(t) => Ok(t),
+            Err(RecvTimeoutError::Timeout) => Err(Trap::LimitExceeded(Limit::Time)),
+            Err(RecvTimeoutError::Disconnected) => Err(gone),
+        },
+    }
+}
@@ -1,64 +1,469 @@
 use std::{
-    collections::BTreeMap,
-    os::raw::c_void,
-    sync::{Arc, Mutex},
+    any::{type_name, Any},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use allot_lib::Type;
 
-pub type CrossHeap = Arc<Mutex<Heap>>; // TODO: Each thread should handle its own heap, add a way to send info to other
-                                       // threads.
+use crate::{limits::Budget, Limit, Trap};
+
+/// The heap region every thread of a program can reach.
+pub type SharedHeap = Arc<Mutex<Heap>>;
+
+/// The heap of one thread, and the shared region. Values are only reachable
+/// from other threads once they are moved into the shared region, which is the
+/// only part that needs a lock.
+#[derive(Debug)]
+pub struct CrossHeap {
+    pub local: Heap,
+    pub shared: SharedHeap,
+    /// The most live entries the heaps of every thread and the shared region
+    /// can hold together, checked by push and share.
+    limit: Option<usize>,
+}
+impl CrossHeap {
+    pub fn new() -> Self {
+        let shared = Heap::shared();
+        let mut local = Heap::new();
+        local.live = shared.lock().unwrap().live.clone();
+        Self {
+            local,
+            shared,
+            limit: None,
+        }
+    }
+
+    /// Creates the heap of a new thread, with the same shared region and
+    /// limit.
+    pub fn for_thread(&self, thread: usize) -> Self {
+        let mut local = Heap::with_owner(thread);
+        local.live = self.local.live.clone();
+        Self {
+            local,
+            shared: self.shared.clone(),
+            limit: self.limit,
+        }
+    }
+
+    /// Puts restored heaps in place of the heap of this thread and the shared
+    /// region, counting their entries with the other threads.
+    pub(crate) fn restore(&mut self, mut local: Heap, mut shared: Heap) {
+        for heap in [&mut local, &mut shared] {
+            self.local.live.fetch_add(heap.len, Ordering::SeqCst);
+            heap.live = self.local.live.clone();
+        }
+        self.local = local;
+        *self.shared.lock().unwrap() = shared;
+    }
+
+    pub(crate) fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    /// Fails if n more entries would go over the limit. Threads that make
+    /// entries at the same time can each be let through for the last ones.
+    pub fn reserve(&self, n: usize) -> Result<(), Trap> {
+        Budget::check(
+            self.limit,
+            self.local.live.load(Ordering::SeqCst) + n,
+            Limit::HeapEntries,
+        )
+    }
+
+    /// Puts the value in the heap of this thread.
+    pub fn push<T: Any + Send>(&mut self, t: T) -> Result<Type, Trap> {
+        self.reserve(1)?;
+        self.local.push(t)
+    }
+
+    /// Whether the pointer points into the shared region.
+    pub fn is_shared(pointer: usize) -> bool {
+        owner(pointer) == SHARED_OWNER
+    }
+
+    /// Runs f with the heap the pointer points into, locking the shared region
+    /// if it is there.
+    pub fn with<R>(&mut self, pointer: usize, f: impl FnOnce(&mut Heap) -> R) -> R {
+        match CrossHeap::is_shared(pointer) {
+            true => f(&mut self.shared.lock().unwrap()),
+            false => f(&mut self.local),
+        }
+    }
+
+    /// Puts the value in the shared region.
+    pub fn share<T: Any + Send>(&self, t: T) -> Result<Type, Trap> {
+        self.reserve(1)?;
+        self.shared.lock().unwrap().push(t)
+    }
+
+    /// Puts both values in the shared region, or neither if there is only room
+    /// for one.
+    pub fn share_pair<T: Any + Send, U: Any + Send>(
+        &self,
+        t: T,
+        u: U,
+    ) -> Result<(Type, Type), Trap> {
+        self.reserve(2)?;
+        let mut shared = self.shared.lock().unwrap();
+        shared.reserve(2)?;
+        Ok((shared.push(t)?, shared.push(u)?))
+    }
+
+    /// Moves the value at the pointer into the shared region, or out of it
+    /// into the heap of this thread.
+    pub fn transfer(&mut self, pointer: usize) -> Result<Type, Trap> {
+        let mut shared = self.shared.lock().unwrap();
+        match CrossHeap::is_shared(pointer) {
+            true => shared.transfer(pointer, &mut self.local),
+            false => self.local.transfer(pointer, &mut shared),
+        }
+    }
+
+    /// The number of live entries in the heap of this thread and the shared
+    /// region.
+    pub fn len(&self) -> usize {
+        self.local.len() + self.shared.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+impl Default for CrossHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// From low to high bits, a pointer is the slot, the generation of the slot
+/// when the pointer was made, and the owner of the heap it points into.
+const SLOT_BITS: u32 = usize::BITS / 2;
+const GENERATION_BITS: u32 = usize::BITS / 4;
+const SLOT_MASK: usize = (1 << SLOT_BITS) - 1;
+const MAX_GENERATION: usize = (1 << GENERATION_BITS) - 1;
+/// The owner of the shared region, thread heaps are owned by their thread id.
+const SHARED_OWNER: usize = usize::MAX >> (SLOT_BITS + GENERATION_BITS);
+/// The highest thread id a heap can be owned by, 65534 (254 on 32-bit).
+pub(crate) const MAX_THREAD: usize = SHARED_OWNER - 1;
+
+#[inline]
+fn owner(pointer: usize) -> usize {
+    pointer >> (SLOT_BITS + GENERATION_BITS)
+}
+
+#[inline]
+fn split(pointer: usize) -> (usize, usize) {
+    (pointer & SLOT_MASK, (pointer >> SLOT_BITS) & MAX_GENERATION)
+}
+
+/// The generation of a heap slot and its value, if it is live.
+pub type SlotSnapshot = (usize, Option<Type>);
+
+#[derive(Debug)]
+struct Entry {
+    value: Box<dyn Any + Send>,
+    type_name: &'static str,
+}
+impl Entry {
+    fn new<T: Any + Send>(t: T) -> Self {
+        Self {
+            value: Box::new(t),
+            type_name: type_name::<T>(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    /// Goes up every time the slot is freed, so old pointers to it stop
+    /// working.
+    generation: usize,
+    entry: Option<Entry>,
+}
 
 #[derive(Debug, Default)]
 pub struct Heap {
-    heap: BTreeMap<usize, *const c_void>,
-    heap_pointer: usize,
+    /// Stamped into every pointer, so a pointer into another heap is caught.
+    owner: usize,
+    slots: Vec<Slot>,
+    /// Empty slots that can be used again.
+    free: Vec<usize>,
+    len: usize,
+    /// Live entries in this heap and the heaps it is counted with, the heaps
+    /// of every thread of a program and their shared region.
+    live: Arc<AtomicUsize>,
+    /// Goes up every time an entry leaves the heap, and when the heap is
+    /// dropped. A pointer that was live when this was last read is still live
+    /// if it has not moved, which can be checked without the lock.
+    removals: Arc<AtomicUsize>,
+}
+impl Drop for Heap {
+    fn drop(&mut self) {
+        self.live.fetch_sub(self.len, Ordering::SeqCst);
+        self.removals.fetch_add(1, Ordering::SeqCst);
+    }
 }
 impl Heap {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the heap of a thread. Panics if the id is over MAX_THREAD, which
+    /// creating a thread checks.
+    pub fn with_owner(thread: usize) -> Self {
+        assert!(thread <= MAX_THREAD, "thread {thread} can not own a heap");
+        Self::owned_by(thread)
+    }
+
+    fn shared() -> SharedHeap {
+        Arc::new(Mutex::new(Self::owned_by(SHARED_OWNER)))
+    }
+
+    fn owned_by(owner: usize) -> Self {
         Self {
-            heap: BTreeMap::new(),
-            heap_pointer: 0,
+            owner,
+            slots: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+            live: Arc::default(),
+            removals: Arc::default(),
+        }
+    }
+
+    #[inline]
+    fn pointer(&self, slot: usize, generation: usize) -> usize {
+        debug_assert!(slot <= SLOT_MASK && generation <= MAX_GENERATION);
+        (self.owner << (SLOT_BITS + GENERATION_BITS)) | (generation << SLOT_BITS) | slot
+    }
+
+    /// Puts the value in the heap, failing if every slot a pointer can reach
+    /// is taken.
+    pub fn push<T: Any + Send>(&mut self, t: T) -> Result<Type, Trap> {
+        self.insert(Entry::new(t)).map(Type::Pointer)
+    }
+
+    /// Fails if there are not n slots left for new entries.
+    fn reserve(&self, n: usize) -> Result<(), Trap> {
+        let left = self.free.len() + (SLOT_MASK - self.slots.len() + 1);
+        match left < n {
+            true => Err(Trap::LimitExceeded(Limit::HeapEntries)),
+            false => Ok(()),
+        }
+    }
+
+    fn insert(&mut self, entry: Entry) -> Result<usize, Trap> {
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            // The slot has to fit in the bits a pointer has for it.
+            None if self.slots.len() > SLOT_MASK => {
+                return Err(Trap::LimitExceeded(Limit::HeapEntries))
+            }
+            None => {
+                self.slots.push(Slot::default());
+                self.slots.len() - 1
+            }
+        };
+        let s = &mut self.slots[slot];
+        s.entry = Some(entry);
+        self.len += 1;
+        self.live.fetch_add(1, Ordering::SeqCst);
+        let generation = s.generation;
+        Ok(self.pointer(slot, generation))
+    }
+
+    fn entry(&self, pointer: usize) -> Result<&Entry, Trap> {
+        if owner(pointer) != self.owner {
+            return Err(Trap::ForeignPointer(pointer));
+        }
+        let (slot, generation) = split(pointer);
+        match self.slots.get(slot) {
+            Some(Slot {
+                generation: g,
+                entry: Some(entry),
+            }) if *g == generation => Ok(entry),
+            Some(s) if s.generation > generation => Err(Trap::DanglingPointer(pointer)),
+            _ => Err(Trap::InvalidPointer(pointer)),
+        }
+    }
+
+    fn entry_mut(&mut self, pointer: usize) -> Result<&mut Entry, Trap> {
+        self.entry(pointer)?;
+        let (slot, _) = split(pointer);
+        Ok(self.slots[slot].entry.as_mut().unwrap())
+    }
+
+    #[inline]
+    fn check<T: Any>(pointer: usize, entry: &Entry) -> Result<(), Trap> {
+        match entry.value.is::<T>() {
+            true => Ok(()),
+            false => Err(Trap::HeapTypeMismatch {
+                pointer,
+                expected: type_name::<T>(),
+                found: entry.type_name,
+            }),
         }
     }
 
-    pub fn cross_new() -> CrossHeap {
-        Arc::new(Mutex::new(Heap::default()))
+    pub fn get<T: Any>(&self, pointer: usize) -> Result<&T, Trap> {
+        let entry = self.entry(pointer)?;
+        Heap::check::<T>(pointer, entry)?;
+        Ok(entry.value.downcast_ref().unwrap())
     }
 
-    pub fn push<T>(&mut self, t: T) -> Type {
-        let raw = Box::into_raw(Box::new(t));
-        let ptr: *const c_void = unsafe { std::mem::transmute(raw) };
+    pub fn get_mut<T: Any>(&mut self, pointer: usize) -> Result<&mut T, Trap> {
+        let entry = self.entry_mut(pointer)?;
+        Heap::check::<T>(pointer, entry)?;
+        Ok(entry.value.downcast_mut().unwrap())
+    }
+
+    /// Removes the value from the heap. The value is left in place if it is
+    /// not a T.
+    pub fn take<T: Any>(&mut self, pointer: usize) -> Result<Box<T>, Trap> {
+        Heap::check::<T>(pointer, self.entry(pointer)?)?;
+        let entry = self.remove(pointer);
+        Ok(entry.value.downcast().unwrap())
+    }
+
+    /// Replaces the value at the pointer, dropping the old one.
+    pub fn update<T: Any + Send>(&mut self, pointer: usize, t: T) -> Result<(), Trap> {
+        *self.entry_mut(pointer)? = Entry::new(t);
+        Ok(())
+    }
+
+    /// Whether the pointer points to a value that has not been freed.
+    pub fn is_live(&self, pointer: usize) -> bool {
+        self.entry(pointer).is_ok()
+    }
 
-        let i = self.heap_pointer;
-        self.heap_pointer += 1;
+    /// Frees the value at the pointer and drops it.
+    pub fn free(&mut self, pointer: usize) -> Result<(), Trap> {
+        self.entry(pointer)?;
+        self.remove(pointer);
+        Ok(())
+    }
+
+    /// Empties a slot that is known to be live.
+    fn remove(&mut self, pointer: usize) -> Entry {
+        let (slot, _) = split(pointer);
+        let s = &mut self.slots[slot];
+        let entry = s.entry.take().unwrap();
+        self.len -= 1;
+        self.live.fetch_sub(1, Ordering::SeqCst);
+        self.removals.fetch_add(1, Ordering::SeqCst);
+        // A slot that ran out of generations is never used again.
+        if s.generation < MAX_GENERATION {
+            s.generation += 1;
+            self.free.push(slot);
+        }
+        entry
+    }
 
-        self.heap.insert(i, ptr);
-        Type::Pointer(i)
+    /// Copies the generation and value of every slot, or returns the pointer
+    /// and type name of the first value that is not a Type.
+    pub fn snapshot(&self) -> Result<Vec<SlotSnapshot>, (usize, &'static str)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .map(|(slot, s)| match &s.entry {
+                None => Ok((s.generation, None)),
+                Some(entry) => match entry.value.downcast_ref::<Type>() {
+                    Some(t) => Ok((s.generation, Some(t.clone()))),
+                    None => Err((self.pointer(slot, s.generation), entry.type_name)),
+                },
+            })
+            .collect()
     }
 
-    // TODO: Need a way to type check?
-    pub fn take<T>(&mut self, pointer: usize) -> Box<T> {
-        match self.heap.remove(&pointer) {
-            None => panic!("Pointer does not point to anything in the heap."),
-            Some(ptr) => {
-                let pointer: *mut T = unsafe { std::mem::transmute(ptr) };
-                unsafe { Box::from_raw(pointer) }
+    /// Moves the value at the pointer into another heap, and returns the
+    /// pointer to it there.
+    pub fn transfer(&mut self, pointer: usize, to: &mut Heap) -> Result<Type, Trap> {
+        self.entry(pointer)?;
+        to.reserve(1)?;
+        let entry = self.remove(pointer);
+        to.insert(entry).map(Type::Pointer)
+    }
+
+    /// Creates the heap of a thread from a snapshot, or returns the first slot
+    /// that a heap can not have.
+    pub fn restore(thread: usize, slots: Vec<SlotSnapshot>) -> Result<Self, usize> {
+        Heap::with_owner(thread).restore_slots(slots)
+    }
+
+    /// Creates the shared region from a snapshot, or returns the first slot
+    /// that a heap can not have.
+    pub fn restore_shared(slots: Vec<SlotSnapshot>) -> Result<Self, usize> {
+        Heap::owned_by(SHARED_OWNER).restore_slots(slots)
+    }
+
+    fn restore_slots(self, slots: Vec<SlotSnapshot>) -> Result<Self, usize> {
+        let mut heap = self;
+        for (slot, (generation, t)) in slots.into_iter().enumerate() {
+            if slot > SLOT_MASK || generation > MAX_GENERATION {
+                return Err(slot);
+            }
+            match &t {
+                Some(_) => {
+                    heap.len += 1;
+                    heap.live.fetch_add(1, Ordering::SeqCst);
+                }
+                None if generation < MAX_GENERATION => heap.free.push(slot),
+                None => {}
             }
+            heap.slots.push(Slot {
+                generation,
+                entry: t.map(Entry::new),
+            });
         }
+        // Use the lowest free slots first.
+        heap.free.reverse();
+        Ok(heap)
     }
 
-    pub fn update<T>(&mut self, pointer: usize, t: T) {
-        let raw = Box::into_raw(Box::new(t));
-        let ptr: *mut c_void = unsafe { std::mem::transmute(raw) };
+    /// Whether the pointer points into this heap.
+    pub(crate) fn owns(&self, pointer: usize) -> bool {
+        owner(pointer) == self.owner
+    }
+
+    /// Whether this heap could have made a pointer into it, live or not. The
+    /// slot has to exist and have reached the generation of the pointer.
+    pub(crate) fn made(&self, pointer: usize) -> bool {
+        let (slot, generation) = split(pointer);
+        self.slots
+            .get(slot)
+            .is_some_and(|s| generation <= s.generation)
+    }
+
+    /// The count of entries that have left this heap, see removals.
+    pub(crate) fn removals(&self) -> &Arc<AtomicUsize> {
+        &self.removals
+    }
+
+    /// The live entries that are values.
+    pub(crate) fn values(&self) -> impl Iterator<Item = &Type> {
+        self.slots
+            .iter()
+            .filter_map(|s| s.entry.as_ref()?.value.downcast_ref::<Type>())
+    }
+
+    /// The pointers to live entries, in order.
+    pub fn pointers(&self) -> Vec<usize> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.entry.is_some())
+            .map(|(slot, s)| self.pointer(slot, s.generation))
+            .collect()
+    }
 
-        self.heap.insert(pointer, ptr);
+    /// The number of live entries.
+    pub fn len(&self) -> usize {
+        self.len
     }
 
-    /// Frees the memory at the current pointer. Does nothing if the pointer
-    /// points to nothing.
-    pub fn free(&mut self, pointer: usize) {
-        self.heap.remove(&pointer);
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
 }
-unsafe impl Send for Heap {}
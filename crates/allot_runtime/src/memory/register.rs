@@ -1,55 +1,72 @@
 use allot_lib::{Register, Type};
 
+use crate::{LibraryRegisters, Trap};
+
+/// How many registers a runtime has.
+pub const REGISTERS: usize = 30;
+
 #[derive(Debug)]
 pub struct Registers(Vec<Type>);
 impl Registers {
     pub fn new() -> Self {
-        let registers = (0..30).map(|_i| Type::None).collect();
+        let registers = (0..REGISTERS).map(|_i| Type::None).collect();
         Self(registers)
     }
 
-    pub fn get(&self, register: Register) -> &Type {
+    pub fn get(&self, register: Register) -> Result<&Type, Trap> {
         match self.0.get(register as usize) {
-            None => panic!("{:?} is not a valid register.", register),
-            Some(i) => i,
+            None => Err(Trap::InvalidRegister(register)),
+            Some(i) => Ok(i),
         }
     }
 
-    pub fn get_mut(&mut self, register: Register) -> &mut Type {
+    pub fn get_mut(&mut self, register: Register) -> Result<&mut Type, Trap> {
         match self.0.get_mut(register as usize) {
-            None => panic!("{:?} is not a valid register.", register),
-            Some(i) => i,
+            None => Err(Trap::InvalidRegister(register)),
+            Some(i) => Ok(i),
         }
     }
 
-    pub fn insert(&mut self, register: Register, t: Type) {
-        let i = register as usize;
-        if i >= 30 {
-            panic!("{:?} is not a valid register.", register)
-        }
+    pub fn insert(&mut self, register: Register, t: Type) -> Result<(), Trap> {
         if let Type::Register(_) = t {
-            panic!("Tried to put a Register type into a register.");
+            return Err(Trap::RegisterInRegister);
         }
 
-        self.0.remove(i);
-        self.0.insert(i, t);
+        *self.get_mut(register)? = t;
+        Ok(())
     }
 
-    pub fn take(&mut self, register: Register) -> Type {
-        let i = register as usize;
-        if i >= 30 {
-            panic!("{:?} is not a valid register.", register)
-        }
+    pub fn take(&mut self, register: Register) -> Result<Type, Trap> {
+        let element = self.get_mut(register)?;
+        Ok(std::mem::replace(element, Type::None))
+    }
 
-        let element = self.0.remove(i);
-        self.0.insert(i, Type::None);
+    pub fn clone(&mut self, register: Register) -> Result<Type, Trap> {
+        let r = self.get(register)?;
+        Ok(r.clone())
+    }
+
+    /// Registers 5-9, passed to library functions.
+    pub fn library_args(&mut self) -> Result<LibraryRegisters<'_>, Trap> {
+        match self
+            .0
+            .get_mut(Register::R5 as usize..=Register::R9 as usize)
+        {
+            Some([r5, r6, r7, r8, r9]) => Ok((r5, r6, r7, r8, r9)),
+            _ => Err(Trap::InvalidRegister(Register::R9)),
+        }
+    }
 
-        element
+    /// Creates registers from a snapshot, missing registers are None. Values
+    /// past the last register are dropped, AllotRuntime::restore rejects them.
+    pub fn restore(mut values: Vec<Type>) -> Self {
+        values.resize(REGISTERS, Type::None);
+        Self(values)
     }
 
-    pub fn clone(&mut self, register: Register) -> Type {
-        let r = self.get(register);
-        r.clone()
+    /// Copies the values of every register.
+    pub fn snapshot(&self) -> Vec<Type> {
+        self.0.clone()
     }
 }
 impl Default for Registers {
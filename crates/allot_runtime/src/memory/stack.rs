@@ -1,16 +1,29 @@
 use allot_lib::Type;
 
+use crate::Trap;
+
+/// What happened to a stack frame while a traced instruction ran.
+#[derive(Debug)]
+struct Journal {
+    /// The lowest the stack went.
+    low: usize,
+    /// Values popped, from the top down.
+    popped: Vec<Type>,
+}
+
 #[derive(Debug, Default)]
 pub struct StackFrame {
     stack: Vec<Type>,
-    // Cannot access this stack frame from another one.
-    // isolated: bool, TODO: Enable this when transferring between stack frames is possible.
+    /// Values cannot be moved between this stack frame and the one below it.
+    isolated: bool,
+    journal: Option<Journal>,
 }
 impl StackFrame {
-    pub fn new(_isolated: bool) -> Self {
+    pub fn new(isolated: bool) -> Self {
         Self {
             stack: Vec::new(),
-            // isolated,
+            isolated,
+            journal: None,
         }
     }
 
@@ -21,7 +34,8 @@ impl StackFrame {
             .collect();
         Self {
             stack,
-            // isolated: false,
+            isolated: false,
+            journal: None,
         }
     }
 
@@ -29,18 +43,67 @@ impl StackFrame {
         self.stack.push(t);
     }
 
-    pub fn pop(&mut self) -> Type {
-        match self.stack.pop() {
-            None => panic!("Tried to pop from stack but it was empty."),
-            Some(v) => v,
+    pub fn pop(&mut self) -> Result<Type, Trap> {
+        let t = self.stack.pop().ok_or(Trap::StackEmpty)?;
+        if let Some(journal) = &mut self.journal {
+            journal.low = journal.low.min(self.stack.len());
+            journal.popped.push(t.clone());
         }
+        Ok(t)
     }
 
-    pub fn clone_offset(&self, offset: usize) -> Type {
-        let offset = self.stack.len() - offset - 1;
-        match self.stack.get(offset) {
-            None => panic!("There is no item on the stack at {offset}."),
-            Some(item) => item.clone(),
+    /// Starts keeping what is popped, so a tracer can see it.
+    pub(crate) fn start_journal(&mut self) {
+        self.journal = Some(Journal {
+            low: self.stack.len(),
+            popped: Vec::new(),
+        });
+    }
+
+    /// Stops keeping what is popped. Returns the values pushed and popped
+    /// since start_journal, or None if it was not started.
+    pub(crate) fn take_journal(&mut self) -> Option<(Vec<Type>, Vec<Type>)> {
+        let journal = self.journal.take()?;
+        let pushed = self.stack[journal.low.min(self.stack.len())..].to_vec();
+        Some((pushed, journal.popped))
+    }
+
+    /// Creates a frame from the values, from the bottom of the stack to the
+    /// top.
+    pub fn with_values(stack: Vec<Type>, isolated: bool) -> Self {
+        Self {
+            stack,
+            isolated,
+            journal: None,
+        }
+    }
+
+    pub fn is_isolated(&self) -> bool {
+        self.isolated
+    }
+
+    /// The values in the frame, from the bottom of the stack to the top.
+    pub fn values(&self) -> &[Type] {
+        &self.stack
+    }
+
+    pub fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stack.is_empty()
+    }
+
+    pub fn clone_offset(&self, offset: usize) -> Result<Type, Trap> {
+        let item = self
+            .stack
+            .len()
+            .checked_sub(offset + 1)
+            .and_then(|i| self.stack.get(i));
+        match item {
+            None => Err(Trap::StackOffset(offset)),
+            Some(item) => Ok(item.clone()),
         }
     }
 }
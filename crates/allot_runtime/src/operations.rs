@@ -1,11 +1,11 @@
 use allot_lib::{OpPrim1, OpPrim2, Operation, RawType, Register, Type};
 
-use crate::memory::Registers;
+use crate::{memory::Registers, Trap};
 
 // TODO: Should casting be allowed for NUMBER->String or should that be a
 // library function?
-pub fn cast(t: &Type, raw: RawType) -> Type {
-    match t {
+pub fn cast(t: &Type, raw: RawType) -> Result<Type, Trap> {
+    Ok(match t {
         Type::Int8(v) => match raw {
             RawType::Int8 => Type::Int8(*v),
             RawType::Int16 => Type::Int16(*v as i16),
@@ -21,9 +21,9 @@ pub fn cast(t: &Type, raw: RawType) -> Type {
             RawType::UInt128 => Type::UInt128(*v as u128),
             RawType::Float32 => Type::Float32(*v as f32),
             RawType::Float64 => Type::Float64(*v as f64),
-            RawType::Char => Type::Char(char::from_u32(*v as u32).unwrap()),
+            RawType::Char => Type::Char(to_char(*v as u32)?),
             RawType::String => Type::String(v.to_string()),
-            _ => panic!("Cannot cast {:?} into {:?}.", t, raw),
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
         },
         Type::Int16(v) => match raw {
             RawType::Int8 => Type::Int8(*v as i8),
@@ -40,9 +40,9 @@ pub fn cast(t: &Type, raw: RawType) -> Type {
             RawType::UInt128 => Type::UInt128(*v as u128),
             RawType::Float32 => Type::Float32(*v as f32),
             RawType::Float64 => Type::Float64(*v as f64),
-            RawType::Char => Type::Char(char::from_u32(*v as u32).unwrap()),
+            RawType::Char => Type::Char(to_char(*v as u32)?),
             RawType::String => Type::String(v.to_string()),
-            _ => panic!("Cannot cast {:?} into {:?}.", t, raw),
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
         },
         Type::Int32(v) => match raw {
             RawType::Int8 => Type::Int8(*v as i8),
@@ -59,9 +59,9 @@ pub fn cast(t: &Type, raw: RawType) -> Type {
             RawType::UInt128 => Type::UInt128(*v as u128),
             RawType::Float32 => Type::Float32(*v as f32),
             RawType::Float64 => Type::Float64(*v as f64),
-            RawType::Char => Type::Char(char::from_u32(*v as u32).unwrap()),
+            RawType::Char => Type::Char(to_char(*v as u32)?),
             RawType::String => Type::String(v.to_string()),
-            _ => panic!("Cannot cast {:?} into {:?}.", t, raw),
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
         },
         Type::Int(v) => match raw {
             RawType::Int8 => Type::Int8(*v as i8),
@@ -78,9 +78,9 @@ pub fn cast(t: &Type, raw: RawType) -> Type {
             RawType::UInt128 => Type::UInt128(*v as u128),
             RawType::Float32 => Type::Float32(*v as f32),
             RawType::Float64 => Type::Float64(*v as f64),
-            RawType::Char => Type::Char(char::from_u32(*v as u32).unwrap()),
+            RawType::Char => Type::Char(to_char(*v as u32)?),
             RawType::String => Type::String(v.to_string()),
-            _ => panic!("Cannot cast {:?} into {:?}.", t, raw),
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
         },
         Type::Int64(v) => match raw {
             RawType::Int8 => Type::Int8(*v as i8),
@@ -97,9 +97,9 @@ pub fn cast(t: &Type, raw: RawType) -> Type {
             RawType::UInt128 => Type::UInt128(*v as u128),
             RawType::Float32 => Type::Float32(*v as f32),
             RawType::Float64 => Type::Float64(*v as f64),
-            RawType::Char => Type::Char(char::from_u32(*v as u32).unwrap()),
+            RawType::Char => Type::Char(to_char(*v as u32)?),
             RawType::String => Type::String(v.to_string()),
-            _ => panic!("Cannot cast {:?} into {:?}.", t, raw),
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
         },
         Type::Int128(v) => match raw {
             RawType::Int8 => Type::Int8(*v as i8),
@@ -116,9 +116,9 @@ pub fn cast(t: &Type, raw: RawType) -> Type {
             RawType::UInt128 => Type::UInt128(*v as u128),
             RawType::Float32 => Type::Float32(*v as f32),
             RawType::Float64 => Type::Float64(*v as f64),
-            RawType::Char => Type::Char(char::from_u32(*v as u32).unwrap()),
+            RawType::Char => Type::Char(to_char(*v as u32)?),
             RawType::String => Type::String(v.to_string()),
-            _ => panic!("Cannot cast {:?} into {:?}.", t, raw),
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
         },
         Type::UInt8(v) => match raw {
             RawType::Int8 => Type::Int8(*v as i8),
@@ -137,7 +137,7 @@ pub fn cast(t: &Type, raw: RawType) -> Type {
             RawType::Float64 => Type::Float64(*v as f64),
             RawType::Char => Type::Char(*v as char),
             RawType::String => Type::String(v.to_string()),
-            _ => panic!("Cannot cast {:?} into {:?}.", t, raw),
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
         },
         Type::UInt16(v) => match raw {
             RawType::Int8 => Type::Int8(*v as i8),
@@ -154,9 +154,9 @@ pub fn cast(t: &Type, raw: RawType) -> Type {
             RawType::UInt128 => Type::UInt128(*v as u128),
             RawType::Float32 => Type::Float32(*v as f32),
             RawType::Float64 => Type::Float64(*v as f64),
-            RawType::Char => Type::Char(char::from_u32(*v as u32).unwrap()),
+            RawType::Char => Type::Char(to_char(*v as u32)?),
             RawType::String => Type::String(v.to_string()),
-            _ => panic!("Cannot cast {:?} into {:?}.", t, raw),
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
         },
         Type::UInt32(v) => match raw {
             RawType::Int8 => Type::Int8(*v as i8),
@@ -173,9 +173,9 @@ pub fn cast(t: &Type, raw: RawType) -> Type {
             RawType::UInt128 => Type::UInt128(*v as u128),
             RawType::Float32 => Type::Float32(*v as f32),
             RawType::Float64 => Type::Float64(*v as f64),
-            RawType::Char => Type::Char(char::from_u32(*v).unwrap()),
+            RawType::Char => Type::Char(to_char(*v)?),
             RawType::String => Type::String(v.to_string()),
-            _ => panic!("Cannot cast {:?} into {:?}.", t, raw),
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
         },
         Type::UInt(v) => match raw {
             RawType::Int8 => Type::Int8(*v as i8),
@@ -192,9 +192,9 @@ pub fn cast(t: &Type, raw: RawType) -> Type {
             RawType::UInt128 => Type::UInt128(*v as u128),
             RawType::Float32 => Type::Float32(*v as f32),
             RawType::Float64 => Type::Float64(*v as f64),
-            RawType::Char => Type::Char(char::from_u32(*v as u32).unwrap()),
+            RawType::Char => Type::Char(to_char(*v as u32)?),
             RawType::String => Type::String(v.to_string()),
-            _ => panic!("Cannot cast {:?} into {:?}.", t, raw),
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
         },
         Type::UInt64(v) => match raw {
             RawType::Int8 => Type::Int8(*v as i8),
@@ -211,9 +211,9 @@ pub fn cast(t: &Type, raw: RawType) -> Type {
             RawType::UInt128 => Type::UInt128(*v as u128),
             RawType::Float32 => Type::Float32(*v as f32),
             RawType::Float64 => Type::Float64(*v as f64),
-            RawType::Char => Type::Char(char::from_u32(*v as u32).unwrap()),
+            RawType::Char => Type::Char(to_char(*v as u32)?),
             RawType::String => Type::String(v.to_string()),
-            _ => panic!("Cannot cast {:?} into {:?}.", t, raw),
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
         },
         Type::UInt128(v) => match raw {
             RawType::Int8 => Type::Int8(*v as i8),
@@ -230,9 +230,9 @@ pub fn cast(t: &Type, raw: RawType) -> Type {
             RawType::UInt128 => Type::UInt128(*v),
             RawType::Float32 => Type::Float32(*v as f32),
             RawType::Float64 => Type::Float64(*v as f64),
-            RawType::Char => Type::Char(char::from_u32(*v as u32).unwrap()),
+            RawType::Char => Type::Char(to_char(*v as u32)?),
             RawType::String => Type::String(v.to_string()),
-            _ => panic!("Cannot cast {:?} into {:?}.", t, raw),
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
         },
         Type::Float32(v) => match raw {
             RawType::Int8 => Type::Int8(*v as i8),
@@ -249,9 +249,9 @@ pub fn cast(t: &Type, raw: RawType) -> Type {
             RawType::UInt128 => Type::UInt128(*v as u128),
             RawType::Float32 => Type::Float32(*v),
             RawType::Float64 => Type::Float64(*v as f64),
-            RawType::Char => Type::Char(char::from_u32(*v as u32).unwrap()),
+            RawType::Char => Type::Char(to_char(*v as u32)?),
             RawType::String => Type::String(v.to_string()),
-            _ => panic!("Cannot cast {:?} into {:?}.", t, raw),
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
         },
         Type::Float64(v) => match raw {
             RawType::Int8 => Type::Int8(*v as i8),
@@ -268,9 +268,9 @@ pub fn cast(t: &Type, raw: RawType) -> Type {
             RawType::UInt128 => Type::UInt128(*v as u128),
             RawType::Float32 => Type::Float32(*v as f32),
             RawType::Float64 => Type::Float64(*v),
-            RawType::Char => Type::Char(char::from_u32(*v as u32).unwrap()),
+            RawType::Char => Type::Char(to_char(*v as u32)?),
             RawType::String => Type::String(v.to_string()),
-            _ => panic!("Cannot cast {:?} into {:?}.", t, raw),
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
         },
         Type::Char(v) => match raw {
             RawType::Int8 => Type::Int8(*v as i8),
@@ -287,69 +287,94 @@ pub fn cast(t: &Type, raw: RawType) -> Type {
             RawType::UInt128 => Type::UInt128(*v as u128),
             RawType::Char => Type::Char(*v),
             RawType::String => Type::String(v.to_string()),
-            _ => panic!("Cannot cast {:?} into {:?}.", t, raw),
-        },
-        _ => panic!("{:?} cannot be cast.", t),
-    }
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
+        },
+        Type::String(v) => match raw {
+            RawType::String => Type::String(v.clone()),
+            RawType::Array => Type::Array(v.chars().map(Type::Char).collect()),
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
+        },
+        Type::Array(v) => match raw {
+            RawType::Array => Type::Array(v.clone()),
+            RawType::String => Type::String(
+                v.iter()
+                    .map(|c| match c {
+                        Type::Char(c) => Ok(*c),
+                        _ => Err(Trap::InvalidCast(t.clone(), raw)),
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => return Err(Trap::InvalidCast(t.clone(), raw)),
+        },
+        _ => return Err(Trap::InvalidCast(t.clone(), raw)),
+    })
 }
 
-pub fn solve(op: &Operation, registers: &mut Registers, regs: &[Register; 2]) {
+pub fn solve(op: &Operation, registers: &mut Registers, regs: &[Register; 2]) -> Result<(), Trap> {
     match op {
         Operation::Prim1(op) => {
-            let v = registers.take(regs[0]);
-            let t = solve_1(op, v);
-            registers.insert(regs[0], t);
+            let v = registers.clone(regs[0])?;
+            let t = solve_1(op, v)?;
+            registers.insert(regs[0], t)
         }
         Operation::Prim2(op) => {
-            let v1 = registers.clone(regs[0]);
-            let v2 = registers.clone(regs[1]);
-            let t = solve_2(op, v1, v2);
-            registers.insert(regs[0], t);
+            let v1 = registers.clone(regs[0])?;
+            let v2 = registers.clone(regs[1])?;
+            let t = solve_2(op, v1, v2)?;
+            registers.insert(regs[0], t)
         }
     }
 }
 
-pub fn solve_1(op: &OpPrim1, t: Type) -> Type {
-    match op {
+pub fn solve_1(op: &OpPrim1, t: Type) -> Result<Type, Trap> {
+    Ok(match op {
         OpPrim1::Increment => match t {
-            Type::Int8(v) => Type::Int8(v + 1),
-            Type::Int16(v) => Type::Int16(v + 1),
-            Type::Int32(v) => Type::Int32(v + 1),
-            Type::Int(v) => Type::Int(v + 1),
-            Type::Int64(v) => Type::Int64(v + 1),
-            Type::Int128(v) => Type::Int128(v + 1),
-            Type::UInt8(v) => Type::UInt8(v + 1),
-            Type::UInt16(v) => Type::UInt16(v + 1),
-            Type::UInt32(v) => Type::UInt32(v + 1),
-            Type::UInt(v) => Type::UInt(v + 1),
-            Type::UInt64(v) => Type::UInt64(v + 1),
-            Type::UInt128(v) => Type::UInt128(v + 1),
+            Type::Int8(v) => Type::Int8(checked(v.checked_add(1))?),
+            Type::Int16(v) => Type::Int16(checked(v.checked_add(1))?),
+            Type::Int32(v) => Type::Int32(checked(v.checked_add(1))?),
+            Type::Int(v) => Type::Int(checked(v.checked_add(1))?),
+            Type::Int64(v) => Type::Int64(checked(v.checked_add(1))?),
+            Type::Int128(v) => Type::Int128(checked(v.checked_add(1))?),
+            Type::UInt8(v) => Type::UInt8(checked(v.checked_add(1))?),
+            Type::UInt16(v) => Type::UInt16(checked(v.checked_add(1))?),
+            Type::UInt32(v) => Type::UInt32(checked(v.checked_add(1))?),
+            Type::UInt(v) => Type::UInt(checked(v.checked_add(1))?),
+            Type::UInt64(v) => Type::UInt64(checked(v.checked_add(1))?),
+            Type::UInt128(v) => Type::UInt128(checked(v.checked_add(1))?),
             Type::Float32(v) => Type::Float32(v + 1.0),
             Type::Float64(v) => Type::Float64(v + 1.0),
-            Type::Char(v) => Type::Char(char::from_u32(v as u32 + 1).unwrap()),
-            _ => panic!("Increment only works on number types."),
+            Type::Char(v) => Type::Char(to_char(v as u32 + 1)?),
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "Increment only works on number types.",
+                ))
+            }
         },
         OpPrim1::Decrement => match t {
-            Type::Int8(v) => Type::Int8(v - 1),
-            Type::Int16(v) => Type::Int16(v - 1),
-            Type::Int32(v) => Type::Int32(v - 1),
-            Type::Int(v) => Type::Int(v - 1),
-            Type::Int64(v) => Type::Int64(v - 1),
-            Type::Int128(v) => Type::Int128(v - 1),
-            Type::UInt8(v) => Type::UInt8(v - 1),
-            Type::UInt16(v) => Type::UInt16(v - 1),
-            Type::UInt32(v) => Type::UInt32(v - 1),
-            Type::UInt(v) => Type::UInt(v - 1),
-            Type::UInt64(v) => Type::UInt64(v - 1),
-            Type::UInt128(v) => Type::UInt128(v - 1),
+            Type::Int8(v) => Type::Int8(checked(v.checked_sub(1))?),
+            Type::Int16(v) => Type::Int16(checked(v.checked_sub(1))?),
+            Type::Int32(v) => Type::Int32(checked(v.checked_sub(1))?),
+            Type::Int(v) => Type::Int(checked(v.checked_sub(1))?),
+            Type::Int64(v) => Type::Int64(checked(v.checked_sub(1))?),
+            Type::Int128(v) => Type::Int128(checked(v.checked_sub(1))?),
+            Type::UInt8(v) => Type::UInt8(checked(v.checked_sub(1))?),
+            Type::UInt16(v) => Type::UInt16(checked(v.checked_sub(1))?),
+            Type::UInt32(v) => Type::UInt32(checked(v.checked_sub(1))?),
+            Type::UInt(v) => Type::UInt(checked(v.checked_sub(1))?),
+            Type::UInt64(v) => Type::UInt64(checked(v.checked_sub(1))?),
+            Type::UInt128(v) => Type::UInt128(checked(v.checked_sub(1))?),
             Type::Float32(v) => Type::Float32(v - 1.0),
             Type::Float64(v) => Type::Float64(v - 1.0),
-            Type::Char(v) => Type::Char(char::from_u32(v as u32 - 1).unwrap()),
-            _ => panic!("Decrement only works on number types."),
+            Type::Char(v) => Type::Char(to_char(checked((v as u32).checked_sub(1))?)?),
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "Decrement only works on number types.",
+                ))
+            }
         },
         OpPrim1::Not => match t {
             Type::Boolean(v) => Type::Boolean(!v),
-            _ => panic!("Not only works on boolean type."),
+            _ => return Err(Trap::InvalidOperation("Not only works on boolean type.")),
         },
         OpPrim1::BitwiseNot => match t {
             Type::Int8(v) => Type::Int8(!v),
@@ -364,128 +389,172 @@ pub fn solve_1(op: &OpPrim1, t: Type) -> Type {
             Type::UInt(v) => Type::UInt(!v),
             Type::UInt64(v) => Type::UInt64(!v),
             Type::UInt128(v) => Type::UInt128(!v),
-            _ => panic!("BitwiseNot only works on int number types."),
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "BitwiseNot only works on int number types.",
+                ))
+            }
         },
-    }
+    })
 }
 
-pub fn solve_2(op: &OpPrim2, t1: Type, t2: Type) -> Type {
-    match op {
+pub fn solve_2(op: &OpPrim2, t1: Type, t2: Type) -> Result<Type, Trap> {
+    if matches!(op, OpPrim2::Division | OpPrim2::Modulus) && is_zero(&t2) {
+        return Err(Trap::DivisionByZero);
+    }
+
+    Ok(match op {
         OpPrim2::Add => match (t1, t2) {
-            (Type::Int8(v1), Type::Int8(v2)) => Type::Int8(v1 + v2),
-            (Type::Int16(v1), Type::Int16(v2)) => Type::Int16(v1 + v2),
-            (Type::Int32(v1), Type::Int32(v2)) => Type::Int32(v1 + v2),
-            (Type::Int(v1), Type::Int(v2)) => Type::Int(v1 + v2),
-            (Type::Int64(v1), Type::Int64(v2)) => Type::Int64(v1 + v2),
-            (Type::Int128(v1), Type::Int128(v2)) => Type::Int128(v1 + v2),
-            (Type::UInt8(v1), Type::UInt8(v2)) => Type::UInt8(v1 + v2),
-            (Type::UInt16(v1), Type::UInt16(v2)) => Type::UInt16(v1 + v2),
-            (Type::UInt32(v1), Type::UInt32(v2)) => Type::UInt32(v1 + v2),
-            (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(v1 + v2),
-            (Type::UInt64(v1), Type::UInt64(v2)) => Type::UInt64(v1 + v2),
-            (Type::UInt128(v1), Type::UInt128(v2)) => Type::UInt128(v1 + v2),
+            (Type::Int8(v1), Type::Int8(v2)) => Type::Int8(checked(v1.checked_add(v2))?),
+            (Type::Int16(v1), Type::Int16(v2)) => Type::Int16(checked(v1.checked_add(v2))?),
+            (Type::Int32(v1), Type::Int32(v2)) => Type::Int32(checked(v1.checked_add(v2))?),
+            (Type::Int(v1), Type::Int(v2)) => Type::Int(checked(v1.checked_add(v2))?),
+            (Type::Int64(v1), Type::Int64(v2)) => Type::Int64(checked(v1.checked_add(v2))?),
+            (Type::Int128(v1), Type::Int128(v2)) => Type::Int128(checked(v1.checked_add(v2))?),
+            (Type::UInt8(v1), Type::UInt8(v2)) => Type::UInt8(checked(v1.checked_add(v2))?),
+            (Type::UInt16(v1), Type::UInt16(v2)) => Type::UInt16(checked(v1.checked_add(v2))?),
+            (Type::UInt32(v1), Type::UInt32(v2)) => Type::UInt32(checked(v1.checked_add(v2))?),
+            (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(checked(v1.checked_add(v2))?),
+            (Type::UInt64(v1), Type::UInt64(v2)) => Type::UInt64(checked(v1.checked_add(v2))?),
+            (Type::UInt128(v1), Type::UInt128(v2)) => Type::UInt128(checked(v1.checked_add(v2))?),
             (Type::Float32(v1), Type::Float32(v2)) => Type::Float32(v1 + v2),
             (Type::Float64(v1), Type::Float64(v2)) => Type::Float64(v1 + v2),
             (Type::Char(v1), Type::Char(v2)) => {
-                Type::Char(char::from_u32(v1 as u32 + v2 as u32).unwrap())
+                Type::Char(to_char(checked((v1 as u32).checked_add(v2 as u32))?)?)
             }
             (Type::String(mut v1), Type::String(v2)) => Type::String({
                 v1.push_str(v2.as_str());
                 v1
             }),
-            _ => panic!("Add only works on two of the same number types and two strings."),
+            (Type::Array(mut v1), Type::Array(v2)) => Type::Array({
+                v1.extend(v2);
+                v1
+            }),
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "Add only works on two of the same number types, two strings, and two arrays.",
+                ))
+            }
         },
         OpPrim2::Subtract => match (t1, t2) {
-            (Type::Int8(v1), Type::Int8(v2)) => Type::Int8(v1 - v2),
-            (Type::Int16(v1), Type::Int16(v2)) => Type::Int16(v1 - v2),
-            (Type::Int32(v1), Type::Int32(v2)) => Type::Int32(v1 - v2),
-            (Type::Int(v1), Type::Int(v2)) => Type::Int(v1 - v2),
-            (Type::Int64(v1), Type::Int64(v2)) => Type::Int64(v1 - v2),
-            (Type::Int128(v1), Type::Int128(v2)) => Type::Int128(v1 - v2),
-            (Type::UInt8(v1), Type::UInt8(v2)) => Type::UInt8(v1 - v2),
-            (Type::UInt16(v1), Type::UInt16(v2)) => Type::UInt16(v1 - v2),
-            (Type::UInt32(v1), Type::UInt32(v2)) => Type::UInt32(v1 - v2),
-            (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(v1 - v2),
-            (Type::UInt64(v1), Type::UInt64(v2)) => Type::UInt64(v1 - v2),
-            (Type::UInt128(v1), Type::UInt128(v2)) => Type::UInt128(v1 - v2),
+            (Type::Int8(v1), Type::Int8(v2)) => Type::Int8(checked(v1.checked_sub(v2))?),
+            (Type::Int16(v1), Type::Int16(v2)) => Type::Int16(checked(v1.checked_sub(v2))?),
+            (Type::Int32(v1), Type::Int32(v2)) => Type::Int32(checked(v1.checked_sub(v2))?),
+            (Type::Int(v1), Type::Int(v2)) => Type::Int(checked(v1.checked_sub(v2))?),
+            (Type::Int64(v1), Type::Int64(v2)) => Type::Int64(checked(v1.checked_sub(v2))?),
+            (Type::Int128(v1), Type::Int128(v2)) => Type::Int128(checked(v1.checked_sub(v2))?),
+            (Type::UInt8(v1), Type::UInt8(v2)) => Type::UInt8(checked(v1.checked_sub(v2))?),
+            (Type::UInt16(v1), Type::UInt16(v2)) => Type::UInt16(checked(v1.checked_sub(v2))?),
+            (Type::UInt32(v1), Type::UInt32(v2)) => Type::UInt32(checked(v1.checked_sub(v2))?),
+            (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(checked(v1.checked_sub(v2))?),
+            (Type::UInt64(v1), Type::UInt64(v2)) => Type::UInt64(checked(v1.checked_sub(v2))?),
+            (Type::UInt128(v1), Type::UInt128(v2)) => Type::UInt128(checked(v1.checked_sub(v2))?),
             (Type::Float32(v1), Type::Float32(v2)) => Type::Float32(v1 - v2),
             (Type::Float64(v1), Type::Float64(v2)) => Type::Float64(v1 - v2),
             (Type::Char(v1), Type::Char(v2)) => {
-                Type::Char(char::from_u32(v1 as u32 - v2 as u32).unwrap())
+                Type::Char(to_char(checked((v1 as u32).checked_sub(v2 as u32))?)?)
+            }
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "Subtract only works on two of the same number types.",
+                ))
             }
-            _ => panic!("Subtract only works on two of the same number types."),
         },
         OpPrim2::Multiplication => match (t1, t2) {
-            (Type::Int8(v1), Type::Int8(v2)) => Type::Int8(v1 * v2),
-            (Type::Int16(v1), Type::Int16(v2)) => Type::Int16(v1 * v2),
-            (Type::Int32(v1), Type::Int32(v2)) => Type::Int32(v1 * v2),
-            (Type::Int(v1), Type::Int(v2)) => Type::Int(v1 * v2),
-            (Type::Int64(v1), Type::Int64(v2)) => Type::Int64(v1 * v2),
-            (Type::Int128(v1), Type::Int128(v2)) => Type::Int128(v1 * v2),
-            (Type::UInt8(v1), Type::UInt8(v2)) => Type::UInt8(v1 * v2),
-            (Type::UInt16(v1), Type::UInt16(v2)) => Type::UInt16(v1 * v2),
-            (Type::UInt32(v1), Type::UInt32(v2)) => Type::UInt32(v1 * v2),
-            (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(v1 * v2),
-            (Type::UInt64(v1), Type::UInt64(v2)) => Type::UInt64(v1 * v2),
-            (Type::UInt128(v1), Type::UInt128(v2)) => Type::UInt128(v1 * v2),
+            (Type::Int8(v1), Type::Int8(v2)) => Type::Int8(checked(v1.checked_mul(v2))?),
+            (Type::Int16(v1), Type::Int16(v2)) => Type::Int16(checked(v1.checked_mul(v2))?),
+            (Type::Int32(v1), Type::Int32(v2)) => Type::Int32(checked(v1.checked_mul(v2))?),
+            (Type::Int(v1), Type::Int(v2)) => Type::Int(checked(v1.checked_mul(v2))?),
+            (Type::Int64(v1), Type::Int64(v2)) => Type::Int64(checked(v1.checked_mul(v2))?),
+            (Type::Int128(v1), Type::Int128(v2)) => Type::Int128(checked(v1.checked_mul(v2))?),
+            (Type::UInt8(v1), Type::UInt8(v2)) => Type::UInt8(checked(v1.checked_mul(v2))?),
+            (Type::UInt16(v1), Type::UInt16(v2)) => Type::UInt16(checked(v1.checked_mul(v2))?),
+            (Type::UInt32(v1), Type::UInt32(v2)) => Type::UInt32(checked(v1.checked_mul(v2))?),
+            (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(checked(v1.checked_mul(v2))?),
+            (Type::UInt64(v1), Type::UInt64(v2)) => Type::UInt64(checked(v1.checked_mul(v2))?),
+            (Type::UInt128(v1), Type::UInt128(v2)) => Type::UInt128(checked(v1.checked_mul(v2))?),
             (Type::Float32(v1), Type::Float32(v2)) => Type::Float32(v1 * v2),
             (Type::Float64(v1), Type::Float64(v2)) => Type::Float64(v1 * v2),
             (Type::Char(v1), Type::Char(v2)) => {
-                Type::Char(char::from_u32(v1 as u32 * v2 as u32).unwrap())
+                Type::Char(to_char(checked((v1 as u32).checked_mul(v2 as u32))?)?)
+            }
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "Multiplication only works on two of the same number types.",
+                ))
             }
-            _ => panic!("Multiplication only works on two of the same number types."),
         },
         OpPrim2::Division => match (t1, t2) {
-            (Type::Int8(v1), Type::Int8(v2)) => Type::Int8(v1 / v2),
-            (Type::Int16(v1), Type::Int16(v2)) => Type::Int16(v1 / v2),
-            (Type::Int32(v1), Type::Int32(v2)) => Type::Int32(v1 / v2),
-            (Type::Int(v1), Type::Int(v2)) => Type::Int(v1 / v2),
-            (Type::Int64(v1), Type::Int64(v2)) => Type::Int64(v1 / v2),
-            (Type::Int128(v1), Type::Int128(v2)) => Type::Int128(v1 / v2),
-            (Type::UInt8(v1), Type::UInt8(v2)) => Type::UInt8(v1 / v2),
-            (Type::UInt16(v1), Type::UInt16(v2)) => Type::UInt16(v1 / v2),
-            (Type::UInt32(v1), Type::UInt32(v2)) => Type::UInt32(v1 / v2),
-            (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(v1 / v2),
-            (Type::UInt64(v1), Type::UInt64(v2)) => Type::UInt64(v1 / v2),
-            (Type::UInt128(v1), Type::UInt128(v2)) => Type::UInt128(v1 / v2),
+            (Type::Int8(v1), Type::Int8(v2)) => Type::Int8(checked(v1.checked_div(v2))?),
+            (Type::Int16(v1), Type::Int16(v2)) => Type::Int16(checked(v1.checked_div(v2))?),
+            (Type::Int32(v1), Type::Int32(v2)) => Type::Int32(checked(v1.checked_div(v2))?),
+            (Type::Int(v1), Type::Int(v2)) => Type::Int(checked(v1.checked_div(v2))?),
+            (Type::Int64(v1), Type::Int64(v2)) => Type::Int64(checked(v1.checked_div(v2))?),
+            (Type::Int128(v1), Type::Int128(v2)) => Type::Int128(checked(v1.checked_div(v2))?),
+            (Type::UInt8(v1), Type::UInt8(v2)) => Type::UInt8(checked(v1.checked_div(v2))?),
+            (Type::UInt16(v1), Type::UInt16(v2)) => Type::UInt16(checked(v1.checked_div(v2))?),
+            (Type::UInt32(v1), Type::UInt32(v2)) => Type::UInt32(checked(v1.checked_div(v2))?),
+            (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(checked(v1.checked_div(v2))?),
+            (Type::UInt64(v1), Type::UInt64(v2)) => Type::UInt64(checked(v1.checked_div(v2))?),
+            (Type::UInt128(v1), Type::UInt128(v2)) => Type::UInt128(checked(v1.checked_div(v2))?),
             (Type::Float32(v1), Type::Float32(v2)) => Type::Float32(v1 / v2),
             (Type::Float64(v1), Type::Float64(v2)) => Type::Float64(v1 / v2),
             (Type::Char(v1), Type::Char(v2)) => {
-                Type::Char(char::from_u32(v1 as u32 / v2 as u32).unwrap())
+                Type::Char(to_char(checked((v1 as u32).checked_div(v2 as u32))?)?)
+            }
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "Division only works on two of the same number types.",
+                ))
             }
-            _ => panic!("Division only works on two of the same number types."),
         },
         OpPrim2::Modulus => match (t1, t2) {
-            (Type::Int8(v1), Type::Int8(v2)) => Type::Int8(v1 % v2),
-            (Type::Int16(v1), Type::Int16(v2)) => Type::Int16(v1 % v2),
-            (Type::Int32(v1), Type::Int32(v2)) => Type::Int32(v1 % v2),
-            (Type::Int(v1), Type::Int(v2)) => Type::Int(v1 % v2),
-            (Type::Int64(v1), Type::Int64(v2)) => Type::Int64(v1 % v2),
-            (Type::Int128(v1), Type::Int128(v2)) => Type::Int128(v1 % v2),
-            (Type::UInt8(v1), Type::UInt8(v2)) => Type::UInt8(v1 % v2),
-            (Type::UInt16(v1), Type::UInt16(v2)) => Type::UInt16(v1 % v2),
-            (Type::UInt32(v1), Type::UInt32(v2)) => Type::UInt32(v1 % v2),
-            (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(v1 % v2),
-            (Type::UInt64(v1), Type::UInt64(v2)) => Type::UInt64(v1 % v2),
-            (Type::UInt128(v1), Type::UInt128(v2)) => Type::UInt128(v1 % v2),
+            (Type::Int8(v1), Type::Int8(v2)) => Type::Int8(checked(v1.checked_rem(v2))?),
+            (Type::Int16(v1), Type::Int16(v2)) => Type::Int16(checked(v1.checked_rem(v2))?),
+            (Type::Int32(v1), Type::Int32(v2)) => Type::Int32(checked(v1.checked_rem(v2))?),
+            (Type::Int(v1), Type::Int(v2)) => Type::Int(checked(v1.checked_rem(v2))?),
+            (Type::Int64(v1), Type::Int64(v2)) => Type::Int64(checked(v1.checked_rem(v2))?),
+            (Type::Int128(v1), Type::Int128(v2)) => Type::Int128(checked(v1.checked_rem(v2))?),
+            (Type::UInt8(v1), Type::UInt8(v2)) => Type::UInt8(checked(v1.checked_rem(v2))?),
+            (Type::UInt16(v1), Type::UInt16(v2)) => Type::UInt16(checked(v1.checked_rem(v2))?),
+            (Type::UInt32(v1), Type::UInt32(v2)) => Type::UInt32(checked(v1.checked_rem(v2))?),
+            (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(checked(v1.checked_rem(v2))?),
+            (Type::UInt64(v1), Type::UInt64(v2)) => Type::UInt64(checked(v1.checked_rem(v2))?),
+            (Type::UInt128(v1), Type::UInt128(v2)) => Type::UInt128(checked(v1.checked_rem(v2))?),
             (Type::Float32(v1), Type::Float32(v2)) => Type::Float32(v1 % v2),
             (Type::Float64(v1), Type::Float64(v2)) => Type::Float64(v1 % v2),
             (Type::Char(v1), Type::Char(v2)) => {
-                Type::Char(char::from_u32(v1 as u32 % v2 as u32).unwrap())
+                Type::Char(to_char(checked((v1 as u32).checked_rem(v2 as u32))?)?)
+            }
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "Modulus only works on two of the same number types.",
+                ))
             }
-            _ => panic!("Modulus only works on two of the same number types."),
         },
         OpPrim2::And => match (t1, t2) {
             (Type::Boolean(v1), Type::Boolean(v2)) => Type::Boolean(v1 && v2),
-            _ => panic!("Add only works on two of the same boolean type."),
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "Add only works on two of the same boolean type.",
+                ))
+            }
         },
         OpPrim2::Or => match (t1, t2) {
             (Type::Boolean(v1), Type::Boolean(v2)) => Type::Boolean(v1 || v2),
-            _ => panic!("Or only works on two of the same boolean type."),
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "Or only works on two of the same boolean type.",
+                ))
+            }
         },
         OpPrim2::Xor => match (t1, t2) {
             (Type::Boolean(v1), Type::Boolean(v2)) => Type::Boolean(v1 ^ v2),
-            _ => panic!("Xor only works on two of the same boolean type."),
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "Xor only works on two of the same boolean type.",
+                ))
+            }
         },
         OpPrim2::Equal => match (t1, t2) {
             (Type::None, Type::None) => Type::Boolean(true),
@@ -508,6 +577,10 @@ pub fn solve_2(op: &OpPrim2, t1: Type, t2: Type) -> Type {
             (Type::Boolean(v1), Type::Boolean(v2)) => Type::Boolean(v1 == v2),
             (Type::Address(v1), Type::Address(v2)) => Type::Boolean(v1 == v2),
             (Type::Pointer(v1), Type::Pointer(v2)) => Type::Boolean(v1 == v2),
+            (Type::Array(v1), Type::Array(v2)) => Type::Boolean(v1 == v2),
+            (Type::Struct(i1, v1), Type::Struct(i2, v2)) => Type::Boolean(i1 == i2 && v1 == v2),
+            (Type::Tagged(t1, v1), Type::Tagged(t2, v2)) => Type::Boolean(t1 == t2 && v1 == v2),
+            (Type::Map(v1), Type::Map(v2)) => Type::Boolean(v1 == v2),
             _ => Type::Boolean(false),
         },
         OpPrim2::NotEqual => match (t1, t2) {
@@ -531,6 +604,10 @@ pub fn solve_2(op: &OpPrim2, t1: Type, t2: Type) -> Type {
             (Type::Boolean(v1), Type::Boolean(v2)) => Type::Boolean(v1 != v2),
             (Type::Address(v1), Type::Address(v2)) => Type::Boolean(v1 != v2),
             (Type::Pointer(v1), Type::Pointer(v2)) => Type::Boolean(v1 != v2),
+            (Type::Array(v1), Type::Array(v2)) => Type::Boolean(v1 != v2),
+            (Type::Struct(i1, v1), Type::Struct(i2, v2)) => Type::Boolean(i1 != i2 || v1 != v2),
+            (Type::Tagged(t1, v1), Type::Tagged(t2, v2)) => Type::Boolean(t1 != t2 || v1 != v2),
+            (Type::Map(v1), Type::Map(v2)) => Type::Boolean(v1 != v2),
             _ => Type::Boolean(true),
         },
         OpPrim2::Greater => match (t1, t2) {
@@ -550,7 +627,11 @@ pub fn solve_2(op: &OpPrim2, t1: Type, t2: Type) -> Type {
             (Type::Float64(v1), Type::Float64(v2)) => Type::Boolean(v1 > v2),
             (Type::Char(v1), Type::Char(v2)) => Type::Boolean(v1 > v2),
             (Type::String(v1), Type::String(v2)) => Type::Boolean(v1.cmp(&v2).is_lt()),
-            _ => panic!("Greater only works on two of the same number/string types."),
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "Greater only works on two of the same number/string types.",
+                ))
+            }
         },
         OpPrim2::Less => match (t1, t2) {
             (Type::Int8(v1), Type::Int8(v2)) => Type::Boolean(v1 < v2),
@@ -569,7 +650,11 @@ pub fn solve_2(op: &OpPrim2, t1: Type, t2: Type) -> Type {
             (Type::Float64(v1), Type::Float64(v2)) => Type::Boolean(v1 < v2),
             (Type::Char(v1), Type::Char(v2)) => Type::Boolean(v1 < v2),
             (Type::String(v1), Type::String(v2)) => Type::Boolean(v1.cmp(&v2).is_gt()),
-            _ => panic!("Less only works on two of the same number/string types."),
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "Less only works on two of the same number/string types.",
+                ))
+            }
         },
         OpPrim2::GreaterEqual => match (t1, t2) {
             (Type::Int8(v1), Type::Int8(v2)) => Type::Boolean(v1 >= v2),
@@ -588,7 +673,11 @@ pub fn solve_2(op: &OpPrim2, t1: Type, t2: Type) -> Type {
             (Type::Float64(v1), Type::Float64(v2)) => Type::Boolean(v1 >= v2),
             (Type::Char(v1), Type::Char(v2)) => Type::Boolean(v1 >= v2),
             (Type::String(v1), Type::String(v2)) => Type::Boolean(v1.cmp(&v2).is_le()),
-            _ => panic!("GreaterEqual only works on two of the same number/string types."),
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "GreaterEqual only works on two of the same number/string types.",
+                ))
+            }
         },
         OpPrim2::LessEqual => match (t1, t2) {
             (Type::Int8(v1), Type::Int8(v2)) => Type::Boolean(v1 <= v2),
@@ -607,7 +696,11 @@ pub fn solve_2(op: &OpPrim2, t1: Type, t2: Type) -> Type {
             (Type::Float64(v1), Type::Float64(v2)) => Type::Boolean(v1 <= v2),
             (Type::Char(v1), Type::Char(v2)) => Type::Boolean(v1 <= v2),
             (Type::String(v1), Type::String(v2)) => Type::Boolean(v1.cmp(&v2).is_ge()),
-            _ => panic!("LessEqual only works on two of the same number/string types."),
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "LessEqual only works on two of the same number/string types.",
+                ))
+            }
         },
         OpPrim2::BitwiseAnd => match (t1, t2) {
             (Type::Int8(v1), Type::Int8(v2)) => Type::Int8(v1 & v2),
@@ -622,10 +715,12 @@ pub fn solve_2(op: &OpPrim2, t1: Type, t2: Type) -> Type {
             (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(v1 & v2),
             (Type::UInt64(v1), Type::UInt64(v2)) => Type::UInt64(v1 & v2),
             (Type::UInt128(v1), Type::UInt128(v2)) => Type::UInt128(v1 & v2),
-            (Type::Char(v1), Type::Char(v2)) => {
-                Type::Char(char::from_u32(v1 as u32 & v2 as u32).unwrap())
+            (Type::Char(v1), Type::Char(v2)) => Type::Char(to_char(v1 as u32 & v2 as u32)?),
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "BitwiseAnd only works on an int number type and an UInt.",
+                ))
             }
-            _ => panic!("BitwiseAnd only works on an int number type and an UInt."),
         },
         OpPrim2::BitwiseOr => match (t1, t2) {
             (Type::Int8(v1), Type::Int8(v2)) => Type::Int8(v1 | v2),
@@ -640,10 +735,12 @@ pub fn solve_2(op: &OpPrim2, t1: Type, t2: Type) -> Type {
             (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(v1 | v2),
             (Type::UInt64(v1), Type::UInt64(v2)) => Type::UInt64(v1 | v2),
             (Type::UInt128(v1), Type::UInt128(v2)) => Type::UInt128(v1 | v2),
-            (Type::Char(v1), Type::Char(v2)) => {
-                Type::Char(char::from_u32(v1 as u32 | v2 as u32).unwrap())
+            (Type::Char(v1), Type::Char(v2)) => Type::Char(to_char(v1 as u32 | v2 as u32)?),
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "BitwiseOr only works on an int number type and an UInt.",
+                ))
             }
-            _ => panic!("BitwiseOr only works on an int number type and an UInt."),
         },
         OpPrim2::BitwiseXor => match (t1, t2) {
             (Type::Int8(v1), Type::Int8(v2)) => Type::Int8(v1 ^ v2),
@@ -658,46 +755,76 @@ pub fn solve_2(op: &OpPrim2, t1: Type, t2: Type) -> Type {
             (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(v1 ^ v2),
             (Type::UInt64(v1), Type::UInt64(v2)) => Type::UInt64(v1 ^ v2),
             (Type::UInt128(v1), Type::UInt128(v2)) => Type::UInt128(v1 ^ v2),
-            (Type::Char(v1), Type::Char(v2)) => {
-                Type::Char(char::from_u32(v1 as u32 ^ v2 as u32).unwrap())
+            (Type::Char(v1), Type::Char(v2)) => Type::Char(to_char(v1 as u32 ^ v2 as u32)?),
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "BitwiseXor only works on an int number type and an UInt.",
+                ))
             }
-            _ => panic!("BitwiseXor only works on an int number type and an UInt."),
         },
         OpPrim2::ShiftLeft => match (t1, t2) {
-            (Type::Int8(v1), Type::UInt(v2)) => Type::Int8(v1 << v2),
-            (Type::Int16(v1), Type::UInt(v2)) => Type::Int16(v1 << v2),
-            (Type::Int32(v1), Type::UInt(v2)) => Type::Int32(v1 << v2),
-            (Type::Int(v1), Type::UInt(v2)) => Type::Int(v1 << v2),
-            (Type::Int64(v1), Type::UInt(v2)) => Type::Int64(v1 << v2),
-            (Type::Int128(v1), Type::UInt(v2)) => Type::Int128(v1 << v2),
-            (Type::UInt8(v1), Type::UInt(v2)) => Type::UInt8(v1 << v2),
-            (Type::UInt16(v1), Type::UInt(v2)) => Type::UInt16(v1 << v2),
-            (Type::UInt32(v1), Type::UInt(v2)) => Type::UInt32(v1 << v2),
-            (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(v1 << v2),
-            (Type::UInt64(v1), Type::UInt(v2)) => Type::UInt64(v1 << v2),
-            (Type::UInt128(v1), Type::UInt(v2)) => Type::UInt128(v1 << v2),
+            (Type::Int8(v1), Type::UInt(v2)) => Type::Int8(checked(v1.checked_shl(shift(v2)?))?),
+            (Type::Int16(v1), Type::UInt(v2)) => Type::Int16(checked(v1.checked_shl(shift(v2)?))?),
+            (Type::Int32(v1), Type::UInt(v2)) => Type::Int32(checked(v1.checked_shl(shift(v2)?))?),
+            (Type::Int(v1), Type::UInt(v2)) => Type::Int(checked(v1.checked_shl(shift(v2)?))?),
+            (Type::Int64(v1), Type::UInt(v2)) => Type::Int64(checked(v1.checked_shl(shift(v2)?))?),
+            (Type::Int128(v1), Type::UInt(v2)) => {
+                Type::Int128(checked(v1.checked_shl(shift(v2)?))?)
+            }
+            (Type::UInt8(v1), Type::UInt(v2)) => Type::UInt8(checked(v1.checked_shl(shift(v2)?))?),
+            (Type::UInt16(v1), Type::UInt(v2)) => {
+                Type::UInt16(checked(v1.checked_shl(shift(v2)?))?)
+            }
+            (Type::UInt32(v1), Type::UInt(v2)) => {
+                Type::UInt32(checked(v1.checked_shl(shift(v2)?))?)
+            }
+            (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(checked(v1.checked_shl(shift(v2)?))?),
+            (Type::UInt64(v1), Type::UInt(v2)) => {
+                Type::UInt64(checked(v1.checked_shl(shift(v2)?))?)
+            }
+            (Type::UInt128(v1), Type::UInt(v2)) => {
+                Type::UInt128(checked(v1.checked_shl(shift(v2)?))?)
+            }
             (Type::Char(v1), Type::UInt(v2)) => {
-                Type::Char(char::from_u32((v1 as u32) << v2).unwrap())
+                Type::Char(to_char(checked((v1 as u32).checked_shl(shift(v2)?))?)?)
+            }
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "ShiftLeft only works on an int number type and an UInt.",
+                ))
             }
-            _ => panic!("ShiftLeft only works on an int number type and an UInt."),
         },
         OpPrim2::ShiftRight => match (t1, t2) {
-            (Type::Int8(v1), Type::UInt(v2)) => Type::Int8(v1 >> v2),
-            (Type::Int16(v1), Type::UInt(v2)) => Type::Int16(v1 >> v2),
-            (Type::Int32(v1), Type::UInt(v2)) => Type::Int32(v1 >> v2),
-            (Type::Int(v1), Type::UInt(v2)) => Type::Int(v1 >> v2),
-            (Type::Int64(v1), Type::UInt(v2)) => Type::Int64(v1 >> v2),
-            (Type::Int128(v1), Type::UInt(v2)) => Type::Int128(v1 >> v2),
-            (Type::UInt8(v1), Type::UInt(v2)) => Type::UInt8(v1 >> v2),
-            (Type::UInt16(v1), Type::UInt(v2)) => Type::UInt16(v1 >> v2),
-            (Type::UInt32(v1), Type::UInt(v2)) => Type::UInt32(v1 >> v2),
-            (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(v1 >> v2),
-            (Type::UInt64(v1), Type::UInt(v2)) => Type::UInt64(v1 >> v2),
-            (Type::UInt128(v1), Type::UInt(v2)) => Type::UInt128(v1 >> v2),
+            (Type::Int8(v1), Type::UInt(v2)) => Type::Int8(checked(v1.checked_shr(shift(v2)?))?),
+            (Type::Int16(v1), Type::UInt(v2)) => Type::Int16(checked(v1.checked_shr(shift(v2)?))?),
+            (Type::Int32(v1), Type::UInt(v2)) => Type::Int32(checked(v1.checked_shr(shift(v2)?))?),
+            (Type::Int(v1), Type::UInt(v2)) => Type::Int(checked(v1.checked_shr(shift(v2)?))?),
+            (Type::Int64(v1), Type::UInt(v2)) => Type::Int64(checked(v1.checked_shr(shift(v2)?))?),
+            (Type::Int128(v1), Type::UInt(v2)) => {
+                Type::Int128(checked(v1.checked_shr(shift(v2)?))?)
+            }
+            (Type::UInt8(v1), Type::UInt(v2)) => Type::UInt8(checked(v1.checked_shr(shift(v2)?))?),
+            (Type::UInt16(v1), Type::UInt(v2)) => {
+                Type::UInt16(checked(v1.checked_shr(shift(v2)?))?)
+            }
+            (Type::UInt32(v1), Type::UInt(v2)) => {
+                Type::UInt32(checked(v1.checked_shr(shift(v2)?))?)
+            }
+            (Type::UInt(v1), Type::UInt(v2)) => Type::UInt(checked(v1.checked_shr(shift(v2)?))?),
+            (Type::UInt64(v1), Type::UInt(v2)) => {
+                Type::UInt64(checked(v1.checked_shr(shift(v2)?))?)
+            }
+            (Type::UInt128(v1), Type::UInt(v2)) => {
+                Type::UInt128(checked(v1.checked_shr(shift(v2)?))?)
+            }
             (Type::Char(v1), Type::UInt(v2)) => {
-                Type::Char(char::from_u32((v1 as u32) >> v2).unwrap())
+                Type::Char(to_char(checked((v1 as u32).checked_shr(shift(v2)?))?)?)
+            }
+            _ => {
+                return Err(Trap::InvalidOperation(
+                    "ShiftRight only works on an int number type and an UInt.",
+                ))
             }
-            _ => panic!("ShiftRight only works on an int number type and an UInt."),
         },
         OpPrim2::SameType => match (t1, t2) {
             (Type::None, Type::None) => Type::Boolean(true),
@@ -720,7 +847,46 @@ pub fn solve_2(op: &OpPrim2, t1: Type, t2: Type) -> Type {
             (Type::Boolean(_), Type::Boolean(_)) => Type::Boolean(true),
             (Type::Address(_), Type::Address(_)) => Type::Boolean(true),
             (Type::Pointer(_), Type::Pointer(_)) => Type::Boolean(true),
+            (Type::Array(_), Type::Array(_)) => Type::Boolean(true),
+            (Type::Struct(i1, _), Type::Struct(i2, _)) => Type::Boolean(i1 == i2),
+            (Type::Tagged(..), Type::Tagged(..)) => Type::Boolean(true),
+            (Type::Map(_), Type::Map(_)) => Type::Boolean(true),
             _ => Type::Boolean(false),
         },
-    }
+    })
+}
+
+#[inline]
+fn to_char(num: u32) -> Result<char, Trap> {
+    char::from_u32(num).ok_or(Trap::InvalidChar(num))
+}
+
+#[inline]
+fn checked<T>(v: Option<T>) -> Result<T, Trap> {
+    v.ok_or(Trap::Overflow)
+}
+
+#[inline]
+fn shift(by: usize) -> Result<u32, Trap> {
+    u32::try_from(by).map_err(|_| Trap::Overflow)
+}
+
+#[inline]
+fn is_zero(t: &Type) -> bool {
+    matches!(
+        t,
+        Type::Int8(0)
+            | Type::Int16(0)
+            | Type::Int32(0)
+            | Type::Int(0)
+            | Type::Int64(0)
+            | Type::Int128(0)
+            | Type::UInt8(0)
+            | Type::UInt16(0)
+            | Type::UInt32(0)
+            | Type::UInt(0)
+            | Type::UInt64(0)
+            | Type::UInt128(0)
+            | Type::Char('\0')
+    )
 }
@@ -0,0 +1,353 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use allot_lib::Instruction;
+
+use crate::{green, AllotRuntime, Trap};
+
+/// Collects profiles from a runtime and the threads it creates. A thread adds
+/// its profile when its runtime is dropped.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    shared: Mutex<Shared>,
+}
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything collected so far, including what runtimes that have not been
+    /// dropped yet (like threads that were never joined) have run.
+    pub fn profile(&self) -> Profile {
+        let shared = self.shared.lock().unwrap();
+        let mut profile = shared.profile.clone();
+        for counts in &shared.running {
+            profile.merge(counts.lock().unwrap().profile());
+        }
+        profile
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    /// The profiles of dropped runtimes.
+    profile: Profile,
+    running: Vec<Arc<Mutex<Counts>>>,
+}
+
+/// Exact counts of what a program ran.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    /// Times each instruction address was run.
+    pub counts: BTreeMap<usize, u64>,
+    /// Library functions called with Instruction::Call.
+    pub calls: BTreeMap<String, CallStats>,
+    /// Keyed by thread id, 0 is the main runtime.
+    pub threads: BTreeMap<usize, ThreadStats>,
+    /// Times a backward Jmp was taken, keyed by (target, jmp address).
+    pub loops: BTreeMap<(usize, usize), u64>,
+    /// Instructions run in each call stack.
+    pub stacks: BTreeMap<Vec<Frame>, u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CallStats {
+    pub calls: u64,
+    pub time: Duration,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ThreadStats {
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+/// A frame of a call stack.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Frame {
+    Thread(usize),
+    /// The address a thread started at, or that CallAddr jumped to.
+    Address(usize),
+    /// A library function.
+    Call(String),
+}
+
+impl Profile {
+    fn merge(&mut self, other: Profile) {
+        for (address, count) in other.counts {
+            *self.counts.entry(address).or_default() += count;
+        }
+        for (function, stats) in other.calls {
+            let entry = self.calls.entry(function).or_default();
+            entry.calls += stats.calls;
+            entry.time += stats.time;
+        }
+        for (thread, stats) in other.threads {
+            let entry = self.threads.entry(thread).or_default();
+            entry.instructions += stats.instructions;
+            entry.time += stats.time;
+        }
+        for (l, count) in other.loops {
+            *self.loops.entry(l).or_default() += count;
+        }
+        for (stack, count) in other.stacks {
+            *self.stacks.entry(stack).or_default() += count;
+        }
+    }
+
+    /// A human readable report. Name is used to show addresses, like with
+    /// labels.
+    pub fn summary(&self, top: usize, name: impl Fn(usize) -> String) -> String {
+        let mut s = String::new();
+        let total: u64 = self.threads.values().map(|t| t.instructions).sum();
+        writeln!(s, "instructions: {total}").unwrap();
+
+        writeln!(s, "\nthreads:").unwrap();
+        for (thread, stats) in &self.threads {
+            writeln!(
+                s,
+                "    {thread:>4}  {:>12} instructions  {:>10.3?}",
+                stats.instructions, stats.time
+            )
+            .unwrap();
+        }
+
+        let mut hottest: Vec<(&usize, &u64)> = self.counts.iter().collect();
+        hottest.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        writeln!(s, "\nhottest instructions:").unwrap();
+        for (address, count) in hottest.into_iter().take(top) {
+            writeln!(s, "    {:>12}  {}", count, name(*address)).unwrap();
+        }
+
+        let mut loops: Vec<(&(usize, usize), &u64)> = self.loops.iter().collect();
+        loops.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        writeln!(s, "\nhottest loops:").unwrap();
+        for ((target, jmp), count) in loops.into_iter().take(top) {
+            // Counts every run of the addresses the loop spans, even ones
+            // outside of it, and leaves out functions it calls.
+            let body: u64 = self.counts.range(*target..=*jmp).map(|(_, c)| c).sum();
+            writeln!(
+                s,
+                "    {:>12} iterations  ~{:>11} instructions  {} <- {}",
+                count,
+                body,
+                name(*target),
+                name(*jmp)
+            )
+            .unwrap();
+        }
+
+        let mut calls: Vec<(&String, &CallStats)> = self.calls.iter().collect();
+        calls.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(b.0)));
+        writeln!(s, "\nlibrary functions:").unwrap();
+        for (function, stats) in calls {
+            writeln!(
+                s,
+                "    {:>12} calls  {:>10.3?}  {}",
+                stats.calls, stats.time, function
+            )
+            .unwrap();
+        }
+
+        s
+    }
+
+    /// Call stacks in the folded format used by flamegraph tools, weighted by
+    /// instructions run.
+    pub fn folded(&self, name: impl Fn(usize) -> String) -> String {
+        let mut s = String::new();
+        for (stack, count) in &self.stacks {
+            let frames: Vec<String> = stack
+                .iter()
+                .map(|frame| match frame {
+                    Frame::Thread(t) => format!("thread {t}"),
+                    Frame::Address(a) => name(*a),
+                    Frame::Call(f) => f.clone(),
+                })
+                .collect();
+            writeln!(s, "{} {}", frames.join(";"), count).unwrap();
+        }
+        s
+    }
+}
+
+/// The profile of one runtime, kept apart from the shared profiler so
+/// threads do not fight over a lock for every instruction.
+#[derive(Debug)]
+pub(crate) struct ThreadProfiler {
+    shared: Arc<Profiler>,
+    counts: Arc<Mutex<Counts>>,
+}
+impl ThreadProfiler {
+    /// Profiles a runtime with len instructions.
+    pub fn new(shared: Arc<Profiler>, thread: usize, start: usize, len: usize) -> Self {
+        let counts = Arc::new(Mutex::new(Counts::new(thread, start, len)));
+        shared.shared.lock().unwrap().running.push(counts.clone());
+        Self { shared, counts }
+    }
+
+    pub fn shared(&self) -> Arc<Profiler> {
+        self.shared.clone()
+    }
+}
+impl Drop for ThreadProfiler {
+    /// Adds this profile to the shared profiler.
+    fn drop(&mut self) {
+        let mut shared = self.shared.shared.lock().unwrap();
+        shared.running.retain(|c| !Arc::ptr_eq(c, &self.counts));
+        let profile = self.counts.lock().unwrap().profile();
+        shared.profile.merge(profile);
+    }
+}
+
+/// What one runtime has run.
+#[derive(Debug)]
+struct Counts {
+    thread: usize,
+    started: Instant,
+    counts: Vec<u64>,
+    calls: HashMap<String, CallStats>,
+    loops: HashMap<(usize, usize), u64>,
+    /// Call stacks seen so far, the index is the stack id.
+    stacks: Vec<Vec<Frame>>,
+    stack_ids: HashMap<Vec<Frame>, usize>,
+    /// Instructions run in each stack id.
+    stack_counts: Vec<u64>,
+    /// Library function calls in each stack id.
+    stack_calls: HashMap<(usize, String), u64>,
+    stack: usize,
+}
+impl Counts {
+    fn new(thread: usize, start: usize, len: usize) -> Self {
+        let mut counts = Self {
+            thread,
+            started: Instant::now(),
+            counts: vec![0; len],
+            calls: HashMap::new(),
+            loops: HashMap::new(),
+            stacks: Vec::new(),
+            stack_ids: HashMap::new(),
+            stack_counts: Vec::new(),
+            stack_calls: HashMap::new(),
+            stack: 0,
+        };
+        counts.stack = counts.intern(vec![Frame::Thread(thread), Frame::Address(start)]);
+        counts
+    }
+
+    fn intern(&mut self, stack: Vec<Frame>) -> usize {
+        if let Some(id) = self.stack_ids.get(&stack) {
+            return *id;
+        }
+        let id = self.stacks.len();
+        self.stacks.push(stack.clone());
+        self.stack_ids.insert(stack, id);
+        self.stack_counts.push(0);
+        id
+    }
+
+    fn enter(&mut self, address: usize) {
+        let mut stack = self.stacks[self.stack].clone();
+        stack.push(Frame::Address(address));
+        self.stack = self.intern(stack);
+    }
+
+    fn leave(&mut self) {
+        let mut stack = self.stacks[self.stack].clone();
+        // The thread and its start address are never left.
+        if stack.len() > 2 {
+            stack.pop();
+            self.stack = self.intern(stack);
+        }
+    }
+
+    /// What has been run so far.
+    fn profile(&self) -> Profile {
+        let mut profile = Profile::default();
+
+        let instructions = self.counts.iter().sum();
+        for (address, count) in self.counts.iter().enumerate() {
+            if *count > 0 {
+                profile.counts.insert(address, *count);
+            }
+        }
+        profile.calls = self.calls.clone().into_iter().collect();
+        profile.loops = self.loops.clone().into_iter().collect();
+        profile.threads.insert(
+            self.thread,
+            ThreadStats {
+                instructions,
+                time: self.started.elapsed(),
+            },
+        );
+
+        for (id, count) in self.stack_counts.iter().enumerate() {
+            if *count > 0 {
+                profile.stacks.insert(self.stacks[id].clone(), *count);
+            }
+        }
+        for ((id, function), count) in &self.stack_calls {
+            let mut stack = self.stacks[*id].clone();
+            stack.push(Frame::Call(function.clone()));
+            profile.stacks.insert(stack, *count);
+        }
+
+        profile
+    }
+}
+
+impl AllotRuntime {
+    /// Runs the current instruction and counts it.
+    pub(crate) fn profiled_step(&mut self) -> Result<Option<i32>, Trap> {
+        let current = self.current;
+        if current >= self.instructions.len() {
+            // Traps with InvalidAddress, there is no instruction to count.
+            return self.hooked_step();
+        }
+        let call = match self.instructions.get(current) {
+            Some(Instruction::Call(function)) => Some(function.clone()),
+            _ => None,
+        };
+        let started = call.as_ref().map(|_| Instant::now());
+
+        let result = self.hooked_step();
+        // Only the try of a blocked green thread that finishes is counted.
+        if green::blocked() {
+            return result;
+        }
+
+        let mut profiler = match self.profiler.as_ref() {
+            None => return result,
+            Some(p) => p.counts.lock().unwrap(),
+        };
+        profiler.counts[current] += 1;
+        let stack = profiler.stack;
+
+        match call {
+            Some(function) => {
+                let stats = profiler.calls.entry(function.clone()).or_default();
+                stats.calls += 1;
+                stats.time += started.map(|s| s.elapsed()).unwrap_or_default();
+                *profiler.stack_calls.entry((stack, function)).or_default() += 1;
+            }
+            None => profiler.stack_counts[stack] += 1,
+        }
+
+        if result.is_ok() {
+            match self.instructions.get(current) {
+                Some(Instruction::Jmp(..)) if self.current <= current => {
+                    *profiler.loops.entry((self.current, current)).or_default() += 1;
+                }
+                Some(Instruction::CallAddr(_)) => profiler.enter(self.current),
+                Some(Instruction::Ret) => profiler.leave(),
+                _ => {}
+            }
+        }
+
+        result
+    }
+}
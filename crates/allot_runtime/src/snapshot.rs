@@ -0,0 +1,278 @@
+use std::{error::Error, fmt, sync::Arc};
+
+use allot_bytecode::{
+    read_len, read_struct, read_type, read_usize, write_struct, write_type, Buffer, DecodeError,
+};
+use allot_lib::{Instruction, StructDef, Type};
+
+use crate::{
+    memory::{Heap, Registers, SlotSnapshot, StackFrame, MAX_THREAD, REGISTERS},
+    AllotRuntime, Trap,
+};
+
+/// Layout: SNAPSHOT_VERSION, instruction hash, structs, thread, current,
+/// registers, stack frames with whether they are isolated, then the heap slots
+/// of the thread and of the shared region with their generation and value if
+/// they are live. Lists start with their length and values and structs use the
+/// bytecode encoding.
+pub const SNAPSHOT_VERSION: u64 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnapshotError {
+    /// A heap entry is not a value, like the handle of a thread.
+    Unserializable {
+        pointer: usize,
+        type_name: &'static str,
+    },
+    /// The snapshot was taken while running other instructions or structs.
+    InstructionMismatch {
+        expected: u64,
+        found: u64,
+    },
+    VersionMismatch(u64),
+    Decode(DecodeError),
+    /// Bytes were left after the snapshot.
+    TrailingBytes(usize),
+    /// A heap slot is past the last slot or generation a heap can have.
+    InvalidSlot(usize),
+    /// A value points into the heap of the thread or the shared region, at a
+    /// slot or generation that is not in the snapshot.
+    InvalidPointer(usize),
+    /// The thread id is past the last thread that can own a heap.
+    InvalidThread(usize),
+    /// A record does not match its struct.
+    InvalidRecord(Trap),
+    /// There are more registers than a runtime has.
+    TooManyRegisters(usize),
+    /// There is not even the root stack frame.
+    NoStackFrames,
+}
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Unserializable { pointer, type_name } => write!(
+                f,
+                "heap entry {pointer:X?} holds a {type_name}, which cannot be serialized"
+            ),
+            SnapshotError::InstructionMismatch { expected, found } => write!(
+                f,
+                "snapshot was taken with instructions {found:016x}, not {expected:016x}"
+            ),
+            SnapshotError::VersionMismatch(v) => write!(
+                f,
+                "snapshot version {v} is not equal to current snapshot version \
+                 {SNAPSHOT_VERSION}"
+            ),
+            SnapshotError::Decode(e) => write!(f, "{e}"),
+            SnapshotError::TrailingBytes(n) => {
+                write!(f, "{n} bytes were left after the snapshot")
+            }
+            SnapshotError::InvalidSlot(slot) => write!(f, "heap slot {slot} cannot exist"),
+            SnapshotError::InvalidPointer(pointer) => write!(
+                f,
+                "pointer {pointer:X?} points to a heap slot that is not in the snapshot"
+            ),
+            SnapshotError::InvalidThread(thread) => {
+                write!(f, "thread {thread} cannot own a heap")
+            }
+            SnapshotError::InvalidRecord(trap) => write!(f, "invalid record: {trap}"),
+            SnapshotError::TooManyRegisters(n) => {
+                write!(
+                    f,
+                    "{n} registers are more than the {REGISTERS} a runtime has"
+                )
+            }
+            SnapshotError::NoStackFrames => write!(f, "there are no stack frames"),
+        }
+    }
+}
+impl Error for SnapshotError {}
+impl From<DecodeError> for SnapshotError {
+    fn from(e: DecodeError) -> Self {
+        SnapshotError::Decode(e)
+    }
+}
+
+/// A hash of the instructions and structs that stays the same between
+/// processes and versions of Rust. (FNV-1a of the bytecode)
+pub fn instruction_hash(instructions: &[Instruction], structs: &[StructDef]) -> u64 {
+    allot_bytecode::gen_with_structs(instructions, structs)
+        .iter()
+        .fold(0xcbf29ce484222325, |hash, b| {
+            (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+impl AllotRuntime {
+    /// Serializes where the program is, its structs, registers, stack frames,
+    /// and heap. The library, io, limits, and hooks are not part of a snapshot.
+    pub fn snapshot(&self) -> Result<Vec<u8>, SnapshotError> {
+        let unserializable =
+            |(pointer, type_name)| SnapshotError::Unserializable { pointer, type_name };
+        let local = self.heap.local.snapshot().map_err(unserializable)?;
+        let shared = self
+            .heap
+            .shared
+            .lock()
+            .unwrap()
+            .snapshot()
+            .map_err(unserializable)?;
+
+        let mut buffer = Buffer::new();
+        buffer.write_u64(SNAPSHOT_VERSION);
+        buffer.write_u64(instruction_hash(&self.instructions, &self.structs));
+        buffer.write_u64(self.structs.len() as u64);
+        for s in self.structs.iter() {
+            write_struct(&mut buffer, s);
+        }
+        buffer.write_u64(self.thread as u64);
+        buffer.write_u64(self.current as u64);
+
+        let registers = self.registers.snapshot();
+        buffer.write_u64(registers.len() as u64);
+        for t in &registers {
+            write_type(&mut buffer, t);
+        }
+
+        buffer.write_u64(self.stack_frames.len() as u64);
+        for frame in &self.stack_frames {
+            buffer.write_bool(frame.is_isolated());
+            buffer.write_u64(frame.len() as u64);
+            for t in frame.values() {
+                write_type(&mut buffer, t);
+            }
+        }
+
+        write_slots(&mut buffer, &local);
+        write_slots(&mut buffer, &shared);
+
+        Ok(buffer.into_inner())
+    }
+
+    /// Creates a runtime from a snapshot of a runtime running the same
+    /// instructions, with the structs it had. It has the default library and
+    /// io, and no limits.
+    pub fn restore(
+        instructions: Arc<Vec<Instruction>>,
+        snapshot: Vec<u8>,
+    ) -> Result<Self, SnapshotError> {
+        let mut buffer = Buffer::with(snapshot);
+
+        let version = buffer.read_u64()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::VersionMismatch(version));
+        }
+        let found = buffer.read_u64()?;
+        let len = read_len(&mut buffer, "structs")?;
+        let structs = (0..len)
+            .map(|_| read_struct(&mut buffer))
+            .collect::<Result<Vec<_>, _>>()?;
+        let expected = instruction_hash(&instructions, &structs);
+        if expected != found {
+            return Err(SnapshotError::InstructionMismatch { expected, found });
+        }
+
+        let mut runtime = AllotRuntime::new_arc(instructions);
+        runtime.set_structs(structs);
+        runtime.thread = read_usize(&mut buffer)?;
+        if runtime.thread > MAX_THREAD {
+            return Err(SnapshotError::InvalidThread(runtime.thread));
+        }
+        runtime.current = read_usize(&mut buffer)?;
+
+        let len = read_len(&mut buffer, "registers")?;
+        if len > REGISTERS {
+            return Err(SnapshotError::TooManyRegisters(len));
+        }
+        let registers = (0..len)
+            .map(|_| read_type(&mut buffer))
+            .collect::<Result<Vec<_>, _>>()?;
+        runtime.registers = Registers::restore(registers);
+
+        let len = read_len(&mut buffer, "stack frames")?;
+        if len == 0 {
+            return Err(SnapshotError::NoStackFrames);
+        }
+        runtime.stack_frames = Vec::with_capacity(len);
+        for _ in 0..len {
+            let isolated = buffer.read_bool()?;
+            let values = read_len(&mut buffer, "stack frame")?;
+            let values = (0..values)
+                .map(|_| read_type(&mut buffer))
+                .collect::<Result<Vec<_>, _>>()?;
+            runtime
+                .stack_frames
+                .push(StackFrame::with_values(values, isolated));
+        }
+
+        let local = read_slots(&mut buffer)?;
+        let shared = read_slots(&mut buffer)?;
+        if !buffer.is_empty() {
+            return Err(SnapshotError::TrailingBytes(buffer.remaining()));
+        }
+        let local = Heap::restore(runtime.thread, local).map_err(SnapshotError::InvalidSlot)?;
+        let shared = Heap::restore_shared(shared).map_err(SnapshotError::InvalidSlot)?;
+
+        // Pointers into other threads are left for the instructions to catch,
+        // like they would be in a running program. Records are checked like
+        // the ones written into instructions.
+        let registers = runtime.registers.snapshot();
+        registers
+            .iter()
+            .chain(runtime.stack_frames.iter().flat_map(|f| f.values()))
+            .chain(local.values())
+            .chain(shared.values())
+            .try_for_each(|t| {
+                check_pointers(t, &local, &shared)?;
+                runtime
+                    .check_records(t)
+                    .map_err(SnapshotError::InvalidRecord)
+            })?;
+        runtime.heap.restore(local, shared);
+
+        Ok(runtime)
+    }
+}
+
+fn check_pointers(t: &Type, local: &Heap, shared: &Heap) -> Result<(), SnapshotError> {
+    match t {
+        Type::Pointer(p)
+            if (local.owns(*p) && !local.made(*p)) || (shared.owns(*p) && !shared.made(*p)) =>
+        {
+            Err(SnapshotError::InvalidPointer(*p))
+        }
+        Type::Array(values) | Type::Struct(_, values) => values
+            .iter()
+            .try_for_each(|t| check_pointers(t, local, shared)),
+        Type::Map(map) => map
+            .values()
+            .try_for_each(|t| check_pointers(t, local, shared)),
+        Type::Tagged(_, t) => check_pointers(t, local, shared),
+        _ => Ok(()),
+    }
+}
+
+fn write_slots(buffer: &mut Buffer, slots: &[SlotSnapshot]) {
+    buffer.write_u64(slots.len() as u64);
+    for (generation, t) in slots {
+        buffer.write_u64(*generation as u64);
+        buffer.write_bool(t.is_some());
+        if let Some(t) = t {
+            write_type(buffer, t);
+        }
+    }
+}
+
+fn read_slots(buffer: &mut Buffer) -> Result<Vec<SlotSnapshot>, DecodeError> {
+    let len = read_len(buffer, "heap slots")?;
+    let mut slots = Vec::with_capacity(len);
+    for _ in 0..len {
+        let generation = read_usize(buffer)?;
+        let t = match buffer.read_bool()? {
+            true => Some(read_type(buffer)?),
+            false => None,
+        };
+        slots.push((generation, t));
+    }
+    Ok(slots)
+}
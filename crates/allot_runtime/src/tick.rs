@@ -1,219 +1,431 @@
-use std::thread::JoinHandle;
+use std::{io::Write, sync::mpsc};
 
 #[doc(hidden)]
 pub use allot_lib::*;
-use allot_lib::{Instruction, OpPrim2, Register, Type};
 
-use crate::{library, memory::StackFrame, operations, AllotRuntime};
+use crate::{
+    green::{self, GreenHandle},
+    library, limits,
+    memory::StackFrame,
+    operations, AllotRuntime, ExitPolicy, RuntimeError, Trap,
+};
+
+/// What an OS thread sends back to ThreadJoin once it ends.
+type ThreadReturn = (Result<i32, Box<RuntimeError>>, StackFrame);
 
 impl AllotRuntime {
-    pub fn tick(&mut self) -> Option<i32> {
+    /// Runs the current instruction. Returns the exit code once the program
+    /// exits, or the trap that stopped it.
+    pub fn tick(&mut self) -> Result<Option<i32>, Box<RuntimeError>> {
+        let result = match self.profiler {
+            None => self.hooked_step(),
+            Some(_) => self.profiled_step(),
+        };
+        if green::blocked() {
+            // The instruction is tried again, and only uses fuel once it runs.
+            self.budget.refund();
+        }
+
+        result.map_err(|trap| self.error(trap))
+    }
+
+    /// The trap, with where the program is.
+    pub(crate) fn error(&self, trap: Trap) -> Box<RuntimeError> {
+        Box::new(RuntimeError {
+            trap,
+            current: self.current,
+            instruction: self.instructions.get(self.current).cloned(),
+            registers: self.registers.snapshot(),
+        })
+    }
+
+    #[inline]
+    pub(crate) fn hooked_step(&mut self) -> Result<Option<i32>, Trap> {
+        match &self.tracer {
+            None => self.step(),
+            Some(tracer) => self.traced_step(tracer.clone().as_ref()),
+        }
+    }
+
+    pub(crate) fn step(&mut self) -> Result<Option<i32>, Trap> {
         let instruction = match self.instructions.get(self.current) {
-            None => panic!("There is no instruction at {}", self.current),
+            None => return Err(Trap::InvalidAddress(self.current)),
             Some(i) => i,
         };
         let mut next = self.current + 1;
+        self.budget.tick()?;
 
         match instruction {
             Instruction::Nop => {}
-            Instruction::Op(op, regs) => operations::solve(op, &mut self.registers, regs),
+            Instruction::Op(op, regs) => {
+                if let Operation::Prim2(OpPrim2::Add) = op {
+                    self.check_concat(regs)?;
+                }
+                operations::solve(op, &mut self.registers, regs)?
+            }
             Instruction::Mov(reg, t) => {
+                self.check_length(t)?;
+                if let Some(trap) = self.invalid_records.get(&self.current) {
+                    return Err(trap.clone());
+                }
                 let val = match t {
-                    Type::Register(reg) => self.registers.take(*reg),
+                    Type::Register(reg) => self.registers.take(*reg)?,
                     _ => t.clone(),
                 };
 
-                self.registers.insert(*reg, val);
+                self.registers.insert(*reg, val)?;
             }
             Instruction::Cpy(reg1, reg2) => {
-                let val = self.registers.get(*reg2);
-                self.registers.insert(*reg1, val.clone())
+                let val = self.registers.get(*reg2)?;
+                self.registers.insert(*reg1, val.clone())?
             }
             Instruction::Cast(reg, raw) => {
-                let val = self.registers.get(*reg);
-                let casted = operations::cast(val, *raw);
-                self.registers.insert(*reg, casted);
+                let val = self.registers.get(*reg)?;
+                let casted = operations::cast(val, *raw)?;
+                self.check_length(&casted)?;
+                self.registers.insert(*reg, casted)?;
+            }
+            Instruction::Lea(reg, address) => {
+                self.registers.insert(*reg, Type::Address(*address))?
             }
-            Instruction::Lea(reg, address) => self.registers.insert(*reg, Type::Address(*address)),
             Instruction::Jmp(opt_reg, t) => {
-                let address = AllotRuntime::get_address(t, &mut self.registers);
+                let address = AllotRuntime::get_address(t, &mut self.registers)?;
 
                 let jmp = match opt_reg {
                     None => true,
-                    Some(reg) => {
-                        let val = self.registers.get_mut(*reg);
-                        if let Type::Boolean(i) = val {
-                            *i
+                    Some(reg) => match self.registers.get(*reg)? {
+                        Type::Boolean(i) => *i,
+                        found => {
+                            return Err(Trap::UnexpectedType {
+                                expected: "Boolean",
+                                found: found.clone(),
+                            })
                         }
-                        else {
-                            panic!("Jmp requires a Boolean Type.");
-                        }
-                    }
+                    },
                 };
 
                 if jmp {
                     next = address;
                 }
             }
+            Instruction::CallAddr(t) => {
+                let address = AllotRuntime::get_address(t, &mut self.registers)?;
+                let frame = self.stack_frames.last_mut().ok_or(Trap::NoStackFrame)?;
+                self.budget.push_value(frame)?;
+                frame.push(Type::Address(next));
+
+                next = address;
+            }
             Instruction::Ret => {
                 let val = match self.stack_frames.last_mut() {
-                    None => panic!("No stack frames."),
-                    Some(frame) => frame.pop(),
+                    None => return Err(Trap::NoStackFrame),
+                    Some(frame) => frame.pop()?,
                 };
 
                 match val {
                     Type::Address(address) => next = address,
-                    _ => panic!("Ret popped an non-address type from the stack."),
+                    found => {
+                        return Err(Trap::UnexpectedType {
+                            expected: "Address",
+                            found,
+                        })
+                    }
                 }
             }
             Instruction::Call(function) => {
-                let stack_frame = self
-                    .stack_frames
-                    .last_mut()
-                    .expect("There was no stack frame to take.");
+                let stack_frame = self.stack_frames.last_mut().ok_or(Trap::NoStackFrame)?;
 
-                let ret = library::call(
+                self.budget.enter();
+                library::enter(self.thread, &self.alive);
+                let ret = self.library.call(
                     function.as_str(),
-                    (
-                        self.registers.get(Register::R5),
-                        self.registers.get(Register::R6),
-                        self.registers.get(Register::R7),
-                        self.registers.get(Register::R8),
-                        self.registers.get(Register::R9),
-                    ),
+                    self.registers.library_args()?,
                     stack_frame,
                     &mut self.heap,
+                    &self.io,
                 );
+                let ret = match ret {
+                    Err(Trap::Exit(code)) => return self.exit(code),
+                    ret => ret?,
+                };
+                for t in [&ret.0, &ret.1, &ret.2, &ret.3, &ret.4]
+                    .into_iter()
+                    .flatten()
+                {
+                    self.check_length(t)?;
+                }
 
                 if let Some(t) = ret.0 {
-                    self.registers.insert(Register::R5, t)
+                    self.registers.insert(Register::R5, t)?
                 }
                 if let Some(t) = ret.1 {
-                    self.registers.insert(Register::R6, t)
+                    self.registers.insert(Register::R6, t)?
                 }
                 if let Some(t) = ret.2 {
-                    self.registers.insert(Register::R7, t)
+                    self.registers.insert(Register::R7, t)?
                 }
                 if let Some(t) = ret.3 {
-                    self.registers.insert(Register::R8, t)
+                    self.registers.insert(Register::R8, t)?
                 }
                 if let Some(t) = ret.4 {
-                    self.registers.insert(Register::R9, t)
+                    self.registers.insert(Register::R9, t)?
                 }
             }
             Instruction::Exit(t) => {
-                let code = AllotRuntime::get_int32(t, &mut self.registers);
-                return Some(code);
+                let code = AllotRuntime::get_int32(t, &mut self.registers)?;
+                return Ok(Some(code));
             }
             Instruction::Push(reg) => {
-                let val = self.registers.take(*reg);
-                match self.stack_frames.last_mut() {
-                    None => panic!("No stack frames."),
-                    Some(frame) => {
-                        frame.push(val);
-                    }
-                }
+                let frame = self.stack_frames.last_mut().ok_or(Trap::NoStackFrame)?;
+                self.budget.push_value(frame)?;
+                frame.push(self.registers.take(*reg)?);
             }
             Instruction::PushCpy(reg) => {
-                let val = self.registers.get(*reg);
-                match self.stack_frames.last_mut() {
-                    None => panic!("No stack frames."),
-                    Some(frame) => {
-                        frame.push(val.clone());
-                    }
-                }
+                let frame = self.stack_frames.last_mut().ok_or(Trap::NoStackFrame)?;
+                self.budget.push_value(frame)?;
+                frame.push(self.registers.get(*reg)?.clone());
             }
             Instruction::Pop(opt_reg) => {
                 let val = match self.stack_frames.last_mut() {
-                    None => panic!("No stack frames."),
-                    Some(frame) => frame.pop(),
+                    None => return Err(Trap::NoStackFrame),
+                    Some(frame) => frame.pop()?,
                 };
 
                 match opt_reg {
                     None => {}
-                    Some(reg) => self.registers.insert(*reg, val),
+                    Some(reg) => self.registers.insert(*reg, val)?,
                 }
             }
             Instruction::PopMany(t) => {
-                let amount = AllotRuntime::get_uint(t, &mut self.registers);
+                let amount = AllotRuntime::get_uint(t, &mut self.registers)?;
 
                 match self.stack_frames.last_mut() {
-                    None => panic!("No stack frames."),
+                    None => return Err(Trap::NoStackFrame),
                     Some(frame) => {
                         for _ in 0..amount {
-                            frame.pop();
+                            frame.pop()?;
                         }
                     }
                 }
             }
             Instruction::StackCpy(reg, t) => {
-                let amount = AllotRuntime::get_uint(t, &mut self.registers);
+                let amount = AllotRuntime::get_uint(t, &mut self.registers)?;
 
                 match self.stack_frames.last_mut() {
-                    None => panic!("No stack frames."),
+                    None => return Err(Trap::NoStackFrame),
                     Some(frame) => {
-                        let t = frame.clone_offset(amount);
-                        self.registers.insert(*reg, t);
+                        let t = frame.clone_offset(amount)?;
+                        self.registers.insert(*reg, t)?;
                     }
                 }
             }
-            Instruction::PushFrame(b) => self.stack_frames.push(StackFrame::new(*b)),
+            Instruction::PushFrame(b) => {
+                self.budget.push_frame(&self.stack_frames)?;
+                self.stack_frames.push(StackFrame::new(*b));
+            }
             Instruction::PopFrame => {
-                let val = self.stack_frames.pop();
-                if val.is_none() || self.stack_frames.is_empty() {
-                    panic!("Could not pop stack frame.");
+                if self.stack_frames.len() <= 1 {
+                    return Err(Trap::RootStackFrame);
+                }
+                self.stack_frames.pop();
+            }
+            Instruction::TakeFrom => {
+                let (below, current) = AllotRuntime::frame_pair(&mut self.stack_frames)?;
+                self.budget.push_value(current)?;
+                current.push(below.pop()?);
+            }
+            Instruction::GiveTo => {
+                let (below, current) = AllotRuntime::frame_pair(&mut self.stack_frames)?;
+                self.budget.push_value(below)?;
+                below.push(current.pop()?);
+            }
+            Instruction::ArrPush(arr, reg) => {
+                let len = AllotRuntime::get_array(*arr, &mut self.registers)?.len();
+                self.budget.collection(len + 1)?;
+                let val = AllotRuntime::take_element(*reg, *arr, &mut self.registers)?;
+                AllotRuntime::get_array(*arr, &mut self.registers)?.push(val);
+            }
+            Instruction::ArrPop(reg, arr) => {
+                self.registers.get(*reg)?;
+                let val = AllotRuntime::get_array(*arr, &mut self.registers)?
+                    .pop()
+                    .ok_or(Trap::ArrayEmpty)?;
+                self.registers.insert(*reg, val)?;
+            }
+            Instruction::ArrGet(reg, arr, t) => {
+                let index = AllotRuntime::get_uint(t, &mut self.registers)?;
+                let array = AllotRuntime::get_array(*arr, &mut self.registers)?;
+                let val = array.get(index).cloned().ok_or(Trap::IndexOutOfBounds {
+                    index,
+                    len: array.len(),
+                })?;
+                self.registers.insert(*reg, val)?;
+            }
+            Instruction::ArrSet(arr, t, reg) => {
+                let index = AllotRuntime::get_uint(t, &mut self.registers)?;
+                let len = AllotRuntime::get_array(*arr, &mut self.registers)?.len();
+                if index >= len {
+                    return Err(Trap::IndexOutOfBounds { index, len });
+                }
+                let val = AllotRuntime::take_element(*reg, *arr, &mut self.registers)?;
+                AllotRuntime::get_array(*arr, &mut self.registers)?[index] = val;
+            }
+            Instruction::ArrLen(reg, arr) => {
+                let len = AllotRuntime::get_array(*arr, &mut self.registers)?.len();
+                self.registers.insert(*reg, Type::UInt(len))?;
+            }
+            Instruction::ArrSlice(arr, t1, t2) => {
+                let start = AllotRuntime::get_uint(t1, &mut self.registers)?;
+                let end = AllotRuntime::get_uint(t2, &mut self.registers)?;
+                let array = AllotRuntime::get_array(*arr, &mut self.registers)?;
+                let len = array.len();
+                if end > len {
+                    return Err(Trap::IndexOutOfBounds { index: end, len });
+                }
+                if start > end {
+                    return Err(Trap::InvalidOperation(
+                        "ArrSlice needs a start that is not after its end.",
+                    ));
+                }
+                array.truncate(end);
+                array.drain(..start);
+            }
+            Instruction::StructNew(reg, id) => {
+                let structs = self.get_struct(*id)?;
+                let def = &structs[*id];
+                AllotRuntime::check_fields(
+                    def,
+                    AllotRuntime::get_array(*reg, &mut self.registers)?,
+                )?;
+                let fields = std::mem::take(AllotRuntime::get_array(*reg, &mut self.registers)?);
+                self.registers.insert(*reg, Type::Struct(*id, fields))?;
+            }
+            Instruction::FieldGet(reg, rec, id, field) => {
+                let structs = self.get_struct(*id)?;
+                let def = &structs[*id];
+                let fields = AllotRuntime::get_record(*rec, *id, def, &mut self.registers)?;
+                let val = fields.get(*field).cloned().ok_or(Trap::UnknownField {
+                    name: def.name.clone(),
+                    field: *field,
+                })?;
+                self.registers.insert(*reg, val)?;
+            }
+            Instruction::FieldSet(rec, id, field, reg) => {
+                let structs = self.get_struct(*id)?;
+                let def = &structs[*id];
+                AllotRuntime::check_field(def, *field, self.registers.get(*reg)?)?;
+                AllotRuntime::get_record(*rec, *id, def, &mut self.registers)?;
+                let val = AllotRuntime::take_element(*reg, *rec, &mut self.registers)?;
+                let fields = AllotRuntime::get_record(*rec, *id, def, &mut self.registers)?;
+                *fields.get_mut(*field).ok_or(Trap::UnknownField {
+                    name: def.name.clone(),
+                    field: *field,
+                })? = val;
+            }
+            Instruction::Tag(reg, tag) => {
+                let val = self.registers.take(*reg)?;
+                self.registers
+                    .insert(*reg, Type::Tagged(*tag, Box::new(val)))?;
+            }
+            Instruction::Match(reg, val, table) => {
+                // Checked before the payload is moved, so a trap leaves both
+                // registers as they were.
+                self.registers.get(*reg)?;
+                let tag = match self.registers.get(*val)? {
+                    Type::Tagged(tag, _) => *tag,
+                    found => {
+                        return Err(Trap::UnexpectedType {
+                            expected: "Tagged",
+                            found: found.clone(),
+                        })
+                    }
+                };
+                if let Type::Tagged(_, payload) = self.registers.take(*val)? {
+                    self.registers.insert(*reg, *payload)?;
+                }
+
+                if let Some(address) = table.get(tag) {
+                    next = *address;
                 }
             }
-            Instruction::TakeFrom => panic!("Not impl yet!"),
-            Instruction::GiveTo => panic!("Not impl yet!"),
             Instruction::ThreadCreate(t) => {
-                let address = AllotRuntime::get_address(t, &mut self.registers);
-                let sf = self.stack_frames.pop();
-                if sf.is_none() || self.stack_frames.is_empty() {
-                    panic!("Could not pop stack frame for thread.");
-                }
-                let sf = sf.unwrap();
-                let instructions = self.instructions.clone();
-                let heap = self.heap.clone();
-
-                let handle = std::thread::spawn(move || {
-                    let mut runtime = AllotRuntime::new_thread(instructions, sf, heap, address);
-                    (runtime.run(), runtime.take_stack_frame())
-                });
+                let address = AllotRuntime::get_address(t, &mut self.registers)?;
+                if self.stack_frames.len() <= 1 {
+                    return Err(Trap::RootStackFrame);
+                }
+                self.heap.reserve(1)?;
+                let id = self.budget.create_thread()?;
+                let sf = self.stack_frames.pop().ok_or(Trap::NoStackFrame)?;
+                let mut runtime = self.new_thread(id, sf, address);
 
-                let i = {
-                    let mut heap = self.heap.lock().unwrap();
-                    heap.push(handle)
+                let i = match green::is_running() {
+                    true => {
+                        green::spawn(runtime);
+                        self.heap.share(GreenHandle(id))?
+                    }
+                    false => {
+                        // A thread that panics drops the sender, so ThreadJoin can tell.
+                        let (sender, receiver) = mpsc::channel::<ThreadReturn>();
+                        // On the heap before the thread can count its own entries,
+                        // in the shared region so any thread can join it.
+                        let i = self.heap.share(receiver)?;
+                        std::thread::spawn(move || {
+                            let ret = (runtime.run(), runtime.take_stack_frame());
+                            // Whatever the runtime shares, like a tracer, is let
+                            // go of before the thread is joined.
+                            drop(runtime);
+                            let _ = sender.send(ret);
+                        });
+                        i
+                    }
                 };
-                self.registers.insert(Register::R5, i);
+                self.registers.insert(Register::R5, i)?;
             }
             Instruction::ThreadJoin(reg) => {
-                let pointer = match self.registers.get(*reg) {
-                    Type::Pointer(p) => p,
-                    _ => panic!("ThreadJoin did not receive a pointer type."),
+                let pointer = match self.registers.get(*reg)? {
+                    Type::Pointer(p) => *p,
+                    found => {
+                        return Err(Trap::UnexpectedType {
+                            expected: "Pointer",
+                            found: found.clone(),
+                        })
+                    }
                 };
 
-                let handle = {
-                    let mut heap = self.heap.lock().unwrap();
-                    heap.take::<JoinHandle<(i32, StackFrame)>>(*pointer)
-                };
+                self.budget.push_frame(&self.stack_frames)?;
 
-                let ret = handle.join().expect("Fatal error on thread join.");
-                self.registers.insert(Register::R5, Type::Int32(ret.0));
+                let green = self.heap.with(pointer, |heap| {
+                    heap.get::<GreenHandle>(pointer).map(|h| h.0)
+                });
+                let ret = match green {
+                    Ok(thread) => {
+                        let ret = green::join(thread)?;
+                        self.heap.with(pointer, |heap| heap.free(pointer))?;
+                        ret.ok_or(Trap::ThreadPanicked)?
+                    }
+                    Err(_) => {
+                        let receiver = self.heap.with(pointer, |heap| {
+                            heap.take::<mpsc::Receiver<ThreadReturn>>(pointer)
+                        })?;
+                        limits::recv(&receiver, self.budget.time_left(), Trap::ThreadPanicked)?
+                    }
+                };
+                let code = ret.0.map_err(Trap::Thread)?;
+                self.registers.insert(Register::R5, Type::Int32(code))?;
                 self.stack_frames.push(ret.1);
             }
             Instruction::Assert(reg, t) => {
                 // TODO: This is a kinda icky way to do this. Maybe check type first, then do
                 // Equal?
-                let val = self.registers.clone(*reg);
-                let result = operations::solve_2(&OpPrim2::Equal, val, t.clone());
+                let val = self.registers.clone(*reg)?;
+                let result = operations::solve_2(&OpPrim2::Equal, val, t.clone())?;
                 if let Type::Boolean(b) = result {
                     if !b {
-                        return Some(-1);
+                        return Ok(Some(-1));
                     }
                 }
                 else {
-                    return Some(-1);
+                    return Ok(Some(-1));
                 }
             }
 
@@ -223,7 +435,7 @@ impl AllotRuntime {
                 #[cfg(debug_assertions)]
                 {
                     println!("Register {:?}", &reg);
-                    let val = self.registers.get(*reg);
+                    let val = self.registers.get(*reg)?;
                     dbg!(val);
                 }
             }
@@ -248,6 +460,48 @@ impl AllotRuntime {
         }
 
         self.current = next;
-        None
+        Ok(None)
+    }
+}
+
+impl AllotRuntime {
+    /// Stops the program for the exit library function.
+    fn exit(&mut self, code: i32) -> Result<Option<i32>, Trap> {
+        if self.exit_policy == ExitPolicy::Process {
+            if let Ok(mut stdout) = self.io.stdout() {
+                let _ = stdout.flush();
+            }
+            if let Ok(mut stderr) = self.io.stderr() {
+                let _ = stderr.flush();
+            }
+            std::process::exit(code);
+        }
+
+        Ok(Some(code))
+    }
+}
+
+impl AllotRuntime {
+    /// Checks the length of a string, array, or map against its limit before
+    /// it is put in a register.
+    #[inline]
+    fn check_length(&self, t: &Type) -> Result<(), Trap> {
+        match t {
+            Type::String(s) => self.budget.string(s.len()),
+            Type::Array(v) => self.budget.collection(v.len()),
+            Type::Map(m) => self.budget.collection(m.len()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the string or array two strings or arrays would add up to against
+    /// the limit, before it is made.
+    #[inline]
+    fn check_concat(&self, regs: &[Register; 2]) -> Result<(), Trap> {
+        match (self.registers.get(regs[0])?, self.registers.get(regs[1])?) {
+            (Type::String(s1), Type::String(s2)) => self.budget.string(s1.len() + s2.len()),
+            (Type::Array(a1), Type::Array(a2)) => self.budget.collection(a1.len() + a2.len()),
+            _ => Ok(()),
+        }
     }
 }
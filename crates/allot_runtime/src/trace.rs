@@ -0,0 +1,267 @@
+use std::{fmt::Write as _, io, io::Write, sync::Mutex};
+
+use allot_lib::{Instruction, Register, Type};
+
+use crate::{green, AllotRuntime, StackFrame, Trap};
+
+/// Receives a record for every instruction a runtime and its threads run.
+pub trait Tracer: Send + Sync {
+    fn trace(&self, record: &TraceRecord);
+}
+
+/// What running one instruction did.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceRecord {
+    /// 0 for the main runtime, threads are numbered in the order they were
+    /// created.
+    pub thread: usize,
+    pub current: usize,
+    /// None if there is no instruction at current.
+    pub instruction: Option<Instruction>,
+    pub registers: Vec<RegisterWrite>,
+    /// Values pushed onto the top stack frame.
+    pub pushed: Vec<Type>,
+    /// Values popped off the top stack frame, from the top down.
+    pub popped: Vec<Type>,
+    pub call: Option<CallTrace>,
+    pub trap: Option<Trap>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegisterWrite {
+    pub register: Register,
+    pub before: Type,
+    pub after: Type,
+}
+
+/// Registers 5-9 before and after a library function was called.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CallTrace {
+    pub function: String,
+    pub args: Vec<Type>,
+    pub results: Vec<Type>,
+}
+
+/// Writes every record as a JSON object on its own line. Instructions and
+/// values are written with their Debug representation.
+pub struct JsonLinesTracer<W: Write + Send> {
+    out: Mutex<W>,
+}
+impl<W: Write + Send> JsonLinesTracer<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out: Mutex::new(out),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out.into_inner().unwrap()
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.out.lock().unwrap().flush()
+    }
+}
+impl<W: Write + Send> Tracer for JsonLinesTracer<W> {
+    fn trace(&self, record: &TraceRecord) {
+        let line = record.to_json();
+        if let Ok(mut out) = self.out.lock() {
+            // A trace that cannot be written should not stop the program.
+            let _ = writeln!(out, "{line}");
+        }
+    }
+}
+
+impl TraceRecord {
+    pub fn to_json(&self) -> String {
+        let mut s = String::new();
+        write!(
+            s,
+            "{{\"thread\":{},\"current\":{},\"instruction\":{}",
+            self.thread,
+            self.current,
+            self.instruction
+                .as_ref()
+                .map_or("null".to_string(), json_debug)
+        )
+        .unwrap();
+
+        s.push_str(",\"registers\":[");
+        for (i, w) in self.registers.iter().enumerate() {
+            if i > 0 {
+                s.push(',');
+            }
+            write!(
+                s,
+                "{{\"register\":{},\"before\":{},\"after\":{}}}",
+                json_debug(&w.register),
+                json_debug(&w.before),
+                json_debug(&w.after)
+            )
+            .unwrap();
+        }
+        s.push(']');
+
+        write!(
+            s,
+            ",\"pushed\":{},\"popped\":{}",
+            json_list(&self.pushed),
+            json_list(&self.popped)
+        )
+        .unwrap();
+
+        if let Some(call) = &self.call {
+            write!(
+                s,
+                ",\"call\":{{\"function\":{},\"args\":{},\"results\":{}}}",
+                json_string(&call.function),
+                json_list(&call.args),
+                json_list(&call.results)
+            )
+            .unwrap();
+        }
+        if let Some(trap) = &self.trap {
+            write!(s, ",\"trap\":{}", json_string(&trap.to_string())).unwrap();
+        }
+
+        s.push('}');
+        s
+    }
+}
+
+#[inline]
+fn json_debug<T: std::fmt::Debug>(t: &T) -> String {
+    json_string(&format!("{:?}", t))
+}
+
+fn json_list(types: &[Type]) -> String {
+    let items: Vec<String> = types.iter().map(json_debug).collect();
+    format!("[{}]", items.join(","))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl AllotRuntime {
+    /// Runs the current instruction and records what it changed.
+    pub(crate) fn traced_step(&mut self, tracer: &dyn Tracer) -> Result<Option<i32>, Trap> {
+        let current = self.current;
+        let instruction = self.instructions.get(current).cloned();
+        let writes = instruction.as_ref().map(writes).unwrap_or_default();
+        let before: Vec<Type> = writes.iter().map(|r| self.register(*r)).collect();
+        let frames = self.stack_frames.len();
+        if let Some(frame) = self.stack_frames.last_mut() {
+            frame.start_journal();
+        }
+
+        let result = self.step();
+
+        let after: Vec<Type> = writes.iter().map(|r| self.register(*r)).collect();
+        let registers = writes
+            .iter()
+            .zip(before.iter().zip(after.iter()))
+            .filter(|(_, (b, a))| b != a)
+            .map(|(register, (b, a))| RegisterWrite {
+                register: *register,
+                before: b.clone(),
+                after: a.clone(),
+            })
+            .collect();
+
+        // The frame that was on top may have been popped or moved to a thread,
+        // and pushes and pops only make sense when it is still on top.
+        let journal = frames
+            .checked_sub(1)
+            .and_then(|top| self.stack_frames.get_mut(top))
+            .and_then(StackFrame::take_journal);
+        let (pushed, popped) = match journal {
+            Some(journal) if self.stack_frames.len() == frames => journal,
+            _ => (Vec::new(), Vec::new()),
+        };
+        // A blocked green thread runs the instruction again, which is traced
+        // then.
+        if green::blocked() {
+            return result;
+        }
+
+        let call = match &instruction {
+            Some(Instruction::Call(function)) => Some(CallTrace {
+                function: function.clone(),
+                args: before,
+                results: after,
+            }),
+            _ => None,
+        };
+
+        tracer.trace(&TraceRecord {
+            thread: self.thread,
+            current,
+            instruction,
+            registers,
+            pushed,
+            popped,
+            call,
+            trap: result.as_ref().err().cloned(),
+        });
+
+        result
+    }
+
+    #[inline]
+    fn register(&self, register: Register) -> Type {
+        self.registers.get(register).cloned().unwrap_or(Type::None)
+    }
+}
+
+/// The registers the instruction can change. Call writes r5-r9, in order.
+fn writes(instruction: &Instruction) -> Vec<Register> {
+    let mut writes = match instruction {
+        Instruction::Op(_, [reg, _])
+        | Instruction::Cpy(reg, _)
+        | Instruction::Cast(reg, _)
+        | Instruction::Lea(reg, _)
+        | Instruction::Push(reg)
+        | Instruction::Pop(Some(reg))
+        | Instruction::StackCpy(reg, _)
+        | Instruction::ArrGet(reg, ..)
+        | Instruction::ArrLen(reg, _)
+        | Instruction::ArrSlice(reg, ..)
+        | Instruction::StructNew(reg, _)
+        | Instruction::FieldGet(reg, ..)
+        | Instruction::Tag(reg, _) => vec![*reg],
+        Instruction::Mov(reg, Type::Register(from)) => vec![*reg, *from],
+        Instruction::Mov(reg, _) => vec![*reg],
+        Instruction::ArrPush(reg1, reg2)
+        | Instruction::ArrPop(reg1, reg2)
+        | Instruction::ArrSet(reg1, _, reg2)
+        | Instruction::FieldSet(reg1, _, _, reg2)
+        | Instruction::Match(reg1, reg2, _) => vec![*reg1, *reg2],
+        Instruction::Call(_) => vec![
+            Register::R5,
+            Register::R6,
+            Register::R7,
+            Register::R8,
+            Register::R9,
+        ],
+        Instruction::ThreadCreate(_) | Instruction::ThreadJoin(_) => vec![Register::R5],
+        _ => Vec::new(),
+    };
+    // Mov r1 r1 writes one register.
+    writes.dedup();
+    writes
+}
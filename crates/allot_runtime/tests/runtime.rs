@@ -1,11 +1,28 @@
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
 use allot_lib::{
-    Instruction::{Assert, Call, Cpy, Exit, Mov, Op},
-    OpPrim2,
-    Operation::Prim2,
-    Register::{R1, R10, R2, R3, R4, R5, R6, R7, R8, R9},
-    Type,
+    Instruction,
+    Instruction::{
+        ArrGet, ArrLen, ArrPop, ArrPush, ArrSet, ArrSlice, Assert, Call, CallAddr, Cast, Cpy, Exit,
+        FieldGet, FieldSet, GiveTo, Jmp, Lea, Match, Mov, Nop, Op, Pop, PopFrame, Push, PushCpy,
+        PushFrame, Ret, StructNew, Tag, TakeFrom, ThreadCreate, ThreadJoin,
+    },
+    OpPrim1, OpPrim2,
+    Operation::{Prim1, Prim2},
+    RawType, Register,
+    Register::{R1, R10, R2, R20, R21, R22, R3, R4, R5, R6, R7, R8, R9},
+    StructDef, Type,
+};
+use allot_runtime::{
+    instruction_hash, AllotRuntime, CrossHeap, Frame, Heap, Io, JsonLinesTracer, Library, Limit,
+    Limits, Profiler, RegisterWrite, SnapshotError, Threading, TraceRecord, Tracer, Trap,
 };
-use allot_runtime::AllotRuntime;
 
 #[test]
 fn mov() {
@@ -17,7 +34,7 @@ fn mov() {
         Exit(Type::Int32(512)),
     ]);
 
-    assert_eq!(runtime.run(), 512);
+    assert_eq!(runtime.run(), Ok(512));
 }
 
 #[test]
@@ -48,7 +65,7 @@ fn cpy() {
         Exit(Type::Int32(512)),
     ]);
 
-    assert_eq!(runtime.run(), 512);
+    assert_eq!(runtime.run(), Ok(512));
 }
 
 #[test]
@@ -61,7 +78,7 @@ fn functions() {
         Exit(Type::Int32(512)),
     ]);
 
-    assert_eq!(runtime.run(), 512);
+    assert_eq!(runtime.run(), Ok(512));
 }
 
 #[test]
@@ -74,5 +91,2289 @@ fn op_add() {
         Exit(Type::Int32(512)),
     ]);
 
-    assert_eq!(runtime.run(), 512);
+    assert_eq!(runtime.run(), Ok(512));
+}
+
+#[test]
+fn call_addr_nested() {
+    let mut runtime = AllotRuntime::new(vec![
+        Lea(R10, 4),
+        CallAddr(Type::Address(6)),
+        Assert(R1, Type::UInt(2)),
+        Exit(Type::Int32(512)),
+        // b
+        Op(Prim1(OpPrim1::Increment), [R1, R1]),
+        Ret,
+        // a
+        Mov(R1, Type::UInt(0)),
+        Op(Prim1(OpPrim1::Increment), [R1, R1]),
+        CallAddr(Type::Register(R10)),
+        Ret,
+    ]);
+
+    assert_eq!(runtime.run(), Ok(512));
+    assert_eq!(runtime.current, 3);
+    // Both return addresses were popped.
+    assert_eq!(runtime.stack_frames[0].len(), 0);
+}
+
+#[test]
+fn call_addr_recursive() {
+    // Sums 5 + 4 + 3 + 2 + 1 into r2.
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::UInt(5)),
+        Mov(R2, Type::UInt(0)),
+        CallAddr(Type::Address(5)),
+        Assert(R2, Type::UInt(15)),
+        Exit(Type::Int32(512)),
+        // sum
+        Mov(R3, Type::UInt(0)),
+        Op(Prim2(OpPrim2::Equal), [R3, R1]),
+        Jmp(Some(R3), Type::Address(12)),
+        Op(Prim2(OpPrim2::Add), [R2, R1]),
+        Op(Prim1(OpPrim1::Decrement), [R1, R1]),
+        CallAddr(Type::Address(5)),
+        Ret,
+        Ret,
+    ]);
+
+    assert_eq!(runtime.run(), Ok(512));
+    assert_eq!(runtime.stack_frames.len(), 1);
+}
+
+#[test]
+fn trap_pop_empty() {
+    let mut runtime = AllotRuntime::new(vec![Mov(R1, Type::UInt(50)), Pop(Some(R2))]);
+
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.trap, Trap::StackEmpty);
+    assert_eq!(err.current, 1);
+    assert_eq!(err.instruction, Some(Pop(Some(R2))));
+    assert_eq!(err.registers[1], Type::UInt(50));
+}
+
+#[test]
+fn trap_jmp_past_end() {
+    let mut runtime = AllotRuntime::new(vec![Jmp(None, Type::Address(10))]);
+
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.trap, Trap::InvalidAddress(10));
+    assert_eq!(err.current, 10);
+    assert_eq!(err.instruction, None);
+}
+
+#[test]
+fn trap_unknown_call() {
+    let mut runtime = AllotRuntime::new(vec![Call("does::not::exist".to_string())]);
+
+    let err = runtime.run().unwrap_err();
+    assert_eq!(
+        err.trap,
+        Trap::UnknownFunction("does::not::exist".to_string())
+    );
+}
+
+#[test]
+fn trap_op() {
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::UInt(50)),
+        Mov(R2, Type::UInt(0)),
+        Op(Prim2(OpPrim2::Division), [R1, R2]),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(runtime.run().unwrap_err().trap, Trap::DivisionByZero);
+
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::UInt(50)),
+        Jmp(Some(R1), Type::Address(0)),
+    ]);
+    assert_eq!(
+        runtime.run().unwrap_err().trap,
+        Trap::UnexpectedType {
+            expected: "Boolean",
+            found: Type::UInt(50)
+        }
+    );
+}
+
+#[test]
+fn trap_overflow() {
+    // Release builds wrapped these before, they trap in every build now.
+    let cases = [
+        (Prim2(OpPrim2::Add), Type::UInt8(255), Type::UInt8(1)),
+        (Prim2(OpPrim2::Subtract), Type::UInt(0), Type::UInt(1)),
+        (
+            Prim2(OpPrim2::Multiplication),
+            Type::Int32(i32::MAX),
+            Type::Int32(2),
+        ),
+        (
+            Prim2(OpPrim2::Division),
+            Type::Int8(i8::MIN),
+            Type::Int8(-1),
+        ),
+        (
+            Prim2(OpPrim2::Modulus),
+            Type::Int64(i64::MIN),
+            Type::Int64(-1),
+        ),
+        (Prim2(OpPrim2::ShiftLeft), Type::UInt32(1), Type::UInt(32)),
+        (
+            Prim2(OpPrim2::ShiftRight),
+            Type::Int16(1),
+            Type::UInt(usize::MAX),
+        ),
+        (Prim2(OpPrim2::Subtract), Type::Char('a'), Type::Char('b')),
+        (Prim1(OpPrim1::Increment), Type::Int(isize::MAX), Type::None),
+        (Prim1(OpPrim1::Decrement), Type::Char('\0'), Type::None),
+    ];
+    for (op, t1, t2) in cases {
+        let mut runtime = AllotRuntime::new(vec![
+            Mov(R1, t1.clone()),
+            Mov(R2, t2.clone()),
+            Op(op, [R1, R2]),
+            Exit(Type::Int32(0)),
+        ]);
+        let err = runtime.run().unwrap_err();
+        assert_eq!(err.trap, Trap::Overflow, "{op:?} {t1:?} {t2:?}");
+        assert_eq!(err.current, 2);
+    }
+
+    // A counter that wrapped to 0 in release builds stops at the overflow, with
+    // the register left at its last value.
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::UInt8(250)),
+        Op(Prim1(OpPrim1::Increment), [R1, R1]),
+        Jmp(None, Type::Address(1)),
+    ]);
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.trap, Trap::Overflow);
+    assert_eq!(err.current, 1);
+    assert_eq!(runtime.registers.get(R1), Ok(&Type::UInt8(255)));
+}
+
+#[test]
+fn register_function() {
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::Int32(20)),
+        Call("host::double".to_string()),
+        Assert(R5, Type::Int32(40)),
+        Exit(Type::Int32(512)),
+    ]);
+    runtime.register_function("host::double", |(r5, ..), _, _, _| match r5 {
+        Type::Int32(i) => Ok((Some(Type::Int32(*i * 2)), None, None, None, None)),
+        _ => Err(Trap::Library("expected Int32".to_string())),
+    });
+
+    assert_eq!(runtime.run(), Ok(512));
+}
+
+#[test]
+fn library_without_defaults() {
+    let mut runtime = AllotRuntime::with_library(
+        Arc::new(vec![Call("println".to_string()), Exit(Type::Int32(0))]),
+        Library::new(),
+    );
+
+    assert_eq!(
+        runtime.run().unwrap_err().trap,
+        Trap::UnknownFunction("println".to_string())
+    );
+}
+
+#[test]
+fn thread_inherits_library() {
+    let mut runtime = AllotRuntime::new(vec![
+        PushFrame(false),
+        ThreadCreate(Type::Address(5)),
+        ThreadJoin(R5),
+        Assert(R5, Type::Int32(7)),
+        Exit(Type::Int32(512)),
+        Call("host::seven".to_string()),
+        Exit(Type::Register(R5)),
+    ]);
+    runtime.register_function("host::seven", |_, _, _, _| {
+        Ok((Some(Type::Int32(7)), None, None, None, None))
+    });
+
+    assert_eq!(runtime.run(), Ok(512));
+}
+
+#[test]
+fn captured_io() {
+    let mut runtime = AllotRuntime::new(vec![
+        Call("read_line".to_string()),
+        Call("print".to_string()),
+        Mov(R5, Type::Int32(12)),
+        Call("eprintln".to_string()),
+        Call("read".to_string()),
+        Assert(R5, Type::UInt8(b'b')),
+        Exit(Type::Int32(512)),
+    ]);
+
+    let captured = runtime.run_captured(b"a line\nb");
+    assert_eq!(captured.code, Ok(512));
+    assert_eq!(captured.stdout, "a line\n");
+    assert_eq!(captured.stderr, "12\n");
+}
+
+#[test]
+fn thread_shares_io() {
+    let mut runtime = AllotRuntime::new(vec![
+        PushFrame(false),
+        ThreadCreate(Type::Address(5)),
+        ThreadJoin(R5),
+        Call("println".to_string()),
+        Exit(Type::Int32(512)),
+        Call("read_line".to_string()),
+        Call("print".to_string()),
+        Exit(Type::Int32(3)),
+    ]);
+
+    let captured = runtime.run_captured(b"from thread\n");
+    assert_eq!(captured.code, Ok(512));
+    assert_eq!(captured.stdout, "from thread\n3\n");
+}
+
+#[test]
+fn library_exit() {
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::Int32(42)),
+        Call("exit".to_string()),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(runtime.run(), Ok(42));
+    assert_eq!(runtime.current, 1);
+
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::UInt(42)),
+        Call("exit".to_string()),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(
+        runtime.run().unwrap_err().trap,
+        Trap::UnexpectedType {
+            expected: "Int32",
+            found: Type::UInt(42)
+        }
+    );
+}
+
+#[test]
+fn library_exit_thread() {
+    let mut runtime = AllotRuntime::new(vec![
+        PushFrame(false),
+        ThreadCreate(Type::Address(5)),
+        ThreadJoin(R5),
+        Assert(R5, Type::Int32(7)),
+        Exit(Type::Int32(512)),
+        Mov(R5, Type::Int32(7)),
+        Call("exit".to_string()),
+        Exit(Type::Int32(0)),
+    ]);
+
+    assert_eq!(runtime.run(), Ok(512));
+}
+
+#[test]
+fn limit_fuel() {
+    let mut runtime = AllotRuntime::new(vec![Nop, Jmp(None, Type::Address(0))]);
+    runtime.set_limits(Limits {
+        fuel: Some(100),
+        ..Limits::default()
+    });
+
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.trap, Trap::LimitExceeded(Limit::Fuel));
+    assert_eq!(err.current, 0);
+
+    let mut runtime = AllotRuntime::new(vec![Nop, Jmp(None, Type::Address(0))]);
+    runtime.set_limits(Limits {
+        time: Some(Duration::from_millis(10)),
+        ..Limits::default()
+    });
+    assert_eq!(
+        runtime.run().unwrap_err().trap,
+        Trap::LimitExceeded(Limit::Time)
+    );
+}
+
+#[test]
+fn limit_memory() {
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::UInt(1)),
+        PushCpy(R1),
+        Jmp(None, Type::Address(1)),
+    ]);
+    runtime.set_limits(Limits {
+        stack_values: Some(64),
+        ..Limits::default()
+    });
+    assert_eq!(
+        runtime.run().unwrap_err().trap,
+        Trap::LimitExceeded(Limit::StackValues)
+    );
+
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::String("ab".to_string())),
+        Op(Prim2(OpPrim2::Add), [R1, R1]),
+        Jmp(None, Type::Address(1)),
+    ]);
+    runtime.set_limits(Limits {
+        string_length: Some(1024),
+        ..Limits::default()
+    });
+    assert_eq!(
+        runtime.run().unwrap_err().trap,
+        Trap::LimitExceeded(Limit::StringLength)
+    );
+    // The string is checked before it is made.
+    assert_eq!(
+        runtime.registers.get(R1),
+        Ok(&Type::String("ab".repeat(512)))
+    );
+
+    // Stdin is read no further than the limit.
+    for (function, stdin) in [("read_line", "abcd\n"), ("std::read_all", "ab\r\ncd\ne")] {
+        let mut runtime = AllotRuntime::new(vec![Call(function.to_string()), Exit(Type::Int32(0))]);
+        runtime.set_limits(Limits {
+            string_length: Some(4),
+            ..Limits::default()
+        });
+        let stdin = format!("{stdin}{}", "x".repeat(1 << 20));
+        let captured = runtime.run_captured(stdin.as_bytes());
+        assert_eq!(
+            captured.code.unwrap_err().trap,
+            Trap::LimitExceeded(Limit::StringLength)
+        );
+    }
+    for (function, stdin, read) in [
+        ("read_line", "abc\n", "abc\n"),
+        ("std::read_all", "ab\r\ncd\n", "abcd"),
+    ] {
+        let mut runtime = AllotRuntime::new(vec![
+            Call(function.to_string()),
+            Assert(R5, Type::String(read.to_string())),
+            Exit(Type::Int32(512)),
+        ]);
+        runtime.set_limits(Limits {
+            string_length: Some(4),
+            ..Limits::default()
+        });
+        assert_eq!(runtime.run_captured(stdin.as_bytes()).code, Ok(512));
+    }
+
+    // Arrays that double every loop, under limits that do not count them.
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::Array(vec![Type::UInt8(0)])),
+        Cpy(R2, R1),
+        Op(Prim2(OpPrim2::Add), [R1, R2]),
+        Jmp(None, Type::Address(1)),
+    ]);
+    runtime.set_limits(Limits {
+        fuel: Some(200),
+        string_length: Some(100),
+        heap_entries: Some(10),
+        collection_length: Some(1024),
+        ..Limits::default()
+    });
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.trap, Trap::LimitExceeded(Limit::CollectionLength));
+    assert_eq!(err.current, 2);
+    assert_eq!(
+        runtime.registers.get(R1),
+        Ok(&Type::Array(vec![Type::UInt8(0); 1024]))
+    );
+
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::Array(vec![])),
+        Mov(R2, Type::UInt8(0)),
+        ArrPush(R1, R2),
+        Jmp(None, Type::Address(1)),
+    ]);
+    runtime.set_limits(Limits {
+        collection_length: Some(3),
+        ..Limits::default()
+    });
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.trap, Trap::LimitExceeded(Limit::CollectionLength));
+    assert_eq!(err.current, 2);
+    assert_eq!(
+        runtime.registers.get(R1),
+        Ok(&Type::Array(vec![Type::UInt8(0); 3]))
+    );
+
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::UInt(1)),
+        Call("heap::alloc".to_string()),
+        Jmp(None, Type::Address(0)),
+    ]);
+    runtime.set_limits(Limits {
+        heap_entries: Some(8),
+        ..Limits::default()
+    });
+    assert_eq!(
+        runtime.run().unwrap_err().trap,
+        Trap::LimitExceeded(Limit::HeapEntries)
+    );
+    assert_eq!(runtime.heap.len(), 8);
+
+    // The limit counts the heaps of every thread and the shared region
+    // together, and freed entries stop counting. The channel makes two
+    // entries, so it goes over whether or not the handle of the thread has
+    // been joined yet.
+    let alloc = [Mov(R5, Type::UInt(1)), Call("heap::alloc".to_string())];
+    let mut instructions = vec![];
+    instructions.extend(alloc.clone());
+    instructions.extend(alloc.clone());
+    instructions.extend([
+        PushFrame(false),
+        ThreadCreate(Type::Address(8)),
+        ThreadJoin(R5),
+        Exit(Type::Int32(0)),
+    ]);
+    instructions.extend(alloc.clone());
+    instructions.push(Call("heap::free".to_string()));
+    instructions.extend(alloc);
+    instructions.push(Call("thread::channel".to_string()));
+    instructions.push(Exit(Type::Int32(0)));
+    let mut runtime = AllotRuntime::new(instructions);
+    runtime.set_limits(Limits {
+        heap_entries: Some(4),
+        ..Limits::default()
+    });
+    match runtime.run().unwrap_err().trap {
+        Trap::Thread(err) => {
+            assert_eq!(err.trap, Trap::LimitExceeded(Limit::HeapEntries));
+            assert_eq!(err.current, 13);
+        }
+        trap => panic!("expected the thread to trap, found {trap:?}"),
+    }
+}
+
+#[test]
+fn limit_memory_checked_first() {
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::UInt(1)),
+        PushCpy(R1),
+        Jmp(None, Type::Address(1)),
+    ]);
+    runtime.set_limits(Limits {
+        stack_values: Some(64),
+        ..Limits::default()
+    });
+    runtime.run().unwrap_err();
+    assert_eq!(runtime.stack_frames[0].len(), 64);
+
+    let mut runtime = AllotRuntime::new(vec![PushFrame(false), Jmp(None, Type::Address(0))]);
+    runtime.set_limits(Limits {
+        stack_frames: Some(16),
+        ..Limits::default()
+    });
+    assert_eq!(
+        runtime.run().unwrap_err().trap,
+        Trap::LimitExceeded(Limit::StackFrames)
+    );
+    assert_eq!(runtime.stack_frames.len(), 16);
+}
+
+#[test]
+fn limit_time_while_waiting() {
+    let limits = Limits {
+        time: Some(Duration::from_millis(50)),
+        ..Limits::default()
+    };
+    let started = Instant::now();
+
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::UInt64(60_000)),
+        Call("thread::sleep".to_string()),
+        Exit(Type::Int32(0)),
+    ]);
+    runtime.set_limits(limits.clone());
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.trap, Trap::LimitExceeded(Limit::Time));
+    assert_eq!(err.current, 1);
+
+    // The thread and the join both stop once the time is up.
+    let mut runtime = AllotRuntime::new(vec![
+        PushFrame(false),
+        ThreadCreate(Type::Address(4)),
+        ThreadJoin(R5),
+        Exit(Type::Int32(0)),
+        Mov(R5, Type::UInt64(60_000)),
+        Call("thread::sleep".to_string()),
+        Exit(Type::Int32(0)),
+    ]);
+    runtime.set_limits(limits.clone());
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.current, 2);
+    assert!(matches!(
+        err.trap,
+        Trap::LimitExceeded(Limit::Time) | Trap::Thread(_)
+    ));
+
+    // Channels that never get a value.
+    for (function, timeout) in [("thread::recv", 0), ("thread::recv_timeout", 60_000)] {
+        let mut runtime = AllotRuntime::new(vec![
+            Call("thread::channel".to_string()),
+            Cpy(R20, R5),
+            Cpy(R5, R6),
+            Mov(R6, Type::UInt64(timeout)),
+            Call(function.to_string()),
+            Exit(Type::Int32(0)),
+        ]);
+        runtime.set_limits(limits.clone());
+        let err = runtime.run().unwrap_err();
+        assert_eq!(err.trap, Trap::LimitExceeded(Limit::Time));
+        assert_eq!(err.current, 4);
+    }
+
+    // Stdin that never gets any input.
+    let (stdin, mut writer) = std::io::pipe().unwrap();
+    let mut runtime = AllotRuntime::new(vec![Call("read_line".to_string()), Exit(Type::Int32(0))]);
+    runtime.set_io(Io::new(
+        Box::new(stdin),
+        Box::new(std::io::sink()),
+        Box::new(std::io::sink()),
+    ));
+    runtime.set_limits(limits.clone());
+    assert_eq!(
+        runtime.run().unwrap_err().trap,
+        Trap::LimitExceeded(Limit::Time)
+    );
+
+    // The read that was given up on gets the first line, the next read waits
+    // for its own.
+    let io = runtime.io.clone();
+    let mut runtime = AllotRuntime::new(vec![
+        Call("read_line".to_string()),
+        Assert(R5, Type::String("second\n".to_string())),
+        Exit(Type::Int32(512)),
+    ]);
+    runtime.io = io;
+    runtime.set_limits(Limits {
+        time: Some(Duration::from_secs(20)),
+        ..Limits::default()
+    });
+    writer.write_all(b"first\nsecond\n").unwrap();
+    assert_eq!(runtime.run(), Ok(512));
+
+    assert!(started.elapsed() < Duration::from_secs(30));
+}
+
+#[test]
+fn limit_time_reads() {
+    let mut runtime = AllotRuntime::new(vec![
+        Call("read".to_string()),
+        Assert(R5, Type::UInt8(b'a')),
+        Call("read".to_string()),
+        Assert(R5, Type::UInt8(b'b')),
+        Call("read_line".to_string()),
+        Assert(R5, Type::String("c\n".to_string())),
+        Call("std::read_all".to_string()),
+        Assert(R5, Type::String("de".to_string())),
+        Exit(Type::Int32(512)),
+    ]);
+    runtime.set_limits(Limits {
+        time: Some(Duration::from_secs(20)),
+        ..Limits::default()
+    });
+    assert_eq!(runtime.run_captured(b"abc\nd\ne").code, Ok(512));
+}
+
+#[test]
+fn limit_threads() {
+    let mut runtime = AllotRuntime::new(vec![
+        PushFrame(false),
+        ThreadCreate(Type::Address(4)),
+        Push(R5),
+        Jmp(None, Type::Address(0)),
+        Exit(Type::Int32(0)),
+    ]);
+    runtime.set_limits(Limits {
+        threads: Some(3),
+        ..Limits::default()
+    });
+
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.trap, Trap::LimitExceeded(Limit::Threads));
+    assert_eq!(err.current, 1);
+
+    // Without a limit, thread ids stop at the last one a heap can be owned by.
+    let mut runtime = AllotRuntime::new(vec![
+        PushFrame(false),
+        ThreadCreate(Type::Address(4)),
+        Push(R5),
+        Jmp(None, Type::Address(0)),
+        Exit(Type::Int32(0)),
+    ]);
+    runtime.threading = Threading::Green {
+        seed: 0,
+        slice: 100,
+    };
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.trap, Trap::LimitExceeded(Limit::Threads));
+    let max_thread = match usize::BITS {
+        64 => 65534,
+        _ => 254,
+    };
+    assert_eq!(runtime.stack_frames[0].len(), max_thread);
+}
+
+#[derive(Default)]
+struct Records(Mutex<Vec<TraceRecord>>);
+impl Tracer for Records {
+    fn trace(&self, record: &TraceRecord) {
+        self.0.lock().unwrap().push(record.clone());
+    }
+}
+
+#[test]
+fn trace() {
+    let records = Arc::new(Records::default());
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::UInt(5)),
+        PushCpy(R1),
+        Pop(Some(R2)),
+        Mov(R5, Type::String(" a ".to_string())),
+        Call("string::trim".to_string()),
+        Exit(Type::Int32(0)),
+    ]);
+    runtime.set_tracer(records.clone());
+    assert_eq!(runtime.run(), Ok(0));
+
+    let records = records.0.lock().unwrap();
+    assert_eq!(records.len(), 6);
+    assert_eq!(
+        records[0].registers,
+        vec![RegisterWrite {
+            register: R1,
+            before: Type::None,
+            after: Type::UInt(5)
+        }]
+    );
+    assert_eq!(records[1].pushed, vec![Type::UInt(5)]);
+    assert_eq!(records[2].popped, vec![Type::UInt(5)]);
+    let call = records[4].call.as_ref().unwrap();
+    assert_eq!(call.args[0], Type::String(" a ".to_string()));
+    assert_eq!(call.results[0], Type::String("a".to_string()));
+}
+
+#[test]
+fn trace_pops_pushes_and_traps() {
+    let records = Arc::new(Records::default());
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::UInt(5)),
+        Push(R1),
+        Call("host::replace_top".to_string()),
+        Jmp(None, Type::Address(10)),
+    ]);
+    runtime.register_function("host::replace_top", |_, stack_frame, _, _| {
+        let t = stack_frame.pop()?;
+        stack_frame.push(Type::Array(vec![t]));
+        Ok((None, None, None, None, None))
+    });
+    runtime.set_tracer(records.clone());
+    assert_eq!(runtime.run().unwrap_err().trap, Trap::InvalidAddress(10));
+
+    let records = records.0.lock().unwrap();
+    assert_eq!(records.len(), 5);
+    assert_eq!(
+        records[1].registers,
+        vec![RegisterWrite {
+            register: R1,
+            before: Type::UInt(5),
+            after: Type::None
+        }]
+    );
+    assert_eq!(records[2].popped, vec![Type::UInt(5)]);
+    assert_eq!(records[2].pushed, vec![Type::Array(vec![Type::UInt(5)])]);
+    assert_eq!(records[4].current, 10);
+    assert_eq!(records[4].instruction, None);
+    assert_eq!(records[4].trap, Some(Trap::InvalidAddress(10)));
+    assert!(records[4].to_json().contains(r#""instruction":null"#));
+}
+
+#[test]
+fn trace_json_lines() {
+    let tracer = Arc::new(JsonLinesTracer::new(Vec::new()));
+    let mut runtime = AllotRuntime::new(vec![
+        PushFrame(false),
+        ThreadCreate(Type::Address(4)),
+        ThreadJoin(R5),
+        Exit(Type::Int32(0)),
+        Mov(R1, Type::String("\"q\"".to_string())),
+        Exit(Type::Int32(1)),
+    ]);
+    runtime.set_tracer(tracer.clone());
+    assert_eq!(runtime.run(), Ok(0));
+    drop(runtime);
+
+    let out = String::from_utf8(Arc::try_unwrap(tracer).ok().unwrap().into_inner()).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 6);
+    assert!(lines.contains(
+        &r#"{"thread":1,"current":4,"instruction":"Mov(R1, String(\"\\\"q\\\"\"))","registers":[{"register":"R1","before":"None","after":"String(\"\\\"q\\\"\")"}],"pushed":[],"popped":[]}"#
+    ));
+}
+
+#[test]
+fn profile() {
+    let profiler = Arc::new(Profiler::new());
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::String(" a ".to_string())),
+        Mov(R1, Type::UInt(3)),
+        CallAddr(Type::Address(8)),
+        Op(Prim1(OpPrim1::Decrement), [R1, R1]),
+        Mov(R2, Type::UInt(0)),
+        Op(Prim2(OpPrim2::NotEqual), [R2, R1]),
+        Jmp(Some(R2), Type::Address(2)),
+        Exit(Type::Int32(0)),
+        Call("string::trim".to_string()),
+        Ret,
+    ]);
+    runtime.set_profiler(profiler.clone());
+    assert_eq!(runtime.run(), Ok(0));
+    drop(runtime);
+
+    let profile = profiler.profile();
+    assert_eq!(profile.counts.get(&2), Some(&3));
+    assert_eq!(profile.counts.get(&9), Some(&3));
+    assert_eq!(profile.loops.get(&(2, 6)), Some(&2));
+    assert_eq!(profile.calls["string::trim"].calls, 3);
+    assert_eq!(profile.threads[&0].instructions, 24);
+    assert_eq!(
+        profile.stacks[&vec![
+            Frame::Thread(0),
+            Frame::Address(0),
+            Frame::Address(8),
+            Frame::Call("string::trim".to_string())
+        ]],
+        3
+    );
+
+    // A jump far past the end traps like it does without a profiler.
+    let profiler = Arc::new(Profiler::new());
+    let mut runtime = AllotRuntime::new(vec![Jmp(None, Type::Address(usize::MAX))]);
+    runtime.set_profiler(profiler.clone());
+    assert_eq!(
+        runtime.run().unwrap_err().trap,
+        Trap::InvalidAddress(usize::MAX)
+    );
+    assert_eq!(profiler.profile().counts, BTreeMap::from([(0, 1)]));
+}
+
+#[test]
+fn profile_unjoined_thread() {
+    let profiler = Arc::new(Profiler::new());
+    let mut runtime = AllotRuntime::new(vec![
+        PushFrame(false),
+        ThreadCreate(Type::Address(3)),
+        Exit(Type::Int32(0)),
+        Mov(R5, Type::UInt64(2000)),
+        Call("thread::sleep".to_string()),
+        Exit(Type::Int32(1)),
+    ]);
+    runtime.set_profiler(profiler.clone());
+    assert_eq!(runtime.run(), Ok(0));
+    drop(runtime);
+
+    // The thread is still sleeping.
+    let profile = profiler.profile();
+    assert_eq!(profile.threads[&0].instructions, 3);
+    assert!(profile.threads.contains_key(&1));
+}
+
+#[test]
+fn snapshot_restore() {
+    // Sums 10 + 9 + ... + 1 into r2, keeping every step on the stack.
+    let instructions = Arc::new(vec![
+        Mov(R1, Type::UInt(10)),
+        Mov(R2, Type::UInt(0)),
+        Mov(R3, Type::UInt(0)),
+        PushFrame(false),
+        Op(Prim2(OpPrim2::Add), [R2, R1]),
+        PushCpy(R2),
+        Op(Prim1(OpPrim1::Decrement), [R1, R1]),
+        Cpy(R4, R1),
+        Op(Prim2(OpPrim2::Equal), [R4, R3]),
+        Jmp(Some(R4), Type::Address(11)),
+        Jmp(None, Type::Address(4)),
+        Assert(R2, Type::UInt(55)),
+        Exit(Type::Int32(512)),
+    ]);
+
+    let mut runtime = AllotRuntime::new_arc(instructions.clone());
+    runtime
+        .heap
+        .local
+        .push(Type::String("kept".to_string()))
+        .unwrap();
+    runtime.heap.share(Type::UInt(7)).unwrap();
+    for _ in 0..20 {
+        runtime.tick().unwrap();
+    }
+    let snapshot = runtime.snapshot().unwrap();
+
+    let mut restored = AllotRuntime::restore(instructions, snapshot.clone()).unwrap();
+    assert_eq!(restored.current, runtime.current);
+    assert_eq!(restored.registers.snapshot(), runtime.registers.snapshot());
+    assert_eq!(restored.stack_frames.len(), 2);
+    assert_eq!(
+        restored.stack_frames[1].values(),
+        runtime.stack_frames[1].values()
+    );
+    assert_eq!(restored.snapshot(), Ok(snapshot));
+
+    assert_eq!(runtime.run(), Ok(512));
+    assert_eq!(restored.run(), Ok(512));
+    assert_eq!(restored.stack_frames[1].len(), 10);
+
+    let structs = vec![StructDef {
+        name: "Empty".to_string(),
+        fields: vec![],
+    }];
+    let instructions = Arc::new(vec![StructNew(R1, 0), Exit(Type::Int32(0))]);
+    let mut runtime = AllotRuntime::new_arc(instructions.clone());
+    runtime.set_structs(structs.clone());
+    let restored = AllotRuntime::restore(instructions, runtime.snapshot().unwrap()).unwrap();
+    assert_eq!(*restored.structs, structs);
+    assert_ne!(
+        instruction_hash(&runtime.instructions, &structs),
+        instruction_hash(&runtime.instructions, &[])
+    );
+}
+
+#[test]
+fn snapshot_errors() {
+    let mut runtime = AllotRuntime::new(vec![
+        PushFrame(false),
+        ThreadCreate(Type::Address(3)),
+        Exit(Type::Int32(0)),
+        Exit(Type::Int32(1)),
+    ]);
+    runtime.tick().unwrap();
+    runtime.tick().unwrap();
+    match runtime.snapshot() {
+        Err(SnapshotError::Unserializable { pointer, type_name }) => {
+            assert_eq!(runtime.registers.get(R5), Ok(&Type::Pointer(pointer)));
+            assert!(type_name.contains("Receiver"));
+        }
+        other => panic!("expected Unserializable, found {other:?}"),
+    }
+
+    let runtime = AllotRuntime::new(vec![Nop, Exit(Type::Int32(0))]);
+    let snapshot = runtime.snapshot().unwrap();
+    assert!(matches!(
+        AllotRuntime::restore(Arc::new(vec![Exit(Type::Int32(0))]), snapshot.clone()),
+        Err(SnapshotError::InstructionMismatch { .. })
+    ));
+    assert!(matches!(
+        AllotRuntime::restore(
+            Arc::new(vec![Nop, Exit(Type::Int32(0))]),
+            snapshot[..snapshot.len() - 1].to_vec()
+        ),
+        Err(SnapshotError::Decode(_))
+    ));
+    let mut trailing = snapshot.clone();
+    trailing.push(0);
+    assert_eq!(
+        AllotRuntime::restore(Arc::new(vec![Nop, Exit(Type::Int32(0))]), trailing).err(),
+        Some(SnapshotError::TrailingBytes(1))
+    );
+
+    // Slot 5 of the heap of thread 0 was never made.
+    let instructions = Arc::new(vec![
+        Mov(R1, Type::Array(vec![Type::Pointer(5)])),
+        Exit(Type::Int32(0)),
+    ]);
+    let mut runtime = AllotRuntime::new_arc(instructions.clone());
+    runtime.tick().unwrap();
+    assert_eq!(
+        AllotRuntime::restore(instructions, runtime.snapshot().unwrap()).err(),
+        Some(SnapshotError::InvalidPointer(5))
+    );
+
+    let mut runtime = AllotRuntime::new(vec![Nop, Exit(Type::Int32(0))]);
+    runtime.thread = usize::MAX;
+    assert_eq!(
+        AllotRuntime::restore(
+            Arc::new(vec![Nop, Exit(Type::Int32(0))]),
+            runtime.snapshot().unwrap()
+        )
+        .err(),
+        Some(SnapshotError::InvalidThread(usize::MAX))
+    );
+
+    let instructions = Arc::new(vec![Nop, Exit(Type::Int32(0))]);
+    let mut runtime = AllotRuntime::new_arc(instructions.clone());
+    runtime.stack_frames.clear();
+    assert_eq!(
+        AllotRuntime::restore(instructions.clone(), runtime.snapshot().unwrap()).err(),
+        Some(SnapshotError::NoStackFrames)
+    );
+    // The register count comes after the version, hash, structs, thread, and
+    // current.
+    let mut snapshot = AllotRuntime::new_arc(instructions.clone())
+        .snapshot()
+        .unwrap();
+    assert_eq!(snapshot[40..48], 30_u64.to_le_bytes());
+    snapshot[40..48].copy_from_slice(&31_u64.to_le_bytes());
+    assert_eq!(
+        AllotRuntime::restore(instructions, snapshot).err(),
+        Some(SnapshotError::TooManyRegisters(31))
+    );
+
+    // Records that do not match their struct, which StructNew could not make.
+    let instructions = Arc::new(vec![Nop, Exit(Type::Int32(0))]);
+    let structs = vec![StructDef {
+        name: "Point".to_string(),
+        fields: vec![("x".to_string(), RawType::Int)],
+    }];
+    let restore = |t: Type| {
+        let mut runtime = AllotRuntime::new_arc(instructions.clone());
+        runtime.set_structs(structs.clone());
+        runtime.heap.share(t).unwrap();
+        AllotRuntime::restore(instructions.clone(), runtime.snapshot().unwrap()).err()
+    };
+    assert_eq!(restore(Type::Struct(0, vec![Type::Int(1)])), None);
+    assert_eq!(
+        restore(Type::Struct(0, vec![])),
+        Some(SnapshotError::InvalidRecord(Trap::FieldCount {
+            name: "Point".to_string(),
+            expected: 1,
+            found: 0,
+        }))
+    );
+    assert!(matches!(
+        restore(Type::Array(vec![Type::Struct(0, vec![Type::UInt8(1)])])),
+        Some(SnapshotError::InvalidRecord(Trap::FieldTypeMismatch { .. }))
+    ));
+    assert_eq!(
+        restore(Type::Struct(1, vec![])),
+        Some(SnapshotError::InvalidRecord(Trap::UnknownStruct(1)))
+    );
+
+    let mut runtime = AllotRuntime::new_arc(instructions.clone());
+    runtime
+        .registers
+        .insert(R1, Type::Struct(0, vec![Type::Int(1)]))
+        .unwrap();
+    assert_eq!(
+        AllotRuntime::restore(instructions, runtime.snapshot().unwrap()).err(),
+        Some(SnapshotError::InvalidRecord(Trap::UnknownStruct(0)))
+    );
+}
+
+/// Puts the value in the heap and returns the pointer to it.
+fn push<T: Any + Send>(heap: &mut Heap, t: T) -> usize {
+    match heap.push(t).unwrap() {
+        Type::Pointer(p) => p,
+        t => panic!("expected a pointer, found {t:?}"),
+    }
+}
+
+#[test]
+fn heap_pointers() {
+    let mut heap = Heap::new();
+    let p = push(&mut heap, Type::UInt(1));
+    assert_eq!(heap.get::<Type>(p), Ok(&Type::UInt(1)));
+    assert!(matches!(
+        heap.take::<String>(p),
+        Err(Trap::HeapTypeMismatch { pointer, .. }) if pointer == p
+    ));
+    assert_eq!(heap.update(p, Type::UInt(2)), Ok(()));
+    assert_eq!(heap.take::<Type>(p), Ok(Box::new(Type::UInt(2))));
+
+    // The slot is used again, but the old pointer stays freed.
+    let q = push(&mut heap, Type::UInt(3));
+    assert_ne!(p, q);
+    assert!(!heap.is_live(p));
+    assert_eq!(heap.get::<Type>(p), Err(Trap::DanglingPointer(p)));
+    assert_eq!(heap.free(p), Err(Trap::DanglingPointer(p)));
+    assert_eq!(heap.get::<Type>(q), Ok(&Type::UInt(3)));
+    assert_eq!(heap.free(123), Err(Trap::InvalidPointer(123)));
+    assert_eq!(heap.len(), 1);
+}
+
+#[test]
+fn heap_free_drops() {
+    let value = Arc::new(());
+    let mut heap = Heap::new();
+    let p = push(&mut heap, value.clone());
+    heap.push(value.clone()).unwrap();
+    assert_eq!(Arc::strong_count(&value), 3);
+
+    heap.free(p).unwrap();
+    assert_eq!(Arc::strong_count(&value), 2);
+    drop(heap);
+    assert_eq!(Arc::strong_count(&value), 1);
+
+    // Joining a freed thread handle traps instead of reading freed memory.
+    let mut runtime = AllotRuntime::new(vec![
+        PushFrame(false),
+        ThreadCreate(Type::Address(6)),
+        Cpy(R1, R5),
+        Call("heap::free".to_string()),
+        ThreadJoin(R1),
+        Exit(Type::Int32(0)),
+        Exit(Type::Int32(1)),
+    ]);
+    match runtime.run().unwrap_err().trap {
+        Trap::DanglingPointer(p) => assert!(CrossHeap::is_shared(p)),
+        trap => panic!("{trap:?}"),
+    }
+}
+
+#[test]
+fn heap_values() {
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::String("a".to_string())),
+        Call("heap::alloc".to_string()),
+        Cpy(R1, R5),
+        Call("heap::load".to_string()),
+        Assert(R5, Type::String("a".to_string())),
+        Cpy(R5, R1),
+        Mov(R6, Type::UInt(1)),
+        Call("heap::store".to_string()),
+        Mov(R6, Type::UInt(2)),
+        Call("heap::swap".to_string()),
+        Assert(R6, Type::UInt(1)),
+        Call("heap::load".to_string()),
+        Assert(R5, Type::UInt(2)),
+        Cpy(R5, R1),
+        Call("heap::is_live".to_string()),
+        Assert(R5, Type::Boolean(true)),
+        Cpy(R5, R1),
+        Call("heap::free".to_string()),
+        Call("heap::is_live".to_string()),
+        Assert(R5, Type::Boolean(false)),
+        Cpy(R5, R1),
+        Call("heap::load".to_string()),
+        Exit(Type::Int32(0)),
+    ]);
+
+    let err = runtime.run().unwrap_err();
+    assert!(matches!(err.trap, Trap::DanglingPointer(_)));
+    assert_eq!(err.current, 21);
+}
+
+#[test]
+fn thread_joined_by_another_thread() {
+    let mut runtime = AllotRuntime::new(vec![
+        PushFrame(false),
+        ThreadCreate(Type::Address(7)),
+        PushFrame(false),
+        Push(R5),
+        ThreadCreate(Type::Address(8)),
+        ThreadJoin(R5),
+        Exit(Type::Register(R5)),
+        // first thread
+        Exit(Type::Int32(7)),
+        // second thread, joins the first
+        Pop(Some(R1)),
+        ThreadJoin(R1),
+        Exit(Type::Register(R5)),
+    ]);
+
+    assert_eq!(runtime.run(), Ok(7));
+    assert!(runtime.heap.shared.lock().unwrap().is_empty());
+}
+
+#[test]
+fn heap_shared_between_threads() {
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::UInt(40)),
+        Call("heap::alloc".to_string()),
+        Call("heap::share".to_string()),
+        Cpy(R1, R5),
+        PushFrame(false),
+        PushCpy(R1),
+        ThreadCreate(Type::Address(15)),
+        ThreadJoin(R5),
+        Cpy(R5, R1),
+        Call("heap::claim".to_string()),
+        Call("heap::load".to_string()),
+        Assert(R5, Type::UInt(42)),
+        Cpy(R5, R1),
+        Call("heap::is_live".to_string()),
+        Exit(Type::Int32(512)),
+        // thread
+        Pop(Some(R5)),
+        Mov(R6, Type::UInt(42)),
+        Call("heap::store".to_string()),
+        Exit(Type::Int32(0)),
+    ]);
+
+    assert_eq!(runtime.run(), Ok(512));
+    assert_eq!(runtime.registers.get(R5), Ok(&Type::Boolean(false)));
+    assert_eq!(runtime.heap.local.len(), 1);
+    assert!(runtime.heap.shared.lock().unwrap().is_empty());
+
+    // Each thread has its own heap.
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::UInt(40)),
+        Call("heap::alloc".to_string()),
+        PushFrame(false),
+        PushCpy(R5),
+        ThreadCreate(Type::Address(7)),
+        ThreadJoin(R5),
+        Exit(Type::Int32(0)),
+        // thread
+        Pop(Some(R5)),
+        Call("heap::load".to_string()),
+        Exit(Type::Int32(0)),
+    ]);
+    let err = runtime.run().unwrap_err();
+    match err.trap {
+        Trap::Thread(err) => assert!(matches!(err.trap, Trap::ForeignPointer(_))),
+        trap => panic!("{trap:?}"),
+    }
+
+    // Heap values that are not Types cannot be read by programs.
+    let mut runtime = AllotRuntime::new(vec![
+        PushFrame(false),
+        ThreadCreate(Type::Address(4)),
+        Call("heap::load".to_string()),
+        Exit(Type::Int32(0)),
+        Exit(Type::Int32(0)),
+    ]);
+    assert!(matches!(
+        runtime.run().unwrap_err().trap,
+        Trap::HeapTypeMismatch { .. }
+    ));
+}
+
+#[test]
+fn arrays() {
+    let array = |items: &[u32]| Type::Array(items.iter().map(|i| Type::UInt32(*i)).collect());
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::Array(vec![])),
+        Mov(R2, Type::UInt32(1)),
+        ArrPush(R1, R2),
+        Assert(R2, Type::None),
+        Mov(R2, Type::UInt32(2)),
+        ArrPush(R1, R2),
+        Mov(R2, Type::UInt32(3)),
+        ArrPush(R1, R2),
+        ArrLen(R3, R1),
+        Assert(R3, Type::UInt(3)),
+        ArrPop(R4, R1),
+        Assert(R4, Type::UInt32(3)),
+        Mov(R3, Type::UInt(1)),
+        ArrGet(R4, R1, Type::Register(R3)),
+        Assert(R4, Type::UInt32(2)),
+        Mov(R4, Type::UInt32(5)),
+        ArrSet(R1, Type::UInt(0), R4),
+        Assert(R1, array(&[5, 2])),
+        Mov(R2, array(&[6, 7])),
+        Op(Prim2(OpPrim2::Add), [R1, R2]),
+        Assert(R1, array(&[5, 2, 6, 7])),
+        ArrSlice(R1, Type::UInt(1), Type::UInt(3)),
+        Assert(R1, array(&[2, 6])),
+        Op(Prim2(OpPrim2::SameType), [R2, R1]),
+        Assert(R2, Type::Boolean(true)),
+        Mov(R5, Type::String("ab".to_string())),
+        Cast(R5, RawType::Array),
+        Assert(R5, Type::Array(vec![Type::Char('a'), Type::Char('b')])),
+        Cpy(R6, R5),
+        Cast(R6, RawType::String),
+        Assert(R6, Type::String("ab".to_string())),
+        Call("println".to_string()),
+        ArrGet(R4, R1, Type::UInt(2)),
+        Exit(Type::Int32(0)),
+    ]);
+
+    let captured = runtime.run_captured(b"");
+    assert_eq!(
+        captured.code.unwrap_err().trap,
+        Trap::IndexOutOfBounds { index: 2, len: 2 }
+    );
+    assert_eq!(captured.stdout, "['a', 'b']\n");
+
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::Array(vec![])),
+        ArrPop(R2, R1),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(runtime.run().unwrap_err().trap, Trap::ArrayEmpty);
+
+    // A pop into an invalid register leaves the array alone.
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, array(&[1])),
+        ArrPop(Register::None, R1),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(
+        runtime.run().unwrap_err().trap,
+        Trap::InvalidRegister(Register::None)
+    );
+    assert_eq!(runtime.registers.get(R1), Ok(&array(&[1])));
+
+    // A trapping set leaves the value in its register.
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, array(&[1])),
+        Mov(R2, Type::UInt32(2)),
+        ArrSet(R1, Type::UInt(1), R2),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(
+        runtime.run().unwrap_err().trap,
+        Trap::IndexOutOfBounds { index: 1, len: 1 }
+    );
+    assert_eq!(runtime.registers.get(R2), Ok(&Type::UInt32(2)));
+
+    // An array can be put into itself.
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, array(&[1])),
+        ArrPush(R1, R1),
+        ArrSet(R1, Type::UInt(0), R1),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(runtime.run(), Ok(0));
+    assert_eq!(
+        runtime.registers.get(R1),
+        Ok(&Type::Array(vec![
+            Type::Array(vec![Type::UInt32(1), array(&[1])]),
+            array(&[1])
+        ]))
+    );
+
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::UInt(0)),
+        ArrLen(R2, R1),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(
+        runtime.run().unwrap_err().trap,
+        Trap::UnexpectedType {
+            expected: "Array",
+            found: Type::UInt(0)
+        }
+    );
+}
+
+#[test]
+fn maps() {
+    let string = |s: &str| Type::String(s.to_string());
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::Map(BTreeMap::new())),
+        Mov(R6, string("b")),
+        Mov(R7, Type::UInt8(2)),
+        Call("map::insert".to_string()),
+        Assert(R6, Type::None),
+        Mov(R6, string("a")),
+        Mov(R7, Type::UInt8(1)),
+        Call("map::insert".to_string()),
+        Mov(R6, string("a")),
+        Mov(R7, Type::UInt8(3)),
+        Call("map::insert".to_string()),
+        Assert(R6, Type::UInt8(1)),
+        Call("map::keys".to_string()),
+        Assert(R6, Type::Array(vec![string("a"), string("b")])),
+        Call("println".to_string()),
+        // A map on the heap is changed in place.
+        Call("heap::alloc".to_string()),
+        Mov(R6, string("c")),
+        Call("map::get".to_string()),
+        Assert(R6, Type::None),
+        Mov(R6, string("b")),
+        Call("map::remove".to_string()),
+        Assert(R6, Type::UInt8(2)),
+        Mov(R6, string("b")),
+        Call("map::contains".to_string()),
+        Assert(R6, Type::Boolean(false)),
+        Call("map::len".to_string()),
+        Assert(R6, Type::UInt(1)),
+        Mov(R6, string("a")),
+        Call("map::get".to_string()),
+        Assert(R6, Type::UInt8(3)),
+        Mov(R6, Type::Float32(1.0)),
+        Call("map::get".to_string()),
+        Exit(Type::Int32(0)),
+    ]);
+
+    let captured = runtime.run_captured(b"");
+    assert_eq!(
+        captured.code.unwrap_err().trap,
+        Trap::UnexpectedType {
+            expected: "Int, UInt, Char, String, or Boolean key",
+            found: Type::Float32(1.0)
+        }
+    );
+    assert_eq!(captured.stdout, "{\"a\": 3, \"b\": 2}\n");
+
+    // Only new keys count against the limit.
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::Map(BTreeMap::new())),
+        Mov(R6, string("a")),
+        Mov(R7, Type::UInt8(1)),
+        Call("map::insert".to_string()),
+        Mov(R6, string("b")),
+        Call("map::insert".to_string()),
+        Mov(R6, string("a")),
+        Call("map::insert".to_string()),
+        Mov(R6, string("c")),
+        Call("map::insert".to_string()),
+        Exit(Type::Int32(0)),
+    ]);
+    runtime.set_limits(Limits {
+        collection_length: Some(2),
+        ..Limits::default()
+    });
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.trap, Trap::LimitExceeded(Limit::CollectionLength));
+    assert_eq!(err.current, 9);
+    assert!(matches!(runtime.registers.get(R5), Ok(Type::Map(map)) if map.len() == 2));
+}
+
+#[test]
+fn structs() {
+    let point = |x: f64, y: f64| Type::Struct(0, vec![Type::Float64(x), Type::Float64(y)]);
+    let structs = vec![
+        StructDef {
+            name: "Point".to_string(),
+            fields: vec![
+                ("x".to_string(), RawType::Float64),
+                ("y".to_string(), RawType::Float64),
+            ],
+        },
+        StructDef {
+            name: "Empty".to_string(),
+            fields: vec![],
+        },
+    ];
+    let run = |instructions: Vec<Instruction>| {
+        let mut runtime = AllotRuntime::new(instructions);
+        runtime.set_structs(structs.clone());
+        runtime.run_captured(b"")
+    };
+
+    let captured = run(vec![
+        Mov(
+            R1,
+            Type::Array(vec![Type::Float64(1.5), Type::Float64(2.0)]),
+        ),
+        StructNew(R1, 0),
+        Assert(R1, point(1.5, 2.0)),
+        FieldGet(R2, R1, 0, 1),
+        Assert(R2, Type::Float64(2.0)),
+        FieldSet(R1, 0, 0, R2),
+        Assert(R1, point(2.0, 2.0)),
+        Cpy(R5, R1),
+        Call("println".to_string()),
+        Mov(R2, Type::String("three".to_string())),
+        FieldSet(R1, 0, 1, R2),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(
+        captured.code.unwrap_err().trap,
+        Trap::FieldTypeMismatch {
+            field: "Point.y".to_string(),
+            expected: RawType::Float64,
+            found: Type::String("three".to_string())
+        }
+    );
+    assert_eq!(captured.stdout, "(2, 2)\n");
+
+    // Trapping instructions leave their registers as they were.
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::Array(vec![Type::Float64(1.0)])),
+        StructNew(R1, 0),
+        Exit(Type::Int32(0)),
+    ]);
+    runtime.set_structs(structs.clone());
+    assert!(runtime.run().is_err());
+    assert_eq!(
+        runtime.registers.get(R1),
+        Ok(&Type::Array(vec![Type::Float64(1.0)]))
+    );
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, point(1.0, 2.0)),
+        Mov(R2, Type::String("three".to_string())),
+        FieldSet(R1, 0, 1, R2),
+        Exit(Type::Int32(0)),
+    ]);
+    runtime.set_structs(structs.clone());
+    assert!(runtime.run().is_err());
+    assert_eq!(
+        runtime.registers.get(R2),
+        Ok(&Type::String("three".to_string()))
+    );
+
+    let trap = |instructions| run(instructions).code.unwrap_err().trap;
+    assert_eq!(
+        trap(vec![
+            Mov(R1, Type::Array(vec![Type::Float64(1.0)])),
+            StructNew(R1, 0),
+        ]),
+        Trap::FieldCount {
+            name: "Point".to_string(),
+            expected: 2,
+            found: 1
+        }
+    );
+    assert_eq!(
+        trap(vec![
+            Mov(R1, Type::Array(vec![])),
+            StructNew(R1, 1),
+            FieldGet(R2, R1, 0, 0),
+        ]),
+        Trap::StructMismatch {
+            expected: "Point".to_string(),
+            found: Type::Struct(1, vec![])
+        }
+    );
+    assert_eq!(
+        trap(vec![Mov(R1, Type::Array(vec![])), StructNew(R1, 2)]),
+        Trap::UnknownStruct(2)
+    );
+
+    // Records written into instructions are checked like StructNew.
+    assert_eq!(
+        trap(vec![Mov(
+            R1,
+            Type::Array(vec![Type::Struct(
+                0,
+                vec![Type::Float64(1.0), Type::UInt(2)]
+            )])
+        )]),
+        Trap::FieldTypeMismatch {
+            field: "Point.y".to_string(),
+            expected: RawType::Float64,
+            found: Type::UInt(2)
+        }
+    );
+    assert_eq!(
+        trap(vec![Mov(R1, Type::Struct(1, vec![Type::None]))]),
+        Trap::FieldCount {
+            name: "Empty".to_string(),
+            expected: 0,
+            found: 1
+        }
+    );
+}
+
+#[test]
+fn tagged() {
+    // Tag 0 is None, 1 is Some, every branch exits with its own code.
+    let run = |t: Type| {
+        AllotRuntime::new(vec![
+            Mov(R1, t),
+            Match(R2, R1, vec![5, 7]),
+            Assert(R2, Type::UInt8(5)), // Unmatched tags go on
+            Assert(R1, Type::None),
+            Exit(Type::Int32(10)),
+            Assert(R2, Type::None), // None
+            Exit(Type::Int32(20)),
+            Assert(R2, Type::UInt8(5)), // Some
+            Exit(Type::Int32(30)),
+        ])
+        .run()
+    };
+    assert_eq!(run(Type::Tagged(0, Box::new(Type::None))), Ok(20));
+    assert_eq!(run(Type::Tagged(1, Box::new(Type::UInt8(5)))), Ok(30));
+    assert_eq!(run(Type::Tagged(3, Box::new(Type::UInt8(5)))), Ok(10));
+    let err = run(Type::UInt8(0)).unwrap_err();
+    assert_eq!(
+        err.trap,
+        Trap::UnexpectedType {
+            expected: "Tagged",
+            found: Type::UInt8(0)
+        }
+    );
+    assert_eq!(err.current, 1);
+
+    // A trapping match leaves the registers as they were.
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::UInt8(0)),
+        Mov(R2, Type::UInt8(1)),
+        Match(R2, R1, vec![]),
+        Exit(Type::Int32(0)),
+    ]);
+    assert!(runtime.run().is_err());
+    assert_eq!(runtime.registers.get(R1), Ok(&Type::UInt8(0)));
+    assert_eq!(runtime.registers.get(R2), Ok(&Type::UInt8(1)));
+
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::UInt8(5)),
+        Tag(R1, 1),
+        Cpy(R5, R1),
+        Call("println".to_string()),
+        Assert(R1, Type::Tagged(1, Box::new(Type::UInt8(5)))),
+        Exit(Type::Int32(0)),
+    ]);
+    let captured = runtime.run_captured(b"");
+    assert_eq!(captured.code, Ok(0));
+    assert_eq!(captured.stdout, "#1(5)\n");
+}
+
+#[test]
+fn frame_passing() {
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::UInt8(1)),
+        Push(R1),
+        Mov(R1, Type::UInt8(2)),
+        Push(R1),
+        PushFrame(false),
+        TakeFrom,
+        Pop(Some(R2)),
+        Assert(R2, Type::UInt8(2)),
+        Mov(R1, Type::UInt8(3)),
+        Push(R1),
+        GiveTo,
+        PopFrame,
+        Pop(Some(R2)),
+        Assert(R2, Type::UInt8(3)),
+        Pop(Some(R2)),
+        Assert(R2, Type::UInt8(1)),
+        // Frames above an isolated frame can still pass values to it.
+        Push(R2),
+        PushFrame(true),
+        PushFrame(false),
+        TakeFrom,
+        Exit(Type::Int32(0)),
+    ]);
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.trap, Trap::StackEmpty);
+    assert_eq!(err.current, 19);
+    // The frame below the isolated one is left alone.
+    assert_eq!(runtime.stack_frames.len(), 3);
+    assert_eq!(runtime.stack_frames[0].values(), &[Type::UInt8(1)]);
+    assert!(runtime.stack_frames[1].is_isolated());
+
+    for i in [TakeFrom, GiveTo] {
+        let mut runtime = AllotRuntime::new(vec![
+            Push(R1),
+            PushFrame(true),
+            Push(R1),
+            i.clone(),
+            Exit(Type::Int32(0)),
+        ]);
+        assert_eq!(runtime.run().unwrap_err().trap, Trap::IsolatedStackFrame);
+
+        let snapshot = runtime.snapshot().unwrap();
+        let mut restored = AllotRuntime::restore(runtime.instructions.clone(), snapshot).unwrap();
+        assert!(restored.stack_frames[1].is_isolated());
+        assert_eq!(restored.run().unwrap_err().trap, Trap::IsolatedStackFrame);
+
+        let mut runtime = AllotRuntime::new(vec![i, Exit(Type::Int32(0))]);
+        assert_eq!(runtime.run().unwrap_err().trap, Trap::RootStackFrame);
+    }
+}
+
+#[test]
+fn thread_frame_hand_off() {
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R1, Type::UInt32(20)),
+        PushFrame(true),
+        Push(R1),
+        Mov(R1, Type::UInt32(22)),
+        Push(R1),
+        ThreadCreate(Type::Address(13)),
+        Assert(R1, Type::None),
+        ThreadJoin(R5),
+        Assert(R5, Type::Int32(0)),
+        Pop(Some(R2)),
+        PopFrame,
+        Assert(R2, Type::UInt32(42)),
+        Exit(Type::Int32(0)),
+        // Thread, the frame it was given is its root frame.
+        Pop(Some(R1)),
+        Pop(Some(R2)),
+        Op(Prim2(OpPrim2::Add), [R1, R2]),
+        Push(R1),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(runtime.run(), Ok(0));
+    assert_eq!(runtime.stack_frames.len(), 1);
+
+    // The frame is taken from the thread that creates it.
+    let mut runtime = AllotRuntime::new(vec![
+        Push(R1),
+        PushFrame(false),
+        ThreadCreate(Type::Address(4)),
+        Pop(None),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(runtime.run(), Ok(0));
+}
+
+#[test]
+fn channels() {
+    let call = |f: &str| Call(f.to_string());
+    let mut runtime = AllotRuntime::new(vec![
+        call("thread::channel"),
+        Cpy(R20, R6),
+        PushFrame(false), // Give the sender to the producer.
+        PushCpy(R5),
+        ThreadCreate(Type::Address(17)),
+        Cpy(R21, R5),
+        Mov(R1, Type::UInt32(0)),
+        Cpy(R5, R20), // Add up values until the channel closes.
+        call("thread::recv"),
+        Jmp(Some(R7), Type::Address(11)),
+        Jmp(None, Type::Address(13)),
+        Op(Prim2(OpPrim2::Add), [R1, R6]),
+        Jmp(None, Type::Address(7)),
+        ThreadJoin(R21),
+        PopFrame,
+        Assert(R1, Type::UInt32(6)),
+        Exit(Type::Int32(0)),
+        // Producer
+        Pop(Some(R5)),
+        Mov(R6, Type::UInt32(1)),
+        call("thread::send"),
+        Mov(R6, Type::UInt32(2)),
+        call("thread::send"),
+        Mov(R6, Type::UInt32(3)),
+        call("thread::send"),
+        call("heap::free"),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(runtime.run(), Ok(0));
+
+    let mut runtime = AllotRuntime::new(vec![
+        call("thread::channel"),
+        Cpy(R20, R5),
+        Cpy(R5, R6),
+        call("thread::try_recv"),
+        Assert(R7, Type::Boolean(false)),
+        Mov(R6, Type::UInt64(10)),
+        call("thread::recv_timeout"),
+        Assert(R7, Type::Boolean(false)),
+        call("heap::free"),
+        Cpy(R5, R20),
+        Mov(R6, Type::Int8(1)),
+        call("thread::send"),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(
+        runtime.run().unwrap_err().trap,
+        Trap::Library("thread::send failed, the channel is closed.".to_string())
+    );
+
+    // A thread waiting to receive does not hold up try_recv or send.
+    let mut runtime = AllotRuntime::new(vec![
+        call("thread::channel"),
+        Cpy(R20, R5),
+        Cpy(R21, R6),
+        PushFrame(false),
+        PushCpy(R6),
+        ThreadCreate(Type::Address(17)),
+        Cpy(R22, R5),
+        Mov(R5, Type::UInt64(20)),
+        call("thread::sleep"),
+        Cpy(R5, R21),
+        call("thread::try_recv"),
+        Assert(R7, Type::Boolean(false)),
+        Cpy(R5, R20),
+        Mov(R6, Type::UInt32(5)),
+        call("thread::send"),
+        ThreadJoin(R22),
+        Exit(Type::Int32(0)),
+        // Receiver
+        Pop(Some(R5)),
+        call("thread::recv"),
+        Assert(R6, Type::UInt32(5)),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(runtime.run(), Ok(0));
+}
+
+#[test]
+fn sync_mutex() {
+    let call = |f: &str| Call(f.to_string());
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::UInt32(0)),
+        call("sync::mutex"),
+        Cpy(R20, R5),
+        PushFrame(false),
+        PushCpy(R20),
+        ThreadCreate(Type::Address(19)),
+        Cpy(R21, R5),
+        PushFrame(false),
+        PushCpy(R20),
+        ThreadCreate(Type::Address(19)),
+        ThreadJoin(R5),
+        PopFrame,
+        ThreadJoin(R21),
+        PopFrame,
+        Cpy(R5, R20),
+        call("sync::lock"),
+        call("sync::load"),
+        Assert(R6, Type::UInt32(200)),
+        Exit(Type::Int32(0)),
+        // Adds 1 to the value 100 times.
+        Pop(Some(R20)),
+        Mov(R1, Type::UInt32(1)),
+        Mov(R2, Type::UInt32(0)),
+        Mov(R3, Type::UInt32(100)),
+        Cpy(R5, R20),
+        call("sync::lock"),
+        call("sync::load"),
+        Op(Prim2(OpPrim2::Add), [R6, R1]),
+        call("sync::store"),
+        call("sync::unlock"),
+        Op(Prim2(OpPrim2::Add), [R2, R1]),
+        Cpy(R4, R2),
+        Op(Prim2(OpPrim2::NotEqual), [R4, R3]),
+        Jmp(Some(R4), Type::Address(23)),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(runtime.run(), Ok(0));
+
+    let mut runtime = AllotRuntime::new(vec![
+        call("sync::mutex"),
+        call("sync::lock"),
+        call("sync::lock"),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(
+        runtime.run().unwrap_err().trap,
+        Trap::Library("sync::lock failed, the lock is already held by this thread.".to_string())
+    );
+}
+
+#[test]
+fn sync_abandoned_lock() {
+    let call = |f: &str| Call(f.to_string());
+    let mut runtime = AllotRuntime::new(vec![
+        call("sync::mutex"),
+        Cpy(R20, R5),
+        call("thread::channel"),
+        Cpy(R21, R6),
+        PushFrame(false),
+        PushCpy(R20),
+        PushCpy(R5),
+        ThreadCreate(Type::Address(12)),
+        Cpy(R5, R21), // Wait until the thread holds the lock.
+        call("thread::recv"),
+        Cpy(R5, R20),
+        call("sync::lock"),
+        // Takes the lock and exits without releasing it.
+        Pop(Some(R22)),
+        Pop(Some(R5)),
+        call("sync::lock"),
+        Cpy(R5, R22),
+        Mov(R6, Type::Boolean(true)),
+        call("thread::send"),
+        Mov(R5, Type::UInt64(20)),
+        call("thread::sleep"),
+        Exit(Type::Int32(0)),
+    ]);
+    let err = runtime.run().unwrap_err();
+    assert!(matches!(err.trap, Trap::AbandonedLock(_)));
+    assert_eq!(err.current, 11);
+
+    // Locks are held by a runtime, not by the OS thread it ran on.
+    let mut holder = AllotRuntime::new(vec![
+        call("sync::mutex"),
+        call("sync::lock"),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(holder.run(), Ok(0));
+    let mutex = holder.registers.get(R5).unwrap().clone();
+    let lock = || {
+        let mut runtime = AllotRuntime::new(vec![
+            Mov(R5, mutex.clone()),
+            call("sync::lock"),
+            Exit(Type::Int32(0)),
+        ]);
+        runtime.heap.shared = holder.heap.shared.clone();
+        runtime.set_limits(Limits {
+            time: Some(Duration::from_millis(50)),
+            ..Limits::default()
+        });
+        runtime.run().unwrap_err().trap
+    };
+    assert_eq!(lock(), Trap::LimitExceeded(Limit::Time));
+    let shared = holder.heap.shared.clone();
+    drop(holder);
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, mutex.clone()),
+        call("sync::lock"),
+        Exit(Type::Int32(0)),
+    ]);
+    runtime.heap.shared = shared;
+    assert!(matches!(
+        runtime.run().unwrap_err().trap,
+        Trap::AbandonedLock(_)
+    ));
+
+    // A condition variable that is never notified.
+    let mut runtime = AllotRuntime::new(vec![
+        call("sync::mutex"),
+        Cpy(R6, R5),
+        call("sync::lock"),
+        call("sync::condvar"),
+        call("sync::wait"),
+        Exit(Type::Int32(0)),
+    ]);
+    runtime.set_limits(Limits {
+        time: Some(Duration::from_millis(50)),
+        ..Limits::default()
+    });
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.trap, Trap::LimitExceeded(Limit::Time));
+    assert_eq!(err.current, 4);
+}
+
+#[test]
+fn sync_condvar_and_barrier() {
+    let call = |f: &str| Call(f.to_string());
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::Boolean(false)),
+        call("sync::mutex"),
+        Cpy(R20, R5),
+        call("sync::condvar"),
+        Cpy(R21, R5),
+        Cpy(R5, R20),
+        call("sync::lock"),
+        PushFrame(false),
+        PushCpy(R20),
+        PushCpy(R21),
+        ThreadCreate(Type::Address(22)),
+        Cpy(R22, R5),
+        Cpy(R5, R20), // Wait until the value is true.
+        call("sync::load"),
+        Jmp(Some(R6), Type::Address(19)),
+        Cpy(R5, R21),
+        Cpy(R6, R20),
+        call("sync::wait"),
+        Jmp(None, Type::Address(12)),
+        Cpy(R5, R20),
+        call("sync::unlock"),
+        call("sync::unlock"),
+        // Sets the value to true.
+        Pop(Some(R21)),
+        Pop(Some(R5)),
+        call("sync::lock"),
+        Mov(R6, Type::Boolean(true)),
+        call("sync::store"),
+        call("sync::unlock"),
+        Cpy(R5, R21),
+        call("sync::notify_one"),
+        Exit(Type::Int32(0)),
+    ]);
+    let err = runtime.run().unwrap_err();
+    assert_eq!(
+        err.trap,
+        Trap::Library("sync::unlock failed, the lock is not held by this thread.".to_string())
+    );
+    assert_eq!(err.current, 21);
+
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::UInt(2)),
+        call("sync::barrier"),
+        Cpy(R20, R5),
+        PushFrame(false),
+        PushCpy(R20),
+        ThreadCreate(Type::Address(11)),
+        Cpy(R21, R5),
+        Cpy(R5, R20),
+        call("sync::barrier_wait"),
+        ThreadJoin(R21),
+        Exit(Type::Int32(0)),
+        Pop(Some(R5)),
+        call("sync::barrier_wait"),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(runtime.run(), Ok(0));
+}
+
+#[test]
+fn atomics() {
+    let call = |f: &str| Call(f.to_string());
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::UInt64(0)),
+        call("atomic::new"),
+        Cpy(R20, R5),
+        PushFrame(false),
+        PushCpy(R20),
+        ThreadCreate(Type::Address(23)),
+        Cpy(R21, R5),
+        PushFrame(false),
+        PushCpy(R20),
+        ThreadCreate(Type::Address(23)),
+        ThreadJoin(R5),
+        ThreadJoin(R21),
+        Cpy(R5, R20),
+        call("atomic::load"),
+        Assert(R6, Type::UInt64(200)),
+        Mov(R6, Type::UInt64(200)),
+        Mov(R7, Type::UInt64(7)),
+        call("atomic::compare_exchange"),
+        Assert(R7, Type::Boolean(true)),
+        Mov(R6, Type::UInt64(200)),
+        Mov(R7, Type::UInt64(8)),
+        call("atomic::compare_exchange"),
+        Exit(Type::Int32(0)),
+        // Adds 1 to the atomic 100 times.
+        Pop(Some(R5)),
+        Mov(R1, Type::UInt32(1)),
+        Mov(R2, Type::UInt32(0)),
+        Mov(R3, Type::UInt32(100)),
+        Mov(R6, Type::UInt64(1)),
+        call("atomic::fetch_add"),
+        Op(Prim2(OpPrim2::Add), [R2, R1]),
+        Cpy(R4, R2),
+        Op(Prim2(OpPrim2::NotEqual), [R4, R3]),
+        Jmp(Some(R4), Type::Address(27)),
+        Exit(Type::Int32(0)),
+    ]);
+    assert_eq!(runtime.run(), Ok(0));
+    assert_eq!(runtime.registers.get(R6), Ok(&Type::UInt64(7)));
+    assert_eq!(runtime.registers.get(R7), Ok(&Type::Boolean(false)));
+
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::Int8(i8::MAX - 1)),
+        call("atomic::new"),
+        Mov(R6, Type::Int8(1)),
+        call("atomic::fetch_add"),
+        Assert(R6, Type::Int8(i8::MAX - 1)),
+        Mov(R6, Type::Int8(0)),
+        call("atomic::swap"),
+        Assert(R6, Type::Int8(i8::MAX)),
+        Mov(R6, Type::Int8(2)),
+        call("atomic::fetch_sub"),
+        call("atomic::load"),
+        Assert(R6, Type::Int8(-2)),
+        Mov(R6, Type::UInt8(2)),
+        call("atomic::store"),
+        Exit(Type::Int32(0)),
+    ]);
+    let err = runtime.run().unwrap_err();
+    assert_eq!(
+        err.trap,
+        Trap::UnexpectedType {
+            expected: "the integer type of the atomic",
+            found: Type::UInt8(2)
+        }
+    );
+    assert_eq!(err.current, 13);
+
+    // Overflow traps instead of wrapping, and leaves the atomic as it was.
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::UInt8(0)),
+        call("atomic::new"),
+        Mov(R6, Type::UInt8(1)),
+        call("atomic::fetch_sub"),
+        Exit(Type::Int32(0)),
+    ]);
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.trap, Trap::Overflow);
+    assert_eq!(err.current, 3);
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::Int8(i8::MAX)),
+        call("atomic::new"),
+        Cpy(R20, R5),
+        Mov(R6, Type::Int8(1)),
+        call("atomic::fetch_add"),
+        Exit(Type::Int32(0)),
+        Cpy(R5, R20),
+        call("atomic::load"),
+        Assert(R6, Type::Int8(i8::MAX)),
+        Exit(Type::Int32(0)),
+    ]);
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.trap, Trap::Overflow);
+    assert_eq!(err.current, 4);
+    runtime.current = 6;
+    assert_eq!(runtime.run(), Ok(0));
+
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::Float64(1.0)),
+        call("atomic::new"),
+        Exit(Type::Int32(0)),
+    ]);
+    assert!(matches!(
+        runtime.run().unwrap_err().trap,
+        Trap::UnexpectedType { .. }
+    ));
+
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::Int32(1)),
+        call("atomic::new"),
+        Mov(R6, Type::Int32(1)),
+        Mov(R7, Type::Int64(2)),
+        call("atomic::compare_exchange"),
+        Exit(Type::Int32(0)),
+    ]);
+    let err = runtime.run().unwrap_err();
+    assert_eq!(
+        err.trap,
+        Trap::UnexpectedType {
+            expected: "the integer type of the atomic",
+            found: Type::Int64(2)
+        }
+    );
+
+    // The old pointer to a cell this thread has used stops working once the
+    // cell is moved, even though the cell is still alive.
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::UInt8(3)),
+        call("atomic::new"),
+        Cpy(R20, R5),
+        call("atomic::load"),
+        call("heap::claim"),
+        Cpy(R21, R5),
+        call("atomic::load"),
+        Assert(R6, Type::UInt8(3)),
+        Cpy(R5, R20),
+        call("atomic::load"),
+        Exit(Type::Int32(0)),
+    ]);
+    let err = runtime.run().unwrap_err();
+    assert!(matches!(err.trap, Trap::DanglingPointer(_)));
+    assert_eq!(err.current, 9);
+
+    // A freed cell whose slot has been used again.
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::UInt8(3)),
+        call("atomic::new"),
+        Cpy(R20, R5),
+        call("atomic::load"),
+        call("heap::free"),
+        Mov(R5, Type::UInt8(4)),
+        call("atomic::new"),
+        Cpy(R5, R20),
+        call("atomic::load"),
+        Exit(Type::Int32(0)),
+    ]);
+    let err = runtime.run().unwrap_err();
+    assert!(matches!(err.trap, Trap::DanglingPointer(_)));
+    assert_eq!(err.current, 8);
+}
+
+#[test]
+fn green_threads() {
+    let call = |f: &str| Call(f.to_string());
+    let printer = |s: &str, start: usize| {
+        vec![
+            Mov(R5, Type::String(s.to_string())),
+            Mov(R1, Type::UInt32(1)),
+            Mov(R2, Type::UInt32(0)),
+            Mov(R3, Type::UInt32(20)),
+            call("print"),
+            Op(Prim2(OpPrim2::Add), [R2, R1]),
+            Cpy(R4, R2),
+            Op(Prim2(OpPrim2::NotEqual), [R4, R3]),
+            Jmp(Some(R4), Type::Address(start + 4)),
+            Exit(Type::Int32(0)),
+        ]
+    };
+    let mut instructions = vec![
+        PushFrame(false),
+        ThreadCreate(Type::Address(8)),
+        Cpy(R20, R5),
+        PushFrame(false),
+        ThreadCreate(Type::Address(18)),
+        ThreadJoin(R5),
+        ThreadJoin(R20),
+        Exit(Type::Int32(0)),
+    ];
+    instructions.extend(printer("a", 8));
+    instructions.extend(printer("b", 18));
+    let instructions = Arc::new(instructions);
+
+    let run = |seed| {
+        let mut runtime = AllotRuntime::new_arc(instructions.clone());
+        runtime.threading = Threading::Green { seed, slice: 2 };
+        let captured = runtime.run_captured(b"");
+        assert_eq!(captured.code, Ok(0));
+        captured.stdout
+    };
+    let first = run(1);
+    assert_eq!(first.matches('a').count(), 20);
+    assert_eq!(first.matches('b').count(), 20);
+    assert!(first.contains("ab") && first.contains("ba"));
+    assert_eq!(run(1), first);
+    assert_ne!(run(2), first);
+
+    // Thousands of threads each add 1 to an atomic.
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::UInt64(0)),
+        call("atomic::new"),
+        Cpy(R20, R5),
+        Mov(R1, Type::UInt(1)),
+        Mov(R2, Type::UInt(0)),
+        Mov(R3, Type::UInt(5000)),
+        PushFrame(false),
+        PushCpy(R20),
+        ThreadCreate(Type::Address(26)),
+        PushCpy(R5),
+        Op(Prim2(OpPrim2::Add), [R2, R1]),
+        Cpy(R4, R2),
+        Op(Prim2(OpPrim2::NotEqual), [R4, R3]),
+        Jmp(Some(R4), Type::Address(6)),
+        Mov(R3, Type::UInt(0)),
+        Pop(Some(R5)),
+        ThreadJoin(R5),
+        PopFrame,
+        Op(Prim2(OpPrim2::Subtract), [R2, R1]),
+        Cpy(R4, R2),
+        Op(Prim2(OpPrim2::NotEqual), [R4, R3]),
+        Jmp(Some(R4), Type::Address(15)),
+        Cpy(R5, R20),
+        call("atomic::load"),
+        Assert(R6, Type::UInt64(5000)),
+        Exit(Type::Int32(0)),
+        // thread
+        Pop(Some(R5)),
+        Mov(R6, Type::UInt64(1)),
+        call("atomic::fetch_add"),
+        Exit(Type::Int32(0)),
+    ]);
+    runtime.threading = Threading::Green { seed: 0, slice: 3 };
+    assert_eq!(runtime.run(), Ok(0));
+}
+
+#[test]
+fn green_threads_wait() {
+    let call = |f: &str| Call(f.to_string());
+    let green = Threading::Green { seed: 5, slice: 10 };
+
+    // The clock moves while the main thread waits for the sleeping one.
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::UInt8(0)),
+        call("atomic::new"),
+        Cpy(R20, R5),
+        PushFrame(false),
+        PushCpy(R20),
+        ThreadCreate(Type::Address(12)),
+        Cpy(R5, R20),
+        call("atomic::load"),
+        Mov(R1, Type::UInt8(0)),
+        Op(Prim2(OpPrim2::Equal), [R6, R1]),
+        Jmp(Some(R6), Type::Address(6)),
+        Exit(Type::Int32(0)),
+        // thread
+        Pop(Some(R20)),
+        Mov(R5, Type::UInt64(20)),
+        call("thread::sleep"),
+        Cpy(R5, R20),
+        Mov(R6, Type::UInt8(1)),
+        call("atomic::store"),
+        Exit(Type::Int32(0)),
+    ]);
+    runtime.threading = green;
+    assert_eq!(runtime.run(), Ok(0));
+
+    // A lock held across a sleep, and a barrier.
+    let mut runtime = AllotRuntime::new(vec![
+        Mov(R5, Type::UInt(2)),
+        call("sync::barrier"),
+        Cpy(R21, R5),
+        call("sync::mutex"),
+        Cpy(R20, R5),
+        PushFrame(false),
+        PushCpy(R20),
+        PushCpy(R21),
+        ThreadCreate(Type::Address(17)),
+        Cpy(R22, R5),
+        Cpy(R5, R21),
+        call("sync::barrier_wait"),
+        Cpy(R5, R20),
+        call("sync::lock"),
+        call("sync::load"),
+        ThreadJoin(R22),
+        Exit(Type::Int32(0)),
+        // thread
+        Pop(Some(R21)),
+        Pop(Some(R20)),
+        Cpy(R5, R20),
+        call("sync::lock"),
+        Cpy(R5, R21),
+        call("sync::barrier_wait"),
+        Mov(R5, Type::UInt64(5)),
+        call("thread::sleep"),
+        Cpy(R5, R20),
+        Mov(R6, Type::UInt32(3)),
+        call("sync::store"),
+        call("sync::unlock"),
+        Exit(Type::Int32(0)),
+    ]);
+    runtime.threading = green;
+    assert_eq!(runtime.run(), Ok(0));
+    assert_eq!(runtime.registers.get(R6), Ok(&Type::UInt32(3)));
+
+    // Nothing will ever be sent.
+    let mut runtime = AllotRuntime::new(vec![
+        call("thread::channel"),
+        Cpy(R5, R6),
+        call("thread::recv"),
+        Exit(Type::Int32(0)),
+    ]);
+    runtime.threading = green;
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.trap, Trap::Deadlock);
+    assert_eq!(err.current, 2);
+}
+
+#[test]
+fn green_threads_retry() {
+    let green = Threading::Green { seed: 3, slice: 1 };
+    // The main thread waits on the join while the thread runs 51 instructions.
+    let mut instructions = vec![
+        PushFrame(false),
+        ThreadCreate(Type::Address(4)),
+        ThreadJoin(R5),
+        Exit(Type::Int32(0)),
+    ];
+    instructions.extend(vec![Nop; 50]);
+    instructions.push(Exit(Type::Int32(0)));
+    let instructions = Arc::new(instructions);
+
+    // Only the try of the join that finishes uses fuel.
+    let run = |fuel| {
+        let mut runtime = AllotRuntime::new_arc(instructions.clone());
+        runtime.threading = green;
+        runtime.set_limits(Limits {
+            fuel: Some(fuel),
+            ..Limits::default()
+        });
+        runtime.run().map_err(|e| e.trap)
+    };
+    assert_eq!(run(55), Ok(0));
+    assert!(run(54).is_err());
+
+    // And is profiled and traced.
+    let profiler = Arc::new(Profiler::new());
+    let records = Arc::new(Records::default());
+    let mut runtime = AllotRuntime::new_arc(instructions.clone());
+    runtime.threading = green;
+    runtime.set_profiler(profiler.clone());
+    runtime.set_tracer(records.clone());
+    assert_eq!(runtime.run(), Ok(0));
+    let profile = profiler.profile();
+    assert_eq!(profile.counts.get(&2), Some(&1));
+    assert_eq!(profile.counts.values().sum::<u64>(), 55);
+    let records = records.0.lock().unwrap();
+    assert_eq!(records.len(), 55);
+    assert!(records.iter().all(|r| r.trap.is_none()));
+
+    // A thread that panics is joined as one, and the others keep running.
+    let mut runtime = AllotRuntime::new(vec![
+        PushFrame(false),
+        ThreadCreate(Type::Address(6)),
+        Cpy(R20, R5),
+        PushFrame(false),
+        ThreadCreate(Type::Address(8)),
+        Jmp(None, Type::Address(10)),
+        Call("host::panic".to_string()),
+        Exit(Type::Int32(1)),
+        Nop,
+        Exit(Type::Int32(2)),
+        ThreadJoin(R5),
+        Assert(R5, Type::Int32(2)),
+        ThreadJoin(R20),
+        Exit(Type::Int32(0)),
+    ]);
+    runtime.register_function("host::panic", |_, _, _, _| panic!("host function failed"));
+    runtime.threading = green;
+    let err = runtime.run().unwrap_err();
+    assert_eq!(err.trap, Trap::ThreadPanicked);
+    assert_eq!(err.current, 12);
 }
@@ -1,11 +1,16 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
-use clap::Parser;
+use allot_runtime::Limits;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Args {
-    pub path: PathBuf,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[arg(required = true)]
+    pub path: Option<PathBuf>,
     /// Compile an allot_asm file to bytecode. (Requires the asm feature)
     #[arg(short, long)]
     pub asm: bool,
@@ -13,4 +18,101 @@ pub struct Args {
     /// feature)
     #[arg(short, long)]
     pub run: bool,
+    #[command(flatten)]
+    pub run_args: RunArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run an allot_asm or bytecode file. allot_asm files are compiled in
+    /// memory, and their labels are used in profiles. (Requires the asm
+    /// feature for allot_asm files)
+    Run {
+        path: PathBuf,
+        #[command(flatten)]
+        run_args: RunArgs,
+    },
+    /// Run a program in the step debugger. Labels and lines can be used as
+    /// breakpoints for allot_asm files. (Requires the asm feature)
+    Debug {
+        path: PathBuf,
+        /// A file the program reads as its stdin. The debugger reads commands
+        /// from stdin, so without this the program reads nothing.
+        #[arg(long, value_name = "FILE")]
+        input: Option<PathBuf>,
+        #[command(flatten)]
+        limits: LimitArgs,
+    },
+}
+
+#[derive(clap::Args)]
+pub struct RunArgs {
+    /// Write every instruction the program runs to a file as JSON Lines.
+    #[arg(long, value_name = "FILE")]
+    pub trace: Option<PathBuf>,
+    /// Count every instruction the program runs, then print a summary and
+    /// write folded call stacks for flamegraph tools.
+    #[arg(long)]
+    pub profile: bool,
+    /// Where to write the folded call stacks. Defaults to the program path
+    /// with a .folded extension.
+    #[arg(long, value_name = "FILE", requires = "profile")]
+    pub folded: Option<PathBuf>,
+    /// Run threads as green threads on one OS thread, in an order picked with
+    /// the seed. The same seed always runs threads in the same order.
+    #[arg(long, value_name = "SEED")]
+    pub green: Option<u64>,
+    /// How many instructions a green thread runs before the next one is
+    /// picked.
+    #[arg(
+        long,
+        value_name = "INSTRUCTIONS",
+        default_value_t = 100,
+        requires = "green"
+    )]
+    pub slice: usize,
+    #[command(flatten)]
+    pub limits: LimitArgs,
+}
+
+#[derive(clap::Args)]
+pub struct LimitArgs {
+    /// Maximum number of instructions to run, across all threads.
+    #[arg(long, value_name = "INSTRUCTIONS")]
+    pub fuel: Option<u64>,
+    /// Maximum number of stack frames per thread.
+    #[arg(long, value_name = "FRAMES")]
+    pub max_stack_frames: Option<usize>,
+    /// Maximum number of values in a stack frame.
+    #[arg(long, value_name = "VALUES")]
+    pub max_stack_values: Option<usize>,
+    /// Maximum number of live heap entries.
+    #[arg(long, value_name = "ENTRIES")]
+    pub max_heap_entries: Option<usize>,
+    /// Maximum length of a string in bytes.
+    #[arg(long, value_name = "BYTES")]
+    pub max_string_length: Option<usize>,
+    /// Maximum number of items in an array or map.
+    #[arg(long, value_name = "ITEMS")]
+    pub max_collection_length: Option<usize>,
+    /// Maximum number of threads the program can create.
+    #[arg(long, value_name = "THREADS")]
+    pub max_threads: Option<usize>,
+    /// Maximum time the program can run for, in milliseconds.
+    #[arg(long, value_name = "MILLISECONDS")]
+    pub timeout: Option<u64>,
+}
+impl LimitArgs {
+    pub fn limits(&self) -> Limits {
+        Limits {
+            fuel: self.fuel,
+            stack_frames: self.max_stack_frames,
+            stack_values: self.max_stack_values,
+            heap_entries: self.max_heap_entries,
+            string_length: self.max_string_length,
+            collection_length: self.max_collection_length,
+            threads: self.max_threads,
+            time: self.timeout.map(Duration::from_millis),
+        }
+    }
 }
@@ -0,0 +1,552 @@
+use std::{
+    collections::BTreeSet,
+    io::{BufRead, Write},
+};
+
+#[cfg(feature = "asm")]
+use allot_asm::Symbols;
+use allot_runtime::{AllotRuntime, Instruction, Register, Trap, Type};
+use anyhow::Result;
+
+const HELP: &str = "\
+commands:
+    s, step [n]          run the next n instructions (default 1)
+    n, next              run until the next instruction, stepping over calladdr
+    c, continue          run until a breakpoint or the end of the program
+    b, break <target>    add a breakpoint, target is an index, a label, or :line
+    d, delete <target>   remove a breakpoint
+    breaks               list breakpoints
+    l, list [n]          show n instructions around the current one (default 5)
+    r, registers         print registers that are not none
+    stack                print every stack frame
+    heap                 print live heap entries
+    set <reg> <value>    set a register, value is written like allot_asm (i32(5))
+    h, help              show this message
+    q, quit              stop debugging";
+
+/// A command typed into the debugger.
+#[derive(Debug, PartialEq)]
+enum Command {
+    Step(usize),
+    Next,
+    Continue,
+    Break(String),
+    Delete(String),
+    Breaks,
+    List(usize),
+    Registers,
+    Stack,
+    Heap,
+    Set(String, String),
+    Help,
+    Quit,
+}
+impl Command {
+    /// Parses a line of input, None if it is empty. Errors are messages for
+    /// the user.
+    fn parse(line: &str) -> Result<Option<Self>, String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            None => return Ok(None),
+            Some(c) => c,
+        };
+        let arg = words.collect::<Vec<&str>>().join(" ");
+
+        Ok(Some(match command {
+            "s" | "step" if arg.is_empty() => Command::Step(1),
+            "s" | "step" => match arg.parse() {
+                Ok(n) => Command::Step(n),
+                Err(_) => return Err("step takes a number of instructions".to_string()),
+            },
+            "n" | "next" => Command::Next,
+            "c" | "continue" => Command::Continue,
+            "b" | "break" => Command::Break(arg),
+            "d" | "delete" => Command::Delete(arg),
+            "breaks" => Command::Breaks,
+            "l" | "list" => Command::List(arg.parse().unwrap_or(5)),
+            "r" | "registers" => Command::Registers,
+            "stack" => Command::Stack,
+            "heap" => Command::Heap,
+            "set" => match arg.split_once(' ') {
+                None => return Err("set takes a register and a value".to_string()),
+                Some((register, value)) => {
+                    Command::Set(register.to_string(), value.trim().to_string())
+                }
+            },
+            "h" | "help" => Command::Help,
+            "q" | "quit" => Command::Quit,
+            _ => return Err(format!("unknown command `{command}`, try help")),
+        }))
+    }
+}
+
+/// Why the debugger stopped running the program.
+enum Stop {
+    Step,
+    Breakpoint,
+    Exit(i32),
+    Trap,
+}
+
+/// A step debugger built on AllotRuntime::tick. Only the main thread is
+/// debugged, threads created by the program run freely.
+pub struct Debugger<W: Write> {
+    runtime: AllotRuntime,
+    #[cfg(feature = "asm")]
+    symbols: Option<Symbols>,
+    breakpoints: BTreeSet<usize>,
+    /// Set once the program exits or traps.
+    finished: bool,
+    out: W,
+}
+impl<W: Write> Debugger<W> {
+    pub fn new(runtime: AllotRuntime, out: W) -> Self {
+        Self {
+            runtime,
+            #[cfg(feature = "asm")]
+            symbols: None,
+            breakpoints: BTreeSet::new(),
+            finished: false,
+            out,
+        }
+    }
+
+    #[cfg(feature = "asm")]
+    pub fn with_symbols(mut self, symbols: Symbols) -> Self {
+        self.symbols = Some(symbols);
+        self
+    }
+
+    /// Reads commands until quit or the end of input. Returns the exit code of
+    /// the program if it exited.
+    pub fn run<R: BufRead>(&mut self, mut input: R) -> Result<Option<i32>> {
+        let mut exit = None;
+        self.location()?;
+
+        loop {
+            write!(self.out, "(allot) ")?;
+            self.out.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(exit);
+            }
+            let command = match Command::parse(&line) {
+                Ok(None) => continue,
+                Ok(Some(command)) => command,
+                Err(e) => {
+                    writeln!(self.out, "{e}")?;
+                    continue;
+                }
+            };
+
+            let stop = match command {
+                Command::Step(n) => self.step(n)?,
+                Command::Next => self.next()?,
+                Command::Continue => self.resume(|_| false)?,
+                Command::Break(target) => {
+                    match self.address(&target) {
+                        None => writeln!(self.out, "unknown breakpoint target `{target}`")?,
+                        Some(address) => {
+                            self.breakpoints.insert(address);
+                            writeln!(self.out, "breakpoint at {}", self.describe(address))?;
+                        }
+                    }
+                    continue;
+                }
+                Command::Delete(target) => {
+                    match self.address(&target) {
+                        Some(address) if self.breakpoints.remove(&address) => {
+                            writeln!(self.out, "removed breakpoint at {address}")?
+                        }
+                        _ => writeln!(self.out, "no breakpoint at `{target}`")?,
+                    }
+                    continue;
+                }
+                Command::Breaks => {
+                    for address in self.breakpoints.clone() {
+                        writeln!(self.out, "    {}", self.describe(address))?;
+                    }
+                    continue;
+                }
+                Command::List(n) => {
+                    self.list(n)?;
+                    continue;
+                }
+                Command::Registers => {
+                    self.registers()?;
+                    continue;
+                }
+                Command::Stack => {
+                    self.stack()?;
+                    continue;
+                }
+                Command::Heap => {
+                    self.heap()?;
+                    continue;
+                }
+                Command::Set(register, value) => {
+                    self.set(&register, &value)?;
+                    continue;
+                }
+                Command::Help => {
+                    writeln!(self.out, "{HELP}")?;
+                    continue;
+                }
+                Command::Quit => return Ok(exit),
+            };
+
+            match stop {
+                None => writeln!(self.out, "the program is not running")?,
+                Some(Stop::Exit(code)) => {
+                    writeln!(self.out, "program exited with code {code}")?;
+                    exit = Some(code);
+                }
+                Some(Stop::Trap) => {}
+                Some(Stop::Breakpoint) => {
+                    writeln!(self.out, "breakpoint")?;
+                    self.location()?;
+                }
+                Some(Stop::Step) => self.location()?,
+            }
+        }
+    }
+
+    /// Runs one instruction.
+    fn tick(&mut self) -> Result<Option<Stop>> {
+        match self.runtime.tick() {
+            Ok(None) => Ok(None),
+            Ok(Some(code)) => {
+                self.finished = true;
+                Ok(Some(Stop::Exit(code)))
+            }
+            Err(err) => {
+                self.finished = true;
+                writeln!(self.out, "error: {err}")?;
+                Ok(Some(Stop::Trap))
+            }
+        }
+    }
+
+    fn step(&mut self, n: usize) -> Result<Option<Stop>> {
+        if self.finished {
+            return Ok(None);
+        }
+        for _ in 0..n {
+            if let Some(stop) = self.tick()? {
+                return Ok(Some(stop));
+            }
+        }
+        Ok(Some(Stop::Step))
+    }
+
+    fn next(&mut self) -> Result<Option<Stop>> {
+        let current = self.runtime.current;
+        match self.runtime.instructions.get(current) {
+            Some(Instruction::CallAddr(_)) => {
+                // Returned once the stack is back to how it was before the call.
+                let frames = self.runtime.stack_frames.len();
+                let values = self.top_frame_len();
+                let stop = self.resume(|d| {
+                    d.runtime.current == current + 1
+                        && d.runtime.stack_frames.len() == frames
+                        && d.top_frame_len() == values
+                })?;
+                Ok(stop)
+            }
+            _ => self.step(1),
+        }
+    }
+
+    /// Runs until a breakpoint, the end of the program, or until done returns
+    /// true. The breakpoint at the current instruction is skipped.
+    fn resume(&mut self, done: impl Fn(&Self) -> bool) -> Result<Option<Stop>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        loop {
+            if let Some(stop) = self.tick()? {
+                return Ok(Some(stop));
+            }
+            if done(self) {
+                return Ok(Some(Stop::Step));
+            }
+            if self.breakpoints.contains(&self.runtime.current) {
+                return Ok(Some(Stop::Breakpoint));
+            }
+        }
+    }
+
+    fn top_frame_len(&self) -> usize {
+        self.runtime.stack_frames.last().map_or(0, |f| f.len())
+    }
+
+    /// Finds the address of an index, label, or :line.
+    fn address(&self, target: &str) -> Option<usize> {
+        if let Ok(address) = target.parse::<usize>() {
+            return Some(address);
+        }
+
+        #[cfg(feature = "asm")]
+        if let Some(symbols) = &self.symbols {
+            return match target.strip_prefix(':') {
+                Some(line) => symbols.address_of_line(line.parse().ok()?),
+                None => symbols.label(target),
+            };
+        }
+
+        None
+    }
+
+    /// The address with its source line and labels, if known.
+    fn describe(&self, address: usize) -> String {
+        #[allow(unused_mut)]
+        let mut s = address.to_string();
+
+        #[cfg(feature = "asm")]
+        if let Some(symbols) = &self.symbols {
+            if let Some(line) = symbols.line_of(address) {
+                s.push_str(&format!(" (line {line}"));
+                for label in symbols.labels_at(address) {
+                    s.push_str(&format!(", {label}"));
+                }
+                s.push(')');
+            }
+        }
+
+        s
+    }
+
+    fn location(&mut self) -> Result<()> {
+        let current = self.runtime.current;
+        match self.runtime.instructions.get(current) {
+            None => writeln!(self.out, "=> {}: <no instruction>", current)?,
+            Some(i) => writeln!(self.out, "=> {}: {:?}", self.describe(current), i)?,
+        }
+        Ok(())
+    }
+
+    fn list(&mut self, around: usize) -> Result<()> {
+        let current = self.runtime.current;
+        let start = current.saturating_sub(around);
+        let end = (current + around + 1).min(self.runtime.instructions.len());
+
+        for address in start..end {
+            let marker = match (address == current, self.breakpoints.contains(&address)) {
+                (true, _) => "=>",
+                (false, true) => " *",
+                (false, false) => "  ",
+            };
+            writeln!(
+                self.out,
+                "{marker} {}: {:?}",
+                self.describe(address),
+                self.runtime.instructions[address]
+            )?;
+        }
+        Ok(())
+    }
+
+    fn registers(&mut self) -> Result<()> {
+        for (i, t) in self.runtime.registers.snapshot().iter().enumerate() {
+            if *t != Type::None {
+                writeln!(self.out, "    r{} = {:?}", i, t)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn stack(&mut self) -> Result<()> {
+        for (i, frame) in self.runtime.stack_frames.iter().enumerate() {
+            writeln!(self.out, "frame {i}:")?;
+            for (j, t) in frame.values().iter().rev().enumerate() {
+                writeln!(self.out, "    {j}: {:?}", t)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn heap(&mut self) -> Result<()> {
+        let heap = &self.runtime.heap;
+        let shared = heap.shared.lock().unwrap();
+        for (region, heap) in [("thread", &heap.local), ("shared", &*shared)] {
+            let pointers = heap.pointers();
+            writeln!(self.out, "{} live {region} entries", pointers.len())?;
+            for p in pointers {
+                match heap.get::<Type>(p) {
+                    Ok(t) => writeln!(self.out, "    {p:X} = {t:?}")?,
+                    // Entries that are not values, like channels.
+                    Err(Trap::HeapTypeMismatch { found, .. }) => {
+                        writeln!(self.out, "    {p:X} = <{found}>")?
+                    }
+                    Err(trap) => writeln!(self.out, "    {p:X}: {trap}")?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, register: &str, value: &str) -> Result<()> {
+        let register = register
+            .strip_prefix('r')
+            .and_then(|r| r.parse::<u8>().ok())
+            .and_then(|r| Register::try_from(r).ok());
+        let register = match register {
+            None => {
+                writeln!(self.out, "invalid register")?;
+                return Ok(());
+            }
+            Some(r) => r,
+        };
+
+        let value = match parse_value(value) {
+            None => {
+                writeln!(self.out, "invalid value")?;
+                return Ok(());
+            }
+            Some(v) => v,
+        };
+        if let Err(trap) = self.runtime.registers.insert(register, value) {
+            writeln!(self.out, "could not set register: {trap}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a value with the allot_asm type syntax.
+#[cfg(feature = "asm")]
+fn parse_value(value: &str) -> Option<Type> {
+    match allot_asm::compile(format!("mov r0 {value}")).ok()?.pop()? {
+        Instruction::Mov(_, t) => Some(t),
+        _ => None,
+    }
+}
+
+#[cfg(not(feature = "asm"))]
+fn parse_value(_value: &str) -> Option<Type> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use allot_runtime::{OpPrim1, Operation};
+
+    use super::*;
+
+    fn debugger(instructions: Vec<Instruction>) -> Debugger<Vec<u8>> {
+        Debugger::new(AllotRuntime::new(instructions), Vec::new())
+    }
+
+    fn output(debugger: &Debugger<Vec<u8>>) -> String {
+        String::from_utf8_lossy(&debugger.out).into_owned()
+    }
+
+    fn increment() -> Instruction {
+        Instruction::Op(
+            Operation::Prim1(OpPrim1::Increment),
+            [Register::R1, Register::R1],
+        )
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("  \n"), Ok(None));
+        assert_eq!(Command::parse("s\n"), Ok(Some(Command::Step(1))));
+        assert_eq!(Command::parse("step 12"), Ok(Some(Command::Step(12))));
+        assert!(Command::parse("step many").is_err());
+        assert_eq!(
+            Command::parse("b  loop"),
+            Ok(Some(Command::Break("loop".to_string())))
+        );
+        assert_eq!(
+            Command::parse("delete :4"),
+            Ok(Some(Command::Delete(":4".to_string())))
+        );
+        assert_eq!(Command::parse("l"), Ok(Some(Command::List(5))));
+        assert_eq!(Command::parse("list 2"), Ok(Some(Command::List(2))));
+        assert_eq!(
+            Command::parse("set r3 i32(5)"),
+            Ok(Some(Command::Set("r3".to_string(), "i32(5)".to_string())))
+        );
+        assert!(Command::parse("set r3").is_err());
+        assert_eq!(Command::parse("q"), Ok(Some(Command::Quit)));
+        assert!(Command::parse("jump 3").is_err());
+    }
+
+    #[test]
+    fn breakpoints_and_steps() {
+        let mut debugger = debugger(vec![
+            Instruction::Mov(Register::R1, Type::UInt(0)),
+            increment(),
+            increment(),
+            increment(),
+            Instruction::Exit(Type::Int32(7)),
+        ]);
+
+        debugger.run("b 3\nc\n".as_bytes()).unwrap();
+        assert_eq!(debugger.runtime.current, 3);
+        assert_eq!(
+            debugger.runtime.registers.get(Register::R1),
+            Ok(&Type::UInt(2))
+        );
+        assert!(output(&debugger).contains("breakpoint\n=> 3: "));
+
+        // Continuing runs past the breakpoint it stopped at.
+        assert_eq!(debugger.run("c\n".as_bytes()).unwrap(), Some(7));
+        debugger.run("s\n".as_bytes()).unwrap();
+        assert!(output(&debugger).ends_with("the program is not running\n(allot) "));
+    }
+
+    #[test]
+    fn steps() {
+        let mut debugger = debugger(vec![
+            Instruction::Mov(Register::R1, Type::UInt(0)),
+            increment(),
+            increment(),
+            Instruction::Exit(Type::Int32(7)),
+        ]);
+
+        debugger.run("s 2\n".as_bytes()).unwrap();
+        assert_eq!(debugger.runtime.current, 2);
+        debugger.run("b 3\nd 3\nstep\n".as_bytes()).unwrap();
+        assert_eq!(debugger.runtime.current, 3);
+        assert!(debugger.breakpoints.is_empty());
+        assert_eq!(debugger.run("s 5\n".as_bytes()).unwrap(), Some(7));
+    }
+
+    #[test]
+    fn next_steps_over_calls() {
+        let mut debugger = debugger(vec![
+            Instruction::Mov(Register::R1, Type::UInt(0)),
+            Instruction::CallAddr(Type::Address(3)),
+            Instruction::Exit(Type::Int32(0)),
+            increment(),
+            increment(),
+            Instruction::Ret,
+        ]);
+
+        debugger.run("n\nn\n".as_bytes()).unwrap();
+        assert_eq!(debugger.runtime.current, 2);
+        assert_eq!(
+            debugger.runtime.registers.get(Register::R1),
+            Ok(&Type::UInt(2))
+        );
+    }
+
+    #[test]
+    fn heap_values() {
+        let mut debugger = debugger(vec![
+            Instruction::Mov(Register::R5, Type::Int32(5)),
+            Instruction::Call("heap::alloc".to_string()),
+            Instruction::Call("thread::channel".to_string()),
+            Instruction::Exit(Type::Int32(0)),
+        ]);
+
+        debugger.run("s 3\nheap\n".as_bytes()).unwrap();
+        let output = output(&debugger);
+        assert!(output.contains("1 live thread entries\n    0 = Int32(5)\n"));
+        assert!(output.contains("2 live shared entries\n"));
+        assert!(output.contains("Sender"));
+    }
+}
@@ -1,29 +1,61 @@
-use std::fs;
+use std::{
+    fs,
+    fs::File,
+    io,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use allot_runtime::AllotRuntime;
+use allot_runtime::{
+    AllotRuntime, Instruction, Io, JsonLinesTracer, Profiler, RuntimeError, StructDef, Threading,
+    Type,
+};
 use anyhow::Result;
 use clap::Parser;
 #[cfg(feature = "mimalloc")]
 use mimalloc::MiMalloc;
 
+use crate::{
+    args::{Command, LimitArgs, RunArgs},
+    debug::Debugger,
+};
+
 mod args;
+mod debug;
 
 #[cfg(feature = "mimalloc")]
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
+/// Exit code used when a program is stopped by a trap.
+const TRAP_EXIT_CODE: i32 = 70;
+/// Rows in each table of a profile summary.
+const PROFILE_TOP: usize = 10;
+
 /// File Exts: asm: .ala, bytecode (program): .allot
 fn main() -> Result<()> {
     let args = args::Args::parse();
-    let mut path: std::path::PathBuf = args.path;
+    if let Some(command) = args.command {
+        return match command {
+            Command::Run { path, run_args } => run_file(path, run_args),
+            Command::Debug {
+                path,
+                input,
+                limits,
+            } => debug(path, input, limits),
+        };
+    }
+    let mut path = args
+        .path
+        .expect("clap requires a path without a subcommand");
 
     if args.asm || args.run {
         // Compile asm
         #[cfg(feature = "asm")]
         {
-            let file = fs::read_to_string(&path)?;
-            let instructions = allot_asm::compile(file);
-            let bytecode = allot_bytecode::gen(instructions);
+            let (instructions, structs, _) = compile(&path)?;
+            let bytecode = allot_bytecode::gen_with_structs(&instructions, &structs);
 
             if path.set_extension("allot") {
                 fs::write(&path, bytecode)?;
@@ -40,10 +72,169 @@ fn main() -> Result<()> {
     if !args.asm || args.run {
         // Run
         let bytecode = fs::read(&path)?;
-        let instructions = allot_bytecode::parse(bytecode);
-        let mut runtime = AllotRuntime::new(instructions);
-        runtime.run();
+        let (instructions, structs) = allot_bytecode::parse_with_structs(bytecode)?;
+        run(
+            &path,
+            (instructions, structs),
+            Box::new(|a| a.to_string()),
+            &args.run_args,
+        )?;
     }
 
     Ok(())
 }
+
+/// Runs an allot_asm file with its labels, or a bytecode file.
+fn run_file(path: PathBuf, run_args: RunArgs) -> Result<()> {
+    let is_asm = path.extension().is_some_and(|ext| ext == "ala");
+
+    if is_asm {
+        #[cfg(feature = "asm")]
+        {
+            let (instructions, structs, symbols) = compile(&path)?;
+            let names = Box::new(move |a| match symbols.labels_at(a).first() {
+                None => a.to_string(),
+                Some(label) => format!("{label}@{a}"),
+            });
+            run(&path, (instructions, structs), names, &run_args)
+        }
+
+        #[cfg(not(feature = "asm"))]
+        anyhow::bail!("The asm feature is not enabled.");
+    }
+    else {
+        let program = allot_bytecode::parse_with_structs(fs::read(&path)?)?;
+        run(&path, program, Box::new(|a| a.to_string()), &run_args)
+    }
+}
+
+/// Runs the program and exits with its exit code. Names is used to show
+/// addresses in profiles.
+fn run(
+    path: &Path,
+    (instructions, structs): (Vec<Instruction>, Vec<StructDef>),
+    names: Box<dyn Fn(usize) -> String>,
+    run_args: &RunArgs,
+) -> Result<()> {
+    let mut runtime = AllotRuntime::new(instructions);
+    runtime.set_structs(structs);
+    runtime.set_limits(run_args.limits.limits());
+    if let Some(seed) = run_args.green {
+        runtime.threading = Threading::Green {
+            seed,
+            slice: run_args.slice,
+        };
+    }
+
+    let tracer = match &run_args.trace {
+        None => None,
+        Some(trace) => {
+            let tracer = Arc::new(JsonLinesTracer::new(BufWriter::new(File::create(trace)?)));
+            runtime.set_tracer(tracer.clone());
+            Some(tracer)
+        }
+    };
+    let profiler = match run_args.profile {
+        false => None,
+        true => {
+            let profiler = Arc::new(Profiler::new());
+            runtime.set_profiler(profiler.clone());
+            Some(profiler)
+        }
+    };
+
+    let result = runtime.run();
+    // Adds the profile of the main runtime to the profiler.
+    drop(runtime);
+
+    if let Some(tracer) = tracer {
+        tracer.flush()?;
+    }
+    if let Some(profiler) = profiler {
+        let profile = profiler.profile();
+        eprintln!("{}", profile.summary(PROFILE_TOP, &names));
+
+        let folded = match &run_args.folded {
+            None => path.with_extension("folded"),
+            Some(folded) => folded.clone(),
+        };
+        fs::write(&folded, profile.folded(&names))?;
+        eprintln!("folded stacks written to {}", folded.display());
+    }
+
+    match result {
+        Ok(code) => std::process::exit(code),
+        Err(err) => {
+            print_trap(&err);
+            std::process::exit(TRAP_EXIT_CODE);
+        }
+    }
+}
+
+/// Compiles an allot_asm file, printing diagnostics if it cannot be compiled.
+#[cfg(feature = "asm")]
+fn compile(path: &Path) -> Result<(Vec<Instruction>, Vec<StructDef>, allot_asm::Symbols)> {
+    let file = fs::read_to_string(path)?;
+    match allot_asm::compile_with_structs(file.clone()) {
+        Ok(compiled) => Ok(compiled),
+        Err(diagnostics) => {
+            let name = path.display().to_string();
+            for d in &diagnostics {
+                eprintln!("{}", d.render(&file, &name));
+            }
+            anyhow::bail!("Could not compile due to {} error(s).", diagnostics.len());
+        }
+    }
+}
+
+/// Debugs an allot_asm file with its labels and lines, or a bytecode file.
+fn debug(path: PathBuf, input: Option<PathBuf>, limits: LimitArgs) -> Result<()> {
+    let is_asm = path.extension().is_some_and(|ext| ext == "ala");
+    // Commands are read from stdin, so the program gets its own.
+    let stdin: Box<dyn io::Read + Send> = match input {
+        None => Box::new(io::empty()),
+        Some(input) => Box::new(File::open(input)?),
+    };
+    let program_io = Io::new(stdin, Box::new(io::stdout()), Box::new(io::stderr()));
+
+    let mut debugger = if is_asm {
+        #[cfg(feature = "asm")]
+        {
+            let (instructions, structs, symbols) = compile(&path)?;
+            let mut runtime = AllotRuntime::new(instructions);
+            runtime.set_structs(structs);
+            runtime.set_limits(limits.limits());
+            runtime.set_io(program_io);
+            Debugger::new(runtime, io::stdout()).with_symbols(symbols)
+        }
+
+        #[cfg(not(feature = "asm"))]
+        anyhow::bail!("The asm feature is not enabled.");
+    }
+    else {
+        let (instructions, structs) = allot_bytecode::parse_with_structs(fs::read(&path)?)?;
+        let mut runtime = AllotRuntime::new(instructions);
+        runtime.set_structs(structs);
+        runtime.set_limits(limits.limits());
+        runtime.set_io(program_io);
+        Debugger::new(runtime, io::stdout())
+    };
+
+    debugger.run(io::stdin().lock())?;
+    Ok(())
+}
+
+fn print_trap(err: &RuntimeError) {
+    eprintln!("error: {}", err);
+    match &err.instruction {
+        None => eprintln!(" --> {}: <no instruction>", err.current),
+        Some(i) => eprintln!(" --> {}: {:?}", err.current, i),
+    }
+
+    eprintln!("registers:");
+    for (i, t) in err.registers.iter().enumerate() {
+        if *t != Type::None {
+            eprintln!("    r{} = {:?}", i, t);
+        }
+    }
+}